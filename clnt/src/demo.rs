@@ -0,0 +1,249 @@
+//! Recording and playback of demos.
+//!
+//! A demo contains every tick that we received from the server, together with
+//! the time at which we received it. Playing back a demo feeds the ticks into
+//! a `Runner` at the same times, so that rendering and interpolation behave
+//! exactly as they did when the demo was recorded.
+
+use std::time::Duration;
+
+use instant::Instant;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+
+use comn::util::diff::Diffable;
+
+use crate::runner::Runner;
+
+pub const MIN_SPEED: f32 = 0.125;
+pub const MAX_SPEED: f32 = 8.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Demo {
+    pub settings: comn::Settings,
+    pub my_player_id: Option<comn::PlayerId>,
+
+    /// Received ticks, with the receive time in seconds since the start of
+    /// the recording. Sorted by receive time.
+    pub ticks: Vec<(f32, comn::Tick)>,
+}
+
+impl Demo {
+    pub fn serialize(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        rmp_serde::from_read_ref(data).ok()
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.ticks.last().map_or(0.0, |(recv_time, _)| *recv_time)
+    }
+}
+
+pub struct Recorder {
+    start_time: Instant,
+    demo: Demo,
+}
+
+impl Recorder {
+    pub fn new(settings: comn::Settings, my_player_id: Option<comn::PlayerId>) -> Self {
+        info!("Starting demo recording");
+
+        Self {
+            start_time: Instant::now(),
+            demo: Demo {
+                settings,
+                my_player_id,
+                ticks: Vec::new(),
+            },
+        }
+    }
+
+    /// Records a state that we already have as a tick that is not delta
+    /// encoded.
+    pub fn record_state(&mut self, state: &comn::Game, my_last_input_num: Option<comn::TickNum>) {
+        let base_state = comn::Game::new(state.settings.clone());
+        let tick = comn::Tick {
+            diff_base: None,
+            diff: base_state.diff(state),
+            events: Vec::new(),
+            your_last_input_num: my_last_input_num,
+            send_interval: 1,
+        };
+
+        self.record_tick(Instant::now(), &tick);
+    }
+
+    pub fn record_tick(&mut self, recv_time: Instant, tick: &comn::Tick) {
+        // The receive time may be slightly before our start time, since
+        // messages are queued before we process them.
+        let recv_time = if recv_time > self.start_time {
+            recv_time.duration_since(self.start_time).as_secs_f32()
+        } else {
+            0.0
+        };
+
+        self.demo.ticks.push((recv_time, tick.clone()));
+    }
+
+    pub fn finish(self) -> Demo {
+        info!(
+            "Finished demo recording with {} ticks",
+            self.demo.ticks.len()
+        );
+
+        self.demo
+    }
+}
+
+/// Lets the browser download the `demo` as a file.
+pub fn download(demo: &Demo, file_name: &str) -> Result<(), JsValue> {
+    let data = js_sys::Uint8Array::from(demo.serialize().as_slice());
+    let parts = js_sys::Array::new();
+    parts.push(&data);
+
+    let mut options = web_sys::BlobPropertyBag::new();
+    options.type_("application/octet-stream");
+    let blob = web_sys::Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob)?;
+
+    let document = web_sys::window().unwrap().document().unwrap();
+    let anchor: web_sys::HtmlAnchorElement = document.create_element("a")?.dyn_into()?;
+    anchor.set_href(&url);
+    anchor.set_download(file_name);
+    anchor.click();
+
+    web_sys::Url::revoke_object_url(&url)
+}
+
+#[derive(Debug, Clone)]
+pub enum LoadError {
+    Fetch(JsValue),
+    ResponseStatus(u16),
+    Deserialize,
+}
+
+/// Loads a demo from the given `url`.
+pub async fn fetch(url: &str) -> Result<Demo, LoadError> {
+    info!("Loading demo from {}", url);
+
+    let window = web_sys::window().unwrap();
+    let resp_value = JsFuture::from(window.fetch_with_str(url))
+        .await
+        .map_err(LoadError::Fetch)?;
+    let resp: web_sys::Response = resp_value.dyn_into().unwrap();
+
+    if !resp.ok() {
+        return Err(LoadError::ResponseStatus(resp.status()));
+    }
+
+    let buffer = JsFuture::from(resp.array_buffer().map_err(LoadError::Fetch)?)
+        .await
+        .map_err(LoadError::Fetch)?;
+    let data = js_sys::Uint8Array::new(&buffer).to_vec();
+
+    Demo::deserialize(&data).ok_or(LoadError::Deserialize)
+}
+
+/// Playback of a demo with a virtual clock, which can be paused, sped up or
+/// moved around.
+pub struct Playback {
+    demo: Demo,
+    start_time: Instant,
+    time: f32,
+    next_index: usize,
+    paused: bool,
+    speed: f32,
+}
+
+impl Playback {
+    pub fn new(demo: Demo) -> Self {
+        Self {
+            demo,
+            start_time: Instant::now(),
+            time: 0.0,
+            next_index: 0,
+            paused: false,
+            speed: 1.0,
+        }
+    }
+
+    pub fn new_runner(&self) -> Runner {
+        Runner::new_playback(
+            self.demo.settings.clone(),
+            self.demo.my_player_id,
+            self.start_time,
+        )
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.demo.duration()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(MIN_SPEED).min(MAX_SPEED);
+    }
+
+    /// Advances the virtual clock by `dt`, feeding all the ticks that have
+    /// been received until then into the `runner`. Returns the virtual time
+    /// and time delta that should be passed to `Runner::update`.
+    pub fn update(&mut self, runner: &mut Runner, dt: Duration) -> (Instant, Duration) {
+        let dt = if self.paused || self.time >= self.duration() {
+            Duration::from_secs(0)
+        } else {
+            dt.mul_f32(self.speed)
+        };
+
+        self.time = (self.time + dt.as_secs_f32()).min(self.duration());
+        self.feed(runner);
+
+        (self.now(), dt)
+    }
+
+    /// Jumps to the given `time`. Since ticks are delta encoded, we cannot
+    /// start in the middle of the demo, so we reset the `runner` and feed it
+    /// all the ticks from the start.
+    pub fn seek(&mut self, runner: &mut Runner, time: f32) {
+        *runner = self.new_runner();
+        self.time = time.max(0.0).min(self.duration());
+        self.next_index = 0;
+        self.feed(runner);
+    }
+
+    fn now(&self) -> Instant {
+        self.start_time + Duration::from_secs_f32(self.time)
+    }
+
+    fn feed(&mut self, runner: &mut Runner) {
+        while let Some((recv_time, tick)) = self.demo.ticks.get(self.next_index) {
+            if *recv_time > self.time {
+                break;
+            }
+
+            let recv_time = self.start_time + Duration::from_secs_f32(*recv_time);
+            runner.record_server_tick(recv_time, tick.clone());
+            self.next_index += 1;
+        }
+    }
+}
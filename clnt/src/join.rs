@@ -23,13 +23,42 @@ pub async fn join_and_connect(
         .map_err(JoinAndConnectError::Request)?
         .map_err(JoinAndConnectError::Join)?;
 
+    connect(join_success, input).await
+}
+
+/// Resumes playing after we lost the connection, keeping our slot in the
+/// game.
+pub async fn reconnect_and_connect(
+    token: comn::PlayerToken,
+    input: &mut Input,
+) -> Result<Runner, JoinAndConnectError> {
+    let join_success = reconnect_request(token)
+        .await
+        .map_err(JoinAndConnectError::Request)?
+        .map_err(JoinAndConnectError::Join)?;
+
+    connect(join_success, input).await
+}
+
+async fn connect(
+    join_success: comn::JoinSuccess,
+    input: &mut Input,
+) -> Result<Runner, JoinAndConnectError> {
     let my_token = join_success.your_token;
     let on_message = Box::new(
         move |client_data: &webrtc::Data, message: &comn::ServerMessage| {
             on_message(my_token, client_data, message)
         },
     );
-    let webrtc_client = webrtc::Client::connect(Default::default(), on_message)
+    let webrtc_config = if let Some(address) = join_success.webrtc_session_url.clone() {
+        webrtc::Config {
+            address,
+            ..Default::default()
+        }
+    } else {
+        Default::default()
+    };
+    let webrtc_client = webrtc::Client::connect(webrtc_config, on_message)
         .await
         .map_err(JoinAndConnectError::WebRTC)?;
 
@@ -48,24 +77,101 @@ pub async fn join_and_connect(
 }
 
 pub async fn join_request(request: comn::JoinRequest) -> Result<comn::JoinReply, JsValue> {
-    let request_json = format!(
-        "{{\"game_id\":{},\"player_name\":\"{}\"}}",
-        request
-            .game_id
-            .map_or("null".to_owned(), |comn::GameId(id)| "\"".to_owned()
-                + &id.to_string()
-                + "\""),
-        request.player_name,
-    );
+    let request_json = to_json(&request)?;
+
+    info!("Requesting to join game: {} ...", request_json);
+
+    let reply = fetch_json("POST", "/join", Some(&request_json)).await?;
+
+    info!("Join reply: {:?}", reply);
+
+    // Use serde to parse the JSON into a struct.
+    Ok(reply.into_serde().unwrap())
+}
+
+pub async fn reconnect_request(token: comn::PlayerToken) -> Result<comn::JoinReply, JsValue> {
+    let request = comn::ReconnectRequest {
+        token,
+        protocol_version: comn::PROTOCOL_VERSION,
+    };
+    let request_json = to_json(&request)?;
+
+    info!("Requesting to reconnect: {} ...", request_json);
+
+    let reply = fetch_json("POST", "/reconnect", Some(&request_json)).await?;
+
+    info!("Reconnect reply: {:?}", reply);
+
+    Ok(reply.into_serde().unwrap())
+}
+
+/// Registers a new account if `register` is true, or logs in otherwise.
+pub async fn auth_request(
+    request: comn::AuthRequest,
+    register: bool,
+) -> Result<comn::AuthReply, JsValue> {
+    // Do not log the request, it contains the password.
+    let request_json = to_json(&request)?;
+    let url = if register { "/register" } else { "/login" };
 
+    let reply = fetch_json("POST", url, Some(&request_json)).await?;
+
+    info!("Auth reply: {:?}", reply);
+
+    Ok(reply.into_serde().unwrap())
+}
+
+pub async fn list_games() -> Result<Vec<comn::GameInfo>, JsValue> {
+    let reply = fetch_json("GET", "/games", None).await?;
+
+    Ok(reply.into_serde().unwrap())
+}
+
+pub async fn leaderboard(period: comn::LeaderboardPeriod) -> Result<comn::Leaderboard, JsValue> {
+    let url = match period {
+        comn::LeaderboardPeriod::Daily => "/leaderboards/daily",
+        comn::LeaderboardPeriod::Weekly => "/leaderboards/weekly",
+        comn::LeaderboardPeriod::AllTime => "/leaderboards/all_time",
+    };
+
+    let reply = fetch_json("GET", url, None).await?;
+
+    reply
+        .into_serde()
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+pub async fn create_game(
+    request: comn::CreateGameRequest,
+) -> Result<comn::CreateGameReply, JsValue> {
+    let request_json = format!("{{\"private\":{}}}", request.private);
+
+    info!("Requesting to create game: {} ...", request_json);
+
+    let reply = fetch_json("POST", "/create_game", Some(&request_json)).await?;
+
+    info!("Create game reply: {:?}", reply);
+
+    Ok(reply.into_serde().unwrap())
+}
+
+/// Serializes a request to JSON. Since names and passwords are typed in by
+/// the user, we let serde take care of escaping.
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String, JsValue> {
+    let value = JsValue::from_serde(value).map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+    js_sys::JSON::stringify(&value).map(String::from)
+}
+
+async fn fetch_json(method: &str, url: &str, body: Option<&str>) -> Result<JsValue, JsValue> {
     let mut opts = web_sys::RequestInit::new();
-    opts.method("POST");
+    opts.method(method);
     opts.mode(web_sys::RequestMode::SameOrigin);
-    opts.body(Some(&JsValue::from_str(&request_json)));
-
-    info!("Requesting to join game: {} ...", request_json);
+    if let Some(body) = body {
+        opts.body(Some(&JsValue::from_str(body)));
+    }
 
-    let request = web_sys::Request::new_with_str_and_init(&"/join", &opts)?;
+    let request = web_sys::Request::new_with_str_and_init(url, &opts)?;
     request.headers().set("Accept", "application/json")?;
 
     let window = web_sys::window().unwrap();
@@ -74,12 +180,7 @@ pub async fn join_request(request: comn::JoinRequest) -> Result<comn::JoinReply,
     let resp: web_sys::Response = resp_value.dyn_into().unwrap();
 
     // Convert this other `Promise` into a rust `Future`.
-    let reply = JsFuture::from(resp.json()?).await?;
-
-    info!("Join reply: {:?}", reply);
-
-    // Use serde to parse the JSON into a struct.
-    Ok(reply.into_serde().unwrap())
+    JsFuture::from(resp.json()?).await
 }
 
 pub fn on_message(
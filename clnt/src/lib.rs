@@ -1,10 +1,12 @@
+mod demo;
 mod join;
+mod menu;
 mod prediction;
 mod runner;
 mod view;
 mod webrtc;
 
-use std::{cell::RefCell, collections::HashSet, rc::Rc};
+use std::{cell::RefCell, collections::HashSet, rc::Rc, time::Duration};
 
 use wasm_bindgen::{
     prelude::{wasm_bindgen, Closure},
@@ -12,7 +14,7 @@ use wasm_bindgen::{
 };
 
 use instant::Instant;
-use log::info;
+use log::{info, warn};
 
 use quicksilver::{
     geom::{Rectangle, Transform, Vector},
@@ -25,6 +27,9 @@ use comn::util::stats;
 
 use crate::view::View;
 
+const DEMO_SEEK_STEP: f32 = 5.0;
+const RECONNECT_PERIOD: Duration = Duration::from_secs(2);
+
 const SCREEN_SIZE: Vector = Vector {
     x: 1280.0,
     y: 720.0,
@@ -47,7 +52,7 @@ pub fn main() {
     );
 }
 
-fn current_input(pressed_keys: &HashSet<Key>) -> comn::Input {
+fn current_input(pressed_keys: &HashSet<Key>, aim: Option<comn::Vector>) -> comn::Input {
     comn::Input {
         move_left: pressed_keys.contains(&Key::A),
         move_right: pressed_keys.contains(&Key::D),
@@ -56,9 +61,39 @@ fn current_input(pressed_keys: &HashSet<Key>) -> comn::Input {
         dash: pressed_keys.contains(&Key::Space),
         use_action: pressed_keys.contains(&Key::LShift),
         shoot: pressed_keys.contains(&Key::Q),
+        aim,
+        move_dir: None,
     }
 }
 
+fn query_param(name: &str) -> Option<String> {
+    let search = web_sys::window()?.location().search().ok()?;
+    let prefix = format!("{}=", name);
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .find(|param| param.starts_with(&prefix))
+        .map(|param| param[prefix.len()..].to_string())
+}
+
+/// A demo is played back by opening the page with a `?demo=<url>` query.
+fn demo_requested() -> Option<String> {
+    query_param("demo")
+}
+
+/// A private game is joined directly by opening the page with an
+/// `?invite=<code>` query.
+fn invite_requested() -> Option<String> {
+    query_param("invite")
+}
+
+/// Spectating is requested by opening the page with a `?spectate` query.
+fn spectate_requested() -> bool {
+    web_sys::window()
+        .and_then(|window| window.location().search().ok())
+        .map_or(false, |search| search.contains("spectate"))
+}
+
 // https://github.com/ryanisaacg/quicksilver/issues/628#issuecomment-670566767
 fn resize(gfx: &mut Graphics, window: &Window, prev_size: Vector) -> Vector {
     let size = window.size() * window.scale_factor();
@@ -82,18 +117,48 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
     info!("Starting up");
 
     let config = view::Config::default();
-    let resources = view::Resources::load(&mut gfx).await?;
+    let mut resources = view::Resources::load(&mut gfx).await?;
+    let mut invite_code = None;
 
     // TODO: Graceful error handling in client
-    let runner = join::join_and_connect(
-        comn::JoinRequest {
-            game_id: None,
-            player_name: "Pioneer".to_string(),
-        },
-        &mut input,
-    )
-    .await
-    .expect("Failed to connect");
+    let (runner, mut playback) = if let Some(demo_url) = demo_requested() {
+        let demo = demo::fetch(&demo_url).await.expect("Failed to load demo");
+        let playback = demo::Playback::new(demo);
+        (playback.new_runner(), Some(playback))
+    } else {
+        let player_name = "Pioneer".to_string();
+        let request = if let Some(code) = invite_requested() {
+            comn::JoinRequest {
+                game_id: None,
+                player_name,
+                spectate: spectate_requested(),
+                invite_code: Some(code),
+                session_token: None,
+                protocol_version: comn::PROTOCOL_VERSION,
+            }
+        } else {
+            resize(&mut gfx, &window, Vector::ZERO);
+            menu::Menu::new(player_name, spectate_requested())
+                .run(&window, &mut gfx, &mut input, &mut resources)
+                .await?
+        };
+        invite_code = request.invite_code.clone();
+
+        let runner = match join::join_and_connect(request, &mut input).await {
+            Ok(runner) => runner,
+            Err(err) => {
+                // Do not get stuck with an expired login when reloading.
+                if let join::JoinAndConnectError::Join(comn::JoinError::InvalidSessionToken) = err {
+                    menu::forget_session();
+                }
+                if let join::JoinAndConnectError::Join(comn::JoinError::IncompatibleVersion) = err {
+                    panic!("The server has been updated, please reload the page");
+                }
+                panic!("Failed to connect: {:?}", err);
+            }
+        };
+        (runner, None)
+    };
 
     let mut view = View::new(
         config,
@@ -109,8 +174,13 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
     let mut lag_frames: usize = 0;
 
     let mut pressed_keys: HashSet<Key> = HashSet::new();
+    let mut mouse_pos: Option<comn::Point> = None;
     let mut last_time = Instant::now();
 
+    // We stop trying to reconnect once the server no longer knows us.
+    let mut can_reconnect = true;
+    let mut last_reconnect_time: Option<Instant> = None;
+
     // Wrap the Runner in RefCell so that it can be used in Window callback
     let runner = Rc::new(RefCell::new(runner));
     let on_before_unload = Closure::wrap(Box::new({
@@ -132,6 +202,27 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
 
         while let Some(event) = input.next_event().await {
             match event {
+                Event::KeyboardInput(event) if view.chat_mut().is_typing() => {
+                    // While typing a chat message, keys do not control the
+                    // game.
+                    if event.is_down() {
+                        match event.key() {
+                            Key::Return => {
+                                if let Some(text) = view.chat_mut().finish_typing() {
+                                    runner
+                                        .borrow_mut()
+                                        .send_reliable(comn::ReliableClientMessage::Chat(text));
+                                }
+                            }
+                            Key::Escape => view.chat_mut().cancel_typing(),
+                            Key::Back => view.chat_mut().backspace(),
+                            _ => (),
+                        }
+                    }
+                }
+                Event::ReceivedCharacter(event) => {
+                    view.chat_mut().type_char(event.character());
+                }
                 Event::KeyboardInput(event) => {
                     if !pressed_keys.contains(&event.key()) {
                         match event.key() {
@@ -150,8 +241,50 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
                             Key::L => {
                                 lag_frames = 30;
                             }
+                            Key::Tab => {
+                                if let Some(state) = runner.borrow().state() {
+                                    view.follow_next_player(&state);
+                                }
+                            }
+                            Key::F => {
+                                if let Some(state) = runner.borrow().state() {
+                                    view.toggle_free_camera(&state);
+                                }
+                            }
+                            Key::Return if playback.is_none() && event.is_down() => {
+                                view.chat_mut().start_typing();
+                                pressed_keys.clear();
+                            }
+                            Key::R if playback.is_none() => {
+                                let mut runner = runner.borrow_mut();
+                                if let Some(demo) = runner.stop_recording() {
+                                    if let Err(err) = demo::download(&demo, "demo.catcheb") {
+                                        log::warn!("Failed to download demo: {:?}", err);
+                                    }
+                                } else {
+                                    runner.start_recording();
+                                }
+                            }
                             _ => (),
                         }
+
+                        if let Some(playback) = playback.as_mut() {
+                            let mut runner = runner.borrow_mut();
+                            match event.key() {
+                                Key::Space => playback.toggle_pause(),
+                                Key::Comma => {
+                                    let time = playback.time() - DEMO_SEEK_STEP;
+                                    playback.seek(&mut runner, time);
+                                }
+                                Key::Period => {
+                                    let time = playback.time() + DEMO_SEEK_STEP;
+                                    playback.seek(&mut runner, time);
+                                }
+                                Key::Minus => playback.set_speed(playback.speed() / 2.0),
+                                Key::Equals => playback.set_speed(playback.speed() * 2.0),
+                                _ => (),
+                            }
+                        }
                     }
 
                     if event.is_down() {
@@ -160,8 +293,12 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
                         pressed_keys.remove(&event.key());
                     }
                 }
+                Event::PointerMoved(event) => {
+                    mouse_pos = Some(comn::Point::new(event.location().x, event.location().y));
+                }
                 Event::FocusChanged(event) if !event.is_focused() => {
                     pressed_keys.clear();
+                    mouse_pos = None;
                 }
                 _ => (),
             }
@@ -171,6 +308,37 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
 
         window_size = resize(&mut gfx, &window, window_size);
 
+        // Try to get our slot back if we lost the connection.
+        let reconnect_token = {
+            let runner = runner.borrow();
+            if can_reconnect
+                && !runner.is_good()
+                && !runner.is_disconnected()
+                && last_reconnect_time.map_or(true, |time| time.elapsed() >= RECONNECT_PERIOD)
+            {
+                runner.my_token()
+            } else {
+                None
+            }
+        };
+        if let Some(token) = reconnect_token {
+            last_reconnect_time = Some(Instant::now());
+
+            match join::reconnect_and_connect(token, &mut input).await {
+                Ok(new_runner) => {
+                    info!("Reconnected");
+                    *runner.borrow_mut() = new_runner;
+                }
+                Err(join::JoinAndConnectError::Join(err)) => {
+                    warn!("Cannot reconnect: {:?}", err);
+                    can_reconnect = false;
+                }
+                Err(err) => {
+                    warn!("Failed to reconnect: {:?}", err);
+                }
+            }
+        }
+
         let mut runner = runner.borrow_mut();
 
         if lag_frames > 0 {
@@ -185,12 +353,20 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
         let game_events = if runner.is_good() {
             coarse_prof::profile!("update");
 
-            runner.update(start_time, last_dt, &current_input(&pressed_keys))
+            if let Some(playback) = playback.as_mut() {
+                let (demo_time, demo_dt) = playback.update(&mut runner, last_dt);
+                runner.update(demo_time, demo_dt, &comn::Input::default())
+            } else {
+                // We aim with the mouse once it has moved.
+                let aim = mouse_pos.and_then(|pos| view.aim_dir(pos));
+                runner.update(start_time, last_dt, &current_input(&pressed_keys, aim))
+            }
         } else {
             Vec::new()
         };
 
         let state = runner.state();
+        let chat_messages = runner.take_chat_messages();
 
         {
             coarse_prof::profile!("update_view");
@@ -205,6 +381,8 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
                 &pressed_keys,
                 state.as_ref(),
                 &game_events,
+                &chat_messages,
+                runner.kill_cam(),
                 runner.interp_game_time(),
             );
         }
@@ -227,12 +405,45 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
         if !runner.is_good() {
             view.resources_mut().font.draw(
                 &mut gfx,
-                "Lost connection to server",
+                if can_reconnect && !runner.is_disconnected() && runner.my_token().is_some() {
+                    "Lost connection to server, reconnecting..."
+                } else {
+                    "Lost connection to server"
+                },
                 Color::RED,
                 Vector::new(250.0, 25.0),
             )?;
         }
 
+        if let Some(playback) = playback.as_ref() {
+            view.resources_mut().font_small.draw(
+                &mut gfx,
+                &format!(
+                    "Demo {:.1}/{:.1}s x{}{} (Space: pause, ,/.: seek, -/=: speed)",
+                    playback.time(),
+                    playback.duration(),
+                    playback.speed(),
+                    if playback.is_paused() { " paused" } else { "" },
+                ),
+                Color::BLACK,
+                Vector::new(10.0, window.size().y * window.scale_factor() - 40.0),
+            )?;
+        } else if runner.is_recording() {
+            view.resources_mut().font_small.draw(
+                &mut gfx,
+                "Recording demo (R: stop and download)",
+                Color::RED,
+                Vector::new(10.0, window.size().y * window.scale_factor() - 40.0),
+            )?;
+        } else if let Some(invite_code) = invite_code.as_ref() {
+            view.resources_mut().font_small.draw(
+                &mut gfx,
+                &format!("Invite code: {}", invite_code),
+                Color::BLACK,
+                Vector::new(10.0, window.size().y * window.scale_factor() - 40.0),
+            )?;
+        }
+
         let mut debug_y: f32 = window.size().y * window.scale_factor() - 200.0;
         let mut debug = |s: &str| -> quicksilver::Result<()> {
             view.resources_mut().font_small.draw(
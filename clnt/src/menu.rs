@@ -0,0 +1,572 @@
+//! A simple menu for logging in, looking at the leaderboards and choosing
+//! which game to join.
+
+use std::time::Duration;
+
+use instant::Instant;
+use log::warn;
+
+use quicksilver::{
+    geom::Vector,
+    graphics::{Color, Graphics},
+    input::{Event, Input, Key},
+    Window,
+};
+
+use crate::{join, view::Resources};
+
+const REFRESH_PERIOD: Duration = Duration::from_secs(3);
+const LINE_HEIGHT: f32 = 24.0;
+const MAX_NAME_LEN: usize = 16;
+const MAX_PASSWORD_LEN: usize = 128;
+const SESSION_NAME_KEY: &str = "session_name";
+const SESSION_TOKEN_KEY: &str = "session_token";
+const LEADERBOARD_PERIODS: &[comn::LeaderboardPeriod] = &[
+    comn::LeaderboardPeriod::Daily,
+    comn::LeaderboardPeriod::Weekly,
+    comn::LeaderboardPeriod::AllTime,
+];
+const MAX_LEADERBOARD_LINES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Name,
+    Password,
+    InviteCode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Edit(Field),
+    Login,
+    Register,
+    Logout,
+    QuickJoin,
+    CreatePublic,
+    CreatePrivate,
+    Leaderboards,
+    Game(comn::GameId),
+}
+
+pub struct Menu {
+    player_name: String,
+    password: String,
+    invite_code: String,
+    spectate: bool,
+
+    /// Our account, if we are logged in.
+    session: Option<comn::AuthSuccess>,
+
+    games: Vec<comn::GameInfo>,
+    last_refresh_time: Option<Instant>,
+    selected: usize,
+
+    /// The field that we are currently typing in, if any.
+    editing: Option<Field>,
+
+    /// The last error, shown to the user.
+    status: Option<String>,
+
+    /// If set, we are showing the leaderboards instead of the games. The
+    /// index refers to `LEADERBOARD_PERIODS`.
+    leaderboard: Option<(usize, Option<comn::Leaderboard>)>,
+}
+
+impl Menu {
+    pub fn new(player_name: String, spectate: bool) -> Self {
+        Self {
+            player_name,
+            password: String::new(),
+            invite_code: String::new(),
+            spectate,
+            session: load_session(),
+            games: Vec::new(),
+            last_refresh_time: None,
+            selected: 0,
+            editing: None,
+            status: None,
+            leaderboard: None,
+        }
+    }
+
+    /// Shows the menu until the user has chosen a game, returning the
+    /// request with which we should join.
+    pub async fn run(
+        mut self,
+        window: &Window,
+        gfx: &mut Graphics,
+        input: &mut Input,
+        resources: &mut Resources,
+    ) -> quicksilver::Result<comn::JoinRequest> {
+        loop {
+            if self
+                .last_refresh_time
+                .map_or(true, |time| time.elapsed() >= REFRESH_PERIOD)
+            {
+                self.refresh().await;
+            }
+
+            while let Some(event) = input.next_event().await {
+                match event {
+                    Event::KeyboardInput(event) if event.is_down() => {
+                        if let Some(request) = self.handle_key(event.key()).await {
+                            return Ok(request);
+                        }
+                    }
+                    Event::ReceivedCharacter(event) => {
+                        self.type_char(event.character());
+                    }
+                    _ => (),
+                }
+            }
+
+            gfx.clear(Color::from_hex("D4D6B9"));
+            if let Some((period_index, leaderboard)) = self.leaderboard.as_ref() {
+                render_leaderboard(
+                    gfx,
+                    resources,
+                    LEADERBOARD_PERIODS[*period_index],
+                    leaderboard.as_ref(),
+                )?;
+            } else {
+                self.render(gfx, resources)?;
+            }
+            gfx.present(window)?;
+        }
+    }
+
+    fn items(&self) -> Vec<Item> {
+        let mut items = if self.session.is_some() {
+            vec![Item::Logout]
+        } else {
+            vec![
+                Item::Edit(Field::Name),
+                Item::Edit(Field::Password),
+                Item::Login,
+                Item::Register,
+            ]
+        };
+        items.extend(vec![
+            Item::QuickJoin,
+            Item::CreatePublic,
+            Item::CreatePrivate,
+            Item::Edit(Field::InviteCode),
+            Item::Leaderboards,
+        ]);
+        items.extend(self.games.iter().map(|game| Item::Game(game.game_id)));
+        items
+    }
+
+    async fn refresh(&mut self) {
+        match join::list_games().await {
+            Ok(games) => self.games = games,
+            Err(err) => warn!("Failed to list games: {:?}", err),
+        }
+
+        self.clamp_selected();
+        self.last_refresh_time = Some(Instant::now());
+    }
+
+    fn clamp_selected(&mut self) {
+        self.selected = self.selected.min(self.items().len() - 1);
+    }
+
+    fn join_request(
+        &self,
+        game_id: Option<comn::GameId>,
+        invite_code: Option<String>,
+    ) -> comn::JoinRequest {
+        comn::JoinRequest {
+            game_id,
+            player_name: self.player_name.clone(),
+            spectate: self.spectate,
+            invite_code,
+            session_token: self
+                .session
+                .as_ref()
+                .map(|session| session.session_token.clone()),
+            protocol_version: comn::PROTOCOL_VERSION,
+        }
+    }
+
+    fn field_mut(&mut self, field: Field) -> &mut String {
+        match field {
+            Field::Name => &mut self.player_name,
+            Field::Password => &mut self.password,
+            Field::InviteCode => &mut self.invite_code,
+        }
+    }
+
+    fn type_char(&mut self, c: char) {
+        let field = if let Some(field) = self.editing {
+            field
+        } else {
+            return;
+        };
+
+        let (accept, max_len) = match field {
+            Field::Name => (
+                c.is_ascii_alphanumeric() || c == '-' || c == '_',
+                MAX_NAME_LEN,
+            ),
+            Field::Password => (!c.is_control(), MAX_PASSWORD_LEN),
+            Field::InviteCode => (c.is_ascii_alphanumeric(), MAX_NAME_LEN),
+        };
+
+        let text = self.field_mut(field);
+        if accept && text.chars().count() < max_len {
+            text.push(if field == Field::InviteCode {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            });
+        }
+    }
+
+    async fn show_leaderboard(&mut self, period_index: usize) {
+        let period = LEADERBOARD_PERIODS[period_index];
+        let leaderboard = match join::leaderboard(period).await {
+            Ok(leaderboard) => Some(leaderboard),
+            Err(err) => {
+                warn!("Failed to load leaderboard: {:?}", err);
+                None
+            }
+        };
+
+        self.leaderboard = Some((period_index, leaderboard));
+    }
+
+    async fn handle_key(&mut self, key: Key) -> Option<comn::JoinRequest> {
+        if let Some((period_index, _)) = self.leaderboard {
+            let num_periods = LEADERBOARD_PERIODS.len();
+            match key {
+                Key::Left | Key::A => {
+                    self.show_leaderboard((period_index + num_periods - 1) % num_periods)
+                        .await
+                }
+                Key::Right | Key::D => {
+                    self.show_leaderboard((period_index + 1) % num_periods)
+                        .await
+                }
+                Key::Escape | Key::Return => self.leaderboard = None,
+                _ => (),
+            }
+
+            return None;
+        }
+
+        if let Some(field) = self.editing {
+            match key {
+                Key::Return => {
+                    self.editing = None;
+
+                    if field == Field::InviteCode && !self.invite_code.is_empty() {
+                        let invite_code = Some(self.invite_code.clone());
+                        return Some(self.join_request(None, invite_code));
+                    }
+                }
+                Key::Escape | Key::Tab => self.editing = None,
+                Key::Back => {
+                    self.field_mut(field).pop();
+                }
+                _ => (),
+            }
+
+            return None;
+        }
+
+        let items = self.items();
+
+        match key {
+            Key::Up | Key::W => {
+                self.selected = (self.selected + items.len() - 1) % items.len();
+            }
+            Key::Down | Key::S => {
+                self.selected = (self.selected + 1) % items.len();
+            }
+            Key::R => self.refresh().await,
+            Key::Return => match &items[self.selected] {
+                Item::Edit(field) => self.editing = Some(*field),
+                Item::Login => self.auth(false).await,
+                Item::Register => self.auth(true).await,
+                Item::Logout => {
+                    self.session = None;
+                    save_session(None);
+                    self.clamp_selected();
+                }
+                Item::QuickJoin => return Some(self.join_request(None, None)),
+                Item::CreatePublic => return self.create_game(false).await,
+                Item::CreatePrivate => return self.create_game(true).await,
+                Item::Leaderboards => self.show_leaderboard(0).await,
+                Item::Game(game_id) => return Some(self.join_request(Some(*game_id), None)),
+            },
+            _ => (),
+        }
+
+        None
+    }
+
+    async fn auth(&mut self, register: bool) {
+        let request = comn::AuthRequest {
+            name: self.player_name.clone(),
+            password: self.password.clone(),
+        };
+
+        match join::auth_request(request, register).await {
+            Ok(Ok(session)) => {
+                self.status = Some(format!("Logged in as {}", session.name));
+                self.password.clear();
+                save_session(Some(&session));
+                self.session = Some(session);
+                self.selected = 0;
+            }
+            Ok(Err(err)) => {
+                self.status = Some(format!("Could not log in: {:?}", err));
+            }
+            Err(err) => {
+                warn!("Failed to log in: {:?}", err);
+                self.status = Some("Could not log in".to_owned());
+            }
+        }
+    }
+
+    async fn create_game(&mut self, private: bool) -> Option<comn::JoinRequest> {
+        match join::create_game(comn::CreateGameRequest { private }).await {
+            Ok(Ok(success)) => Some(self.join_request(Some(success.game_id), success.invite_code)),
+            Ok(Err(err)) => {
+                self.status = Some(format!("Could not create game: {:?}", err));
+                None
+            }
+            Err(err) => {
+                warn!("Failed to create game: {:?}", err);
+                self.status = Some("Could not create game".to_owned());
+                None
+            }
+        }
+    }
+
+    fn item_string(&self, item: &Item) -> String {
+        let cursor = |field| {
+            if self.editing == Some(field) {
+                "_"
+            } else {
+                ""
+            }
+        };
+
+        match item {
+            Item::Edit(Field::Name) => format!("Name: {}{}", self.player_name, cursor(Field::Name)),
+            Item::Edit(Field::Password) => format!(
+                "Password: {}{}",
+                "*".repeat(self.password.chars().count()),
+                cursor(Field::Password)
+            ),
+            Item::Edit(Field::InviteCode) => format!(
+                "Join with invite code: {}{}",
+                self.invite_code,
+                cursor(Field::InviteCode)
+            ),
+            Item::Login => "Log in".to_owned(),
+            Item::Register => "Register".to_owned(),
+            Item::Logout => format!(
+                "Log out {}",
+                self.session
+                    .as_ref()
+                    .map_or("", |session| session.name.as_str())
+            ),
+            Item::QuickJoin => "Quick join".to_owned(),
+            Item::CreatePublic => "Create public game".to_owned(),
+            Item::CreatePrivate => "Create private game".to_owned(),
+            Item::Leaderboards => "Leaderboards".to_owned(),
+            Item::Game(game_id) => {
+                let game = self
+                    .games
+                    .iter()
+                    .find(|game| game.game_id == *game_id)
+                    .unwrap();
+                format!(
+                    "{} ({:?}): {}/{} players, {} bots, {} watching, catcher: {}",
+                    game.map_name,
+                    game.mode,
+                    game.num_players + game.num_bots,
+                    game.max_num_players,
+                    game.num_bots,
+                    game.num_spectators,
+                    game.catcher_name.as_deref().unwrap_or("nobody"),
+                )
+            }
+        }
+    }
+
+    fn render(&self, gfx: &mut Graphics, resources: &mut Resources) -> quicksilver::Result<()> {
+        let mut pos = Vector::new(50.0, 60.0);
+
+        resources.font_large.draw(
+            gfx,
+            if self.spectate {
+                "Choose a game to watch"
+            } else {
+                "Choose a game"
+            },
+            Color::BLACK,
+            pos,
+        )?;
+        pos.y += 2.0 * LINE_HEIGHT;
+
+        for (index, item) in self.items().iter().enumerate() {
+            let (prefix, color) = if index == self.selected {
+                ("> ", Color::BLUE)
+            } else {
+                ("  ", Color::BLACK)
+            };
+
+            resources.font.draw(
+                gfx,
+                &format!("{}{}", prefix, self.item_string(item)),
+                color,
+                pos,
+            )?;
+            pos.y += LINE_HEIGHT;
+        }
+
+        pos.y += LINE_HEIGHT;
+        if let Some(status) = self.status.as_ref() {
+            resources.font.draw(gfx, status, Color::RED, pos)?;
+            pos.y += LINE_HEIGHT;
+        }
+
+        resources.font_small.draw(
+            gfx,
+            "Up/Down: select, Enter: choose or edit, R: refresh",
+            Color::BLACK,
+            pos,
+        )?;
+
+        Ok(())
+    }
+}
+
+fn render_leaderboard(
+    gfx: &mut Graphics,
+    resources: &mut Resources,
+    period: comn::LeaderboardPeriod,
+    leaderboard: Option<&comn::Leaderboard>,
+) -> quicksilver::Result<()> {
+    let mut pos = Vector::new(50.0, 60.0);
+
+    let title = match period {
+        comn::LeaderboardPeriod::Daily => "Today's best",
+        comn::LeaderboardPeriod::Weekly => "This week's best",
+        comn::LeaderboardPeriod::AllTime => "All-time best",
+    };
+    resources.font_large.draw(gfx, title, Color::BLACK, pos)?;
+    pos.y += 2.0 * LINE_HEIGHT;
+
+    let left = pos.x;
+    let columns = [0.0, 60.0, 360.0, 480.0, 600.0, 720.0];
+    let draw_row = |gfx: &mut Graphics,
+                    resources: &mut Resources,
+                    cells: &[String],
+                    color: Color,
+                    y: f32|
+     -> quicksilver::Result<()> {
+        for (cell, x) in cells.iter().zip(columns.iter()) {
+            resources
+                .font
+                .draw(gfx, cell, color, Vector::new(left + x, y))?;
+        }
+        Ok(())
+    };
+
+    if let Some(leaderboard) = leaderboard {
+        let header: Vec<String> = ["#", "name", "food", "catches", "deaths", "matches"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        draw_row(gfx, resources, &header, Color::BLUE, pos.y)?;
+        pos.y += LINE_HEIGHT;
+
+        for (rank, entry) in leaderboard
+            .entries
+            .iter()
+            .take(MAX_LEADERBOARD_LINES)
+            .enumerate()
+        {
+            let row = vec![
+                (rank + 1).to_string(),
+                entry.name.clone(),
+                entry.food.to_string(),
+                entry.catches.to_string(),
+                entry.deaths.to_string(),
+                entry.matches.to_string(),
+            ];
+            draw_row(gfx, resources, &row, Color::BLACK, pos.y)?;
+            pos.y += LINE_HEIGHT;
+        }
+
+        if leaderboard.entries.is_empty() {
+            resources
+                .font
+                .draw(gfx, "Nobody has played yet", Color::BLACK, pos)?;
+            pos.y += LINE_HEIGHT;
+        }
+    } else {
+        resources
+            .font
+            .draw(gfx, "Leaderboards are not available", Color::RED, pos)?;
+        pos.y += LINE_HEIGHT;
+    }
+
+    pos.y += LINE_HEIGHT;
+    resources.font_small.draw(
+        gfx,
+        "Left/Right: change period, Enter/Escape: back",
+        Color::BLACK,
+        pos,
+    )?;
+
+    Ok(())
+}
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+/// Loads the session of a previous login, so that we stay logged in when
+/// reloading the page.
+fn load_session() -> Option<comn::AuthSuccess> {
+    let storage = local_storage()?;
+
+    Some(comn::AuthSuccess {
+        name: storage.get_item(SESSION_NAME_KEY).ok()??,
+        session_token: storage.get_item(SESSION_TOKEN_KEY).ok()??,
+    })
+}
+
+/// Forgets about the session of a previous login, for example because it has
+/// expired.
+pub fn forget_session() {
+    save_session(None);
+}
+
+fn save_session(session: Option<&comn::AuthSuccess>) {
+    let storage = if let Some(storage) = local_storage() {
+        storage
+    } else {
+        return;
+    };
+
+    let result = if let Some(session) = session {
+        storage
+            .set_item(SESSION_NAME_KEY, &session.name)
+            .and_then(|()| storage.set_item(SESSION_TOKEN_KEY, &session.session_token))
+    } else {
+        storage
+            .remove_item(SESSION_NAME_KEY)
+            .and_then(|()| storage.remove_item(SESSION_TOKEN_KEY))
+    };
+
+    if let Err(err) = result {
+        warn!("Failed to save session: {:?}", err);
+    }
+}
@@ -375,6 +375,12 @@ impl Prediction {
                     comn::Entity::Player(server),
                 ) => {
                     predicted.size_bump = server.size_bump;
+
+                    // Spawn protection runs out over time on the server, which
+                    // we do not predict. We only predict that it ends early
+                    // when we dash or shoot.
+                    predicted.spawn_protection =
+                        predicted.spawn_protection.min(server.spawn_protection);
                 }
                 _ => (),
             }
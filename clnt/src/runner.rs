@@ -7,9 +7,9 @@ use std::{
 use instant::Instant;
 use log::{debug, info, warn};
 
-use comn::util::{diff::Diff, stats, GameTimeEstimation, LossEstimation, PingEstimation};
+use comn::util::{diff::Diff, reliable, stats, GameTimeEstimation, LossEstimation, PingEstimation};
 
-use crate::{prediction::Prediction, webrtc};
+use crate::{demo, prediction::Prediction, webrtc};
 
 pub struct ReceivedState {
     pub game: comn::Game,
@@ -36,14 +36,31 @@ const MAX_TICKS_PER_UPDATE: usize = 5;
 const MAX_TIME_LAG_DEVIATION: f32 = 0.075;
 const KEEP_STATES_BUFFER: u32 = 5;
 
+/// Number of times that we send each input when we have no estimate of the
+/// loss yet.
+const DEFAULT_INPUT_REDUNDANCY: usize = 5;
+const MIN_INPUT_REDUNDANCY: usize = 2;
+
+/// We send each input often enough that it is lost with at most this
+/// probability.
+const INPUT_LOSS_TARGET: f32 = 0.001;
+
 pub struct Runner {
     settings: Arc<comn::Settings>,
-    my_token: comn::PlayerToken,
-    my_player_id: comn::PlayerId,
 
-    webrtc_client: webrtc::Client,
+    /// Our player id, or `None` if we are spectating.
+    my_player_id: Option<comn::PlayerId>,
+
+    /// Our connection to the server. This is `None` when playing back a demo.
+    connection: Option<(comn::PlayerToken, webrtc::Client)>,
     disconnected: bool,
 
+    /// Reliable messages exchanged with the server.
+    reliable: reliable::Endpoint<comn::ReliableClientMessage, comn::ReliableServerMessage>,
+
+    /// If set, we are recording the ticks that we receive.
+    recorder: Option<demo::Recorder>,
+
     last_inputs: VecDeque<(comn::TickNum, comn::Input)>,
 
     // TODO: Maximal size for received states
@@ -51,6 +68,13 @@ pub struct Runner {
     received_events: BTreeMap<comn::TickNum, Vec<comn::Event>>,
     prediction: Option<Prediction>,
 
+    /// The kill cam of our most recent death, assembled from the chunks that
+    /// we have received so far.
+    kill_cam: Option<comn::KillCam>,
+
+    /// Chat messages that have not been taken yet by `take_chat_messages`.
+    chat_messages: Vec<comn::ChatMessage>,
+
     interp_game_time: comn::GameTime,
     next_tick_num: Option<comn::TickNum>,
 
@@ -59,43 +83,128 @@ pub struct Runner {
     recv_tick_time: GameTimeEstimation,
     next_time_warp_factor: f32,
 
+    /// The server sends only every `send_interval`-th tick to us, so we need
+    /// to stay further behind to have ticks to interpolate between.
+    send_interval: u32,
+
+    /// Loss of the ticks that we received since `send_interval` last
+    /// changed. Ticks received under a different interval would skew the
+    /// correction in `input_redundancy`.
+    send_interval_loss: LossEstimation,
+
     ping: PingEstimation,
     stats: Stats,
 }
 
 impl Runner {
     pub fn new(join: comn::JoinSuccess, webrtc_client: webrtc::Client) -> Self {
-        let prediction = Some(Prediction::new(join.your_player_id));
-        let recv_tick_time = GameTimeEstimation::new(join.game_settings.tick_period());
+        // Spectators have nothing to predict, they just watch the
+        // authorative state.
+        let prediction = join.your_player_id.map(Prediction::new);
+
+        Self::new_with(
+            join.game_settings,
+            join.your_player_id,
+            Some((join.your_token, webrtc_client)),
+            prediction,
+            Instant::now(),
+        )
+    }
+
+    /// Creates a runner for playing back a demo. There is no network, ticks
+    /// are instead fed in through `record_server_tick`, using a virtual clock
+    /// that starts at `start_time`. We do not have the inputs that were
+    /// recorded, so there is no prediction either.
+    pub fn new_playback(
+        settings: comn::Settings,
+        my_player_id: Option<comn::PlayerId>,
+        start_time: Instant,
+    ) -> Self {
+        Self::new_with(settings, my_player_id, None, None, start_time)
+    }
+
+    fn new_with(
+        settings: comn::Settings,
+        my_player_id: Option<comn::PlayerId>,
+        connection: Option<(comn::PlayerToken, webrtc::Client)>,
+        prediction: Option<Prediction>,
+        start_time: Instant,
+    ) -> Self {
+        let recv_tick_time = GameTimeEstimation::new(settings.tick_period());
 
         Self {
-            settings: Arc::new(join.game_settings),
-            my_token: join.your_token,
-            my_player_id: join.your_player_id,
-            webrtc_client,
+            settings: Arc::new(settings),
+            my_player_id,
+            connection,
             disconnected: false,
+            reliable: reliable::Endpoint::default(),
+            recorder: None,
             last_inputs: VecDeque::new(),
             received_states: BTreeMap::new(),
             received_events: BTreeMap::new(),
             prediction,
+            kill_cam: None,
+            chat_messages: Vec::new(),
             interp_game_time: 0.0,
             next_tick_num: None,
-            start_time: Instant::now(),
+            start_time,
             recv_tick_time,
             next_time_warp_factor: 1.0,
+            send_interval: 1,
+            send_interval_loss: LossEstimation::default(),
             ping: PingEstimation::default(),
             stats: Stats::default(),
         }
     }
 
-    pub fn my_player_id(&self) -> comn::PlayerId {
+    pub fn my_player_id(&self) -> Option<comn::PlayerId> {
         self.my_player_id
     }
 
+    pub fn is_spectator(&self) -> bool {
+        self.my_player_id.is_none()
+    }
+
+    pub fn my_token(&self) -> Option<comn::PlayerToken> {
+        self.connection.as_ref().map(|(my_token, _)| *my_token)
+    }
+
+    /// Returns true if the server told us to disconnect, or if we
+    /// disconnected on our own.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
     pub fn is_good(&self) -> bool {
-        self.webrtc_client.status() == webrtc::Status::Open
-            && !self.disconnected
-            && !self.ping.is_timeout(Instant::now())
+        if let Some((_, webrtc_client)) = self.connection.as_ref() {
+            webrtc_client.status() == webrtc::Status::Open
+                && !self.disconnected
+                && !self.ping.is_timeout(Instant::now())
+        } else {
+            !self.disconnected
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Starts recording the ticks that we receive into a demo.
+    ///
+    /// Ticks are delta encoded w.r.t. states that we have received before, so
+    /// we start the demo with all the states that we currently remember.
+    pub fn start_recording(&mut self) {
+        let mut recorder = demo::Recorder::new((*self.settings).clone(), self.my_player_id);
+
+        for received_state in self.received_states.values() {
+            recorder.record_state(&received_state.game, received_state.my_last_input_num);
+        }
+
+        self.recorder = Some(recorder);
+    }
+
+    pub fn stop_recording(&mut self) -> Option<demo::Demo> {
+        self.recorder.take().map(demo::Recorder::finish)
     }
 
     pub fn settings(&self) -> &comn::Settings {
@@ -114,8 +223,16 @@ impl Runner {
         self.interp_game_time
     }
 
+    pub fn kill_cam(&self) -> Option<&comn::KillCam> {
+        self.kill_cam.as_ref()
+    }
+
+    pub fn take_chat_messages(&mut self) -> Vec<comn::ChatMessage> {
+        std::mem::replace(&mut self.chat_messages, Vec::new())
+    }
+
     fn target_time_lag(&self) -> comn::GameTime {
-        self.settings.tick_period() * 1.5
+        self.settings.tick_period() * (self.send_interval as f32 + 0.5)
     }
 
     fn tick_num(&self) -> comn::TickNum {
@@ -125,11 +242,22 @@ impl Runner {
     pub fn update(&mut self, now: Instant, dt: Duration, input: &comn::Input) -> Vec<comn::Event> {
         assert!(self.is_good());
 
+        // The server gets our input with reduced precision, so we predict
+        // with the same input.
+        let input = &input.quantized();
+
         {
             coarse_prof::profile!("webrtc");
 
-            self.webrtc_client.set_now((Instant::now(), now));
-            while let Some((recv_time, message)) = self.webrtc_client.take_message() {
+            let mut messages = Vec::new();
+            if let Some((_, webrtc_client)) = self.connection.as_mut() {
+                webrtc_client.set_now((Instant::now(), now));
+                while let Some(message) = webrtc_client.take_message() {
+                    messages.push(message);
+                }
+            }
+
+            for (recv_time, message) in messages {
                 self.handle_message(recv_time, message);
             }
         }
@@ -138,6 +266,10 @@ impl Runner {
             self.send(comn::ClientMessage::Ping(sequence_num));
         }
 
+        for packet in self.reliable.packets_to_send(now, self.ping.estimate()) {
+            self.send(comn::ClientMessage::Reliable(packet));
+        }
+
         // Determine new local game time, making sure to stay behind the receive
         // stream by our desired lag time. We do this so that we have ticks
         // between which we can interpolate.
@@ -249,15 +381,31 @@ impl Runner {
                 self.received_events.remove(tick_num);
             }
 
-            // Send inputs for server ticks we cross.
-            self.last_inputs.push_back((*tick_num, input.clone()));
-            while self.last_inputs.len() > comn::MAX_INPUTS_PER_MESSAGE {
-                self.last_inputs.pop_front();
-            }
+            // Send inputs for server ticks we cross. Spectators have no
+            // input to send.
+            if !self.is_spectator() {
+                // Inputs are sent for consecutive ticks, so we forget older
+                // inputs if we have skipped some ticks.
+                if self.last_inputs.back().map_or(false, |(last_tick_num, _)| {
+                    last_tick_num.next() != *tick_num
+                }) {
+                    self.last_inputs.clear();
+                }
 
-            self.send(comn::ClientMessage::Input(
-                self.last_inputs.iter().cloned().collect(),
-            ));
+                self.last_inputs.push_back((*tick_num, input.clone()));
+                while self.last_inputs.len() > comn::MAX_INPUTS_PER_MESSAGE {
+                    self.last_inputs.pop_front();
+                }
+
+                let redundancy = self.input_redundancy();
+                let skip = self.last_inputs.len().saturating_sub(redundancy);
+                let mut runs = comn::InputRuns::new(self.last_inputs[skip].0);
+                for (_, input) in self.last_inputs.iter().skip(skip) {
+                    runs.push(input);
+                }
+
+                self.send(comn::ClientMessage::Input(runs));
+            }
 
             // Predict effects of our own input locally.
             if let Some(prediction) = self.prediction.as_mut() {
@@ -281,7 +429,8 @@ impl Runner {
         // Do we have a tick to interpolate into ready?
         if self.next_tick_num.is_none() {
             let min_ready_num = self.received_states.keys().find(|tick_num| {
-                **tick_num > self.tick_num() && tick_num.0 - self.tick_num().0 <= 3
+                **tick_num > self.tick_num()
+                    && tick_num.0 - self.tick_num().0 <= 3 * self.send_interval
             });
 
             if let Some(min_ready_num) = min_ready_num {
@@ -326,8 +475,10 @@ impl Runner {
             .time_warp_factor
             .record(self.next_time_warp_factor);
 
-        self.stats.send_rate = self.webrtc_client.send_rate();
-        self.stats.recv_rate = self.webrtc_client.recv_rate();
+        if let Some((_, webrtc_client)) = self.connection.as_ref() {
+            self.stats.send_rate = webrtc_client.send_rate();
+            self.stats.recv_rate = webrtc_client.recv_rate();
+        }
         self.stats.recv_delay_std_dev = self.recv_tick_time.recv_delay_std_dev().unwrap_or(-1.0);
 
         events
@@ -425,11 +576,50 @@ impl Runner {
                 }
             }
             comn::ServerMessage::Tick(tick) => {
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.record_tick(recv_time, &tick);
+                }
+
                 self.record_server_tick(recv_time, tick);
             }
+            comn::ServerMessage::KillCam(kill_cam) => {
+                self.record_kill_cam(kill_cam);
+            }
             comn::ServerMessage::Disconnect => {
                 self.disconnected = true;
             }
+            comn::ServerMessage::Reliable(packet) => {
+                match self.reliable.record_packet(packet) {
+                    Ok(Some(ack_num)) => {
+                        self.send(comn::ClientMessage::AckReliable(ack_num));
+                    }
+                    Ok(None) => (),
+                    Err(err) => {
+                        warn!("Ignoring reliable message: {:?}", err);
+                    }
+                }
+
+                // Handle the reliable messages that are now ready in order.
+                while let Some(reliable_message) = self.reliable.take_message() {
+                    self.handle_reliable_message(reliable_message);
+                }
+            }
+            comn::ServerMessage::AckReliable(ack_num) => {
+                self.reliable.record_ack(ack_num);
+            }
+        }
+    }
+
+    /// Handles a message that we received in order through the reliable
+    /// layer.
+    fn handle_reliable_message(&mut self, message: comn::ReliableServerMessage) {
+        match message {
+            comn::ReliableServerMessage::Chat(message) => {
+                self.chat_messages.push(message);
+            }
+            comn::ReliableServerMessage::Disconnect => {
+                self.disconnected = true;
+            }
         }
     }
 
@@ -442,20 +632,76 @@ impl Runner {
         self.disconnected = true;
     }
 
+    /// Sends a message to the server reliably and in order. The message is
+    /// resent until the server acknowledges it.
+    pub fn send_reliable(&mut self, message: comn::ReliableClientMessage) {
+        self.reliable.push(message);
+    }
+
     fn send(&self, message: comn::ClientMessage) {
         coarse_prof::profile!("send");
 
-        let signed_message = comn::SignedClientMessage(self.my_token, message);
+        let (my_token, webrtc_client) = if let Some(connection) = self.connection.as_ref() {
+            connection
+        } else {
+            // Playing back a demo, there is no one to talk to.
+            return;
+        };
+
+        let signed_message = comn::SignedClientMessage(*my_token, message);
 
         let data = signed_message.serialize();
 
         coarse_prof::profile!("webrtc");
-        if let Err(err) = self.webrtc_client.send(&data) {
+        if let Err(err) = webrtc_client.send(&data) {
             warn!("Failed to send message: {:?}", err);
         }
     }
 
-    fn record_server_tick(&mut self, recv_time: Instant, tick: comn::Tick) {
+    fn record_kill_cam(&mut self, chunk: comn::KillCam) {
+        match self.kill_cam.as_mut() {
+            Some(kill_cam) if kill_cam.death_tick_num == chunk.death_tick_num => {
+                // Another chunk of the kill cam we are already assembling.
+                // Chunks may arrive out of order, so keep frames sorted.
+                kill_cam.frames.extend(chunk.frames);
+                kill_cam.frames.sort_by_key(|(tick_num, _)| *tick_num);
+                kill_cam.frames.dedup_by_key(|(tick_num, _)| *tick_num);
+            }
+            Some(kill_cam) if kill_cam.death_tick_num > chunk.death_tick_num => {
+                debug!("Ignoring chunk of old kill cam {:?}", chunk.death_tick_num);
+            }
+            _ => {
+                self.kill_cam = Some(chunk);
+            }
+        }
+    }
+
+    /// Returns how many of our last inputs to send in each message. The
+    /// more messages are lost, the more often we repeat each input.
+    fn input_redundancy(&self) -> usize {
+        // We assume that the loss towards the server is about the same as the
+        // loss of the ticks that we receive. The ticks that the server skips
+        // on purpose do not count as lost.
+        let loss = if let Some(loss) = self.send_interval_loss.estimate() {
+            (1.0 - (1.0 - loss) * self.send_interval as f32).max(0.0)
+        } else {
+            return DEFAULT_INPUT_REDUNDANCY;
+        };
+
+        let redundancy = if loss <= 0.0 {
+            MIN_INPUT_REDUNDANCY
+        } else if loss >= 1.0 {
+            comn::MAX_INPUTS_PER_MESSAGE
+        } else {
+            (INPUT_LOSS_TARGET.ln() / loss.ln()).ceil() as usize
+        };
+
+        redundancy
+            .max(MIN_INPUT_REDUNDANCY)
+            .min(comn::MAX_INPUTS_PER_MESSAGE)
+    }
+
+    pub fn record_server_tick(&mut self, recv_time: Instant, tick: comn::Tick) {
         let recv_tick_num = tick.diff.tick_num;
         let recv_game_time = self.settings.tick_game_time(recv_tick_num);
 
@@ -475,6 +721,14 @@ impl Runner {
             return;
         }
 
+        let send_interval = tick.send_interval.max(1);
+        if send_interval != self.send_interval {
+            self.send_interval = send_interval;
+            self.send_interval_loss = LossEstimation::default();
+        }
+        self.send_interval_loss
+            .record_received(recv_tick_num.0 as usize);
+
         if !self.recv_tick_time.has_started() {
             // If this is the first tick we have recevied from the server, reset
             // to the correct time
@@ -548,6 +802,10 @@ impl Runner {
             self.stats.received_ticks.record(1.0);
         }
 
+        // The server compares this with the state that it sent, so that it
+        // notices if we have decoded the tick incorrectly.
+        let checksum = new_state.checksum();
+
         self.received_states.insert(
             recv_tick_num,
             ReceivedState {
@@ -558,7 +816,7 @@ impl Runner {
 
         // Let the server know which ticks we actually received, so
         // that this can be used as the basis for delta encoding.
-        self.send(comn::ClientMessage::AckTick(recv_tick_num));
+        self.send(comn::ClientMessage::AckTick(recv_tick_num, checksum));
 
         // Keep updating our estimate for when we expect to receive
         // ticks. This is an attempt to counter network jitter.
@@ -11,6 +11,7 @@ use comn::geom;
 pub struct Config {
     pub smooth_pos_factor: f32,
     pub max_smooth_dist: f32,
+    pub free_move_speed: f32,
 }
 
 impl Default for Config {
@@ -18,10 +19,21 @@ impl Default for Config {
         Self {
             smooth_pos_factor: 5.0,
             max_smooth_dist: 300.0,
+            free_move_speed: 800.0,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Follow the entity of the given player.
+    Follow(comn::PlayerId),
+
+    /// Move around freely with the movement keys. This is only used for
+    /// spectators, since players need the movement keys for themselves.
+    Free,
+}
+
 pub struct Camera {
     config: Config,
     pos: comn::Point,
@@ -29,10 +41,11 @@ pub struct Camera {
     target: comn::Point,
     map_size: comn::Vector,
     scale: f32,
+    mode: Mode,
 }
 
 impl Camera {
-    pub fn new(config: Config, map_size: comn::Vector) -> Self {
+    pub fn new(config: Config, map_size: comn::Vector, mode: Mode) -> Self {
         Self {
             config,
             pos: comn::Point::origin(),
@@ -40,13 +53,22 @@ impl Camera {
             target: comn::Point::origin(),
             map_size,
             scale: 0.75,
+            mode,
         }
     }
 
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
     pub fn update(
         &mut self,
         dt: Duration,
-        _pressed_keys: &HashSet<Key>,
+        pressed_keys: &HashSet<Key>,
         follow_entity: Option<comn::Entity>,
         game_time: comn::GameTime,
         window_size: comn::Vector,
@@ -54,7 +76,32 @@ impl Camera {
     ) {
         let offset = window_size / (2.0 * self.scale / window_scale_factor);
 
-        self.target = follow_entity.map_or(self.target, |entity| entity.pos(game_time));
+        match self.mode {
+            Mode::Follow(_) => {
+                self.target = follow_entity.map_or(self.target, |entity| entity.pos(game_time));
+            }
+            Mode::Free => {
+                let mut delta = comn::Vector::zeros();
+                if pressed_keys.contains(&Key::A) || pressed_keys.contains(&Key::Left) {
+                    delta.x -= 1.0;
+                }
+                if pressed_keys.contains(&Key::D) || pressed_keys.contains(&Key::Right) {
+                    delta.x += 1.0;
+                }
+                if pressed_keys.contains(&Key::W) || pressed_keys.contains(&Key::Up) {
+                    delta.y -= 1.0;
+                }
+                if pressed_keys.contains(&Key::S) || pressed_keys.contains(&Key::Down) {
+                    delta.y += 1.0;
+                }
+
+                if delta.norm() > 0.0 {
+                    self.target += delta.normalize() * self.config.free_move_speed / self.scale
+                        * dt.as_secs_f32();
+                }
+            }
+        }
+
         self.target.x = self
             .target
             .x
@@ -80,6 +127,11 @@ impl Camera {
         self.centered_pos = self.pos - offset;
     }
 
+    /// Inverse of `transform`.
+    pub fn screen_to_world(&self, screen_pos: comn::Point) -> comn::Point {
+        self.centered_pos + screen_pos.coords / self.scale
+    }
+
     pub fn transform(&self) -> Transform {
         let offset: mint::Vector2<f32> = (-self.centered_pos.coords).into();
         Transform::translate(offset.into())
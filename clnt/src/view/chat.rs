@@ -0,0 +1,129 @@
+use std::{collections::VecDeque, time::Duration};
+
+use instant::Instant;
+
+use quicksilver::{
+    geom::Vector,
+    graphics::{Color, FontRenderer, Graphics},
+};
+
+use crate::view::overlay;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub num_lines: usize,
+    pub max_age: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            num_lines: 6,
+            max_age: Duration::from_secs(20),
+        }
+    }
+}
+
+pub struct ChatBox {
+    config: Config,
+    messages: VecDeque<(Instant, comn::ChatMessage)>,
+
+    /// The message that we are currently typing, if any.
+    input: Option<String>,
+}
+
+impl ChatBox {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            messages: VecDeque::new(),
+            input: None,
+        }
+    }
+
+    pub fn push(&mut self, now: Instant, message: comn::ChatMessage) {
+        self.messages.push_back((now, message));
+
+        if self.messages.len() > self.config.num_lines {
+            self.messages.pop_front();
+        }
+    }
+
+    pub fn is_typing(&self) -> bool {
+        self.input.is_some()
+    }
+
+    pub fn start_typing(&mut self) {
+        self.input = Some(String::new());
+    }
+
+    pub fn cancel_typing(&mut self) {
+        self.input = None;
+    }
+
+    /// Stops typing, returning the message that should be sent, if any.
+    pub fn finish_typing(&mut self) -> Option<String> {
+        self.input
+            .take()
+            .map(|input| input.trim().to_string())
+            .filter(|input| !input.is_empty())
+    }
+
+    pub fn type_char(&mut self, c: char) {
+        if let Some(input) = self.input.as_mut() {
+            if !c.is_control() && input.chars().count() < comn::MAX_CHAT_MESSAGE_LEN {
+                input.push(c);
+            }
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(input) = self.input.as_mut() {
+            input.pop();
+        }
+    }
+
+    pub fn render(
+        &mut self,
+        now: Instant,
+        gfx: &mut Graphics,
+        font: &mut FontRenderer,
+        mut pos: Vector,
+    ) -> quicksilver::Result<()> {
+        // Remove messages that are too old.
+        while let Some((oldest_time, _)) = self.messages.front() {
+            if now.duration_since(*oldest_time) <= self.config.max_age {
+                break;
+            }
+
+            self.messages.pop_front();
+        }
+
+        // While typing, we show the input line below the messages.
+        let input_string = self.input.as_ref().map(|input| format!("> {}_", input));
+        let num_lines = self.messages.len() + input_string.iter().count();
+
+        if num_lines > 0 {
+            overlay::box_thing(
+                gfx,
+                pos - Vector::new(0.0, 6.0),
+                Vector::new(360.0, 12.0 * num_lines as f32 + 14.0),
+            )?;
+            pos += Vector::new(10.0, 10.0);
+            for (_, message) in self.messages.iter() {
+                font.draw(
+                    gfx,
+                    &format!("{}: {}", message.player_name, message.text),
+                    Color::BLACK,
+                    pos,
+                )?;
+                pos.y += 12.0;
+            }
+            if let Some(input_string) = input_string {
+                font.draw(gfx, &input_string, Color::BLUE, pos)?;
+            }
+        }
+
+        Ok(())
+    }
+}
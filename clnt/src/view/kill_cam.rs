@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+use crate::view::render;
+
+/// The state of a kill cam at some point in its playback. The fields are
+/// chosen so that they can be passed directly to `render::render_game`.
+pub struct Playback {
+    pub killer: comn::PlayerId,
+    pub state: comn::Game,
+    pub next_entities: BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
+    pub time: comn::GameTime,
+}
+
+impl Playback {
+    /// Determines what to show of the `kill_cam` at our current `game_time`.
+    /// We start playback when we reach the tick of our death, and keep
+    /// showing the kill cam for as long as we are not alive.
+    ///
+    /// The kill cam only contains the entities that change over time, so we
+    /// take the static entities from our current `state`.
+    pub fn new(
+        state: &comn::Game,
+        my_player_id: comn::PlayerId,
+        kill_cam: &comn::KillCam,
+        game_time: comn::GameTime,
+    ) -> Option<Self> {
+        let is_alive = state
+            .players
+            .get(&my_player_id)
+            .map_or(true, |player| player.state == comn::PlayerState::Alive);
+        let elapsed = game_time - state.tick_game_time(kill_cam.death_tick_num);
+
+        if is_alive || elapsed < 0.0 || kill_cam.frames.is_empty() {
+            return None;
+        }
+
+        let first_time = state.tick_game_time(kill_cam.frames.first().unwrap().0);
+        let last_time = state.tick_game_time(kill_cam.frames.last().unwrap().0);
+        let time = (first_time + elapsed).min(last_time);
+
+        let index = kill_cam
+            .frames
+            .iter()
+            .rposition(|(tick_num, _)| state.tick_game_time(*tick_num) <= time)
+            .unwrap_or(0);
+        let (tick_num, entities) = &kill_cam.frames[index];
+
+        let mut frame_state = state.clone();
+        frame_state.tick_num = *tick_num;
+        frame_state.entities.retain(|_, entity| match entity {
+            comn::Entity::Wall(_) | comn::Entity::FoodSpawn(_) => true,
+            _ => false,
+        });
+        frame_state.entities.extend(entities.clone());
+
+        let next_entities = kill_cam
+            .frames
+            .get(index + 1)
+            .map(|(next_tick_num, next_entities)| {
+                let next_time = state.tick_game_time(*next_tick_num);
+                next_entities
+                    .iter()
+                    .map(|(entity_id, entity)| (*entity_id, (next_time, entity.clone())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Self {
+            killer: kill_cam.killer,
+            state: frame_state,
+            next_entities,
+            time,
+        })
+    }
+
+    pub fn killer_entity(&self) -> Option<comn::Entity> {
+        render::interp_entities(&self.state, &self.next_entities, self.time).find(|entity| {
+            match entity {
+                comn::Entity::PlayerView(player) => player.owner == self.killer,
+                _ => false,
+            }
+        })
+    }
+}
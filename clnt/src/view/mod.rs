@@ -1,6 +1,8 @@
 mod active_event;
 mod camera;
+mod chat;
 mod event_list;
+mod kill_cam;
 mod overlay;
 mod particles;
 mod render;
@@ -21,6 +23,7 @@ use quicksilver::{
 
 use active_event::ActiveEvent;
 use camera::Camera;
+use chat::ChatBox;
 use event_list::EventList;
 use particles::Particles;
 
@@ -29,13 +32,16 @@ pub use resources::Resources;
 #[derive(Debug, Clone, Default)]
 pub struct Config {
     pub event_list: event_list::Config,
+    pub chat: chat::Config,
     pub camera: camera::Config,
 }
 
 pub struct View {
-    my_player_id: comn::PlayerId,
+    /// Our player id, or `None` if we are spectating.
+    my_player_id: Option<comn::PlayerId>,
     resources: Resources,
     event_list: EventList,
+    chat: ChatBox,
     camera: Camera,
     window_size: comn::Vector,
     window_scale_factor: f32,
@@ -43,19 +49,25 @@ pub struct View {
     air_particles: Particles,
     last_game_time: Option<comn::GameTime>,
     active_events: Vec<ActiveEvent>,
+    kill_cam: Option<kill_cam::Playback>,
+
+    /// Position of our player entity in the last update, used for aiming.
+    my_pos: Option<comn::Point>,
 }
 
 impl View {
     pub fn new(
         config: Config,
         settings: comn::Settings,
-        my_player_id: comn::PlayerId,
+        my_player_id: Option<comn::PlayerId>,
         resources: Resources,
         window_size: comn::Vector,
         window_scale_factor: f32,
     ) -> Self {
         let event_list = EventList::new(config.event_list);
-        let camera = Camera::new(config.camera, settings.map.size);
+        let chat = ChatBox::new(config.chat);
+        let camera_mode = my_player_id.map_or(camera::Mode::Free, camera::Mode::Follow);
+        let camera = Camera::new(config.camera, settings.map.size, camera_mode);
         let ground_particles = Particles::new();
         let air_particles = Particles::new();
 
@@ -63,6 +75,7 @@ impl View {
             my_player_id,
             resources,
             event_list,
+            chat,
             camera,
             window_size,
             window_scale_factor,
@@ -70,6 +83,8 @@ impl View {
             air_particles,
             last_game_time: None,
             active_events: Vec::new(),
+            kill_cam: None,
+            my_pos: None,
         }
     }
 
@@ -77,6 +92,55 @@ impl View {
         &mut self.resources
     }
 
+    pub fn chat_mut(&mut self) -> &mut ChatBox {
+        &mut self.chat
+    }
+
+    /// For spectators, switches the camera to follow the next player in
+    /// the game, ordered by player id.
+    pub fn follow_next_player(&mut self, state: &comn::Game) {
+        if self.my_player_id.is_some() {
+            return;
+        }
+
+        let current = match self.camera.mode() {
+            camera::Mode::Follow(player_id) => Some(player_id),
+            camera::Mode::Free => None,
+        };
+        let next = state
+            .players
+            .keys()
+            .find(|player_id| current.map_or(true, |current| **player_id > current))
+            .or_else(|| state.players.keys().next());
+
+        if let Some(next) = next {
+            self.camera.set_mode(camera::Mode::Follow(*next));
+        }
+    }
+
+    /// For spectators, toggles between following a player and the free
+    /// camera.
+    pub fn toggle_free_camera(&mut self, state: &comn::Game) {
+        if self.my_player_id.is_some() {
+            return;
+        }
+
+        match self.camera.mode() {
+            camera::Mode::Follow(_) => self.camera.set_mode(camera::Mode::Free),
+            camera::Mode::Free => self.follow_next_player(state),
+        }
+    }
+
+    /// Returns the direction from our player towards the given position on
+    /// the screen, given in logical pixels.
+    pub fn aim_dir(&self, screen_pos: comn::Point) -> Option<comn::Vector> {
+        let world_pos = self
+            .camera
+            .screen_to_world(screen_pos * self.window_scale_factor);
+
+        self.my_pos.map(|my_pos| world_pos - my_pos)
+    }
+
     pub fn set_window_size(&mut self, size: comn::Vector, scale_factor: f32) {
         self.window_size = size;
         self.window_scale_factor = scale_factor;
@@ -89,6 +153,8 @@ impl View {
         pressed_keys: &HashSet<Key>,
         state: Option<&comn::Game>,
         game_events: &[comn::Event],
+        chat_messages: &[comn::ChatMessage],
+        kill_cam: Option<&comn::KillCam>,
         game_time: comn::GameTime,
     ) {
         let game_dt = self
@@ -97,12 +163,33 @@ impl View {
             .max(0.0);
         self.last_game_time = Some(game_time);
 
-        let follow_entity = state.and_then(|state| {
+        self.my_pos = self.my_player_id.and_then(|my_player_id| {
             state
-                .get_player_entity(self.my_player_id)
-                .map(|(_id, e)| comn::Entity::Player(e.clone()))
+                .and_then(|state| state.get_player_entity(my_player_id))
+                .map(|(_, entity)| entity.pos)
         });
 
+        // While we are waiting to respawn, show how we died.
+        self.kill_cam = match (state, self.my_player_id, kill_cam) {
+            (Some(state), Some(my_player_id), Some(kill_cam)) => {
+                kill_cam::Playback::new(state, my_player_id, kill_cam, game_time)
+            }
+            _ => None,
+        };
+
+        let follow_entity = if let Some(kill_cam) = self.kill_cam.as_ref() {
+            kill_cam.killer_entity()
+        } else {
+            match self.camera.mode() {
+                camera::Mode::Follow(player_id) => state.and_then(|state| {
+                    state
+                        .get_player_entity(player_id)
+                        .map(|(_id, e)| comn::Entity::Player(e.clone()))
+                }),
+                camera::Mode::Free => None,
+            }
+        };
+
         self.camera.update(
             dt,
             &pressed_keys,
@@ -114,6 +201,10 @@ impl View {
         self.ground_particles.update(game_dt);
         self.air_particles.update(game_dt);
 
+        for message in chat_messages {
+            self.chat.push(now, message.clone());
+        }
+
         for event in game_events {
             self.event_list.push(now, event.clone());
 
@@ -199,15 +290,27 @@ impl View {
 
             {
                 coarse_prof::profile!("game");
-                render::render_game(
-                    gfx,
-                    &mut self.resources,
-                    state,
-                    next_entities,
-                    game_time,
-                    self.my_player_id,
-                    self.camera.transform(),
-                )?;
+                if let Some(kill_cam) = self.kill_cam.as_ref() {
+                    render::render_game(
+                        gfx,
+                        &mut self.resources,
+                        &kill_cam.state,
+                        &kill_cam.next_entities,
+                        kill_cam.time,
+                        self.my_player_id,
+                        self.camera.transform(),
+                    )?;
+                } else {
+                    render::render_game(
+                        gfx,
+                        &mut self.resources,
+                        state,
+                        next_entities,
+                        game_time,
+                        self.my_player_id,
+                        self.camera.transform(),
+                    )?;
+                }
             }
 
             {
@@ -215,7 +318,7 @@ impl View {
                 self.air_particles.render(gfx, self.camera.transform());
             }
 
-            {
+            if self.kill_cam.is_none() {
                 coarse_prof::profile!("active_events");
                 for active_event in &self.active_events {
                     active_event.render(gfx, state, game_time, self.camera.transform());
@@ -226,7 +329,9 @@ impl View {
             overlay::render(
                 gfx,
                 &mut self.resources,
-                state.get_player_entity(self.my_player_id).map(|(_, e)| e),
+                self.my_player_id
+                    .and_then(|my_player_id| state.get_player_entity(my_player_id))
+                    .map(|(_, e)| e),
                 Vector::new(self.window_size.x, self.window_size.y) * self.window_scale_factor,
             )?;
         }
@@ -238,6 +343,44 @@ impl View {
             &mut self.resources.font_small,
             Vector::new(10.0, 10.0),
         )?;
+        self.chat.render(
+            now,
+            gfx,
+            &mut self.resources.font_small,
+            Vector::new(10.0, 90.0),
+        )?;
+
+        if let (Some(kill_cam), Some(state)) = (self.kill_cam.as_ref(), state) {
+            let killer_name = state
+                .players
+                .get(&kill_cam.killer)
+                .map_or("someone", |player| player.name.as_str());
+            self.resources.font.draw(
+                gfx,
+                &format!("Killed by {}", killer_name),
+                Color::RED,
+                Vector::new(250.0, 25.0),
+            )?;
+        }
+
+        if let (None, Some(state)) = (self.my_player_id, state) {
+            let following = match self.camera.mode() {
+                camera::Mode::Follow(player_id) => state
+                    .players
+                    .get(&player_id)
+                    .map_or("nobody".to_owned(), |player| player.name.clone()),
+                camera::Mode::Free => "free camera".to_owned(),
+            };
+            self.resources.font_small.draw(
+                gfx,
+                &format!(
+                    "Spectating: {} (Tab: next player, F: free camera)",
+                    following
+                ),
+                Color::BLACK,
+                Vector::new(10.0, self.window_size.y * self.window_scale_factor - 20.0),
+            )?;
+        }
 
         if let Some(state) = state {
             scoreboard::render(
@@ -73,7 +73,7 @@ pub fn render_game(
     state: &comn::Game,
     next_entities: &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
     time: comn::GameTime,
-    my_player_id: comn::PlayerId,
+    my_player_id: Option<comn::PlayerId>,
     camera_transform: Transform,
 ) -> quicksilver::Result<()> {
     {
@@ -291,7 +291,7 @@ pub fn render_game(
             comn::Entity::Bullet(bullet) => {
                 let origin: mint::Vector2<f32> = bullet.pos(time).coords.into();
                 let circle = Circle::new(origin.into(), BULLET_RADIUS);
-                let color = if bullet.owner == Some(my_player_id) {
+                let color = if bullet.owner.is_some() && bullet.owner == my_player_id {
                     Color::ORANGE
                 } else {
                     color_enemy()
@@ -303,7 +303,7 @@ pub fn render_game(
             comn::Entity::Rocket(rocket) => {
                 let origin: mint::Vector2<f32> = rocket.pos(time).coords.into();
                 let circle = Circle::new(origin.into(), ROCKET_RADIUS);
-                let color = if rocket.owner == Some(my_player_id) {
+                let color = if rocket.owner.is_some() && rocket.owner == my_player_id {
                     Color::ORANGE
                 } else {
                     color_enemy()
@@ -362,7 +362,7 @@ fn render_player(
     state: &comn::Game,
     next_entities: &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
     time: comn::GameTime,
-    my_player_id: comn::PlayerId,
+    my_player_id: Option<comn::PlayerId>,
     camera_transform: Transform,
     player: &comn::PlayerView,
 ) -> quicksilver::Result<()> {
@@ -371,7 +371,7 @@ fn render_player(
 
     gfx.set_transform(Transform::rotate(90.0).then(transform.then(camera_transform)));
 
-    let row = if player.owner == my_player_id {
+    let row = if Some(player.owner) == my_player_id {
         0.0
     } else if state.catcher == Some(player.owner) {
         1.0
@@ -388,6 +388,17 @@ fn render_player(
 
     gfx.set_transform(camera_transform);
 
+    if player.is_protected {
+        // Pulsing bubble around players that have just spawned
+        let pulse = (time * 8.0).sin() * 0.5 + 0.5;
+        let radius = player.size.norm() / 2.0 + 4.0 + 4.0 * pulse;
+        let pos: mint::Vector2<f32> = player.pos.coords.into();
+        gfx.stroke_circle(
+            &Circle::new(pos.into(), radius),
+            Color::from_rgba(100, 200, 255, 0.4 + 0.4 * pulse),
+        );
+    }
+
     if let Some(hook) = player.hook.as_ref() {
         render_hook(gfx, state, next_entities, time, player.pos, hook)?;
     }
@@ -11,7 +11,7 @@ pub fn render(
     gfx: &mut Graphics,
     font: &mut FontRenderer,
     state: &comn::Game,
-    my_player_id: comn::PlayerId,
+    my_player_id: Option<comn::PlayerId>,
     mut pos: Vector,
     _size: Vector,
 ) -> quicksilver::Result<()> {
@@ -21,13 +21,15 @@ pub fn render(
         players.pop();
     }
 
-    if !players
-        .iter()
-        .any(|(player_id, _)| *player_id == my_player_id)
-    {
-        if let Some(me) = state.players.get(&my_player_id) {
-            players.pop();
-            players.push((my_player_id, me.clone()));
+    if let Some(my_player_id) = my_player_id {
+        if !players
+            .iter()
+            .any(|(player_id, _)| *player_id == my_player_id)
+        {
+            if let Some(me) = state.players.get(&my_player_id) {
+                players.pop();
+                players.push((my_player_id, me.clone()));
+            }
         }
     }
 
@@ -48,7 +50,7 @@ pub fn render(
 
     for (i, (player_id, player)) in players.into_iter().enumerate() {
         let y = pos.y + (i + 1) as f32 * 12.0;
-        let color = if player_id == my_player_id {
+        let color = if Some(player_id) == my_player_id {
             Color::ORANGE
         } else {
             Color::BLACK
@@ -16,7 +16,7 @@ use web_sys::{
     RtcDataChannelType, RtcPeerConnection, RtcSessionDescriptionInit,
 };
 
-use comn::util::stats;
+use comn::util::{fragment, stats};
 
 #[derive(Debug, Clone)]
 pub enum ConnectError {
@@ -67,6 +67,8 @@ pub struct Data {
     status: Status,
     received: VecDeque<(Instant, comn::ServerMessage)>,
     now: (Instant, Instant),
+    reassembler: fragment::Reassembler,
+    splitter: RefCell<fragment::Splitter>,
 
     recv_rate: stats::Var,
     send_rate: RefCell<stats::Var>,
@@ -100,6 +102,8 @@ impl Client {
             status: Status::Connecting,
             received: VecDeque::new(),
             now: (Instant::now(), Instant::now()),
+            reassembler: fragment::Reassembler::default(),
+            splitter: RefCell::new(fragment::Splitter::default()),
             recv_rate: stats::Var::new(Duration::from_secs(10)),
             send_rate: RefCell::new(stats::Var::new(Duration::from_secs(10))),
             _peer: peer.clone(),
@@ -248,7 +252,18 @@ impl Data {
 
             self.recv_rate.record(vec.len() as f32);
 
-            if let Some(message) = comn::ServerMessage::deserialize(&vec) {
+            // Large messages are split into fragments, so we may need to wait
+            // for more datagrams.
+            let data = match self.reassembler.recv(recv_time, &vec) {
+                Ok(Some(data)) => data,
+                Ok(None) => return,
+                Err(err) => {
+                    warn!("Received invalid datagram ({:?}), ignoring", err);
+                    return;
+                }
+            };
+
+            if let Some(message) = comn::ServerMessage::deserialize(&data) {
                 message
             } else {
                 warn!("Failed to deserialize message, ignoring");
@@ -265,9 +280,20 @@ impl Data {
     }
 
     pub fn send(&self, data: &[u8]) -> Result<(), JsValue> {
-        self.send_rate.borrow_mut().record(data.len() as f32);
+        let datagrams =
+            self.splitter
+                .borrow_mut()
+                .split(data)
+                .map_err(|fragment::TooLarge(size)| {
+                    JsValue::from_str(&format!("Message of {} bytes is too large", size))
+                })?;
+
+        for datagram in datagrams {
+            self.send_rate.borrow_mut().record(datagram.len() as f32);
+            self.channel.send_with_u8_array(&datagram)?;
+        }
 
-        self.channel.send_with_u8_array(data)
+        Ok(())
     }
 }
 
@@ -306,7 +332,9 @@ async fn request_session(
 ) -> Result<JsValue, ConnectError> {
     let mut opts = web_sys::RequestInit::new();
     opts.method("POST");
-    opts.mode(web_sys::RequestMode::SameOrigin);
+    // The session endpoint may be on a different server than the one that
+    // served the client.
+    opts.mode(web_sys::RequestMode::Cors);
     opts.body(Some(
         &Reflect::get(&offer, &JsValue::from_str("sdp")).unwrap(),
     ));
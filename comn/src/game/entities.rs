@@ -157,6 +157,7 @@ pub struct PlayerEntity {
     pub hook: Option<Hook>,
     pub hook_cooldown: GameTime,
     pub anim_frame: AnimState,
+    pub spawn_protection: GameTime,
 }
 
 impl PlayerEntity {
@@ -179,6 +180,7 @@ impl PlayerEntity {
             hook: None,
             hook_cooldown: 0.0,
             anim_frame: (0, 0.0),
+            spawn_protection: run::PLAYER_SPAWN_PROTECTION_DURATION,
         }
     }
 
@@ -191,9 +193,14 @@ impl PlayerEntity {
             hook: self.hook.clone(),
             is_dashing: self.dash.is_some(),
             anim_frame: self.anim_frame.0,
+            is_protected: self.is_protected(),
         }
     }
 
+    pub fn is_protected(&self) -> bool {
+        self.spawn_protection > 0.0
+    }
+
     pub fn size(&self) -> Vector {
         Vector::new(
             (/*self.size_bump +*/self.size_scale * run::PLAYER_SIT_W) * (1.0 + self.size_skew),
@@ -244,6 +251,7 @@ pub struct PlayerView {
     pub hook: Option<Hook>,
     pub is_dashing: bool,
     pub anim_frame: Frame,
+    pub is_protected: bool,
 }
 
 impl PlayerView {
@@ -0,0 +1,556 @@
+//! Procedural generation of arenas.
+//!
+//! Maps are generated on a grid of square cells. Walls are placed as short
+//! segments until the desired density is reached, after which we carve
+//! passages so that every free cell is reachable from every other free cell.
+//! Spawn points are spread out by path distance, so that no spawn point is
+//! favored over another.
+
+use std::collections::VecDeque;
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::{
+    entities::{FoodSpawn, Wall},
+    geom::AaRect,
+    DangerGuy, Entity, Map, Point, Turret, Vector,
+};
+
+pub const CELL_SIZE: f32 = 128.0;
+
+/// Minimal path distance in cells between spawn points and hazards.
+pub const MIN_SPAWN_DIST_TURRET: usize = 4;
+pub const MIN_SPAWN_DIST_DANGER_GUY: usize = 3;
+
+const MIN_SIZE: usize = 8;
+const MAX_SIZE: usize = 128;
+const MAX_WALL_DENSITY: f32 = 0.6;
+const MAX_WALL_SEGMENT_LEN: usize = 4;
+const MIN_DANGER_GUY_RUN: usize = 4;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub seed: u64,
+
+    /// Size of the map in cells.
+    pub size: (usize, usize),
+
+    /// Fraction of cells that should be covered by walls.
+    pub wall_density: f32,
+
+    pub num_spawn_points: usize,
+    pub num_turrets: usize,
+    pub num_food_spawns: usize,
+    pub num_danger_guys: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            size: (25, 25),
+            wall_density: 0.2,
+            num_spawn_points: 8,
+            num_turrets: 6,
+            num_food_spawns: 40,
+            num_danger_guys: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum GenError {
+    InvalidSize((usize, usize)),
+    InvalidWallDensity(f32),
+    NotEnoughSpace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    Free,
+    Wall,
+    Turret,
+}
+
+struct Grid {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+}
+
+type Pos = (usize, usize);
+
+impl Grid {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::Free; width * height],
+        }
+    }
+
+    fn get(&self, (x, y): Pos) -> Cell {
+        self.cells[y * self.width + x]
+    }
+
+    fn set(&mut self, (x, y): Pos, cell: Cell) {
+        self.cells[y * self.width + x] = cell;
+    }
+
+    fn is_free(&self, pos: Pos) -> bool {
+        self.get(pos) == Cell::Free
+    }
+
+    fn positions(&self) -> impl Iterator<Item = Pos> {
+        let width = self.width;
+        (0..self.height).flat_map(move |y| (0..width).map(move |x| (x, y)))
+    }
+
+    fn free_positions(&self) -> Vec<Pos> {
+        self.positions().filter(|pos| self.is_free(*pos)).collect()
+    }
+
+    fn neighbors(&self, (x, y): Pos) -> impl Iterator<Item = Pos> {
+        let (width, height) = (self.width as isize, self.height as isize);
+
+        [(-1, 0), (1, 0), (0, -1), (0, 1)]
+            .iter()
+            .map(move |(dx, dy)| (x as isize + dx, y as isize + dy))
+            .filter(move |(nx, ny)| *nx >= 0 && *ny >= 0 && *nx < width && *ny < height)
+            .map(|(nx, ny)| (nx as usize, ny as usize))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Breadth-first search over free cells, returning the path distance from
+    /// the closest of the given start positions for every cell.
+    fn distances(&self, starts: &[Pos]) -> Vec<Option<usize>> {
+        let mut dist = vec![None; self.cells.len()];
+        let mut queue = VecDeque::new();
+
+        for &start in starts {
+            dist[start.1 * self.width + start.0] = Some(0);
+            queue.push_back(start);
+        }
+
+        while let Some(pos) = queue.pop_front() {
+            let d = dist[pos.1 * self.width + pos.0].unwrap();
+
+            for next in self.neighbors(pos) {
+                let index = next.1 * self.width + next.0;
+                if self.is_free(next) && dist[index].is_none() {
+                    dist[index] = Some(d + 1);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        dist
+    }
+
+    fn components(&self) -> Vec<Vec<Pos>> {
+        let mut seen = vec![false; self.cells.len()];
+        let mut components = Vec::new();
+
+        for pos in self.positions() {
+            if !self.is_free(pos) || seen[pos.1 * self.width + pos.0] {
+                continue;
+            }
+
+            seen[pos.1 * self.width + pos.0] = true;
+            let mut component = vec![pos];
+            let mut next_index = 0;
+
+            while let Some(&current) = component.get(next_index) {
+                next_index += 1;
+
+                for next in self.neighbors(current) {
+                    let index = next.1 * self.width + next.0;
+                    if self.is_free(next) && !seen[index] {
+                        seen[index] = true;
+                        component.push(next);
+                    }
+                }
+            }
+
+            components.push(component);
+        }
+
+        components
+    }
+
+    /// Checks if the free cells stay connected when `pos` is blocked,
+    /// assuming that they are connected now. For this, the free neighbors
+    /// of `pos` need to stay connected with each other. Usually, they are
+    /// connected through the cells right around `pos`, so we try that before
+    /// searching the rest of the grid.
+    fn stays_connected_without(&self, pos: Pos) -> bool {
+        let neighbors: Vec<Pos> = self
+            .neighbors(pos)
+            .filter(|next| self.is_free(*next))
+            .collect();
+
+        neighbors.len() <= 1
+            || self.connects_around(pos, &neighbors, 1)
+            || self.connects_around(pos, &neighbors, self.width.max(self.height))
+    }
+
+    /// Searches the free cells within `radius` of `center`, not including
+    /// `center` itself, to check if all the `targets` are connected.
+    fn connects_around(&self, center: Pos, targets: &[Pos], radius: usize) -> bool {
+        let is_near = |(x, y): Pos| {
+            x + radius >= center.0
+                && x <= center.0 + radius
+                && y + radius >= center.1
+                && y <= center.1 + radius
+        };
+
+        let mut visited = vec![false; self.cells.len()];
+        let mut queue = VecDeque::new();
+        let mut num_missing = targets.len() - 1;
+
+        visited[center.1 * self.width + center.0] = true;
+        visited[targets[0].1 * self.width + targets[0].0] = true;
+        queue.push_back(targets[0]);
+
+        while let Some(pos) = queue.pop_front() {
+            for next in self.neighbors(pos) {
+                let index = next.1 * self.width + next.0;
+                if visited[index] || !self.is_free(next) || !is_near(next) {
+                    continue;
+                }
+
+                visited[index] = true;
+                queue.push_back(next);
+
+                if targets.contains(&next) {
+                    num_missing -= 1;
+                    if num_missing == 0 {
+                        return true;
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Clears the walls on a shortest path from `from` to any cell of
+    /// `targets`, where walls are allowed to be crossed.
+    fn carve_path(&mut self, from: Pos, targets: &[Pos]) {
+        let mut is_target = vec![false; self.cells.len()];
+        for target in targets {
+            is_target[target.1 * self.width + target.0] = true;
+        }
+
+        let mut prev: Vec<Option<Pos>> = vec![None; self.cells.len()];
+        let mut visited = vec![false; self.cells.len()];
+        let mut queue = VecDeque::new();
+
+        visited[from.1 * self.width + from.0] = true;
+        queue.push_back(from);
+
+        while let Some(pos) = queue.pop_front() {
+            if is_target[pos.1 * self.width + pos.0] {
+                let mut current = pos;
+                while let Some(p) = prev[current.1 * self.width + current.0] {
+                    if self.get(p) == Cell::Wall {
+                        self.set(p, Cell::Free);
+                    }
+                    current = p;
+                }
+                return;
+            }
+
+            for next in self.neighbors(pos) {
+                let index = next.1 * self.width + next.0;
+                if !visited[index] && self.get(next) != Cell::Turret {
+                    visited[index] = true;
+                    prev[index] = Some(pos);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+}
+
+pub fn generate_map(config: &Config) -> Result<Map, GenError> {
+    let (width, height) = config.size;
+    if width < MIN_SIZE || height < MIN_SIZE || width > MAX_SIZE || height > MAX_SIZE {
+        return Err(GenError::InvalidSize(config.size));
+    }
+    if !(0.0..=MAX_WALL_DENSITY).contains(&config.wall_density) {
+        return Err(GenError::InvalidWallDensity(config.wall_density));
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut grid = Grid::new(width, height);
+
+    place_walls(&mut grid, config.wall_density, &mut rng);
+    connect_components(&mut grid);
+
+    let spawn_points = place_spawn_points(&grid, config.num_spawn_points, &mut rng)?;
+    let spawn_dist = grid.distances(&spawn_points);
+
+    let turrets = place_turrets(&mut grid, config.num_turrets, &spawn_dist, &mut rng)?;
+    let spawn_dist = grid.distances(&spawn_points);
+
+    let danger_guys = place_danger_guys(&grid, config.num_danger_guys, &spawn_dist, &mut rng)?;
+
+    let mut food_candidates: Vec<Pos> = grid
+        .free_positions()
+        .into_iter()
+        .filter(|pos| !spawn_points.contains(pos))
+        .collect();
+    if food_candidates.len() < config.num_food_spawns {
+        return Err(GenError::NotEnoughSpace);
+    }
+    food_candidates.shuffle(&mut rng);
+    let food_spawns = &food_candidates[..config.num_food_spawns];
+
+    let mut entities: Vec<Entity> = wall_rects(&grid)
+        .into_iter()
+        .map(|rect| Entity::Wall(Wall { rect }))
+        .collect();
+    entities.extend(
+        turrets
+            .iter()
+            .map(|pos| Entity::Turret(Turret::new(cell_center(*pos)))),
+    );
+    entities.extend(danger_guys.into_iter().map(Entity::DangerGuy));
+    entities.extend(
+        food_spawns
+            .iter()
+            .map(|pos| Entity::FoodSpawn(FoodSpawn::new(cell_center(*pos)))),
+    );
+
+    Ok(Map {
+        name: format!("generated-{}", config.seed),
+        spawn_points: spawn_points.into_iter().map(cell_center).collect(),
+        entities,
+        size: Vector::new(width as f32 * CELL_SIZE, height as f32 * CELL_SIZE),
+    })
+}
+
+fn place_walls(grid: &mut Grid, density: f32, rng: &mut StdRng) {
+    let target = (density * grid.cells.len() as f32) as usize;
+    let mut num_walls = 0;
+    let mut attempts = 0;
+
+    // Walls are placed as short horizontal or vertical segments, which looks
+    // a bit more like an arena than uniformly scattered blocks.
+    while num_walls < target && attempts < 10 * grid.cells.len() {
+        attempts += 1;
+
+        let (mut x, mut y) = (rng.gen_range(0, grid.width), rng.gen_range(0, grid.height));
+        let horizontal = rng.gen::<bool>();
+        let len = rng.gen_range(1, MAX_WALL_SEGMENT_LEN + 1);
+
+        for _ in 0..len {
+            if x >= grid.width || y >= grid.height || num_walls >= target {
+                break;
+            }
+
+            if grid.is_free((x, y)) {
+                grid.set((x, y), Cell::Wall);
+                num_walls += 1;
+            }
+
+            if horizontal {
+                x += 1;
+            } else {
+                y += 1;
+            }
+        }
+    }
+}
+
+fn connect_components(grid: &mut Grid) {
+    loop {
+        let mut components = grid.components();
+        if components.len() <= 1 {
+            return;
+        }
+
+        // Connect the smallest component to the largest one and try again.
+        components.sort_by_key(|component| component.len());
+        let largest = components.pop().unwrap();
+        grid.carve_path(components[0][0], &largest);
+    }
+}
+
+/// Spread out spawn points by always choosing the free cell that is farthest
+/// away from the spawn points chosen so far.
+fn place_spawn_points(grid: &Grid, num: usize, rng: &mut StdRng) -> Result<Vec<Pos>, GenError> {
+    let free = grid.free_positions();
+    if free.len() < num {
+        return Err(GenError::NotEnoughSpace);
+    }
+
+    let mut spawn_points: Vec<Pos> = free.choose(rng).into_iter().copied().collect();
+
+    while spawn_points.len() < num {
+        let dist = grid.distances(&spawn_points);
+        let next = free
+            .iter()
+            .filter(|pos| !spawn_points.contains(pos))
+            .max_by_key(|pos| dist[pos.1 * grid.width + pos.0].unwrap_or(0))
+            .copied()
+            .ok_or(GenError::NotEnoughSpace)?;
+
+        spawn_points.push(next);
+    }
+
+    Ok(spawn_points)
+}
+
+/// Turrets are placed next to walls, away from spawn points. Since turrets
+/// block movement, we only keep a turret if the map stays connected.
+fn place_turrets(
+    grid: &mut Grid,
+    num: usize,
+    spawn_dist: &[Option<usize>],
+    rng: &mut StdRng,
+) -> Result<Vec<Pos>, GenError> {
+    let mut candidates: Vec<Pos> = grid
+        .free_positions()
+        .into_iter()
+        .filter(|pos| {
+            spawn_dist[pos.1 * grid.width + pos.0].map_or(false, |d| d >= MIN_SPAWN_DIST_TURRET)
+        })
+        .filter(|pos| grid.neighbors(*pos).any(|n| grid.get(n) == Cell::Wall))
+        .collect();
+    candidates.shuffle(rng);
+
+    let mut turrets = Vec::new();
+
+    for pos in candidates {
+        if turrets.len() == num {
+            break;
+        }
+
+        if grid.stays_connected_without(pos) {
+            grid.set(pos, Cell::Turret);
+            turrets.push(pos);
+        }
+    }
+
+    if turrets.len() < num {
+        Err(GenError::NotEnoughSpace)
+    } else {
+        Ok(turrets)
+    }
+}
+
+/// Danger guys patrol straight runs of free cells that do not contain any
+/// spawn points.
+fn place_danger_guys(
+    grid: &Grid,
+    num: usize,
+    spawn_dist: &[Option<usize>],
+    rng: &mut StdRng,
+) -> Result<Vec<DangerGuy>, GenError> {
+    let is_candidate = |pos: Pos| {
+        grid.is_free(pos)
+            && spawn_dist[pos.1 * grid.width + pos.0]
+                .map_or(false, |d| d >= MIN_SPAWN_DIST_DANGER_GUY)
+    };
+
+    let mut runs = Vec::new();
+    for horizontal in [true, false].iter().copied() {
+        let (outer, inner) = if horizontal {
+            (grid.height, grid.width)
+        } else {
+            (grid.width, grid.height)
+        };
+
+        for i in 0..outer {
+            let mut start = None;
+
+            for j in 0..=inner {
+                let pos = if horizontal { (j, i) } else { (i, j) };
+                let free = j < inner && is_candidate(pos);
+
+                match (free, start) {
+                    (true, None) => start = Some(j),
+                    (false, Some(s)) => {
+                        if j - s >= MIN_DANGER_GUY_RUN {
+                            runs.push((horizontal, i, s, j - 1));
+                        }
+                        start = None;
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    if runs.len() < num {
+        return Err(GenError::NotEnoughSpace);
+    }
+    runs.shuffle(rng);
+
+    Ok(runs
+        .into_iter()
+        .take(num)
+        .map(|(horizontal, i, first, last)| {
+            let (start, end, size) = if horizontal {
+                (
+                    cell_center((first, i)),
+                    cell_center((last, i)),
+                    Vector::new(0.5 * CELL_SIZE, CELL_SIZE),
+                )
+            } else {
+                (
+                    cell_center((i, first)),
+                    cell_center((i, last)),
+                    Vector::new(CELL_SIZE, 0.5 * CELL_SIZE),
+                )
+            };
+
+            DangerGuy {
+                start_pos: start,
+                end_pos: end,
+                size,
+                speed: (200.0, 100.0),
+                wait_time: (0.0, 0.0),
+                phase: 0.0,
+                is_hot: true,
+            }
+        })
+        .collect())
+}
+
+/// Merge horizontal runs of wall cells into rectangles, so that we do not
+/// produce one entity per cell.
+fn wall_rects(grid: &Grid) -> Vec<AaRect> {
+    let mut rects = Vec::new();
+
+    for y in 0..grid.height {
+        let mut start = None;
+
+        for x in 0..=grid.width {
+            let wall = x < grid.width && grid.get((x, y)) == Cell::Wall;
+
+            match (wall, start) {
+                (true, None) => start = Some(x),
+                (false, Some(s)) => {
+                    rects.push(AaRect::new_top_left(
+                        Point::new(s as f32 * CELL_SIZE, y as f32 * CELL_SIZE),
+                        Vector::new((x - s) as f32 * CELL_SIZE, CELL_SIZE),
+                    ));
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    rects
+}
+
+fn cell_center((x, y): Pos) -> Point {
+    Point::new((x as f32 + 0.5) * CELL_SIZE, (y as f32 + 0.5) * CELL_SIZE)
+}
@@ -1,5 +1,8 @@
 pub mod entities;
+pub mod mapgen;
 pub mod run;
+pub mod sent_ticks;
+pub mod wire;
 
 use std::collections::BTreeMap;
 use std::sync::Arc;
@@ -10,7 +13,10 @@ use nalgebra as na;
 
 use crate::{
     geom,
-    util::diff::{ApplyError, BTreeMapDiff, Diff, Diffable},
+    util::{
+        bits, checksum,
+        diff::{ApplyError, BTreeMapDiff, Diff, Diffable},
+    },
     GameTime,
 };
 
@@ -32,6 +38,9 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Map {
+    /// Name shown in the game browser.
+    #[serde(default)]
+    pub name: String,
     pub spawn_points: Vec<Point>,
     pub entities: Vec<Entity>,
     pub size: Vector,
@@ -94,6 +103,165 @@ pub struct Input {
     pub dash: bool,
     pub use_action: bool,
     pub shoot: bool,
+
+    /// Direction that we aim in, for example towards the mouse cursor. Only
+    /// the direction matters, not the length. If not given, we aim in the
+    /// direction that we are facing.
+    #[serde(default)]
+    pub aim: Option<Vector>,
+
+    /// Analog movement, for example from a gamepad stick. Its length, up to
+    /// one, is the fraction of the full speed. If given, the movement buttons
+    /// are ignored.
+    #[serde(default)]
+    pub move_dir: Option<Vector>,
+}
+
+/// An input as it is sent over the network, with the buttons packed into
+/// bits and the vectors quantized into bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackedInput {
+    pub buttons: u8,
+    pub aim: Option<u8>,
+
+    /// Angle and length of the movement.
+    pub move_dir: Option<(u8, u8)>,
+}
+
+impl Input {
+    /// Packs the buttons of the input into one bit per button.
+    pub fn to_bits(&self) -> u8 {
+        [
+            self.move_left,
+            self.move_right,
+            self.move_up,
+            self.move_down,
+            self.dash,
+            self.use_action,
+            self.shoot,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &pressed)| bits | ((pressed as u8) << i))
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        let pressed = |i: usize| (bits >> i) & 1 == 1;
+
+        Self {
+            move_left: pressed(0),
+            move_right: pressed(1),
+            move_up: pressed(2),
+            move_down: pressed(3),
+            dash: pressed(4),
+            use_action: pressed(5),
+            shoot: pressed(6),
+            aim: None,
+            move_dir: None,
+        }
+    }
+
+    /// Packs the input for sending it over the network. Vectors that are not
+    /// finite or too short to have a direction are dropped.
+    pub fn pack(&self) -> PackedInput {
+        let valid = |v: &Vector| v.x.is_finite() && v.y.is_finite() && v.norm() > 0.0;
+        let angle = |v: &Vector| bits::angle_to_byte(v.y.atan2(v.x));
+
+        PackedInput {
+            buttons: self.to_bits(),
+            aim: self.aim.filter(valid).map(|aim| angle(&aim)),
+            move_dir: self
+                .move_dir
+                .filter(valid)
+                .map(|dir| (angle(&dir), (dir.norm().min(1.0) * 255.0).round() as u8))
+                .filter(|(_, len)| *len > 0),
+        }
+    }
+
+    pub fn unpack(packed: &PackedInput) -> Self {
+        let dir = |angle: u8| {
+            let angle = bits::byte_to_angle(angle);
+            Vector::new(angle.cos(), angle.sin())
+        };
+
+        Self {
+            aim: packed.aim.map(dir),
+            move_dir: packed
+                .move_dir
+                .map(|(angle, len)| dir(angle) * (len as f32 / 255.0)),
+            ..Self::from_bits(packed.buttons)
+        }
+    }
+
+    /// Returns the input as the server will see it after it has been sent
+    /// over the network. Clients use this for prediction, so that it matches
+    /// what happens on the server.
+    pub fn quantized(&self) -> Self {
+        Self::unpack(&self.pack())
+    }
+}
+
+/// Inputs for consecutive ticks, as sent by clients. Inputs often stay the
+/// same for a while, so runs of identical inputs are stored only once.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputRuns {
+    /// Tick number of the first input.
+    pub first_tick_num: TickNum,
+
+    /// Packed inputs, each with the number of ticks in a row that it was
+    /// given for.
+    pub runs: Vec<(PackedInput, u8)>,
+}
+
+impl InputRuns {
+    pub fn new(first_tick_num: TickNum) -> Self {
+        Self {
+            first_tick_num,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Appends the input for the tick after the last one.
+    pub fn push(&mut self, input: &Input) {
+        let packed = input.pack();
+
+        match self.runs.last_mut() {
+            Some((last_packed, len)) if *last_packed == packed && *len < std::u8::MAX => {
+                *len += 1;
+            }
+            _ => self.runs.push((packed, 1)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the inputs together with their tick numbers. Returns `None` if
+    /// there are more than `max_len` inputs, or if the runs are invalid.
+    pub fn inputs(&self, max_len: usize) -> Option<Vec<(TickNum, Input)>> {
+        if self.len() > max_len || self.runs.iter().any(|(_, len)| *len == 0) {
+            return None;
+        }
+
+        let mut tick_num = self.first_tick_num;
+        let mut inputs = Vec::with_capacity(self.len());
+
+        for (packed, len) in self.runs.iter() {
+            for _ in 0..*len {
+                if !inputs.is_empty() {
+                    tick_num = TickNum(tick_num.0.checked_add(1)?);
+                }
+                inputs.push((tick_num, Input::unpack(packed)));
+            }
+        }
+
+        Some(inputs)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -189,6 +357,13 @@ impl Game {
         self.settings.tick_game_time(tick_num)
     }
 
+    /// Returns a hash of the state that is the same on every platform. Clients
+    /// send it back to the server when acknowledging a tick, so that we
+    /// notice if delta decoding went wrong.
+    pub fn checksum(&self) -> u64 {
+        checksum::hash(&(self.tick_num, &self.players, &self.entities, self.catcher))
+    }
+
     pub fn game_time(&self) -> GameTime {
         self.tick_game_time(self.tick_num)
     }
@@ -233,4 +408,19 @@ pub struct Tick {
     pub diff: GameDiff,
     pub events: Vec<(TickNum, Vec<Event>)>,
     pub your_last_input_num: Option<TickNum>,
+
+    /// The server sends only every `send_interval`-th tick to us, depending
+    /// on the quality of our connection.
+    pub send_interval: u32,
+}
+
+/// A short replay of the moments before a player died, centered on their
+/// killer. Since a whole kill cam is too large for a single packet, it is sent
+/// in multiple chunks, each containing some of the frames. Chunks belonging to
+/// the same kill cam share the same `death_tick_num`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KillCam {
+    pub killer: PlayerId,
+    pub death_tick_num: TickNum,
+    pub frames: Vec<(TickNum, EntityMap)>,
 }
@@ -16,6 +16,7 @@ pub const PLAYER_DASH_DURATION: GameTime = 0.6;
 pub const PLAYER_DASH_SPEED: f32 = 850.0;
 pub const PLAYER_DASH_TURN_FACTOR: f32 = 0.8;
 pub const PLAYER_MAX_SIZE_BUMP: f32 = 50.0;
+pub const PLAYER_MIN_ANALOG_INPUT: f32 = 0.01;
 pub const PLAYER_MOVE_L: f32 = 28.2;
 pub const PLAYER_MOVE_SPEED: f32 = 300.0;
 pub const PLAYER_MOVE_W: f32 = 56.6;
@@ -26,6 +27,7 @@ pub const PLAYER_SIZE_BUMP_FACTOR: f32 = 20.0;
 pub const PLAYER_SIZE_SCALE_FACTOR: f32 = 10.0;
 pub const PLAYER_SIZE_SKEW: f32 = 0.15;
 pub const PLAYER_SIZE_SKEW_FACTOR: f32 = 20.0;
+pub const PLAYER_SPAWN_PROTECTION_DURATION: GameTime = 2.0;
 pub const PLAYER_TAKE_FOOD_SIZE_BUMP: f32 = 25.0;
 pub const PLAYER_TARGET_SIZE_BUMP_FACTOR: f32 = 30.0;
 pub const PLAYER_TURN_DURATION: GameTime = 0.5;
@@ -139,19 +141,37 @@ impl Game {
         }
         assert!(ent.hook_cooldown.is_finite());
         assert!(ent.anim_frame.1.is_finite());
+        assert!(ent.spawn_protection.is_finite());
 
         let dt = self.settings.tick_period();
         let input_state = input_state.unwrap_or(self);
         let input_time = input_state.game_time();
 
+        // Analog input comes from untrusted clients, so we only accept finite
+        // vectors, and movement is limited to full speed.
+        let valid =
+            |v: &Vector| v.x.is_finite() && v.y.is_finite() && v.norm() >= PLAYER_MIN_ANALOG_INPUT;
+        let move_dir = input.move_dir.filter(valid).map(|dir| {
+            if dir.norm() > 1.0 {
+                dir.normalize()
+            } else {
+                dir
+            }
+        });
+        let aim = input.aim.filter(valid).map(|aim| aim.normalize());
+
         // Movement
         let prev_target_angle = ent.target_angle;
-        let mut any_move_key = false;
+        let mut move_speed_factor = 0.0;
 
         if let Some(dash) = ent.dash.as_ref() {
             // Movement is constricted while dashing.
             ent.target_angle = dash.dir.y.atan2(dash.dir.x);
             assert!(ent.target_angle.is_finite());
+        } else if let Some(dir) = move_dir {
+            // Analog movement replaces the movement keys.
+            ent.target_angle = dir.y.atan2(dir.x);
+            move_speed_factor = dir.norm();
         } else {
             // Normal movement when not dashing.
             let mut delta = Vector::new(0.0, 0.0);
@@ -170,7 +190,7 @@ impl Game {
 
             if delta.norm() > 0.0 {
                 ent.target_angle = delta.y.atan2(delta.x);
-                any_move_key = true;
+                move_speed_factor = 1.0;
             }
         }
 
@@ -257,7 +277,7 @@ impl Game {
             } else {
                 Vector::new(ent.angle.cos(), ent.angle.sin())
                     * PLAYER_MOVE_SPEED
-                    * (any_move_key as usize as f32)
+                    * move_speed_factor
             };
             let factor = if ent.dash.is_some() {
                 PLAYER_DASH_ACCEL_FACTOR
@@ -344,7 +364,8 @@ impl Game {
                 }
             }
         } else if input.use_action && ent.hook.is_none() && ent.hook_cooldown == 0.0 {
-            let vel = Vector::new(ent.angle.cos(), ent.angle.sin()) * HOOK_SHOOT_SPEED;
+            let vel = aim.unwrap_or_else(|| Vector::new(ent.angle.cos(), ent.angle.sin()))
+                * HOOK_SHOOT_SPEED;
             Some(Hook::Shooting {
                 pos: ent.pos + vel * 0.05,
                 vel,
@@ -384,11 +405,17 @@ impl Game {
             if let Some(collision) = collision {
                 let mut collide = true;
 
+                let other_is_protected = match other_entity {
+                    Entity::Player(other_ent) => other_ent.is_protected(),
+                    Entity::PlayerView(other_ent) => other_ent.is_protected,
+                    _ => false,
+                };
+
                 if let Entity::Player(_) | Entity::PlayerView(_) = other_entity {
                     // TODO: Decide whom to favor regarding catching... or if
                     // we should even make it happen over a longer duration.
                     if self.catcher == Some(ent.owner) {
-                        if ent.dash.is_some() {
+                        if ent.dash.is_some() && !other_is_protected {
                             caught_players.insert(*other_entity_id);
                         }
 
@@ -430,6 +457,13 @@ impl Game {
 
         ent.pos += offset;
 
+        // Spawn protection ends as soon as the player starts dashing or
+        // shooting. Otherwise, it runs out over time on the server, and the
+        // client takes it from the server state instead of predicting it.
+        if input.dash || input.shoot {
+            ent.spawn_protection = 0.0;
+        }
+
         // Clip to map boundary
         ent.pos.x = ent
             .pos
@@ -459,7 +493,7 @@ impl Game {
             assert!(ent.angle.sin().is_finite());
             Some(Dash {
                 time_left: PLAYER_DASH_DURATION,
-                dir: Vector::new(ent.angle.cos(), ent.angle.sin()),
+                dir: aim.unwrap_or_else(|| Vector::new(ent.angle.cos(), ent.angle.sin())),
             })
         } else {
             None
@@ -478,7 +512,7 @@ impl Game {
                     owner: Some(ent.owner),
                     start_time: input_time,
                     start_pos,
-                    angle: ent.angle,
+                    angle: aim.map_or(ent.angle, |aim| aim.y.atan2(aim.x)),
                 }));
 
                 ent.shots_left -= 1;
@@ -491,29 +525,31 @@ impl Game {
             }
         }
 
-        // Check for death
+        // Check for death, unless we are still protected after spawning
         let mut killed = None;
 
-        for (entity_id, entity) in input_state.entities.iter() {
-            match entity {
-                Entity::DangerGuy(danger_guy) if danger_guy.is_hot => {
-                    if geom::rect_collision(
-                        &danger_guy.aa_rect(input_time).to_rect(),
-                        &ent.rect(),
-                        Vector::zeros(),
-                    )
-                    .is_some()
-                    {
-                        killed = Some(DeathReason::TouchedTheDanger);
+        if !ent.is_protected() {
+            for (entity_id, entity) in input_state.entities.iter() {
+                match entity {
+                    Entity::DangerGuy(danger_guy) if danger_guy.is_hot => {
+                        if geom::rect_collision(
+                            &danger_guy.aa_rect(input_time).to_rect(),
+                            &ent.rect(),
+                            Vector::zeros(),
+                        )
+                        .is_some()
+                        {
+                            killed = Some(DeathReason::TouchedTheDanger);
+                        }
                     }
-                }
-                Entity::Bullet(bullet) if bullet.owner != Some(ent.owner) => {
-                    if ent.rect().contains_point(bullet.pos(input_time)) {
-                        context.removed_entities.insert(*entity_id);
-                        killed = Some(DeathReason::ShotBy(bullet.owner));
+                    Entity::Bullet(bullet) if bullet.owner != Some(ent.owner) => {
+                        if ent.rect().contains_point(bullet.pos(input_time)) {
+                            context.removed_entities.insert(*entity_id);
+                            killed = Some(DeathReason::ShotBy(bullet.owner));
+                        }
                     }
+                    _ => (),
                 }
-                _ => (),
             }
         }
 
@@ -525,7 +561,7 @@ impl Game {
                 (1, 0.0)
             }
         } else {
-            if any_move_key {
+            if move_speed_factor > 0.0 {
                 Self::cycle_anim(&[2, 3], 4.0, dt, ent.anim_frame)
             } else {
                 (0, 0.0)
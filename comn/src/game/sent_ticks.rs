@@ -0,0 +1,203 @@
+//! Bookkeeping of the ticks that the server has sent to one player.
+//!
+//! The states that we sent are kept until the player acknowledges a later
+//! tick, so that the acknowledged state can serve as the basis for delta
+//! encoding. Events are sent again with every tick until the player
+//! acknowledges a tick that contained them. If the checksum in an
+//! acknowledgement does not match the state that we sent, the state of the
+//! player has diverged. The next tick is then sent from scratch, together with
+//! the events that may not have reached the player.
+
+use std::collections::VecDeque;
+
+use crate::{game::GameDiff, util::diff::Diffable, Event, Game, TickNum};
+
+/// Maximal number of ticks between a state and the state that its diff is
+/// based on. This also bounds the number of states that we keep.
+pub const MAX_DIFF_TICKS: u32 = 50;
+
+#[derive(Debug, Clone)]
+struct SentTick {
+    /// Events that we sent for the first time with this tick. They are sent
+    /// again with every tick until the player acknowledges this one.
+    events: Vec<(TickNum, Vec<Event>)>,
+    state: Game,
+
+    /// Checksum of `state`, which the player sends back when acknowledging
+    /// the tick.
+    checksum: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ack {
+    /// The player has decoded the state that we sent.
+    Valid,
+
+    /// The player has decoded a different state than the one that we sent.
+    Desync,
+
+    /// We have already received the acknowledgement of a later tick.
+    Outdated,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SentTicks {
+    /// Last tick that the player has acknowledged receiving from us. Used as
+    /// the basis for delta encoding.
+    last_ack_tick: Option<TickNum>,
+
+    /// Last states that we have sent to the player, ordered by the tick number
+    /// ascending.
+    last_sent: VecDeque<SentTick>,
+
+    /// Set when the player acknowledged a tick with the wrong checksum. The
+    /// next tick is then sent without delta encoding.
+    force_full_tick: bool,
+
+    /// Events of the ticks that we did not send to the player. They are sent
+    /// along with the next tick.
+    skipped_events: Vec<(TickNum, Vec<Event>)>,
+}
+
+impl SentTicks {
+    pub fn last_ack_tick(&self) -> Option<TickNum> {
+        self.last_ack_tick
+    }
+
+    /// Returns the number of states that we keep for delta encoding.
+    pub fn num_states(&self) -> usize {
+        self.last_sent.len()
+    }
+
+    /// Remembers the events of a tick that we do not send to the player, so
+    /// that they are sent along with the next tick.
+    pub fn skip(&mut self, tick_num: TickNum, events: Vec<Event>) {
+        self.skipped_events.push((tick_num, events));
+    }
+
+    /// Prepares sending `state` to the player, with the `events` that
+    /// happened in its tick. Returns the tick number of the state that the
+    /// diff is based on, if any, the diff, and the events to send.
+    pub fn send(
+        &mut self,
+        state: Game,
+        events: Vec<Event>,
+    ) -> (Option<TickNum>, GameDiff, Vec<(TickNum, Vec<Event>)>) {
+        // Events of the ticks that we skipped for this player are sent for the
+        // first time with this tick.
+        let mut new_events = std::mem::take(&mut self.skipped_events);
+        new_events.push((state.tick_num, events));
+
+        // Re-send all the events that happened since the last tick that the
+        // player acknowledged. Older states have been pruned already, and
+        // after a desync, these are all the states we have sent since.
+        let mut events = new_events.clone();
+        for sent_tick in self.last_sent.iter() {
+            events.extend(
+                sent_tick
+                    .events
+                    .iter()
+                    .filter(|(_, sent_events)| !sent_events.is_empty())
+                    .cloned(),
+            );
+        }
+
+        // Attempt to do delta encoding w.r.t. a previous state if
+        // possible.
+        let ack_num_and_sent_state = self
+            .last_ack_tick
+            .filter(|_| !self.force_full_tick)
+            .and_then(|ack_num| {
+                self.last_sent
+                    .front()
+                    .map(|sent_tick| (ack_num, &sent_tick.state))
+            })
+            .filter(|(ack_num, sent_state)| {
+                *ack_num == sent_state.tick_num && ack_num.0 + MAX_DIFF_TICKS > state.tick_num.0
+            });
+
+        let (diff_base, diff) = if let Some((ack_num, sent_state)) = ack_num_and_sent_state {
+            // Okay, we know that the player has acknowledged a tick for which
+            // we also still have the state. We can use this state as the basis
+            // for delta encoding.
+            (Some(ack_num), sent_state.diff(&state))
+        } else {
+            // We cannot do delta encoding.
+            let base_state = Game::new(state.settings.clone());
+            (None, base_state.diff(&state))
+        };
+
+        // Remember the state we're sending, so that we may use it as the basis
+        // for delta encoding in the future (assuming that we will receive the
+        // client's receival acknowledgement).
+        self.force_full_tick = false;
+        self.last_sent.push_back(SentTick {
+            events: new_events,
+            checksum: state.checksum(),
+            state,
+        });
+
+        // Prune the state memory. This should be rarely necessary, since we
+        // already prune states when we receive acknowledgements.
+        if self.last_sent.len() > MAX_DIFF_TICKS as usize {
+            self.last_sent.pop_front();
+        }
+
+        (diff_base, diff, events)
+    }
+
+    /// Records that the player has received the tick `ack_num` and decoded a
+    /// state with the given checksum from it.
+    pub fn record_ack(&mut self, ack_num: TickNum, checksum: u64) -> Ack {
+        if self
+            .last_ack_tick
+            .map_or(false, |last_ack_num| ack_num <= last_ack_num)
+        {
+            return Ack::Outdated;
+        }
+
+        let is_desync = self
+            .last_sent
+            .iter()
+            .find(|sent_tick| sent_tick.state.tick_num == ack_num)
+            .map_or(false, |sent_tick| sent_tick.checksum != checksum);
+        if is_desync {
+            // The player's state differs from what we sent, so we cannot use
+            // it as the basis for delta encoding anymore.
+            self.resend_full_state(ack_num);
+            return Ack::Desync;
+        }
+
+        self.last_ack_tick = Some(ack_num);
+
+        // We can now forget all the states that are older than the one whose
+        // acknowledgment we just received.
+        while self
+            .last_sent
+            .front()
+            .map_or(false, |sent_tick| sent_tick.state.tick_num < ack_num)
+        {
+            self.last_sent.pop_front();
+        }
+
+        Ack::Valid
+    }
+
+    /// Forgets the states that we sent to the player, so that the next tick
+    /// is sent from scratch. The player has received the events up to the
+    /// acknowledged tick, but the events of the later ticks would be lost
+    /// with the states, so they are sent again with the next tick.
+    fn resend_full_state(&mut self, ack_num: TickNum) {
+        let mut events: Vec<_> = self
+            .last_sent
+            .drain(..)
+            .filter(|sent_tick| sent_tick.state.tick_num > ack_num)
+            .flat_map(|sent_tick| sent_tick.events)
+            .collect();
+        events.append(&mut self.skipped_events);
+
+        self.skipped_events = events;
+        self.last_ack_tick = None;
+        self.force_full_tick = true;
+    }
+}
@@ -9,13 +9,16 @@ pub mod geom;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::util::reliable;
+
 pub use crate::{
     game::{
         entities::{DangerGuy, Hook, PlayerEntity, PlayerView, Rocket, Turret},
-        DeathReason, Entity, EntityId, EntityMap, Event, Game, Input, Item, Map, Matrix, Player,
-        PlayerId, PlayerMap, PlayerState, Point, Settings, Tick, TickNum, Time, Vector,
+        DeathReason, Entity, EntityId, EntityMap, Event, Game, Input, InputRuns, Item, KillCam,
+        Map, Matrix, PackedInput, Player, PlayerId, PlayerMap, PlayerState, Point, Settings, Tick,
+        TickNum, Time, Vector,
     },
-    util::ping::SequenceNum,
+    util::{ping::SequenceNum, reliable::ReliableNum},
 };
 
 pub use crate::game::entities;
@@ -23,6 +26,14 @@ pub use crate::game::Error as GameError;
 pub use crate::game::Result as GameResult;
 pub use crate::game::Time as GameTime;
 
+/// Version of the messages exchanged between client and server. This needs to
+/// be increased with every change that breaks the serialization of messages,
+/// how they are split into datagrams, how states are checksummed or what join
+/// and reconnect requests contain, so that outdated clients are rejected when
+/// joining or reconnecting. The tests in
+/// `tests/protocol_corpus.rs` fail when any of these change.
+pub const PROTOCOL_VERSION: u32 = 8;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct GameId(pub Uuid);
 
@@ -33,6 +44,33 @@ pub struct PlayerToken(pub Uuid);
 pub struct JoinRequest {
     pub game_id: Option<GameId>,
     pub player_name: String,
+
+    /// If set, we join the game only as an observer, without being spawned.
+    #[serde(default)]
+    pub spectate: bool,
+
+    /// Needed for joining a private game. If set, `game_id` is ignored.
+    #[serde(default)]
+    pub invite_code: Option<String>,
+
+    /// If set, we join with the name of our account, and `player_name` is
+    /// ignored.
+    #[serde(default)]
+    pub session_token: Option<String>,
+
+    /// The `PROTOCOL_VERSION` of the client. Clients from before versioning
+    /// do not send this, so it defaults to zero.
+    #[serde(default)]
+    pub protocol_version: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// The player that sent the message, or `None` if it was sent by a
+    /// spectator.
+    pub player_id: Option<PlayerId>,
+    pub player_name: String,
+    pub text: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,23 +78,150 @@ pub struct JoinSuccess {
     pub game_id: GameId,
     pub game_settings: Settings,
     pub your_token: PlayerToken,
-    pub your_player_id: PlayerId,
+
+    /// Our id in the game. This is `None` for spectators.
+    pub your_player_id: Option<PlayerId>,
+
+    /// Where to establish the WebRTC connection, if the game runs on a
+    /// different server than the one that we joined through.
+    #[serde(default)]
+    pub webrtc_session_url: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum JoinError {
     InvalidGameId,
+    InvalidInviteCode,
     InvalidPlayerName,
+    ReservedPlayerName,
+    InvalidPlayerToken,
+    InvalidSessionToken,
     FullGame,
+    IncompatibleVersion,
 }
 
 pub type JoinReply = Result<JoinSuccess, JoinError>;
 
+/// Request to register or to log in to an account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthRequest {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthSuccess {
+    pub name: String,
+    pub session_token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AuthError {
+    Disabled,
+    InvalidName,
+    InvalidPassword,
+    NameTaken,
+    WrongNameOrPassword,
+}
+
+pub type AuthReply = Result<AuthSuccess, AuthError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LeaderboardPeriod {
+    Daily,
+    Weekly,
+    AllTime,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub name: String,
+    pub matches: u64,
+    pub food: u64,
+    pub catches: u64,
+    pub deaths: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Leaderboard {
+    pub period: LeaderboardPeriod,
+
+    /// Sorted by rank.
+    pub entries: Vec<LeaderboardEntry>,
+}
+
+/// Request to resume playing after losing the connection. The server keeps
+/// our slot for a while, so that we keep our food and catcher status.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconnectRequest {
+    pub token: PlayerToken,
+
+    /// The `PROTOCOL_VERSION` of the client, checked like in `JoinRequest`.
+    #[serde(default)]
+    pub protocol_version: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameMode {
+    Catch,
+}
+
+/// Summary of a running game, as shown in the game browser.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameInfo {
+    pub game_id: GameId,
+    pub map_name: String,
+    pub mode: GameMode,
+    pub num_players: usize,
+    pub num_bots: usize,
+    pub num_spectators: usize,
+    pub max_num_players: usize,
+    pub game_time: GameTime,
+
+    /// Name of the current catcher. This is `None` while the game is
+    /// waiting for someone to become the catcher.
+    pub catcher_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateGameRequest {
+    /// Private games are not listed in the game browser and can only be
+    /// joined with their invite code.
+    pub private: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateGameSuccess {
+    pub game_id: GameId,
+    pub invite_code: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CreateGameError {
+    TooManyGames,
+}
+
+pub type CreateGameReply = Result<CreateGameSuccess, CreateGameError>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
     Ping(SequenceNum),
     Pong(SequenceNum),
     Tick(Tick),
+    KillCam(KillCam),
+    Disconnect,
+
+    /// A message that is delivered reliably and in order. The receiver needs
+    /// to acknowledge it with `AckReliable`.
+    Reliable(reliable::Packet<ReliableServerMessage>),
+    AckReliable(ReliableNum),
+}
+
+/// Messages that the server sends inside of `ServerMessage::Reliable`. This
+/// is a separate type, so that reliable messages cannot be nested.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReliableServerMessage {
+    Chat(ChatMessage),
     Disconnect,
 }
 
@@ -64,13 +229,30 @@ pub enum ServerMessage {
 pub enum ClientMessage {
     Ping(SequenceNum),
     Pong(SequenceNum),
-    Input(Vec<(TickNum, Input)>),
-    // TODO: Send some kind of hash with the AckTick
-    AckTick(TickNum),
+    /// Our last inputs. Each input is sent multiple times, in case that
+    /// some messages are lost.
+    Input(InputRuns),
+    /// Acknowledges receiving a tick, together with the checksum of the state
+    /// that we decoded from it.
+    AckTick(TickNum, u64),
     Disconnect,
+
+    /// A message that is delivered reliably and in order. The receiver needs
+    /// to acknowledge it with `AckReliable`.
+    Reliable(reliable::Packet<ReliableClientMessage>),
+    AckReliable(ReliableNum),
+}
+
+/// Messages that the client sends inside of `ClientMessage::Reliable`. Chat
+/// messages are only sent reliably, since they could be duplicated or
+/// reordered otherwise.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReliableClientMessage {
+    Chat(String),
 }
 
-pub const MAX_INPUTS_PER_MESSAGE: usize = 5;
+pub const MAX_INPUTS_PER_MESSAGE: usize = 16;
+pub const MAX_CHAT_MESSAGE_LEN: usize = 200;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignedClientMessage(pub PlayerToken, pub ClientMessage);
@@ -0,0 +1,207 @@
+//! Writing and reading values that are not aligned to bytes, for compact
+//! message encodings. Used by the bit-packed encoding of ticks in
+//! `game::wire`, which is only measured and not sent. The quantization of
+//! angles is also used for the inputs that clients send.
+//!
+//! Bits are stored starting with the least significant bit of each byte.
+
+/// Quantizes an angle in radians to a single byte.
+pub fn angle_to_byte(angle: f32) -> u8 {
+    let turn = 2.0 * std::f32::consts::PI;
+
+    ((angle.rem_euclid(turn) / turn * 256.0).round() as u32 % 256) as u8
+}
+
+pub fn byte_to_angle(byte: u8) -> f32 {
+    byte as f32 / 256.0 * 2.0 * std::f32::consts::PI
+}
+
+pub struct BitWriter {
+    data: Vec<u8>,
+    num_bits: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            num_bits: 0,
+        }
+    }
+
+    pub fn write_bits(&mut self, value: u32, num_bits: usize) {
+        assert!(num_bits <= 32);
+        assert!(num_bits == 32 || value < (1 << num_bits));
+
+        for i in 0..num_bits {
+            if self.num_bits % 8 == 0 {
+                self.data.push(0);
+            }
+
+            if (value >> i) & 1 == 1 {
+                *self.data.last_mut().unwrap() |= 1 << (self.num_bits % 8);
+            }
+
+            self.num_bits += 1;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
+    /// Writes the value in groups of seven bits, each followed by a bit
+    /// telling if there are more groups. Small values, such as most ids, take
+    /// only a single byte.
+    pub fn write_varint(&mut self, mut value: u32) {
+        loop {
+            self.write_bits(value & 0x7f, 7);
+            value >>= 7;
+
+            self.write_bool(value != 0);
+            if value == 0 {
+                return;
+            }
+        }
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_bits(value.to_bits(), 32);
+    }
+
+    /// Writes a value from the range `[min, max]` with the given precision.
+    /// The value must be within the range.
+    pub fn write_quantized(&mut self, value: f32, min: f32, max: f32, num_bits: usize) {
+        assert!(value >= min && value <= max);
+
+        let steps = ((1u64 << num_bits) - 1) as f32;
+        let q = ((value - min) / (max - min) * steps).round() as u32;
+
+        self.write_bits(q.min(steps as u32), num_bits);
+    }
+
+    /// Writes an angle in radians as a single byte.
+    pub fn write_angle(&mut self, angle: f32) {
+        self.write_bits(angle_to_byte(angle) as u32, 8);
+    }
+
+    pub fn write_string(&mut self, value: &str) {
+        self.write_varint(value.len() as u32);
+
+        for byte in value.bytes() {
+            self.write_bits(byte as u32, 8);
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for BitWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads values written by `BitWriter`. All methods return `None` if the data
+/// ends too early or is invalid.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn read_bits(&mut self, num_bits: usize) -> Option<u32> {
+        assert!(num_bits <= 32);
+
+        if self.pos + num_bits > self.data.len() * 8 {
+            return None;
+        }
+
+        let mut value = 0;
+        for i in 0..num_bits {
+            let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+            value |= (bit as u32) << i;
+            self.pos += 1;
+        }
+
+        Some(value)
+    }
+
+    pub fn read_bool(&mut self) -> Option<bool> {
+        self.read_bits(1).map(|bit| bit == 1)
+    }
+
+    pub fn read_varint(&mut self) -> Option<u32> {
+        let mut value: u32 = 0;
+
+        for shift in (0..32).step_by(7) {
+            let group = self.read_bits(7)?;
+            value |= group.checked_shl(shift)?;
+
+            if !self.read_bool()? {
+                return Some(value);
+            }
+        }
+
+        // Too many groups for a u32.
+        None
+    }
+
+    pub fn read_f32(&mut self) -> Option<f32> {
+        self.read_bits(32).map(f32::from_bits)
+    }
+
+    pub fn read_quantized(&mut self, min: f32, max: f32, num_bits: usize) -> Option<f32> {
+        let steps = ((1u64 << num_bits) - 1) as f32;
+        let q = self.read_bits(num_bits)?;
+
+        // Clamp to make up for rounding errors, so that writing the value
+        // again gives the same result.
+        Some((min + q as f32 / steps * (max - min)).max(min).min(max))
+    }
+
+    pub fn read_angle(&mut self) -> Option<f32> {
+        self.read_bits(8).map(|q| byte_to_angle(q as u8))
+    }
+
+    pub fn read_string(&mut self) -> Option<String> {
+        let len = self.read_varint()? as usize;
+
+        // Check the length before allocating, so that invalid data cannot
+        // make us reserve huge amounts of memory.
+        if len.saturating_mul(8) > self.data.len() * 8 - self.pos {
+            return None;
+        }
+
+        let bytes = (0..len)
+            .map(|_| self.read_bits(8).map(|byte| byte as u8))
+            .collect::<Option<Vec<_>>>()?;
+
+        String::from_utf8(bytes).ok()
+    }
+
+    /// Reads the length of a sequence whose elements take at least
+    /// `min_bits` each, rejecting lengths that cannot fit into the rest of
+    /// the data.
+    pub fn read_len(&mut self, min_bits: usize) -> Option<usize> {
+        let len = self.read_varint()? as usize;
+
+        if len.saturating_mul(min_bits) > self.data.len() * 8 - self.pos {
+            return None;
+        }
+
+        Some(len)
+    }
+
+    /// Returns true if all the data has been read, ignoring the padding in the
+    /// last byte.
+    pub fn is_done(&self) -> bool {
+        self.data.len() * 8 - self.pos < 8
+    }
+}
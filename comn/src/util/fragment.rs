@@ -0,0 +1,258 @@
+//! Splitting of large messages into datagrams that are small enough to be
+//! sent over WebRTC without being dropped, and reassembly on the other side.
+//!
+//! Every datagram starts with a byte telling if it contains a whole message
+//! or a fragment. Fragments are followed by a header with the number of the
+//! message they belong to, their index and the number of fragments. If any
+//! fragment of a message is lost, the whole message is lost, so we drop
+//! incomplete messages after a while. Fragments may also arrive twice, so we
+//! remember which messages we have completed recently. Since the sender may
+//! be malicious, we also bound the size of datagrams and the number of bytes
+//! that are buffered for incomplete messages.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::time::Duration;
+
+use instant::Instant;
+
+/// Maximal size of datagrams that we send. Larger messages are split up.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Maximal number of fragments of a single message.
+pub const MAX_FRAGMENTS: usize = 255;
+
+const WHOLE: u8 = 0;
+const FRAGMENT: u8 = 1;
+
+/// Size of the header of fragments: kind, message number, index and count.
+const FRAGMENT_HEADER_SIZE: usize = 1 + 4 + 1 + 1;
+
+/// Maximal size of the data in a single fragment.
+pub const MAX_FRAGMENT_DATA_SIZE: usize = MAX_DATAGRAM_SIZE - FRAGMENT_HEADER_SIZE;
+
+/// Incomplete messages that are older than this are dropped.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bounds the memory used for incomplete messages.
+const MAX_INCOMPLETE_MESSAGES: usize = 32;
+
+/// Number of completed messages whose late fragments we recognize.
+const MAX_COMPLETED_MESSAGES: usize = 64;
+
+/// Bounds the total size of the fragments that we buffer for incomplete
+/// messages. This is enough for a few messages of the maximal size.
+pub const MAX_BUFFERED_SIZE: usize = 4 * MAX_FRAGMENTS * MAX_FRAGMENT_DATA_SIZE;
+
+#[derive(Debug, Clone)]
+pub struct TooLarge(pub usize);
+
+/// Splits messages into datagrams.
+#[derive(Debug, Clone, Default)]
+pub struct Splitter {
+    next_message_num: u32,
+}
+
+impl Splitter {
+    pub fn split(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>, TooLarge> {
+        if 1 + data.len() <= MAX_DATAGRAM_SIZE {
+            let mut datagram = Vec::with_capacity(1 + data.len());
+            datagram.push(WHOLE);
+            datagram.extend_from_slice(data);
+
+            return Ok(vec![datagram]);
+        }
+
+        let count = (data.len() + MAX_FRAGMENT_DATA_SIZE - 1) / MAX_FRAGMENT_DATA_SIZE;
+        if count > MAX_FRAGMENTS {
+            return Err(TooLarge(data.len()));
+        }
+
+        let message_num = self.next_message_num;
+        self.next_message_num = self.next_message_num.wrapping_add(1);
+
+        Ok(data
+            .chunks(MAX_FRAGMENT_DATA_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut datagram = Vec::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+                datagram.push(FRAGMENT);
+                datagram.extend_from_slice(&message_num.to_le_bytes());
+                datagram.push(index as u8);
+                datagram.push(count as u8);
+                datagram.extend_from_slice(chunk);
+                datagram
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Incomplete {
+    first_recv_time: Instant,
+    fragments: Vec<Option<Vec<u8>>>,
+    num_missing: usize,
+}
+
+impl Incomplete {
+    fn size(&self) -> usize {
+        self.fragments
+            .iter()
+            .flatten()
+            .map(|fragment| fragment.len())
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Empty,
+    InvalidKind(u8),
+    InvalidHeader,
+    InconsistentCount,
+    DatagramTooLarge(usize),
+    FragmentTooLarge(usize),
+}
+
+/// Reassembles messages from the datagrams of a single sender.
+#[derive(Debug, Clone, Default)]
+pub struct Reassembler {
+    incomplete: HashMap<u32, Incomplete>,
+
+    /// Numbers of the messages that we completed most recently, so that
+    /// duplicate fragments arriving later are not taken as the start of a
+    /// new message.
+    completed: VecDeque<u32>,
+
+    /// Total size of the fragments in `incomplete`.
+    buffered_size: usize,
+}
+
+impl Reassembler {
+    /// Handles a received datagram. Returns the message once all of its
+    /// fragments have been received.
+    pub fn recv(&mut self, now: Instant, datagram: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if datagram.len() > MAX_DATAGRAM_SIZE {
+            return Err(Error::DatagramTooLarge(datagram.len()));
+        }
+
+        let (&kind, rest) = datagram.split_first().ok_or(Error::Empty)?;
+
+        match kind {
+            WHOLE => Ok(Some(rest.to_vec())),
+            FRAGMENT => self.recv_fragment(now, rest),
+            kind => Err(Error::InvalidKind(kind)),
+        }
+    }
+
+    fn recv_fragment(&mut self, now: Instant, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if data.len() < FRAGMENT_HEADER_SIZE - 1 {
+            return Err(Error::InvalidHeader);
+        }
+
+        let message_num = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let index = data[4] as usize;
+        let count = data[5] as usize;
+        let chunk = &data[6..];
+
+        if index >= count {
+            return Err(Error::InvalidHeader);
+        }
+        if chunk.len() > MAX_FRAGMENT_DATA_SIZE {
+            return Err(Error::FragmentTooLarge(chunk.len()));
+        }
+
+        if self.completed.contains(&message_num) {
+            return Ok(None);
+        }
+
+        let buffered_size = &mut self.buffered_size;
+        self.incomplete.retain(|_, incomplete| {
+            let keep = now.duration_since(incomplete.first_recv_time) < REASSEMBLY_TIMEOUT;
+            if !keep {
+                *buffered_size -= incomplete.size();
+            }
+            keep
+        });
+
+        if !self.incomplete.contains_key(&message_num)
+            && self.incomplete.len() >= MAX_INCOMPLETE_MESSAGES
+        {
+            // Make room by dropping the oldest message.
+            self.remove_oldest_except(message_num);
+        }
+
+        while self.buffered_size + chunk.len() > MAX_BUFFERED_SIZE {
+            // Make room by dropping old messages. The message that this
+            // fragment belongs to always fits on its own, since it has at
+            // most `MAX_FRAGMENTS` fragments.
+            if !self.remove_oldest_except(message_num) {
+                break;
+            }
+        }
+
+        let incomplete = self
+            .incomplete
+            .entry(message_num)
+            .or_insert_with(|| Incomplete {
+                first_recv_time: now,
+                fragments: vec![None; count],
+                num_missing: count,
+            });
+
+        if incomplete.fragments.len() != count {
+            return Err(Error::InconsistentCount);
+        }
+
+        if incomplete.fragments[index].is_none() {
+            incomplete.fragments[index] = Some(chunk.to_vec());
+            incomplete.num_missing -= 1;
+            self.buffered_size += chunk.len();
+        }
+
+        if incomplete.num_missing > 0 {
+            return Ok(None);
+        }
+
+        let incomplete = self.incomplete.remove(&message_num).unwrap();
+        self.buffered_size -= incomplete.size();
+
+        if self.completed.len() >= MAX_COMPLETED_MESSAGES {
+            self.completed.pop_front();
+        }
+        self.completed.push_back(message_num);
+
+        Ok(Some(
+            incomplete
+                .fragments
+                .into_iter()
+                .flat_map(|fragment| fragment.unwrap())
+                .collect(),
+        ))
+    }
+
+    /// Total size of the fragments that are buffered for incomplete
+    /// messages.
+    pub fn buffered_size(&self) -> usize {
+        self.buffered_size
+    }
+
+    /// Drops the oldest incomplete message other than `message_num`. Returns
+    /// false if there is no such message.
+    fn remove_oldest_except(&mut self, message_num: u32) -> bool {
+        let oldest = self
+            .incomplete
+            .iter()
+            .filter(|(other_num, _)| **other_num != message_num)
+            .min_by_key(|(_, incomplete)| incomplete.first_recv_time)
+            .map(|(other_num, _)| *other_num);
+
+        if let Some(oldest) = oldest {
+            let incomplete = self.incomplete.remove(&oldest).unwrap();
+            self.buffered_size -= incomplete.size();
+            true
+        } else {
+            false
+        }
+    }
+}
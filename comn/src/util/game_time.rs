@@ -4,6 +4,9 @@ use crate::{util::stats, GameTime};
 
 const SAMPLE_DURATION: f32 = 2.0;
 
+/// Estimates the game time of a stream of ticks given the times at which we
+/// receive them. The ticks need not arrive at a fixed rate, since the server
+/// may skip sending some of them.
 #[derive(Debug, Clone)]
 pub struct GameTimeEstimation {
     recv_period: GameTime,
@@ -37,13 +40,18 @@ impl GameTimeEstimation {
         }
     }
 
+    /// Returns the standard deviation of the receive delays. Ticks can be
+    /// spaced differently, so we compare the time between receiving two ticks
+    /// to the game time between them.
     pub fn recv_delay_std_dev(&self) -> Option<f32> {
         if !self.recv_times.is_empty() {
             Some(stats::std_dev(
                 self.recv_times
                     .iter()
                     .zip(self.recv_times.iter().skip(1))
-                    .map(|((recv_a, _), (recv_b, _))| recv_b - recv_a),
+                    .map(|((recv_a, game_a), (recv_b, game_b))| {
+                        (recv_b - recv_a) - (game_b - game_a)
+                    }),
             ))
         } else {
             None
@@ -1,7 +1,11 @@
+pub mod bits;
+pub mod checksum;
+pub mod fragment;
 pub mod game_time;
 pub mod join;
 pub mod loss;
 pub mod ping;
+pub mod reliable;
 pub mod stats;
 pub mod timer;
 #[macro_use]
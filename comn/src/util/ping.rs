@@ -10,6 +10,9 @@ pub struct SequenceNum(pub usize);
 const INITIAL_ESTIMATE_MS: u64 = 100;
 const PING_PERIOD_MS: u64 = 500;
 const TIMEOUT_MS: u64 = 5_000;
+
+/// Pings that have not been answered after this long are counted as lost.
+pub const LOST_PING_TIMEOUT: Duration = Duration::from_millis(2_000);
 const NUM_KEEP_DURATIONS: usize = 100;
 
 #[derive(Debug, Clone)]
@@ -25,6 +28,10 @@ pub struct PingEstimation {
     last_received_pong_time: Instant,
     last_rtts: VecDeque<Duration>,
     estimate: Duration,
+
+    /// For the last pings, whether we received a pong. Used for estimating
+    /// packet loss.
+    last_answered: VecDeque<bool>,
 }
 
 impl Default for PingEstimation {
@@ -36,6 +43,7 @@ impl Default for PingEstimation {
             last_received_pong_time: Instant::now(),
             last_rtts: VecDeque::new(),
             estimate: Duration::from_millis(INITIAL_ESTIMATE_MS),
+            last_answered: VecDeque::new(),
         }
     }
 }
@@ -45,7 +53,26 @@ impl PingEstimation {
         self.estimate
     }
 
+    /// Fraction of recent pings that were not answered, between zero and
+    /// one. Pings are counted as lost once a later ping is answered, or once
+    /// they are older than `LOST_PING_TIMEOUT`, so that the estimate also
+    /// goes up when no pongs arrive at all.
+    pub fn loss(&self) -> f32 {
+        if self.last_answered.is_empty() {
+            0.0
+        } else {
+            let num_lost = self
+                .last_answered
+                .iter()
+                .filter(|answered| !**answered)
+                .count();
+            num_lost as f32 / self.last_answered.len() as f32
+        }
+    }
+
     pub fn next_ping_sequence_num(&mut self, now: Instant) -> Option<SequenceNum> {
+        self.expire_waiting_pings(now);
+
         if self.last_send_time.map_or(true, |last_time| {
             now - last_time > Duration::from_millis(PING_PERIOD_MS)
         }) {
@@ -82,8 +109,20 @@ impl PingEstimation {
 
             // Due to the unreliable connection, it is possible that earlier
             // waiting pings have not been answered.
+            let num_lost = self
+                .waiting_pings
+                .iter()
+                .filter(|(send_num, _)| *send_num < num)
+                .count();
             self.waiting_pings.retain(|(send_num, _)| *send_num > num);
 
+            for answered in std::iter::repeat(false)
+                .take(num_lost)
+                .chain(std::iter::once(true))
+            {
+                self.record_answered(answered);
+            }
+
             Ok(())
         } else {
             Err(ReceivedPongError::InvalidSequenceNum)
@@ -94,6 +133,29 @@ impl PingEstimation {
         now - self.last_received_pong_time >= Duration::from_millis(TIMEOUT_MS)
     }
 
+    /// Counts the pings that have been waiting for too long as lost. If
+    /// their pong arrives later, it is ignored.
+    fn expire_waiting_pings(&mut self, now: Instant) {
+        let num_expired = self
+            .waiting_pings
+            .iter()
+            .take_while(|(_, send_time)| now - *send_time >= LOST_PING_TIMEOUT)
+            .count();
+
+        self.waiting_pings.drain(..num_expired);
+
+        for _ in 0..num_expired {
+            self.record_answered(false);
+        }
+    }
+
+    fn record_answered(&mut self, answered: bool) {
+        self.last_answered.push_back(answered);
+        while self.last_answered.len() > NUM_KEEP_DURATIONS {
+            self.last_answered.pop_front();
+        }
+    }
+
     fn calculate_estimate(&self) -> Duration {
         if self.last_rtts.is_empty() {
             Duration::from_millis(INITIAL_ESTIMATE_MS)
@@ -0,0 +1,159 @@
+//! Reliable, ordered delivery of messages on top of an unreliable channel.
+//!
+//! Each reliable message is assigned a sequence number. The sender keeps
+//! resending messages until the receiver acknowledges them. Acknowledgements
+//! are cumulative: acknowledging a number means that all messages up to and
+//! including that number have been received. The receiver buffers messages
+//! that arrive out of order, so that they are handed out in order.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use instant::Instant;
+use serde::{Deserialize, Serialize};
+
+const MIN_RESEND_MS: u64 = 100;
+const RESEND_RTT_FACTOR: f32 = 1.5;
+
+/// Maximal number of messages that the receiver buffers ahead of the next
+/// message in order. The sender keeps at most this many messages in flight,
+/// so that it never sends messages that the receiver would have to drop.
+pub const MAX_BUFFERED_MESSAGES: usize = 256;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+pub struct ReliableNum(pub u32);
+
+impl ReliableNum {
+    pub fn next(self) -> Self {
+        ReliableNum(self.0 + 1)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Packet<T> {
+    pub num: ReliableNum,
+    pub message: T,
+}
+
+#[derive(Debug, Clone)]
+pub enum RecordPacketError {
+    TooFarAhead,
+}
+
+#[derive(Debug, Clone)]
+struct Unacked<T> {
+    num: ReliableNum,
+    message: T,
+    last_send_time: Option<Instant>,
+}
+
+/// One end of a reliable connection, sending messages of type `S` and
+/// receiving messages of type `R`.
+#[derive(Debug, Clone)]
+pub struct Endpoint<S, R> {
+    next_send_num: ReliableNum,
+    unacked: VecDeque<Unacked<S>>,
+
+    next_recv_num: ReliableNum,
+    received: BTreeMap<ReliableNum, R>,
+}
+
+impl<S, R> Default for Endpoint<S, R> {
+    fn default() -> Self {
+        Self {
+            next_send_num: ReliableNum(0),
+            unacked: VecDeque::new(),
+            next_recv_num: ReliableNum(0),
+            received: BTreeMap::new(),
+        }
+    }
+}
+
+impl<S: Clone, R> Endpoint<S, R> {
+    /// Queues a message to be sent. It will be returned by the next call to
+    /// `packets_to_send`.
+    pub fn push(&mut self, message: S) {
+        self.unacked.push_back(Unacked {
+            num: self.next_send_num,
+            message,
+            last_send_time: None,
+        });
+        self.next_send_num = self.next_send_num.next();
+    }
+
+    /// Returns the packets that need to be (re)sent now. Messages that have
+    /// not been acknowledged are resent after a duration that depends on the
+    /// round-trip time `rtt`. Only the oldest `MAX_BUFFERED_MESSAGES`
+    /// unacknowledged messages are sent, the others stay queued until
+    /// earlier ones have been acknowledged.
+    pub fn packets_to_send(&mut self, now: Instant, rtt: Duration) -> Vec<Packet<S>> {
+        let resend_duration =
+            Duration::from_millis(MIN_RESEND_MS).max(rtt.mul_f32(RESEND_RTT_FACTOR));
+
+        self.unacked
+            .iter_mut()
+            .take(MAX_BUFFERED_MESSAGES)
+            .filter(|unacked| {
+                unacked.last_send_time.map_or(true, |last_send_time| {
+                    now - last_send_time >= resend_duration
+                })
+            })
+            .map(|unacked| {
+                unacked.last_send_time = Some(now);
+                Packet {
+                    num: unacked.num,
+                    message: unacked.message.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Forgets about all the messages up to and including `num`, since the
+    /// receiver has them.
+    pub fn record_ack(&mut self, num: ReliableNum) {
+        while self
+            .unacked
+            .front()
+            .map_or(false, |unacked| unacked.num <= num)
+        {
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Returns true if all the messages we have sent have been acknowledged.
+    pub fn is_idle(&self) -> bool {
+        self.unacked.is_empty()
+    }
+
+    /// Records a packet that we received. Returns the number that should be
+    /// acknowledged to the sender, which we need to do even for duplicate
+    /// packets, since our previous acknowledgement may have been lost.
+    pub fn record_packet(
+        &mut self,
+        packet: Packet<R>,
+    ) -> Result<Option<ReliableNum>, RecordPacketError> {
+        if packet.num.0 >= self.next_recv_num.0 + MAX_BUFFERED_MESSAGES as u32 {
+            return Err(RecordPacketError::TooFarAhead);
+        }
+
+        if packet.num >= self.next_recv_num {
+            self.received.insert(packet.num, packet.message);
+        }
+
+        // Acknowledge everything that we have received in order, including
+        // messages that have not been taken yet.
+        let mut ack_num = self.next_recv_num;
+        while self.received.contains_key(&ack_num) {
+            ack_num = ack_num.next();
+        }
+
+        Ok(ack_num.0.checked_sub(1).map(ReliableNum))
+    }
+
+    /// Returns the next message in order, if we have received it.
+    pub fn take_message(&mut self) -> Option<R> {
+        let message = self.received.remove(&self.next_recv_num)?;
+        self.next_recv_num = self.next_recv_num.next();
+        Some(message)
+    }
+}
@@ -0,0 +1,246 @@
+//! Tests for splitting messages into datagrams in `comn::util::fragment`.
+
+use std::time::Duration;
+
+use instant::Instant;
+
+use comn::util::fragment::{
+    Error, Reassembler, Splitter, TooLarge, MAX_BUFFERED_SIZE, MAX_DATAGRAM_SIZE, MAX_FRAGMENTS,
+    MAX_FRAGMENT_DATA_SIZE, REASSEMBLY_TIMEOUT,
+};
+
+/// A message that needs four fragments.
+fn large_message() -> Vec<u8> {
+    (0..3 * MAX_DATAGRAM_SIZE + 100).map(|i| i as u8).collect()
+}
+
+/// Passes the datagrams to the reassembler, returning the messages that were
+/// completed.
+fn recv_all(reassembler: &mut Reassembler, now: Instant, datagrams: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    datagrams
+        .iter()
+        .filter_map(|datagram| reassembler.recv(now, datagram).unwrap())
+        .collect()
+}
+
+#[test]
+fn small_messages_are_not_fragmented() {
+    let mut splitter = Splitter::default();
+    let mut reassembler = Reassembler::default();
+    let message = vec![1, 2, 3];
+
+    let datagrams = splitter.split(&message).unwrap();
+    assert_eq!(datagrams.len(), 1);
+
+    assert_eq!(
+        recv_all(&mut reassembler, Instant::now(), &datagrams),
+        vec![message]
+    );
+}
+
+#[test]
+fn fragments_are_reassembled() {
+    let mut splitter = Splitter::default();
+    let mut reassembler = Reassembler::default();
+    let message = large_message();
+
+    let datagrams = splitter.split(&message).unwrap();
+    assert_eq!(datagrams.len(), 4);
+    assert!(datagrams
+        .iter()
+        .all(|datagram| datagram.len() <= MAX_DATAGRAM_SIZE));
+
+    assert_eq!(
+        recv_all(&mut reassembler, Instant::now(), &datagrams),
+        vec![message]
+    );
+}
+
+#[test]
+fn reordered_fragments_are_reassembled() {
+    let mut splitter = Splitter::default();
+    let mut reassembler = Reassembler::default();
+    let first = large_message();
+    let second: Vec<u8> = first.iter().rev().copied().collect();
+
+    // Fragments of two messages arrive interleaved and out of order.
+    let mut datagrams = splitter.split(&first).unwrap();
+    datagrams.reverse();
+    let mut second_datagrams = splitter.split(&second).unwrap();
+    second_datagrams.swap(0, 2);
+    for (index, datagram) in second_datagrams.into_iter().enumerate() {
+        datagrams.insert(2 * index, datagram);
+    }
+
+    assert_eq!(
+        recv_all(&mut reassembler, Instant::now(), &datagrams),
+        vec![second, first]
+    );
+}
+
+#[test]
+fn duplicate_fragments_are_ignored() {
+    let mut splitter = Splitter::default();
+    let mut reassembler = Reassembler::default();
+    let message = large_message();
+    let now = Instant::now();
+
+    let datagrams = splitter.split(&message).unwrap();
+    let duplicated: Vec<Vec<u8>> = datagrams
+        .iter()
+        .flat_map(|datagram| vec![datagram.clone(), datagram.clone()])
+        .collect();
+
+    assert_eq!(recv_all(&mut reassembler, now, &duplicated), vec![message]);
+
+    // Late duplicates of a completed message do not start it over.
+    assert!(recv_all(&mut reassembler, now, &datagrams).is_empty());
+    assert!(recv_all(&mut reassembler, now + REASSEMBLY_TIMEOUT * 2, &datagrams).is_empty());
+}
+
+#[test]
+fn messages_with_lost_fragments_are_dropped() {
+    let mut splitter = Splitter::default();
+    let mut reassembler = Reassembler::default();
+    let lost = large_message();
+    let complete: Vec<u8> = lost.iter().map(|byte| byte ^ 0xff).collect();
+    let now = Instant::now();
+
+    let lost_datagrams = splitter.split(&lost).unwrap();
+    assert!(recv_all(&mut reassembler, now, &lost_datagrams[1..]).is_empty());
+
+    let complete_datagrams = splitter.split(&complete).unwrap();
+    assert_eq!(
+        recv_all(&mut reassembler, now, &complete_datagrams),
+        vec![complete]
+    );
+}
+
+#[test]
+fn incomplete_messages_time_out() {
+    let mut splitter = Splitter::default();
+    let mut reassembler = Reassembler::default();
+    let message = large_message();
+    let now = Instant::now();
+
+    let datagrams = splitter.split(&message).unwrap();
+    assert!(recv_all(&mut reassembler, now, &datagrams[..2]).is_empty());
+
+    // The first fragments have been dropped by the time the others arrive.
+    let later = now + REASSEMBLY_TIMEOUT + Duration::from_millis(1);
+    assert!(recv_all(&mut reassembler, later, &datagrams[2..]).is_empty());
+
+    // Before the timeout, the message would have been completed.
+    let mut reassembler = Reassembler::default();
+    assert!(recv_all(&mut reassembler, now, &datagrams[..2]).is_empty());
+    let earlier = now + REASSEMBLY_TIMEOUT - Duration::from_millis(1);
+    assert_eq!(
+        recv_all(&mut reassembler, earlier, &datagrams[2..]),
+        vec![message]
+    );
+}
+
+#[test]
+fn too_large_messages_are_rejected() {
+    let mut splitter = Splitter::default();
+    let size = MAX_FRAGMENTS * MAX_DATAGRAM_SIZE;
+
+    match splitter.split(&vec![0; size]) {
+        Err(TooLarge(too_large_size)) => assert_eq!(too_large_size, size),
+        Ok(datagrams) => panic!("Split into {} datagrams", datagrams.len()),
+    }
+}
+
+#[test]
+fn invalid_datagrams_are_rejected() {
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+
+    assert_eq!(reassembler.recv(now, &[]), Err(Error::Empty));
+    assert_eq!(
+        reassembler.recv(now, &[7, 1, 2]),
+        Err(Error::InvalidKind(7))
+    );
+    assert_eq!(reassembler.recv(now, &[1, 0, 0]), Err(Error::InvalidHeader));
+
+    // Index out of range.
+    assert_eq!(
+        reassembler.recv(now, &[1, 0, 0, 0, 0, 2, 2, 42]),
+        Err(Error::InvalidHeader)
+    );
+
+    // The count differs between fragments of the same message.
+    assert_eq!(reassembler.recv(now, &[1, 0, 0, 0, 0, 0, 2, 42]), Ok(None));
+    assert_eq!(
+        reassembler.recv(now, &[1, 0, 0, 0, 0, 1, 3, 42]),
+        Err(Error::InconsistentCount)
+    );
+}
+
+/// Builds a fragment datagram by hand, as a malicious sender could.
+fn fragment(message_num: u32, index: u8, count: u8, chunk_size: usize) -> Vec<u8> {
+    let mut datagram = vec![1];
+    datagram.extend_from_slice(&message_num.to_le_bytes());
+    datagram.push(index);
+    datagram.push(count);
+    datagram.extend(std::iter::repeat(42).take(chunk_size));
+    datagram
+}
+
+#[test]
+fn oversized_fragments_are_rejected() {
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+
+    let too_large = vec![0; MAX_DATAGRAM_SIZE + 1];
+    assert_eq!(
+        reassembler.recv(now, &too_large),
+        Err(Error::DatagramTooLarge(MAX_DATAGRAM_SIZE + 1))
+    );
+
+    let too_large = fragment(0, 0, 2, 0x10000 - 7);
+    assert_eq!(
+        reassembler.recv(now, &too_large),
+        Err(Error::DatagramTooLarge(0x10000))
+    );
+    assert_eq!(reassembler.buffered_size(), 0);
+
+    // Fragments of the maximal size are accepted.
+    let datagram = fragment(0, 0, 2, MAX_FRAGMENT_DATA_SIZE);
+    assert_eq!(datagram.len(), MAX_DATAGRAM_SIZE);
+    assert_eq!(reassembler.recv(now, &datagram), Ok(None));
+    assert_eq!(reassembler.buffered_size(), MAX_FRAGMENT_DATA_SIZE);
+}
+
+#[test]
+fn buffered_size_is_bounded() {
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+
+    // Send many incomplete messages that never finish.
+    for message_num in 1000..1032 {
+        for index in 0..MAX_FRAGMENTS - 1 {
+            let datagram = fragment(
+                message_num,
+                index as u8,
+                MAX_FRAGMENTS as u8,
+                MAX_FRAGMENT_DATA_SIZE,
+            );
+            assert_eq!(reassembler.recv(now, &datagram), Ok(None));
+            assert!(reassembler.buffered_size() <= MAX_BUFFERED_SIZE);
+        }
+    }
+    assert!(reassembler.buffered_size() > MAX_BUFFERED_SIZE / 2);
+
+    // Messages can still be completed.
+    let mut splitter = Splitter::default();
+    let message = large_message();
+    let datagrams = splitter.split(&message).unwrap();
+    assert_eq!(recv_all(&mut reassembler, now, &datagrams), vec![message]);
+    assert!(reassembler.buffered_size() <= MAX_BUFFERED_SIZE);
+
+    // Everything is dropped once it times out.
+    let later = now + REASSEMBLY_TIMEOUT;
+    assert_eq!(reassembler.recv(later, &fragment(100, 0, 2, 1)), Ok(None));
+    assert_eq!(reassembler.buffered_size(), 1);
+}
@@ -0,0 +1,323 @@
+//! Tests for the guarantees of generated maps in `comn::game::mapgen`.
+//!
+//! The maps are checked from the outside: we rebuild the grid of cells from
+//! the generated entities and measure path distances on it.
+
+use std::collections::VecDeque;
+
+use comn::{
+    game::mapgen::{
+        self, Config, GenError, CELL_SIZE, MIN_SPAWN_DIST_DANGER_GUY, MIN_SPAWN_DIST_TURRET,
+    },
+    Entity, Map, Point,
+};
+
+type Pos = (usize, usize);
+
+fn configs() -> Vec<Config> {
+    let mut configs = Vec::new();
+
+    for seed in 0..10 {
+        configs.push(Config {
+            seed,
+            ..Config::default()
+        });
+        configs.push(Config {
+            seed,
+            size: (12, 40),
+            wall_density: 0.4,
+            num_spawn_points: 4,
+            num_food_spawns: 20,
+            ..Config::default()
+        });
+    }
+
+    configs.push(Config {
+        seed: 42,
+        size: (128, 128),
+        wall_density: 0.6,
+        num_spawn_points: 32,
+        num_turrets: 64,
+        num_food_spawns: 400,
+        num_danger_guys: 16,
+    });
+
+    configs
+}
+
+fn generate(config: &Config) -> Map {
+    mapgen::generate_map(config)
+        .unwrap_or_else(|err| panic!("Failed to generate {:?}: {:?}", config, err))
+}
+
+fn cell(pos: Point) -> Pos {
+    (
+        (pos.x / CELL_SIZE).floor() as usize,
+        (pos.y / CELL_SIZE).floor() as usize,
+    )
+}
+
+/// The cells of a generated map, telling which ones block movement.
+struct Grid {
+    width: usize,
+    height: usize,
+    blocked: Vec<bool>,
+}
+
+impl Grid {
+    fn new(map: &Map, with_turrets: bool) -> Self {
+        let width = (map.size.x / CELL_SIZE).round() as usize;
+        let height = (map.size.y / CELL_SIZE).round() as usize;
+        let mut blocked = vec![false; width * height];
+
+        for entity in &map.entities {
+            match entity {
+                Entity::Wall(wall) => {
+                    let left = (wall.rect.top_left.x / CELL_SIZE).round() as usize;
+                    let top = (wall.rect.top_left.y / CELL_SIZE).round() as usize;
+                    let num_x = (wall.rect.size.x / CELL_SIZE).round() as usize;
+                    let num_y = (wall.rect.size.y / CELL_SIZE).round() as usize;
+
+                    for y in top..top + num_y {
+                        for x in left..left + num_x {
+                            blocked[y * width + x] = true;
+                        }
+                    }
+                }
+                Entity::Turret(turret) if with_turrets => {
+                    let (x, y) = cell(turret.pos);
+                    blocked[y * width + x] = true;
+                }
+                _ => (),
+            }
+        }
+
+        Self {
+            width,
+            height,
+            blocked,
+        }
+    }
+
+    fn is_free(&self, (x, y): Pos) -> bool {
+        !self.blocked[y * self.width + x]
+    }
+
+    fn free_positions(&self) -> Vec<Pos> {
+        (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .filter(|pos| self.is_free(*pos))
+            .collect()
+    }
+
+    /// Path distances from the closest of the given start positions.
+    fn distances(&self, starts: &[Pos]) -> Vec<Option<usize>> {
+        let mut dist = vec![None; self.blocked.len()];
+        let mut queue = VecDeque::new();
+
+        for &(x, y) in starts {
+            dist[y * self.width + x] = Some(0);
+            queue.push_back((x, y));
+        }
+
+        while let Some((x, y)) = queue.pop_front() {
+            let d = dist[y * self.width + x].unwrap();
+            let neighbors = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+
+            for &(nx, ny) in neighbors.iter() {
+                if nx < self.width
+                    && ny < self.height
+                    && self.is_free((nx, ny))
+                    && dist[ny * self.width + nx].is_none()
+                {
+                    dist[ny * self.width + nx] = Some(d + 1);
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+
+        dist
+    }
+
+    fn dist(&self, dist: &[Option<usize>], (x, y): Pos) -> Option<usize> {
+        dist[y * self.width + x]
+    }
+}
+
+fn spawn_cells(map: &Map) -> Vec<Pos> {
+    map.spawn_points.iter().copied().map(cell).collect()
+}
+
+#[test]
+fn free_cells_are_connected() {
+    for config in configs() {
+        let map = generate(&config);
+        let grid = Grid::new(&map, true);
+        let spawns = spawn_cells(&map);
+        let dist = grid.distances(&spawns[..1]);
+
+        for pos in grid.free_positions() {
+            assert!(
+                grid.dist(&dist, pos).is_some(),
+                "{:?} is not reachable in {:?}",
+                pos,
+                config,
+            );
+        }
+        for pos in spawns {
+            assert!(grid.is_free(pos), "Spawn point {:?} is blocked", pos);
+        }
+    }
+}
+
+#[test]
+fn spawn_points_are_spread_out() {
+    for config in configs() {
+        let map = generate(&config);
+        let grid = Grid::new(&map, false);
+        let spawns = spawn_cells(&map);
+        assert_eq!(spawns.len(), config.num_spawn_points);
+
+        // The closest two spawn points are at least as far apart as any cell
+        // is from its closest spawn point, so no part of the map is left
+        // without a spawn point nearby.
+        let separation = spawns
+            .iter()
+            .enumerate()
+            .map(|(index, pos)| {
+                let others: Vec<Pos> = spawns
+                    .iter()
+                    .enumerate()
+                    .filter(|(other_index, _)| *other_index != index)
+                    .map(|(_, other)| *other)
+                    .collect();
+                grid.dist(&grid.distances(&others), *pos).unwrap()
+            })
+            .min()
+            .unwrap();
+
+        let dist = grid.distances(&spawns);
+        let coverage = grid
+            .free_positions()
+            .into_iter()
+            .map(|pos| grid.dist(&dist, pos).unwrap())
+            .max()
+            .unwrap();
+
+        assert!(separation > 0, "Spawn points overlap in {:?}", config);
+        assert!(
+            coverage <= separation,
+            "Coverage {} exceeds separation {} in {:?}",
+            coverage,
+            separation,
+            config,
+        );
+    }
+}
+
+#[test]
+fn hazards_are_away_from_spawn_points() {
+    for config in configs() {
+        let map = generate(&config);
+        let spawns = spawn_cells(&map);
+
+        // Turrets are placed before danger guys, which therefore keep their
+        // distance on the grid with turrets.
+        let grid = Grid::new(&map, false);
+        let dist = grid.distances(&spawns);
+        let grid_with_turrets = Grid::new(&map, true);
+        let dist_with_turrets = grid_with_turrets.distances(&spawns);
+
+        let mut num_turrets = 0;
+        let mut num_danger_guys = 0;
+
+        for entity in &map.entities {
+            match entity {
+                Entity::Turret(turret) => {
+                    num_turrets += 1;
+
+                    let d = grid.dist(&dist, cell(turret.pos)).unwrap();
+                    assert!(d >= MIN_SPAWN_DIST_TURRET, "Turret {} from spawn", d);
+                }
+                Entity::DangerGuy(danger_guy) => {
+                    num_danger_guys += 1;
+
+                    let (start, end) = (cell(danger_guy.start_pos), cell(danger_guy.end_pos));
+                    assert!(start.0 == end.0 || start.1 == end.1);
+
+                    for x in start.0..=end.0 {
+                        for y in start.1..=end.1 {
+                            let d = grid_with_turrets.dist(&dist_with_turrets, (x, y)).unwrap();
+                            assert!(
+                                d >= MIN_SPAWN_DIST_DANGER_GUY,
+                                "Danger guy {} from spawn",
+                                d
+                            );
+                        }
+                    }
+                }
+                Entity::FoodSpawn(food_spawn) => {
+                    let pos = cell(food_spawn.pos);
+                    assert!(grid_with_turrets.is_free(pos));
+                    assert!(!spawns.contains(&pos));
+                }
+                _ => (),
+            }
+        }
+
+        assert_eq!(num_turrets, config.num_turrets);
+        assert_eq!(num_danger_guys, config.num_danger_guys);
+    }
+}
+
+#[test]
+fn same_seed_gives_same_map() {
+    let config = Config::default();
+
+    assert_eq!(
+        serde_json::to_string(&generate(&config)).unwrap(),
+        serde_json::to_string(&generate(&config)).unwrap(),
+    );
+}
+
+#[test]
+fn invalid_configs_are_rejected() {
+    let too_small = Config {
+        size: (4, 40),
+        ..Config::default()
+    };
+    match mapgen::generate_map(&too_small) {
+        Err(GenError::InvalidSize(size)) => assert_eq!(size, (4, 40)),
+        result => panic!("Expected InvalidSize, got {:?}", result.map(|map| map.name)),
+    }
+
+    let too_dense = Config {
+        wall_density: 0.9,
+        ..Config::default()
+    };
+    match mapgen::generate_map(&too_dense) {
+        Err(GenError::InvalidWallDensity(_)) => (),
+        result => panic!(
+            "Expected InvalidWallDensity, got {:?}",
+            result.map(|map| map.name)
+        ),
+    }
+
+    let crowded = Config {
+        size: (8, 8),
+        num_spawn_points: 65,
+        ..Config::default()
+    };
+    match mapgen::generate_map(&crowded) {
+        Err(GenError::NotEnoughSpace) => (),
+        result => panic!(
+            "Expected NotEnoughSpace, got {:?}",
+            result.map(|map| map.name)
+        ),
+    }
+}
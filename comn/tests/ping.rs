@@ -0,0 +1,59 @@
+//! Tests for estimating ping and loss in `comn::util::ping`.
+
+use std::time::Duration;
+
+use instant::Instant;
+
+use comn::util::{
+    ping::{SequenceNum, LOST_PING_TIMEOUT},
+    PingEstimation,
+};
+
+const PING_PERIOD: Duration = Duration::from_secs(1);
+
+#[test]
+fn answered_pings_are_not_lost() {
+    let mut ping = PingEstimation::default();
+    let start_time = Instant::now();
+
+    for i in 0..10 {
+        let send_time = start_time + PING_PERIOD * i;
+        let sequence_num = ping.next_ping_sequence_num(send_time).unwrap();
+        ping.record_pong(send_time + Duration::from_millis(50), sequence_num)
+            .unwrap();
+    }
+
+    assert_eq!(ping.loss(), 0.0);
+}
+
+#[test]
+fn unanswered_pings_are_lost_after_timeout() {
+    let mut ping = PingEstimation::default();
+    let start_time = Instant::now();
+
+    for i in 0..5 {
+        let send_time = start_time + PING_PERIOD * i;
+        let sequence_num = ping.next_ping_sequence_num(send_time).unwrap();
+        ping.record_pong(send_time + Duration::from_millis(50), sequence_num)
+            .unwrap();
+    }
+
+    // From now on, no pong arrives at all.
+    let loss_start_time = start_time + PING_PERIOD * 5;
+    ping.next_ping_sequence_num(loss_start_time).unwrap();
+    assert_eq!(ping.loss(), 0.0);
+
+    for i in 1..5 {
+        ping.next_ping_sequence_num(loss_start_time + PING_PERIOD * i)
+            .unwrap();
+    }
+
+    // Pings are lost once they are older than the timeout, even though no
+    // later pong has arrived.
+    let now = loss_start_time + PING_PERIOD * 4 + LOST_PING_TIMEOUT;
+    ping.next_ping_sequence_num(now);
+    assert_eq!(ping.loss(), 0.5);
+
+    // Their pongs do not count anymore if they arrive late.
+    assert!(ping.record_pong(now, SequenceNum(5)).is_err());
+}
@@ -0,0 +1,356 @@
+//! Compatibility tests for the serialization of messages.
+//!
+//! We keep a corpus of serialized messages and datagrams for every
+//! `PROTOCOL_VERSION` in `tests/corpus/v<version>`. If the serialization of a
+//! message changes, these tests fail, and `PROTOCOL_VERSION` needs to be
+//! increased, so that outdated clients are rejected when joining instead of
+//! failing silently.
+//!
+//! The corpus of a new version is written by running the tests with
+//! `WRITE_PROTOCOL_CORPUS=1` set, and then needs to be committed. Without it,
+//! missing corpus files make the tests fail. Corpora of older versions are
+//! kept as a record of what changed:
+//!
+//! - v1: Join requests contain the protocol version.
+//! - v2: Server messages are split into fragments if they do not fit into a
+//!   single datagram (`datagram_*.bin`).
+//! - v3: Inputs are sent as runs of bit-packed inputs (`client_input`).
+//! - v4: Inputs contain analog movement and the aim (`client_input`).
+//! - v5: Checksums of states ignore the sign of zero (`checksum.bin`).
+//! - v6: Client messages are split into datagrams too
+//!   (`datagram_client_ping.bin`).
+//! - v7: Reconnect requests contain the protocol version
+//!   (`*_request.json`).
+//! - v8: Reliable messages have their own types and chat is only sent
+//!   reliably (`*_chat`, `*_reliable`, `*_disconnect`, `*_ack_reliable`).
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use uuid::Uuid;
+
+use comn::util::{diff::Diffable, fragment, reliable};
+
+const WRITE_CORPUS_VAR: &str = "WRITE_PROTOCOL_CORPUS";
+
+fn corpus_dir(version: u32) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("corpus")
+        .join(format!("v{}", version))
+}
+
+fn read_corpus(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("Failed to read corpus {}: {}", dir.display(), err))
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&path).unwrap())
+        })
+        .collect()
+}
+
+fn check_corpus(file_name: &str, data: &[u8]) {
+    let dir = corpus_dir(comn::PROTOCOL_VERSION);
+    let path = dir.join(file_name);
+
+    match fs::read(&path) {
+        Ok(expected) => assert!(
+            data == expected.as_slice(),
+            "Serialization of {} has changed, PROTOCOL_VERSION needs to be increased",
+            file_name,
+        ),
+        Err(_) if env::var_os(WRITE_CORPUS_VAR).is_some() => {
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(&path, data).unwrap();
+        }
+        Err(err) => panic!(
+            "Corpus file {} is missing ({}). Run the tests with {}=1 to write it",
+            path.display(),
+            err,
+            WRITE_CORPUS_VAR,
+        ),
+    }
+}
+
+fn settings() -> comn::Settings {
+    comn::Settings {
+        max_num_players: 8,
+        ticks_per_second: 30,
+        map: comn::Map {
+            name: "corpus".to_string(),
+            spawn_points: vec![comn::Point::new(10.0, 20.0)],
+            entities: Vec::new(),
+            size: comn::Vector::new(400.0, 300.0),
+        },
+    }
+}
+
+fn state(respawn_time: comn::GameTime) -> comn::Game {
+    let mut state = comn::Game::new(Arc::new(settings()));
+    state.tick_num = comn::TickNum(42);
+    state.catcher = Some(comn::PlayerId(1));
+    state.players.insert(
+        comn::PlayerId(1),
+        comn::Player {
+            name: "Pioneer".to_string(),
+            state: comn::PlayerState::Respawning { respawn_time },
+            food: 3,
+        },
+    );
+    state
+}
+
+fn tick() -> comn::Tick {
+    let base = comn::Game::new(Arc::new(settings()));
+
+    comn::Tick {
+        diff_base: Some(comn::TickNum(40)),
+        diff: base.diff(&state(1.5)),
+        events: vec![(
+            comn::TickNum(42),
+            vec![comn::Event::PlayerDied {
+                player_id: comn::PlayerId(2),
+                pos: comn::Point::new(1.0, 2.0),
+                reason: comn::DeathReason::CaughtBy(comn::PlayerId(1)),
+            }],
+        )],
+        your_last_input_num: Some(comn::TickNum(41)),
+        send_interval: 2,
+    }
+}
+
+fn server_messages() -> Vec<(&'static str, comn::ServerMessage)> {
+    vec![
+        (
+            "server_ping",
+            comn::ServerMessage::Ping(comn::SequenceNum(7)),
+        ),
+        (
+            "server_pong",
+            comn::ServerMessage::Pong(comn::SequenceNum(8)),
+        ),
+        ("server_tick", comn::ServerMessage::Tick(tick())),
+        (
+            "server_kill_cam",
+            comn::ServerMessage::KillCam(comn::KillCam {
+                killer: comn::PlayerId(1),
+                death_tick_num: comn::TickNum(42),
+                frames: vec![(comn::TickNum(41), comn::EntityMap::new())],
+            }),
+        ),
+        (
+            "server_chat",
+            comn::ServerMessage::Reliable(reliable::Packet {
+                num: comn::ReliableNum(3),
+                message: comn::ReliableServerMessage::Chat(comn::ChatMessage {
+                    player_id: Some(comn::PlayerId(1)),
+                    player_name: "Pioneer".to_string(),
+                    text: "hello".to_string(),
+                }),
+            }),
+        ),
+        ("server_disconnect", comn::ServerMessage::Disconnect),
+        (
+            "server_reliable",
+            comn::ServerMessage::Reliable(reliable::Packet {
+                num: comn::ReliableNum(3),
+                message: comn::ReliableServerMessage::Disconnect,
+            }),
+        ),
+        (
+            "server_ack_reliable",
+            comn::ServerMessage::AckReliable(comn::ReliableNum(4)),
+        ),
+    ]
+}
+
+fn token() -> comn::PlayerToken {
+    comn::PlayerToken(Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef))
+}
+
+fn client_messages() -> Vec<(&'static str, comn::SignedClientMessage)> {
+    let token = token();
+    let input = comn::Input {
+        move_left: true,
+        dash: true,
+        aim: Some(comn::Vector::new(1.0, 1.0)),
+        ..comn::Input::default()
+    };
+
+    vec![
+        (
+            "client_ping",
+            comn::ClientMessage::Ping(comn::SequenceNum(7)),
+        ),
+        (
+            "client_pong",
+            comn::ClientMessage::Pong(comn::SequenceNum(8)),
+        ),
+        (
+            "client_input",
+            comn::ClientMessage::Input(comn::InputRuns {
+                first_tick_num: comn::TickNum(41),
+                runs: vec![(input.pack(), 3)],
+            }),
+        ),
+        (
+            "client_ack_tick",
+            comn::ClientMessage::AckTick(comn::TickNum(42), 0xdead_beef),
+        ),
+        (
+            "client_chat",
+            comn::ClientMessage::Reliable(reliable::Packet {
+                num: comn::ReliableNum(3),
+                message: comn::ReliableClientMessage::Chat("hello".to_string()),
+            }),
+        ),
+        ("client_disconnect", comn::ClientMessage::Disconnect),
+        (
+            "client_reliable",
+            comn::ClientMessage::Reliable(reliable::Packet {
+                num: comn::ReliableNum(300),
+                message: comn::ReliableClientMessage::Chat(String::new()),
+            }),
+        ),
+        (
+            "client_ack_reliable",
+            comn::ClientMessage::AckReliable(comn::ReliableNum(4)),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, message)| (name, comn::SignedClientMessage(token, message)))
+    .collect()
+}
+
+#[test]
+fn server_messages_match_corpus() {
+    for (name, message) in server_messages() {
+        let data = message.serialize();
+
+        assert!(comn::ServerMessage::deserialize(&data).is_some());
+        check_corpus(&format!("{}.msgpack", name), &data);
+    }
+}
+
+#[test]
+fn client_messages_match_corpus() {
+    for (name, message) in client_messages() {
+        let data = message.serialize();
+
+        assert!(comn::SignedClientMessage::deserialize(&data).is_some());
+        check_corpus(&format!("{}.msgpack", name), &data);
+    }
+}
+
+#[test]
+fn datagrams_match_corpus() {
+    let mut splitter = fragment::Splitter::default();
+
+    let message = comn::ServerMessage::Ping(comn::SequenceNum(7)).serialize();
+    let whole = splitter.split(&message).unwrap();
+    assert_eq!(whole.len(), 1);
+    check_corpus("datagram_whole.bin", &whole[0]);
+
+    let large: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+    let fragments = splitter.split(&large).unwrap();
+    assert_eq!(fragments.len(), 3);
+    for (index, fragment) in fragments.iter().enumerate() {
+        check_corpus(&format!("datagram_fragment_{}.bin", index), fragment);
+    }
+
+    let (_, ping) = client_messages()
+        .into_iter()
+        .find(|(name, _)| *name == "client_ping")
+        .unwrap();
+    let client_whole = fragment::Splitter::default()
+        .split(&ping.serialize())
+        .unwrap();
+    assert_eq!(client_whole.len(), 1);
+    check_corpus("datagram_client_ping.bin", &client_whole[0]);
+}
+
+#[test]
+fn requests_match_corpus() {
+    let join_request = comn::JoinRequest {
+        game_id: None,
+        player_name: "Pioneer".to_string(),
+        spectate: false,
+        invite_code: None,
+        session_token: None,
+        protocol_version: comn::PROTOCOL_VERSION,
+    };
+    check_corpus(
+        "join_request.json",
+        &serde_json::to_vec(&join_request).unwrap(),
+    );
+
+    let reconnect_request = comn::ReconnectRequest {
+        token: token(),
+        protocol_version: comn::PROTOCOL_VERSION,
+    };
+    check_corpus(
+        "reconnect_request.json",
+        &serde_json::to_vec(&reconnect_request).unwrap(),
+    );
+}
+
+#[test]
+fn checksum_matches_corpus() {
+    check_corpus("checksum.bin", &state(1.5).checksum().to_le_bytes());
+}
+
+#[test]
+fn checksum_ignores_sign_of_zero() {
+    // Diffs do not contain values that compare as equal, so the client may
+    // never see the sign change.
+    assert_eq!(state(0.0).checksum(), state(-0.0).checksum());
+    assert_ne!(state(0.0).checksum(), state(1.5).checksum());
+}
+
+#[test]
+fn every_version_has_a_corpus() {
+    let current = read_corpus(&corpus_dir(comn::PROTOCOL_VERSION));
+
+    for version in 1..comn::PROTOCOL_VERSION {
+        let older = read_corpus(&corpus_dir(version));
+        let newer = read_corpus(&corpus_dir(version + 1));
+
+        assert!(!older.is_empty(), "Corpus of v{} is empty", version);
+        assert!(
+            older != newer,
+            "Corpora of v{} and v{} are equal, the version was increased without a change",
+            version,
+            version + 1,
+        );
+        assert!(
+            older.keys().all(|name| current.contains_key(name)),
+            "Corpus of v{} has files that are not checked anymore",
+            version,
+        );
+    }
+}
+
+#[test]
+fn join_request_without_version_is_outdated() {
+    let request: comn::JoinRequest =
+        serde_json::from_str(r#"{"game_id":null,"player_name":"Pioneer"}"#).unwrap();
+
+    assert_eq!(request.protocol_version, 0);
+    assert_ne!(request.protocol_version, comn::PROTOCOL_VERSION);
+}
+
+#[test]
+fn reconnect_request_without_version_is_outdated() {
+    let request: comn::ReconnectRequest =
+        serde_json::from_str(r#"{"token":"01234567-89ab-cdef-0123-456789abcdef"}"#).unwrap();
+
+    assert_eq!(request.token, token());
+    assert_eq!(request.protocol_version, 0);
+    assert_ne!(request.protocol_version, comn::PROTOCOL_VERSION);
+}
@@ -0,0 +1,209 @@
+//! Tests for the reliable, ordered message layer in `comn::util::reliable`.
+//!
+//! Packets are passed between two endpoints by hand, so that we can lose,
+//! reorder and duplicate them on purpose.
+
+use std::time::Duration;
+
+use instant::Instant;
+
+use comn::util::reliable::{Endpoint, Packet, ReliableNum, MAX_BUFFERED_MESSAGES};
+
+type Sender = Endpoint<u32, ()>;
+type Receiver = Endpoint<(), u32>;
+
+const RTT: Duration = Duration::from_millis(50);
+
+/// Long enough for all unacknowledged messages to be resent.
+const RESEND_WAIT: Duration = Duration::from_secs(1);
+
+/// Hands the packets to the receiver and the acknowledgements back to the
+/// sender. Returns the messages that the receiver got in order.
+fn deliver(sender: &mut Sender, receiver: &mut Receiver, packets: Vec<Packet<u32>>) -> Vec<u32> {
+    for packet in packets {
+        if let Some(ack_num) = receiver.record_packet(packet).unwrap() {
+            sender.record_ack(ack_num);
+        }
+    }
+
+    std::iter::from_fn(|| receiver.take_message()).collect()
+}
+
+fn push_all(sender: &mut Sender, messages: impl Iterator<Item = u32>) {
+    for message in messages {
+        sender.push(message);
+    }
+}
+
+#[test]
+fn messages_arrive_in_order() {
+    let mut sender = Sender::default();
+    let mut receiver = Receiver::default();
+    let now = Instant::now();
+
+    push_all(&mut sender, 0..10);
+    let packets = sender.packets_to_send(now, RTT);
+
+    assert_eq!(
+        deliver(&mut sender, &mut receiver, packets),
+        (0..10).collect::<Vec<_>>()
+    );
+    assert!(sender.is_idle());
+    assert!(sender.packets_to_send(now + RESEND_WAIT, RTT).is_empty());
+}
+
+#[test]
+fn lost_packets_are_resent() {
+    let mut sender = Sender::default();
+    let mut receiver = Receiver::default();
+    let now = Instant::now();
+
+    push_all(&mut sender, 0..10);
+    let packets = sender
+        .packets_to_send(now, RTT)
+        .into_iter()
+        .filter(|packet| packet.num.0 % 3 != 0)
+        .collect();
+
+    // The first message is missing, so nothing can be handed out yet.
+    assert!(deliver(&mut sender, &mut receiver, packets).is_empty());
+    assert!(!sender.is_idle());
+
+    // Nothing is resent before the resend duration has passed.
+    assert!(sender.packets_to_send(now, RTT).is_empty());
+
+    let packets = sender.packets_to_send(now + RESEND_WAIT, RTT);
+    assert_eq!(
+        deliver(&mut sender, &mut receiver, packets),
+        (0..10).collect::<Vec<_>>()
+    );
+    assert!(sender.is_idle());
+}
+
+#[test]
+fn lost_acks_are_repeated() {
+    let mut sender = Sender::default();
+    let mut receiver = Receiver::default();
+    let now = Instant::now();
+
+    push_all(&mut sender, 0..5);
+
+    // The receiver gets everything, but all the acknowledgements are lost.
+    for packet in sender.packets_to_send(now, RTT) {
+        receiver.record_packet(packet).unwrap();
+    }
+    let received: Vec<u32> = std::iter::from_fn(|| receiver.take_message()).collect();
+    assert_eq!(received, (0..5).collect::<Vec<_>>());
+    assert!(!sender.is_idle());
+
+    // The resent packets are acknowledged again, but not handed out twice.
+    let packets = sender.packets_to_send(now + RESEND_WAIT, RTT);
+    assert_eq!(packets.len(), 5);
+    assert!(deliver(&mut sender, &mut receiver, packets).is_empty());
+    assert!(sender.is_idle());
+}
+
+#[test]
+fn reordered_packets_are_handed_out_in_order() {
+    let mut sender = Sender::default();
+    let mut receiver = Receiver::default();
+    let now = Instant::now();
+
+    push_all(&mut sender, 0..20);
+    let mut packets = sender.packets_to_send(now, RTT);
+    packets.reverse();
+    packets.swap(3, 11);
+
+    assert_eq!(
+        deliver(&mut sender, &mut receiver, packets),
+        (0..20).collect::<Vec<_>>()
+    );
+    assert!(sender.is_idle());
+}
+
+#[test]
+fn duplicate_packets_are_handed_out_once() {
+    let mut sender = Sender::default();
+    let mut receiver = Receiver::default();
+    let now = Instant::now();
+
+    push_all(&mut sender, 0..10);
+    let packets = sender.packets_to_send(now, RTT);
+    let duplicated = packets
+        .iter()
+        .chain(packets.iter().rev())
+        .chain(packets.iter())
+        .cloned()
+        .collect();
+
+    assert_eq!(
+        deliver(&mut sender, &mut receiver, duplicated),
+        (0..10).collect::<Vec<_>>()
+    );
+
+    // Duplicates of messages that have already been handed out are
+    // acknowledged, but ignored otherwise.
+    assert_eq!(
+        receiver
+            .record_packet(Packet {
+                num: ReliableNum(4),
+                message: 4,
+            })
+            .unwrap(),
+        Some(ReliableNum(9)),
+    );
+    assert_eq!(receiver.take_message(), None);
+}
+
+#[test]
+fn messages_in_flight_are_limited() {
+    let mut sender = Sender::default();
+    let mut receiver = Receiver::default();
+    let now = Instant::now();
+    let num_messages = 2 * MAX_BUFFERED_MESSAGES as u32 + 10;
+
+    push_all(&mut sender, 0..num_messages);
+
+    let packets = sender.packets_to_send(now, RTT);
+    assert_eq!(packets.len(), MAX_BUFFERED_MESSAGES);
+
+    // The receiver can buffer all the packets in flight, even if the first
+    // one is lost.
+    let received = deliver(&mut sender, &mut receiver, packets[1..].to_vec());
+    assert!(received.is_empty());
+
+    let mut received = Vec::new();
+    let mut time = now;
+    while !sender.is_idle() {
+        time += RESEND_WAIT;
+
+        let packets = sender.packets_to_send(time, RTT);
+        assert!(!packets.is_empty());
+        assert!(packets.len() <= MAX_BUFFERED_MESSAGES);
+
+        received.extend(deliver(&mut sender, &mut receiver, packets));
+    }
+
+    assert_eq!(received, (0..num_messages).collect::<Vec<_>>());
+}
+
+#[test]
+fn packets_too_far_ahead_are_rejected() {
+    let mut receiver = Receiver::default();
+
+    assert!(receiver
+        .record_packet(Packet {
+            num: ReliableNum(MAX_BUFFERED_MESSAGES as u32),
+            message: 0,
+        })
+        .is_err());
+    assert_eq!(
+        receiver
+            .record_packet(Packet {
+                num: ReliableNum(MAX_BUFFERED_MESSAGES as u32 - 1),
+                message: 0,
+            })
+            .unwrap(),
+        None,
+    );
+}
@@ -0,0 +1,142 @@
+//! Tests for the bookkeeping of sent ticks in `comn::game::sent_ticks`, in
+//! particular that events reach the player even if ticks are skipped or the
+//! player desyncs.
+
+use std::sync::Arc;
+
+use comn::game::sent_ticks::{Ack, SentTicks};
+
+fn settings() -> comn::Settings {
+    comn::Settings {
+        max_num_players: 8,
+        ticks_per_second: 30,
+        map: comn::Map {
+            name: "test".to_string(),
+            spawn_points: vec![comn::Point::new(10.0, 20.0)],
+            entities: Vec::new(),
+            size: comn::Vector::new(400.0, 300.0),
+        },
+    }
+}
+
+fn state(tick_num: u32) -> comn::Game {
+    let mut state = comn::Game::new(Arc::new(settings()));
+    state.tick_num = comn::TickNum(tick_num);
+    state
+}
+
+fn events() -> Vec<comn::Event> {
+    vec![comn::Event::NewCatcher {
+        player_id: comn::PlayerId(0),
+    }]
+}
+
+/// Sends the tick with the given number, in which one event happened.
+/// Returns the diff base and the tick numbers of the events that are sent.
+fn send_tick(
+    sent_ticks: &mut SentTicks,
+    tick_num: u32,
+) -> (Option<comn::TickNum>, Vec<comn::TickNum>) {
+    let (diff_base, _, events) = sent_ticks.send(state(tick_num), events());
+
+    (
+        diff_base,
+        events.iter().map(|(tick_num, _)| *tick_num).collect(),
+    )
+}
+
+fn tick_nums(nums: &[u32]) -> Vec<comn::TickNum> {
+    nums.iter().copied().map(comn::TickNum).collect()
+}
+
+#[test]
+fn events_are_resent_until_acknowledged() {
+    let mut sent_ticks = SentTicks::default();
+
+    assert_eq!(send_tick(&mut sent_ticks, 1), (None, tick_nums(&[1])));
+    assert_eq!(send_tick(&mut sent_ticks, 2), (None, tick_nums(&[2, 1])));
+    assert_eq!(send_tick(&mut sent_ticks, 3), (None, tick_nums(&[3, 1, 2])));
+
+    assert_eq!(
+        sent_ticks.record_ack(comn::TickNum(2), state(2).checksum()),
+        Ack::Valid,
+    );
+    assert_eq!(sent_ticks.last_ack_tick(), Some(comn::TickNum(2)));
+
+    // The acknowledged state is the new diff base, and the states before it
+    // are forgotten.
+    assert_eq!(
+        send_tick(&mut sent_ticks, 4),
+        (Some(comn::TickNum(2)), tick_nums(&[4, 2, 3])),
+    );
+    assert_eq!(sent_ticks.num_states(), 3);
+}
+
+#[test]
+fn skipped_events_are_sent_with_the_next_tick() {
+    let mut sent_ticks = SentTicks::default();
+
+    send_tick(&mut sent_ticks, 1);
+    sent_ticks.skip(comn::TickNum(2), events());
+    sent_ticks.skip(comn::TickNum(3), events());
+
+    assert_eq!(
+        send_tick(&mut sent_ticks, 4),
+        (None, tick_nums(&[2, 3, 4, 1]))
+    );
+}
+
+#[test]
+fn outdated_acks_are_ignored() {
+    let mut sent_ticks = SentTicks::default();
+
+    send_tick(&mut sent_ticks, 1);
+    send_tick(&mut sent_ticks, 2);
+
+    assert_eq!(
+        sent_ticks.record_ack(comn::TickNum(2), state(2).checksum()),
+        Ack::Valid,
+    );
+    assert_eq!(
+        sent_ticks.record_ack(comn::TickNum(1), state(1).checksum()),
+        Ack::Outdated,
+    );
+    assert_eq!(sent_ticks.last_ack_tick(), Some(comn::TickNum(2)));
+}
+
+#[test]
+fn events_are_resent_after_desync() {
+    let mut sent_ticks = SentTicks::default();
+
+    send_tick(&mut sent_ticks, 1);
+    send_tick(&mut sent_ticks, 2);
+    send_tick(&mut sent_ticks, 3);
+
+    // Tick 4 is skipped, so its events wait for the next tick.
+    sent_ticks.skip(comn::TickNum(4), events());
+
+    // The player acknowledges tick 2 with the wrong checksum, so the events
+    // of tick 3 and 4 have not reached it yet.
+    assert_eq!(
+        sent_ticks.record_ack(comn::TickNum(2), state(2).checksum() ^ 1),
+        Ack::Desync,
+    );
+    assert_eq!(sent_ticks.last_ack_tick(), None);
+
+    assert_eq!(send_tick(&mut sent_ticks, 5), (None, tick_nums(&[3, 4, 5])));
+
+    // The events keep being resent until the new state is acknowledged.
+    assert_eq!(
+        send_tick(&mut sent_ticks, 6),
+        (None, tick_nums(&[6, 3, 4, 5])),
+    );
+
+    assert_eq!(
+        sent_ticks.record_ack(comn::TickNum(6), state(6).checksum()),
+        Ack::Valid,
+    );
+    assert_eq!(
+        send_tick(&mut sent_ticks, 7),
+        (Some(comn::TickNum(6)), tick_nums(&[7, 6])),
+    );
+}
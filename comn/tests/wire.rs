@@ -0,0 +1,457 @@
+//! Round-trip tests for the bit-packed encoding of ticks in `comn::game::wire`.
+//!
+//! Ticks are generated randomly with a fixed seed, so that failures can be
+//! reproduced.
+
+use std::f32::consts::PI;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use comn::{
+    entities::{Bullet, Dash, Food, FoodSpawn, Wall},
+    game::wire,
+    geom::AaRect,
+    util::{
+        bits::{BitReader, BitWriter},
+        diff::BTreeMapDiff,
+    },
+};
+
+const NUM_TICKS: usize = 200;
+
+fn map_size() -> comn::Vector {
+    comn::Vector::new(1600.0, 1200.0)
+}
+
+/// Largest error of quantized positions.
+fn pos_tolerance() -> f32 {
+    3.0 * map_size().x.max(map_size().y) / 65535.0
+}
+
+fn point(rng: &mut StdRng) -> comn::Point {
+    // Some positions are outside of the map, or even outside of the range
+    // that is quantized.
+    let size = map_size();
+    comn::Point::new(
+        rng.gen_range(-1.5 * size.x, 2.5 * size.x),
+        rng.gen_range(-1.5 * size.y, 2.5 * size.y),
+    )
+}
+
+fn vector(rng: &mut StdRng) -> comn::Vector {
+    comn::Vector::new(rng.gen_range(-500.0, 500.0), rng.gen_range(-500.0, 500.0))
+}
+
+fn angle(rng: &mut StdRng) -> f32 {
+    rng.gen_range(-2.0 * PI, 2.0 * PI)
+}
+
+fn time(rng: &mut StdRng) -> f32 {
+    rng.gen_range(0.0, 1000.0)
+}
+
+fn player_id(rng: &mut StdRng) -> comn::PlayerId {
+    comn::PlayerId(rng.gen_range(0, 1000))
+}
+
+fn entity_id(rng: &mut StdRng) -> comn::EntityId {
+    // Large ids take several varint groups.
+    comn::EntityId(rng.gen())
+}
+
+fn option<T>(rng: &mut StdRng, f: impl FnOnce(&mut StdRng) -> T) -> Option<T> {
+    if rng.gen() {
+        Some(f(rng))
+    } else {
+        None
+    }
+}
+
+fn player(rng: &mut StdRng) -> comn::Player {
+    let name_len = rng.gen_range(0, 20);
+
+    comn::Player {
+        name: (0..name_len)
+            .map(|_| if rng.gen() { 'a' } else { 'ö' })
+            .collect(),
+        state: match rng.gen_range(0, 3) {
+            0 => comn::PlayerState::Alive,
+            1 => comn::PlayerState::Dead,
+            _ => comn::PlayerState::Respawning {
+                respawn_time: time(rng),
+            },
+        },
+        food: rng.gen(),
+    }
+}
+
+fn hook(rng: &mut StdRng) -> comn::Hook {
+    match rng.gen_range(0, 3) {
+        0 => comn::Hook::Shooting {
+            pos: point(rng),
+            vel: vector(rng),
+            time_left: time(rng),
+        },
+        1 => comn::Hook::Attached {
+            target: entity_id(rng),
+            offset: vector(rng),
+        },
+        _ => comn::Hook::Contracting { pos: point(rng) },
+    }
+}
+
+fn entity(rng: &mut StdRng) -> comn::Entity {
+    match rng.gen_range(0, 9) {
+        0 => comn::Entity::Player(comn::PlayerEntity {
+            vel: vector(rng),
+            angle: angle(rng),
+            turn_time_left: time(rng),
+            target_angle: angle(rng),
+            size_scale: rng.gen(),
+            size_skew: rng.gen(),
+            size_bump: rng.gen(),
+            target_size_bump: rng.gen(),
+            next_shot_time: time(rng),
+            shots_left: rng.gen(),
+            dash: option(rng, |rng| Dash {
+                time_left: time(rng),
+                dir: vector(rng),
+            }),
+            dash_cooldown: time(rng),
+            hook: option(rng, hook),
+            hook_cooldown: time(rng),
+            anim_frame: (rng.gen(), time(rng)),
+            spawn_protection: time(rng),
+            ..comn::PlayerEntity::new(player_id(rng), point(rng))
+        }),
+        1 => comn::Entity::PlayerView(comn::PlayerView {
+            owner: player_id(rng),
+            pos: point(rng),
+            angle: angle(rng),
+            size: vector(rng),
+            hook: option(rng, hook),
+            is_dashing: rng.gen(),
+            anim_frame: rng.gen(),
+            is_protected: rng.gen(),
+        }),
+        2 => comn::Entity::Bullet(Bullet {
+            owner: option(rng, player_id),
+            start_time: time(rng),
+            start_pos: point(rng),
+            vel: vector(rng),
+        }),
+        3 => comn::Entity::Rocket(comn::Rocket {
+            owner: option(rng, player_id),
+            start_time: time(rng),
+            start_pos: point(rng),
+            angle: angle(rng),
+        }),
+        4 => comn::Entity::DangerGuy(comn::DangerGuy {
+            start_pos: point(rng),
+            end_pos: point(rng),
+            size: vector(rng),
+            speed: (rng.gen(), rng.gen()),
+            wait_time: (time(rng), time(rng)),
+            phase: rng.gen(),
+            is_hot: rng.gen(),
+        }),
+        5 => comn::Entity::Turret(comn::Turret {
+            pos: point(rng),
+            target: option(rng, entity_id),
+            angle: angle(rng),
+            next_shot_time: time(rng),
+        }),
+        6 => comn::Entity::Wall(Wall {
+            rect: AaRect::new_top_left(point(rng), vector(rng)),
+        }),
+        7 => comn::Entity::FoodSpawn(FoodSpawn {
+            pos: point(rng),
+            has_food: rng.gen(),
+            respawn_time: option(rng, time),
+        }),
+        _ => comn::Entity::Food(Food {
+            start_time: time(rng),
+            start_pos: point(rng),
+            start_vel: vector(rng),
+            factor: rng.gen(),
+            amount: rng.gen(),
+        }),
+    }
+}
+
+fn death_reason(rng: &mut StdRng) -> comn::DeathReason {
+    match rng.gen_range(0, 3) {
+        0 => comn::DeathReason::ShotBy(option(rng, player_id)),
+        1 => comn::DeathReason::TouchedTheDanger,
+        _ => comn::DeathReason::CaughtBy(player_id(rng)),
+    }
+}
+
+fn event(rng: &mut StdRng) -> comn::Event {
+    match rng.gen_range(0, 6) {
+        0 => comn::Event::PlayerShotGun {
+            player_id: player_id(rng),
+            dir: vector(rng),
+        },
+        1 => comn::Event::PlayerShotStunGun {
+            player_id: player_id(rng),
+            dir: vector(rng),
+        },
+        2 => comn::Event::PlayerSpawned {
+            player_id: player_id(rng),
+            pos: point(rng),
+        },
+        3 => comn::Event::PlayerDied {
+            player_id: player_id(rng),
+            pos: point(rng),
+            reason: death_reason(rng),
+        },
+        4 => comn::Event::PlayerAteFood {
+            player_id: player_id(rng),
+            amount: rng.gen(),
+        },
+        _ => comn::Event::NewCatcher {
+            player_id: player_id(rng),
+        },
+    }
+}
+
+fn vec<T>(rng: &mut StdRng, max_len: usize, f: impl Fn(&mut StdRng) -> T) -> Vec<T> {
+    let len = rng.gen_range(0, max_len + 1);
+    (0..len).map(|_| f(rng)).collect()
+}
+
+fn map_diff<K, V>(
+    rng: &mut StdRng,
+    key: impl Fn(&mut StdRng) -> K,
+    value: impl Fn(&mut StdRng) -> V,
+) -> BTreeMapDiff<K, V>
+where
+    V: comn::util::diff::Diffable<Diff = V>,
+{
+    BTreeMapDiff {
+        insert: vec(rng, 10, |rng| (key(rng), value(rng))),
+        remove: vec(rng, 10, &key),
+        update: vec(rng, 10, |rng| (key(rng), value(rng))),
+    }
+}
+
+fn tick(rng: &mut StdRng) -> comn::Tick {
+    comn::Tick {
+        diff_base: option(rng, |rng| comn::TickNum(rng.gen())),
+        diff: comn::game::GameDiff {
+            tick_num: comn::TickNum(rng.gen()),
+            players: map_diff(rng, player_id, player),
+            entities: map_diff(rng, entity_id, entity),
+            catcher: option(rng, player_id),
+        },
+        events: vec(rng, 5, |rng| (comn::TickNum(rng.gen()), vec(rng, 3, event))),
+        your_last_input_num: option(rng, |rng| comn::TickNum(rng.gen())),
+        send_interval: rng.gen_range(1, 4),
+    }
+}
+
+fn entity_pos(entity: &comn::Entity) -> comn::Point {
+    match entity {
+        comn::Entity::Bullet(e) => e.start_pos,
+        comn::Entity::Rocket(e) => e.start_pos,
+        comn::Entity::Food(e) => e.start_pos,
+        comn::Entity::DangerGuy(e) => e.start_pos,
+        comn::Entity::Wall(e) => e.rect.top_left,
+        e => e.pos(0.0),
+    }
+}
+
+#[test]
+fn encoding_is_stable_after_decoding() {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    for _ in 0..NUM_TICKS {
+        let data = wire::encode_tick(&tick(&mut rng), map_size());
+        let decoded = wire::decode_tick(&data, map_size()).unwrap();
+
+        assert_eq!(wire::encode_tick(&decoded, map_size()), data);
+    }
+}
+
+#[test]
+fn decoding_keeps_everything_but_precision() {
+    let mut rng = StdRng::seed_from_u64(1);
+
+    for _ in 0..NUM_TICKS {
+        let tick = tick(&mut rng);
+        let data = wire::encode_tick(&tick, map_size());
+        let decoded = wire::decode_tick(&data, map_size()).unwrap();
+
+        assert_eq!(decoded.diff_base, tick.diff_base);
+        assert_eq!(decoded.diff.tick_num, tick.diff.tick_num);
+        assert_eq!(decoded.diff.catcher, tick.diff.catcher);
+        assert_eq!(decoded.your_last_input_num, tick.your_last_input_num);
+        assert_eq!(decoded.send_interval, tick.send_interval);
+
+        // Players do not contain anything that is quantized.
+        assert_eq!(decoded.diff.players.insert, tick.diff.players.insert);
+        assert_eq!(decoded.diff.players.remove, tick.diff.players.remove);
+        assert_eq!(decoded.diff.players.update, tick.diff.players.update);
+
+        assert_eq!(decoded.diff.entities.remove, tick.diff.entities.remove);
+        let entities = tick
+            .diff
+            .entities
+            .insert
+            .iter()
+            .chain(tick.diff.entities.update.iter());
+        let decoded_entities = decoded
+            .diff
+            .entities
+            .insert
+            .iter()
+            .chain(decoded.diff.entities.update.iter());
+        for ((id, entity), (decoded_id, decoded_entity)) in entities.zip(decoded_entities) {
+            assert_eq!(decoded_id, id);
+            assert_eq!(
+                std::mem::discriminant(decoded_entity),
+                std::mem::discriminant(entity)
+            );
+
+            let error = (entity_pos(decoded_entity) - entity_pos(entity)).norm();
+            assert!(
+                error <= pos_tolerance(),
+                "position error {} is too large",
+                error
+            );
+        }
+
+        assert_eq!(decoded.events.len(), tick.events.len());
+        for ((tick_num, events), (decoded_tick_num, decoded_events)) in
+            tick.events.iter().zip(decoded.events.iter())
+        {
+            assert_eq!(decoded_tick_num, tick_num);
+            assert_eq!(decoded_events.len(), events.len());
+        }
+    }
+}
+
+#[test]
+fn truncated_data_is_rejected() {
+    let mut rng = StdRng::seed_from_u64(2);
+
+    for _ in 0..NUM_TICKS / 10 {
+        let data = wire::encode_tick(&tick(&mut rng), map_size());
+
+        for len in 0..data.len() {
+            assert!(wire::decode_tick(&data[..len], map_size()).is_none());
+        }
+    }
+}
+
+#[test]
+fn garbage_does_not_panic() {
+    let mut rng = StdRng::seed_from_u64(3);
+
+    for _ in 0..NUM_TICKS * 10 {
+        let data = vec(&mut rng, 200, |rng| rng.gen::<u8>());
+        let _ = wire::decode_tick(&data, map_size());
+    }
+}
+
+#[test]
+fn packed_ticks_are_smaller_than_msgpack() {
+    let mut rng = StdRng::seed_from_u64(4);
+
+    for _ in 0..NUM_TICKS {
+        let tick = tick(&mut rng);
+        let packed = wire::encode_tick(&tick, map_size());
+        let msgpack = comn::ServerMessage::Tick(tick).serialize();
+
+        assert!(packed.len() < msgpack.len());
+    }
+}
+
+#[test]
+fn varints_round_trip() {
+    let values = [0, 1, 127, 128, 16_383, 16_384, 1 << 28, std::u32::MAX];
+
+    let mut writer = BitWriter::new();
+    for &value in values.iter() {
+        // Misalign the varints on purpose.
+        writer.write_bool(true);
+        writer.write_varint(value);
+    }
+
+    let data = writer.into_bytes();
+    let mut reader = BitReader::new(&data);
+    for &value in values.iter() {
+        assert_eq!(reader.read_bool(), Some(true));
+        assert_eq!(reader.read_varint(), Some(value));
+    }
+    assert!(reader.is_done());
+}
+
+#[test]
+fn angles_round_trip() {
+    let mut rng = StdRng::seed_from_u64(5);
+
+    for _ in 0..1000 {
+        let angle = angle(&mut rng);
+
+        let mut writer = BitWriter::new();
+        writer.write_angle(angle);
+        let data = writer.into_bytes();
+        assert_eq!(data.len(), 1);
+
+        let decoded = BitReader::new(&data).read_angle().unwrap();
+        let error = comn::geom::angle_dist(angle, decoded).abs();
+        assert!(
+            error <= PI / 256.0 + 1e-4,
+            "angle error {} is too large",
+            error
+        );
+    }
+}
+
+#[test]
+fn inputs_round_trip() {
+    for bits in 0..128 {
+        assert_eq!(comn::Input::from_bits(bits).to_bits(), bits);
+    }
+}
+
+#[test]
+fn analog_inputs_round_trip() {
+    let mut rng = StdRng::seed_from_u64(6);
+
+    for _ in 0..1000 {
+        let input = comn::Input {
+            aim: option(&mut rng, vector),
+            move_dir: option(&mut rng, |rng| {
+                comn::Vector::new(rng.gen_range(-1.5, 1.5), rng.gen_range(-1.5, 1.5))
+            }),
+            ..comn::Input::from_bits(rng.gen_range(0, 128))
+        };
+        let packed = input.pack();
+        let unpacked = comn::Input::unpack(&packed);
+
+        assert_eq!(unpacked.to_bits(), input.to_bits());
+        assert_eq!(unpacked.aim.is_some(), input.aim.is_some());
+        assert_eq!(unpacked.pack(), packed);
+
+        if let (Some(dir), Some(unpacked_dir)) = (input.move_dir, unpacked.move_dir) {
+            let error = (unpacked_dir.norm() - dir.norm().min(1.0)).abs();
+            assert!(error <= 1.0 / 255.0, "length error {} is too large", error);
+        }
+    }
+}
+
+#[test]
+fn invalid_analog_inputs_are_dropped() {
+    let input = comn::Input {
+        aim: Some(comn::Vector::new(std::f32::NAN, 1.0)),
+        move_dir: Some(comn::Vector::zeros()),
+        ..comn::Input::default()
+    };
+    let packed = input.pack();
+
+    assert_eq!(packed.aim, None);
+    assert_eq!(packed.move_dir, None);
+}
@@ -0,0 +1,259 @@
+//! Types for the admin HTTP API, which allows operators to inspect and
+//! control the running games.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use comn::game::mapgen;
+
+use crate::{fake_bad_net, profile};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerInfo {
+    pub token: comn::PlayerToken,
+    pub name: String,
+    pub account: Option<String>,
+
+    /// Spectators do not have a player id.
+    pub player_id: Option<comn::PlayerId>,
+    pub ip: Option<IpAddr>,
+    pub ping_ms: f32,
+
+    /// Fraction of recent pings that were not answered.
+    pub loss: f32,
+
+    /// We send every `send_interval`-th tick to the player.
+    pub send_interval: u32,
+    pub rating: f32,
+    pub disconnected: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GameInfo {
+    pub game_id: comn::GameId,
+    pub map_name: String,
+    pub private: bool,
+    pub num_bots: usize,
+    pub max_num_players: usize,
+    pub game_time: comn::GameTime,
+    pub players: Vec<PlayerInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlayerRequest {
+    pub token: comn::PlayerToken,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GameRequest {
+    pub game_id: comn::GameId,
+}
+
+/// Bans either the address of a player, or an address directly.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BanRequest {
+    pub token: Option<comn::PlayerToken>,
+    pub ip: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnbanRequest {
+    pub ip: IpAddr,
+}
+
+/// Changes to the settings of a running game. The map and the tick rate of
+/// a running game cannot be changed: clients receive the map only when
+/// joining, and both sides derive the game time from the tick number at the
+/// tick rate that the game started with. These are changed for new games
+/// with `NewGameSettingsRequest` instead.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SettingsRequest {
+    pub game_id: comn::GameId,
+    pub max_num_players: Option<usize>,
+
+    /// Requests that try to change the tick rate or the map are rejected
+    /// with `Error::UseNewGameSettings`, rather than silently ignored.
+    pub ticks_per_second: Option<usize>,
+    pub map_gen: Option<serde_json::Value>,
+}
+
+/// Changes to the settings with which new games are created. Running games
+/// keep their settings.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewGameSettingsRequest {
+    pub ticks_per_second: Option<usize>,
+
+    /// Generates the maps of new games with the given config. The seed is
+    /// increased for every game, so that the maps keep changing.
+    pub map_gen: Option<mapgen::Config>,
+}
+
+/// Changes the simulated network conditions, either for all peers or for a
+/// single address. The link is given either as a preset name or in full.
+/// If neither is given, the conditions are reset.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FakeBadNetRequest {
+    pub ip: Option<IpAddr>,
+    pub preset: Option<String>,
+    pub link: Option<fake_bad_net::Link>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Kick(comn::PlayerToken),
+    Ban(BanRequest),
+    Unban(IpAddr),
+    EndGame(comn::GameId),
+    AddBot(comn::GameId),
+    RemoveBot(comn::GameId),
+    UpdateSettings(SettingsRequest),
+    UpdateNewGameSettings(NewGameSettingsRequest),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum Error {
+    InvalidPlayerToken,
+    InvalidGameId,
+    UnknownAddress,
+    FullGame,
+    NoBots,
+    InvalidSettings,
+
+    /// The setting can only be changed for new games, with
+    /// `/admin/new_game_settings`.
+    UseNewGameSettings,
+    FakeBadNetDisabled,
+    UnknownPreset,
+    InvalidLink(String),
+}
+
+pub type Reply = Result<(), Error>;
+
+pub fn update_fake_bad_net(
+    profiles: &mut fake_bad_net::Profiles,
+    request: FakeBadNetRequest,
+) -> Reply {
+    let link = match (request.preset, request.link) {
+        (Some(preset), _) => Some(fake_bad_net::Link::preset(&preset).ok_or(Error::UnknownPreset)?),
+        (None, Some(link)) => Some(link),
+        (None, None) => None,
+    };
+
+    if let Some(link) = link.as_ref() {
+        link.validate().map_err(Error::InvalidLink)?;
+    }
+
+    match (request.ip, link) {
+        (Some(ip), Some(link)) => {
+            profiles.peers.insert(ip, link);
+        }
+        (Some(ip), None) => {
+            profiles.peers.remove(&ip);
+        }
+        (None, link) => {
+            profiles.default = link.unwrap_or_default();
+        }
+    }
+
+    Ok(())
+}
+
+/// Banned IP addresses, shared between the HTTP server, which rejects
+/// requests from them, and the runner, which ignores their messages. In a
+/// cluster, the front door keeps the bans and sends them to the workers.
+///
+/// If a file is given, the bans are stored there, so that they survive
+/// restarts.
+#[derive(Default)]
+pub struct Bans {
+    ips: Mutex<HashSet<IpAddr>>,
+    path: Option<PathBuf>,
+    dirty: Mutex<bool>,
+}
+
+impl Bans {
+    /// Loads the bans from `path`. If the file does not exist yet, nobody is
+    /// banned.
+    pub fn load(path: PathBuf) -> Result<Self, profile::Error> {
+        let ips: HashSet<IpAddr> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        info!("Loaded {} bans from {:?}", ips.len(), path);
+
+        Ok(Self {
+            ips: Mutex::new(ips),
+            path: Some(path),
+            dirty: Mutex::new(false),
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.ips.lock().unwrap().contains(&ip)
+    }
+
+    pub fn insert(&self, ip: IpAddr) {
+        if self.ips.lock().unwrap().insert(ip) {
+            *self.dirty.lock().unwrap() = true;
+        }
+    }
+
+    pub fn remove(&self, ip: IpAddr) -> bool {
+        let removed = self.ips.lock().unwrap().remove(&ip);
+        if removed {
+            *self.dirty.lock().unwrap() = true;
+        }
+        removed
+    }
+
+    pub fn list(&self) -> Vec<IpAddr> {
+        self.ips.lock().unwrap().iter().copied().collect()
+    }
+
+    pub fn replace(&self, ips: Vec<IpAddr>) {
+        *self.ips.lock().unwrap() = ips.into_iter().collect();
+        *self.dirty.lock().unwrap() = true;
+    }
+
+    /// Writes the bans to their file if they have changed. As with the
+    /// profiles, we write to a temporary file first.
+    pub fn save(&self) {
+        let path = if let Some(path) = self.path.as_ref() {
+            path
+        } else {
+            return;
+        };
+
+        // Holding this lock keeps concurrent saves from overtaking each
+        // other.
+        let mut dirty = self.dirty.lock().unwrap();
+        if !*dirty {
+            return;
+        }
+
+        let ips: BTreeSet<IpAddr> = self.ips.lock().unwrap().iter().copied().collect();
+        let result = serde_json::to_vec_pretty(&ips)
+            .map_err(profile::Error::from)
+            .and_then(|data| {
+                let tmp_path = path.with_extension("tmp");
+                std::fs::write(&tmp_path, data)?;
+                std::fs::rename(&tmp_path, path)?;
+                Ok(())
+            });
+
+        match result {
+            Ok(()) => *dirty = false,
+            Err(err) => warn!("Failed to save bans: {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Optional player accounts: password hashing, signed session tokens and
+//! validation of player names.
+//!
+//! A session token has the form `<name>.<expiry>.<signature>`, where the
+//! signature is an HMAC over the name and the expiry time. This way, we do
+//! not need to keep track of sessions on the server.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+
+use crate::profile;
+
+pub const MIN_PLAYER_NAME_LEN: usize = 1;
+pub const MAX_PLAYER_NAME_LEN: usize = 16;
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+const SECRET_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const PBKDF2_ITERATIONS: usize = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Key for signing session tokens. If not set, a random key is
+    /// generated on startup, so sessions do not survive a restart.
+    pub secret: Option<Vec<u8>>,
+
+    /// How long session tokens stay valid.
+    pub session_duration: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            session_duration: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    OpenSSL(openssl::error::ErrorStack),
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        Error::OpenSSL(err)
+    }
+}
+
+/// A salted password hash, hex encoded.
+#[derive(Debug, Clone)]
+pub struct PasswordHash {
+    pub salt: String,
+    pub hash: String,
+}
+
+pub struct Auth {
+    secret: Vec<u8>,
+    session_duration: Duration,
+}
+
+impl Auth {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let secret = if let Some(secret) = config.secret {
+            secret
+        } else {
+            let mut secret = vec![0; SECRET_LEN];
+            openssl::rand::rand_bytes(&mut secret)?;
+            secret
+        };
+
+        Ok(Self {
+            secret,
+            session_duration: config.session_duration,
+        })
+    }
+
+    pub fn new_session_token(&self, name: &str) -> Result<String, Error> {
+        let expiry = unix_time() + self.session_duration.as_secs();
+        let payload = format!("{}.{}", name, expiry);
+        let signature = self.sign(&payload)?;
+
+        Ok(format!("{}.{}", payload, to_hex(&signature)))
+    }
+
+    /// Returns the account name if the session token is valid and has not
+    /// expired.
+    pub fn verify_session_token(&self, token: &str) -> Option<String> {
+        let mut parts = token.rsplitn(3, '.');
+        let signature = from_hex(parts.next()?)?;
+        let expiry: u64 = parts.next()?.parse().ok()?;
+        let name = parts.next()?;
+
+        let expected = self.sign(&format!("{}.{}", name, expiry)).ok()?;
+        if signature.len() != expected.len() || !memcmp::eq(&signature, &expected) {
+            return None;
+        }
+
+        if expiry < unix_time() {
+            return None;
+        }
+
+        Some(name.to_string())
+    }
+
+    fn sign(&self, payload: &str) -> Result<Vec<u8>, Error> {
+        hmac(&self.secret, payload.as_bytes())
+    }
+}
+
+/// Registered accounts, shared between the HTTP server, which handles
+/// registration and logins, and the runner, which updates the stats.
+pub struct Accounts {
+    auth: Auth,
+    profiles: Mutex<profile::Store>,
+}
+
+impl Accounts {
+    pub fn new(auth: Auth, profiles: profile::Store) -> Self {
+        Self {
+            auth,
+            profiles: Mutex::new(profiles),
+        }
+    }
+
+    /// Creates a new account. Hashing the password is slow, so this should
+    /// not be called on the runner thread.
+    pub fn register(&self, request: &comn::AuthRequest) -> comn::AuthReply {
+        if !is_valid_player_name(&request.name) {
+            return Err(comn::AuthError::InvalidName);
+        }
+        if !is_valid_password(&request.password) {
+            return Err(comn::AuthError::InvalidPassword);
+        }
+        if self.is_registered(&request.name) {
+            return Err(comn::AuthError::NameTaken);
+        }
+
+        let password_hash = hash_password(&request.password).map_err(|err| {
+            warn!("Failed to hash password: {:?}", err);
+            comn::AuthError::InvalidPassword
+        })?;
+        let profile = profile::Profile::new(request.name.clone(), password_hash);
+
+        // Someone may have taken the name while we were hashing.
+        if !self.profiles.lock().unwrap().insert(profile) {
+            return Err(comn::AuthError::NameTaken);
+        }
+
+        info!("Registered account {:?}", request.name);
+
+        self.new_session(&request.name)
+    }
+
+    /// Checks the password of an account. Like `register`, this is slow.
+    pub fn login(&self, request: &comn::AuthRequest) -> comn::AuthReply {
+        let profile = self.profiles.lock().unwrap().get(&request.name).cloned();
+        let profile = profile.ok_or(comn::AuthError::WrongNameOrPassword)?;
+
+        if !verify_password(&request.password, &profile.password_hash()) {
+            return Err(comn::AuthError::WrongNameOrPassword);
+        }
+
+        self.new_session(&profile.name)
+    }
+
+    pub fn verify_session_token(&self, token: &str) -> Option<String> {
+        let name = self.auth.verify_session_token(token)?;
+
+        // The account may have been deleted from the profile store.
+        if self.is_registered(&name) {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    /// Names of registered accounts are reserved for their owners.
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.profiles.lock().unwrap().contains(name)
+    }
+
+    pub fn stats(&self, name: &str) -> Option<profile::Stats> {
+        self.profiles.lock().unwrap().stats(name).cloned()
+    }
+
+    pub fn update_stats(&self, name: &str, f: impl FnOnce(&mut profile::Stats)) {
+        self.profiles.lock().unwrap().update_stats(name, f);
+    }
+
+    pub fn save(&self) {
+        if let Err(err) = self.profiles.lock().unwrap().save_if_dirty() {
+            warn!("Failed to save profiles: {:?}", err);
+        }
+    }
+
+    fn new_session(&self, name: &str) -> comn::AuthReply {
+        let session_token = self.auth.new_session_token(name).map_err(|err| {
+            warn!("Failed to create session token: {:?}", err);
+            comn::AuthError::WrongNameOrPassword
+        })?;
+
+        Ok(comn::AuthSuccess {
+            name: name.to_string(),
+            session_token,
+        })
+    }
+}
+
+/// Computes an HMAC-SHA256 of the payload.
+pub fn hmac(secret: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(payload)?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+pub fn hash_password(password: &str) -> Result<PasswordHash, Error> {
+    let mut salt = vec![0; SALT_LEN];
+    openssl::rand::rand_bytes(&mut salt)?;
+
+    let hash = pbkdf2(password, &salt)?;
+
+    Ok(PasswordHash {
+        salt: to_hex(&salt),
+        hash: to_hex(&hash),
+    })
+}
+
+pub fn verify_password(password: &str, password_hash: &PasswordHash) -> bool {
+    let (salt, expected) = match (from_hex(&password_hash.salt), from_hex(&password_hash.hash)) {
+        (Some(salt), Some(expected)) => (salt, expected),
+        _ => return false,
+    };
+
+    match pbkdf2(password, &salt) {
+        Ok(hash) => hash.len() == expected.len() && memcmp::eq(&hash, &expected),
+        Err(_) => false,
+    }
+}
+
+/// Player names are between `MIN_PLAYER_NAME_LEN` and `MAX_PLAYER_NAME_LEN`
+/// characters long, and consist of ASCII letters, digits, `-` and `_`. In
+/// particular, names cannot contain `.`, which separates the parts of a
+/// session token.
+pub fn is_valid_player_name(name: &str) -> bool {
+    (MIN_PLAYER_NAME_LEN..=MAX_PLAYER_NAME_LEN).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn is_valid_password(password: &str) -> bool {
+    (MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password.len())
+}
+
+fn pbkdf2(password: &str, salt: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut hash = vec![0; HASH_LEN];
+    openssl::pkcs5::pbkdf2_hmac(
+        password.as_bytes(),
+        salt,
+        PBKDF2_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut hash,
+    )?;
+
+    Ok(hash)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,98 @@
+use std::{path::Path, time::Instant};
+
+/// Number of chat messages that a player may send in quick succession.
+const RATE_LIMIT_BURST: f32 = 5.0;
+
+/// Number of chat messages per second that a player may send in the long run.
+const RATE_LIMIT_PER_SEC: f32 = 0.5;
+
+/// Token bucket for limiting how often a player may send chat messages.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    allowance: f32,
+    last_time: Option<Instant>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            allowance: RATE_LIMIT_BURST,
+            last_time: None,
+        }
+    }
+}
+
+impl RateLimit {
+    /// Returns true if a message may be sent at time `now`, consuming some of
+    /// the allowance.
+    pub fn try_send(&mut self, now: Instant) -> bool {
+        if let Some(last_time) = self.last_time {
+            let elapsed = now.duration_since(last_time).as_secs_f32();
+            self.allowance = (self.allowance + elapsed * RATE_LIMIT_PER_SEC).min(RATE_LIMIT_BURST);
+        }
+        self.last_time = Some(now);
+
+        if self.allowance >= 1.0 {
+            self.allowance -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Replaces blocklisted words in chat messages with asterisks.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    words: Vec<String>,
+}
+
+impl Filter {
+    /// Loads a blocklist with one word per line. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let words = std::fs::read_to_string(path)?
+            .lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+
+        Ok(Self { words })
+    }
+
+    pub fn censor(&self, text: &str) -> String {
+        text.split(' ')
+            .map(|word| {
+                let normalized: String = word
+                    .chars()
+                    .filter(|c| c.is_alphanumeric())
+                    .flat_map(char::to_lowercase)
+                    .collect();
+
+                if self.words.iter().any(|bad_word| normalized == *bad_word) {
+                    "*".repeat(word.chars().count())
+                } else {
+                    word.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Cleans up a chat message, or returns `None` if there is nothing left to
+/// send.
+pub fn sanitize(text: &str) -> Option<String> {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(comn::MAX_CHAT_MESSAGE_LEN)
+        .collect();
+    let text = text.trim();
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
@@ -0,0 +1,930 @@
+//! Running the server as several processes: a front door, which serves HTTP
+//! and routes joins, and any number of game workers, which run the games.
+//!
+//! Workers connect to the front door over TCP, and both sides exchange
+//! newline-delimited JSON messages. Before a worker is registered, it needs
+//! to prove that it knows the cluster secret by signing a random challenge
+//! of the front door. Workers regularly report their load, so
+//! that the front door can route joins to a worker with capacity. Clients
+//! then establish their WebRTC connection directly with that worker, so game
+//! messages never pass through the front door.
+//!
+//! Accounts, leaderboards and bans are kept by the front door only. Joins
+//! are passed on together with the identity of the player, workers report
+//! their `shared::Update`s to the front door, and the front door sends the
+//! bans to all workers whenever they change.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::future::join_all;
+use log::{info, warn};
+use openssl::memcmp;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::{
+    admin, auth, metrics,
+    runner::{Request as RunnerRequest, RequestTx},
+    shared::{Identity, Shared, Update, UpdateRx},
+};
+
+pub const LOAD_REPORT_PERIOD: Duration = Duration::from_secs(1);
+pub const CONNECT_RETRY_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long the front door waits for a new connection to prove that it is
+/// one of our workers.
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CHALLENGE_LEN: usize = 32;
+
+/// How long the front door waits for a worker to answer. Joins can wait in
+/// the matchmaking queue of the worker, so this needs to be generous.
+pub const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the front door waits for a worker to answer calls that are sent
+/// to all workers at once. These are answered right away, so a worker that
+/// takes longer than this is left out.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximal length of a message line. Connections that send longer lines are
+/// dropped, so that they cannot make us buffer arbitrary amounts of data.
+pub const MAX_LINE_LEN: u64 = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Address of the front door.
+    pub front_addr: SocketAddr,
+
+    /// URL at which clients reach the WebRTC session endpoint of this worker.
+    pub session_url: String,
+
+    /// Secret shared with the front door.
+    pub secret: Vec<u8>,
+}
+
+/// Load of a worker, as reported to the front door.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Load {
+    pub num_games: usize,
+    pub max_num_games: usize,
+    pub num_players: usize,
+    pub max_num_players_per_game: usize,
+
+    /// Games running on the worker, so that joins for a specific game can be
+    /// routed.
+    pub game_ids: Vec<comn::GameId>,
+}
+
+impl Load {
+    pub fn capacity(&self) -> usize {
+        self.max_num_games * self.max_num_players_per_game
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.num_players < self.capacity()
+    }
+
+    pub fn fraction(&self) -> f32 {
+        self.num_players as f32 / self.capacity().max(1) as f32
+    }
+}
+
+/// Requests that the front door passes on to workers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Call {
+    /// The front door has already checked the player against the accounts.
+    Join(comn::JoinRequest, Identity),
+    Reconnect(comn::ReconnectRequest),
+    CreateGame(comn::CreateGameRequest),
+    ListGames,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Answer {
+    Join(comn::JoinReply),
+    CreateGame(comn::CreateGameReply),
+    ListGames(Vec<comn::GameInfo>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FrontMessage {
+    /// Sent right after a worker connects. The worker answers with `Hello`.
+    Challenge {
+        challenge: String,
+    },
+    Call {
+        call_id: u64,
+        call: Call,
+    },
+
+    /// All the banned addresses. Sent after registering and whenever the
+    /// bans change.
+    Bans(Vec<IpAddr>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WorkerMessage {
+    /// Registers the worker. The proof is the HMAC of the challenge with the
+    /// cluster secret, hex encoded.
+    Hello {
+        session_url: String,
+        proof: String,
+    },
+    Load(Load),
+    Answer {
+        call_id: u64,
+        answer: Answer,
+    },
+    Update(Update),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    JSON(serde_json::Error),
+    Disconnected,
+    Timeout,
+    UnexpectedMessage,
+    InvalidProof,
+    LineTooLong,
+    OpenSSL(openssl::error::ErrorStack),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JSON(err)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        Error::OpenSSL(err)
+    }
+}
+
+impl From<auth::Error> for Error {
+    fn from(err: auth::Error) -> Self {
+        match err {
+            auth::Error::OpenSSL(err) => Error::OpenSSL(err),
+        }
+    }
+}
+
+type WorkerId = u64;
+
+struct Worker {
+    addr: SocketAddr,
+    session_url: String,
+
+    /// The last load that was reported. Workers do not get any joins until
+    /// they have reported their load.
+    load: Option<Load>,
+
+    message_tx: mpsc::UnboundedSender<FrontMessage>,
+    next_call_id: u64,
+    pending_calls: HashMap<u64, oneshot::Sender<Answer>>,
+}
+
+#[derive(Default)]
+struct Workers {
+    next_worker_id: WorkerId,
+    workers: BTreeMap<WorkerId, Worker>,
+}
+
+/// The front door, which keeps track of the connected workers and routes
+/// requests to them.
+#[derive(Clone)]
+pub struct Front {
+    secret: Arc<Vec<u8>>,
+    shared: Shared,
+    workers: Arc<Mutex<Workers>>,
+
+    /// Number of failed join requests by `JoinError`. Only the outcome over
+    /// all workers counts, since most workers do not have the game that a
+    /// join refers to.
+    join_failures: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl Front {
+    pub fn new(secret: Vec<u8>, shared: Shared) -> Self {
+        Self {
+            secret: Arc::new(secret),
+            shared,
+            workers: Arc::new(Mutex::new(Workers::default())),
+            join_failures: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Accepts connections from workers.
+    pub async fn listen(self, listen_addr: SocketAddr) -> Result<(), Error> {
+        info!("Waiting for workers at {:?}", listen_addr);
+
+        let mut listener = TcpListener::bind(listen_addr).await?;
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+
+            tokio::spawn({
+                let front = self.clone();
+
+                async move {
+                    if let Err(err) = front.handle_worker(stream, addr).await {
+                        warn!("Worker {} failed: {:?}", addr, err);
+                    }
+                }
+            });
+        }
+    }
+
+    pub async fn join(&self, request: comn::JoinRequest) -> Result<comn::JoinReply, Error> {
+        let reply = self.route_join(request).await;
+        self.record_join_reply(&reply);
+        reply
+    }
+
+    async fn route_join(&self, request: comn::JoinRequest) -> Result<comn::JoinReply, Error> {
+        let identity = match self.shared.identify(&request) {
+            Ok(identity) => identity,
+            Err(err) => return Ok(Err(err)),
+        };
+
+        // Private games are only known by their invite code, so we ask every
+        // worker until one of them knows the code.
+        let (worker_ids, not_found) = if request.invite_code.is_some() {
+            (self.worker_ids(), comn::JoinError::InvalidInviteCode)
+        } else if let Some(game_id) = request.game_id {
+            (
+                self.find_worker_for_game(game_id).into_iter().collect(),
+                comn::JoinError::InvalidGameId,
+            )
+        } else if let Some(worker_id) = self.find_worker_for_join() {
+            return self
+                .join_on(worker_id, Call::Join(request, identity), CALL_TIMEOUT)
+                .await;
+        } else {
+            return Ok(Err(comn::JoinError::FullGame));
+        };
+
+        // Joins for specific games skip the matchmaking queue, so all workers
+        // can be asked at once with a short timeout.
+        let replies = join_all(worker_ids.iter().map(|&worker_id| {
+            self.join_on(
+                worker_id,
+                Call::Join(request.clone(), identity.clone()),
+                QUERY_TIMEOUT,
+            )
+        }))
+        .await;
+
+        // A worker that fails to answer should not keep players from
+        // finding their game on the other workers.
+        let mut last_err = None;
+
+        for (worker_id, reply) in worker_ids.into_iter().zip(replies) {
+            match reply {
+                Ok(Err(comn::JoinError::InvalidGameId))
+                | Ok(Err(comn::JoinError::InvalidInviteCode)) => {}
+                Ok(reply) => return Ok(reply),
+                Err(err) => {
+                    warn!("Worker {} failed to answer join: {:?}", worker_id, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        // If the game could have been on the failed worker, the client
+        // should try again instead of being told that the game is gone.
+        last_err.map_or(Ok(Err(not_found)), Err)
+    }
+
+    pub async fn reconnect(
+        &self,
+        request: comn::ReconnectRequest,
+    ) -> Result<comn::JoinReply, Error> {
+        let reply = self.route_reconnect(request).await;
+        self.record_join_reply(&reply);
+        reply
+    }
+
+    async fn route_reconnect(
+        &self,
+        request: comn::ReconnectRequest,
+    ) -> Result<comn::JoinReply, Error> {
+        // Only the worker that runs the game knows the player token.
+        let worker_ids = self.worker_ids();
+        let replies = join_all(worker_ids.iter().map(|&worker_id| {
+            self.join_on(worker_id, Call::Reconnect(request.clone()), QUERY_TIMEOUT)
+        }))
+        .await;
+
+        let mut last_err = None;
+
+        for (worker_id, reply) in worker_ids.into_iter().zip(replies) {
+            match reply {
+                Ok(Err(comn::JoinError::InvalidPlayerToken)) => {}
+                Ok(reply) => return Ok(reply),
+                Err(err) => {
+                    warn!("Worker {} failed to answer reconnect: {:?}", worker_id, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        last_err.map_or(Ok(Err(comn::JoinError::InvalidPlayerToken)), Err)
+    }
+
+    pub async fn create_game(
+        &self,
+        request: comn::CreateGameRequest,
+    ) -> Result<comn::CreateGameReply, Error> {
+        let worker_id = if let Some(worker_id) = self.find_worker_for_new_game() {
+            worker_id
+        } else {
+            return Ok(Err(comn::CreateGameError::TooManyGames));
+        };
+
+        match self
+            .call(worker_id, Call::CreateGame(request), CALL_TIMEOUT)
+            .await?
+        {
+            Answer::CreateGame(reply) => Ok(reply),
+            _ => Err(Error::UnexpectedMessage),
+        }
+    }
+
+    pub async fn list_games(&self) -> Result<Vec<comn::GameInfo>, Error> {
+        let worker_ids = self.worker_ids();
+        let answers = join_all(
+            worker_ids
+                .iter()
+                .map(|&worker_id| self.call(worker_id, Call::ListGames, QUERY_TIMEOUT)),
+        )
+        .await;
+
+        let mut games = Vec::new();
+
+        // Games of workers that fail to answer are left out of the list.
+        for (worker_id, answer) in worker_ids.into_iter().zip(answers) {
+            match answer {
+                Ok(Answer::ListGames(worker_games)) => games.extend(worker_games),
+                Ok(_) => warn!("Worker {} sent unexpected answer to ListGames", worker_id),
+                Err(err) => warn!("Worker {} failed to list games: {:?}", worker_id, err),
+            }
+        }
+
+        Ok(games)
+    }
+
+    pub fn metrics(&self) -> String {
+        let mut writer = metrics::Writer::default();
+
+        {
+            let workers = self.workers.lock().unwrap();
+            let loads: Vec<&Load> = workers
+                .workers
+                .values()
+                .filter_map(|worker| worker.load.as_ref())
+                .collect();
+
+            writer.gauge(
+                "workers",
+                "Number of connected game workers.",
+                workers.workers.len() as f64,
+            );
+            writer.gauge(
+                "players",
+                "Number of players on all workers, as last reported.",
+                loads.iter().map(|load| load.num_players).sum::<usize>() as f64,
+            );
+            writer.gauge(
+                "games",
+                "Number of games on all workers, as last reported.",
+                loads.iter().map(|load| load.num_games).sum::<usize>() as f64,
+            );
+        }
+
+        writer.labeled_counter(
+            "join_failures_total",
+            "Number of failed join requests.",
+            "error",
+            self.join_failures
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(err, count)| (err.clone(), *count)),
+        );
+
+        writer.finish()
+    }
+
+    fn record_join_reply(&self, reply: &Result<comn::JoinReply, Error>) {
+        if let Ok(Err(err)) = reply {
+            *self
+                .join_failures
+                .lock()
+                .unwrap()
+                .entry(format!("{:?}", err))
+                .or_insert(0) += 1;
+        }
+    }
+
+    async fn join_on(
+        &self,
+        worker_id: WorkerId,
+        call: Call,
+        timeout: Duration,
+    ) -> Result<comn::JoinReply, Error> {
+        let reply = match self.call(worker_id, call, timeout).await? {
+            Answer::Join(reply) => reply,
+            _ => return Err(Error::UnexpectedMessage),
+        };
+
+        // The client needs to establish its WebRTC connection with the
+        // worker that runs its game.
+        let session_url = self
+            .workers
+            .lock()
+            .unwrap()
+            .workers
+            .get(&worker_id)
+            .ok_or(Error::Disconnected)?
+            .session_url
+            .clone();
+
+        Ok(reply.map(|join_success| comn::JoinSuccess {
+            webrtc_session_url: Some(session_url),
+            ..join_success
+        }))
+    }
+
+    async fn call(
+        &self,
+        worker_id: WorkerId,
+        call: Call,
+        timeout: Duration,
+    ) -> Result<Answer, Error> {
+        let (call_id, answer_rx) = {
+            let mut workers = self.workers.lock().unwrap();
+            let worker = workers
+                .workers
+                .get_mut(&worker_id)
+                .ok_or(Error::Disconnected)?;
+
+            let call_id = worker.next_call_id;
+            worker.next_call_id += 1;
+
+            let (answer_tx, answer_rx) = oneshot::channel();
+            worker.pending_calls.insert(call_id, answer_tx);
+            worker
+                .message_tx
+                .send(FrontMessage::Call { call_id, call })
+                .map_err(|_| Error::Disconnected)?;
+
+            (call_id, answer_rx)
+        };
+
+        match time::timeout(timeout, answer_rx).await {
+            Ok(Ok(answer)) => Ok(answer),
+            Ok(Err(_)) => Err(Error::Disconnected),
+            Err(_) => {
+                if let Some(worker) = self.workers.lock().unwrap().workers.get_mut(&worker_id) {
+                    worker.pending_calls.remove(&call_id);
+                }
+
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    fn worker_ids(&self) -> Vec<WorkerId> {
+        self.workers
+            .lock()
+            .unwrap()
+            .workers
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    fn find_worker_for_game(&self, game_id: comn::GameId) -> Option<WorkerId> {
+        self.workers
+            .lock()
+            .unwrap()
+            .workers
+            .iter()
+            .find(|(_, worker)| {
+                worker
+                    .load
+                    .as_ref()
+                    .map_or(false, |load| load.game_ids.contains(&game_id))
+            })
+            .map(|(worker_id, _)| *worker_id)
+    }
+
+    /// Returns the least loaded worker that still has room for players.
+    fn find_worker_for_join(&self) -> Option<WorkerId> {
+        self.workers
+            .lock()
+            .unwrap()
+            .workers
+            .iter()
+            .filter_map(|(worker_id, worker)| {
+                worker
+                    .load
+                    .as_ref()
+                    .filter(|load| load.has_capacity())
+                    .map(|load| (*worker_id, load.fraction()))
+            })
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .map(|(worker_id, _)| worker_id)
+    }
+
+    /// Returns the worker with the fewest games that can start another game.
+    fn find_worker_for_new_game(&self) -> Option<WorkerId> {
+        self.workers
+            .lock()
+            .unwrap()
+            .workers
+            .iter()
+            .filter_map(|(worker_id, worker)| {
+                worker
+                    .load
+                    .as_ref()
+                    .filter(|load| load.num_games < load.max_num_games)
+                    .map(|load| (*worker_id, load.num_games))
+            })
+            .min_by_key(|(_, num_games)| *num_games)
+            .map(|(worker_id, _)| worker_id)
+    }
+
+    async fn handle_worker(&self, stream: TcpStream, addr: SocketAddr) -> Result<(), Error> {
+        let (read, write) = tokio::io::split(stream);
+        let mut read = BufReader::new(read);
+
+        let (message_tx, message_rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_messages(write, message_rx));
+
+        // Anyone who can reach the address can connect, so the worker needs
+        // to prove that it knows the secret before we send any players to it.
+        let mut challenge = vec![0; CHALLENGE_LEN];
+        openssl::rand::rand_bytes(&mut challenge)?;
+        let challenge = auth::to_hex(&challenge);
+
+        message_tx
+            .send(FrontMessage::Challenge {
+                challenge: challenge.clone(),
+            })
+            .map_err(|_| Error::Disconnected)?;
+
+        let hello = time::timeout(HELLO_TIMEOUT, read_message(&mut read))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        let (session_url, proof) = match hello {
+            Some(WorkerMessage::Hello { session_url, proof }) => (session_url, proof),
+            Some(_) => return Err(Error::UnexpectedMessage),
+            None => return Err(Error::Disconnected),
+        };
+
+        let expected = auth::hmac(&self.secret, challenge.as_bytes())?;
+        let proof = auth::from_hex(&proof).ok_or(Error::InvalidProof)?;
+        if proof.len() != expected.len() || !memcmp::eq(&proof, &expected) {
+            return Err(Error::InvalidProof);
+        }
+
+        let worker_id = {
+            let mut workers = self.workers.lock().unwrap();
+            let worker_id = workers.next_worker_id;
+            workers.next_worker_id += 1;
+
+            info!(
+                "Worker {} connected from {} with session URL {}",
+                worker_id, addr, session_url
+            );
+
+            // Bans are sent while holding the lock, so that the worker does
+            // not miss any change.
+            let _ = message_tx.send(FrontMessage::Bans(self.shared.bans.list()));
+
+            workers.workers.insert(
+                worker_id,
+                Worker {
+                    addr,
+                    session_url,
+                    load: None,
+                    message_tx,
+                    next_call_id: 0,
+                    pending_calls: HashMap::new(),
+                },
+            );
+
+            worker_id
+        };
+
+        let result = self.handle_worker_messages(worker_id, &mut read).await;
+
+        // Removing the worker fails its pending calls and stops the writer.
+        if let Some(worker) = self.workers.lock().unwrap().workers.remove(&worker_id) {
+            info!("Worker {} at {} disconnected", worker_id, worker.addr);
+        }
+
+        result
+    }
+
+    async fn handle_worker_messages(
+        &self,
+        worker_id: WorkerId,
+        read: &mut (impl AsyncBufRead + Unpin),
+    ) -> Result<(), Error> {
+        while let Some(message) = read_message(read).await? {
+            match message {
+                WorkerMessage::Hello { .. } => return Err(Error::UnexpectedMessage),
+                WorkerMessage::Load(load) => {
+                    self.with_worker(worker_id, |worker| worker.load = Some(load))?;
+                }
+                WorkerMessage::Answer { call_id, answer } => {
+                    self.with_worker(worker_id, |worker| {
+                        // The caller may have given up already.
+                        if let Some(answer_tx) = worker.pending_calls.remove(&call_id) {
+                            let _ = answer_tx.send(answer);
+                        }
+                    })?;
+                }
+                WorkerMessage::Update(update) => {
+                    let changes_bans = update.changes_bans();
+                    self.shared.apply(update);
+
+                    if changes_bans {
+                        self.send_bans();
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn with_worker<T>(
+        &self,
+        worker_id: WorkerId,
+        f: impl FnOnce(&mut Worker) -> T,
+    ) -> Result<T, Error> {
+        let mut workers = self.workers.lock().unwrap();
+        let worker = workers
+            .workers
+            .get_mut(&worker_id)
+            .ok_or(Error::Disconnected)?;
+
+        Ok(f(worker))
+    }
+
+    /// Sends the bans to all workers.
+    fn send_bans(&self) {
+        let workers = self.workers.lock().unwrap();
+        let bans = self.shared.bans.list();
+
+        for worker in workers.workers.values() {
+            let _ = worker.message_tx.send(FrontMessage::Bans(bans.clone()));
+        }
+    }
+}
+
+/// Connects a worker to the front door, reconnecting if the connection is
+/// lost. Returns once the runner has stopped.
+///
+/// Updates of the runner are passed on to the front door. While we are not
+/// connected, they wait in `update_rx`.
+pub async fn run_worker(
+    config: WorkerConfig,
+    request_tx: RequestTx,
+    bans: Arc<admin::Bans>,
+    mut update_rx: UpdateRx,
+) {
+    loop {
+        match TcpStream::connect(config.front_addr).await {
+            Ok(stream) => {
+                info!("Connected to front door at {:?}", config.front_addr);
+
+                let result =
+                    serve_front(&config, stream, request_tx.clone(), &bans, &mut update_rx).await;
+
+                if let Err(err) = result {
+                    warn!("Lost connection to front door: {:?}", err);
+                }
+            }
+            Err(err) => {
+                warn!(
+                    "Could not connect to front door at {:?}: {:?}",
+                    config.front_addr, err
+                );
+            }
+        }
+
+        if runner_call(&request_tx, RunnerRequest::Load)
+            .await
+            .is_none()
+        {
+            // The runner has stopped.
+            return;
+        }
+
+        time::delay_for(CONNECT_RETRY_PERIOD).await;
+    }
+}
+
+async fn serve_front(
+    config: &WorkerConfig,
+    stream: TcpStream,
+    request_tx: RequestTx,
+    bans: &admin::Bans,
+    update_rx: &mut UpdateRx,
+) -> Result<(), Error> {
+    let (read, write) = tokio::io::split(stream);
+    let mut read = BufReader::new(read);
+
+    let (message_tx, message_rx) = mpsc::unbounded_channel();
+    tokio::spawn(write_messages(write, message_rx));
+
+    let challenge = match read_message(&mut read).await? {
+        Some(FrontMessage::Challenge { challenge }) => challenge,
+        Some(_) => return Err(Error::UnexpectedMessage),
+        None => return Err(Error::Disconnected),
+    };
+    let proof = auth::hmac(&config.secret, challenge.as_bytes())?;
+
+    message_tx
+        .send(WorkerMessage::Hello {
+            session_url: config.session_url.clone(),
+            proof: auth::to_hex(&proof),
+        })
+        .map_err(|_| Error::Disconnected)?;
+
+    tokio::spawn(report_load(request_tx.clone(), message_tx.clone()));
+
+    // Reading a line cannot be interrupted without losing data, so we read
+    // in a separate task and wait for whichever message comes first here.
+    let (front_tx, mut front_rx) = mpsc::unbounded_channel();
+    tokio::spawn(read_messages(read, front_tx));
+
+    loop {
+        tokio::select! {
+            message = front_rx.recv() => match message {
+                Some(Ok(FrontMessage::Challenge { .. })) => return Err(Error::UnexpectedMessage),
+                Some(Ok(FrontMessage::Call { call_id, call })) => {
+                    tokio::spawn(answer_call(
+                        call_id,
+                        call,
+                        request_tx.clone(),
+                        message_tx.clone(),
+                    ));
+                }
+                Some(Ok(FrontMessage::Bans(ips))) => bans.replace(ips),
+                Some(Err(err)) => return Err(err),
+                None => return Ok(()),
+            },
+            update = update_rx.recv() => match update {
+                Some(update) => message_tx
+                    .send(WorkerMessage::Update(update))
+                    .map_err(|_| Error::Disconnected)?,
+                // The runner has stopped.
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+async fn report_load(request_tx: RequestTx, message_tx: mpsc::UnboundedSender<WorkerMessage>) {
+    let mut interval = time::interval(LOAD_REPORT_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        let load = if let Some(load) = runner_call(&request_tx, RunnerRequest::Load).await {
+            load
+        } else {
+            return;
+        };
+
+        if message_tx.send(WorkerMessage::Load(load)).is_err() {
+            return;
+        }
+    }
+}
+
+async fn answer_call(
+    call_id: u64,
+    call: Call,
+    request_tx: RequestTx,
+    message_tx: mpsc::UnboundedSender<WorkerMessage>,
+) {
+    let answer = match call {
+        Call::Join(request, identity) => runner_call(&request_tx, |reply_tx| {
+            RunnerRequest::Join(request, Ok(identity), reply_tx)
+        })
+        .await
+        .map(Answer::Join),
+        Call::Reconnect(request) => runner_call(&request_tx, |reply_tx| {
+            RunnerRequest::Reconnect(request, reply_tx)
+        })
+        .await
+        .map(Answer::Join),
+        Call::CreateGame(request) => runner_call(&request_tx, |reply_tx| {
+            RunnerRequest::CreateGame(request, reply_tx)
+        })
+        .await
+        .map(Answer::CreateGame),
+        Call::ListGames => runner_call(&request_tx, RunnerRequest::ListGames)
+            .await
+            .map(Answer::ListGames),
+    };
+
+    // If the runner is gone, the front door will time out the call.
+    if let Some(answer) = answer {
+        let _ = message_tx.send(WorkerMessage::Answer { call_id, answer });
+    }
+}
+
+async fn runner_call<T>(
+    request_tx: &RequestTx,
+    request: impl FnOnce(oneshot::Sender<T>) -> RunnerRequest,
+) -> Option<T> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    if request_tx.send(request(reply_tx)).is_err() {
+        return None;
+    }
+
+    reply_rx.await.ok()
+}
+
+async fn read_message<T: DeserializeOwned>(
+    read: &mut (impl AsyncBufRead + Unpin),
+) -> Result<Option<T>, Error> {
+    let mut line = String::new();
+
+    // Taking from a mutable reference leaves the rest of the stream in
+    // `read` for the next message.
+    if read.take(MAX_LINE_LEN).read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    if line.len() as u64 >= MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(Error::LineTooLong);
+    }
+
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+/// Reads messages until the connection is closed or fails.
+async fn read_messages<T: DeserializeOwned>(
+    mut read: impl AsyncBufRead + Unpin,
+    message_tx: mpsc::UnboundedSender<Result<T, Error>>,
+) {
+    loop {
+        match read_message(&mut read).await {
+            Ok(Some(message)) => {
+                if message_tx.send(Ok(message)).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(err) => {
+                let _ = message_tx.send(Err(err));
+                return;
+            }
+        }
+    }
+}
+
+async fn write_messages<T: Serialize>(
+    mut write: impl AsyncWrite + Unpin,
+    mut message_rx: mpsc::UnboundedReceiver<T>,
+) {
+    while let Some(message) = message_rx.recv().await {
+        // JSON without pretty printing does not contain any newlines.
+        let mut line = serde_json::to_string(&message).unwrap();
+        line.push('\n');
+
+        if let Err(err) = write.write_all(line.as_bytes()).await {
+            warn!("Failed to write cluster message: {:?}", err);
+            return;
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! Server configuration, loaded from a TOML file. Every setting has a
+//! default, and most of them can be overridden on the command line.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use comn::game::mapgen;
+
+use crate::fake_bad_net;
+
+pub const MAX_TICKS_PER_SECOND: usize = 120;
+
+/// The front door only accepts workers from the same machine, unless told
+/// otherwise.
+pub const DEFAULT_CLUSTER_ADDRESS: &str = "127.0.0.1:7000";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct File {
+    pub http: Http,
+    pub webrtc: WebRTC,
+    pub runner: Runner,
+    pub accounts: Accounts,
+    pub cluster: Cluster,
+
+    /// Simulates bad network conditions for testing. Disabled if not given.
+    pub fake_bad_net: Option<fake_bad_net::Profiles>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub address: Option<SocketAddr>,
+    pub clnt_dir: PathBuf,
+    pub admin_token_file: Option<PathBuf>,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            address: None,
+            clnt_dir: PathBuf::from("clnt/static"),
+            admin_token_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebRTC {
+    pub address: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Runner {
+    pub max_num_games: usize,
+    pub max_num_players: usize,
+    pub ticks_per_second: usize,
+
+    /// Number of threads that run game ticks in parallel.
+    pub num_workers: usize,
+
+    /// Path to the TMX map file. Not used if `map_gen` is given.
+    pub map: PathBuf,
+
+    /// If given, a new map is generated for every game.
+    pub map_gen: Option<mapgen::Config>,
+
+    pub chat_filter: Option<PathBuf>,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self {
+            max_num_games: 32,
+            max_num_players: 64,
+            ticks_per_second: 30,
+            num_workers: 4,
+            map: PathBuf::from("maps/test.tmx"),
+            map_gen: None,
+            chat_filter: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Accounts {
+    /// Enables player accounts, storing the profiles in the given file.
+    pub profiles: Option<PathBuf>,
+
+    /// Path to a file with the key for signing session tokens. Like the
+    /// cluster secret, it is not given on the command line, so that it does
+    /// not show up in the process list. Without it, sessions end on restart.
+    pub auth_secret_file: Option<PathBuf>,
+    pub session_duration_secs: u64,
+
+    /// Enables leaderboards, storing match results in the given file.
+    pub leaderboard: Option<PathBuf>,
+
+    /// Keeps the bans made through the admin API in the given file, so that
+    /// they survive restarts.
+    pub bans: Option<PathBuf>,
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Self {
+            profiles: None,
+            auth_secret_file: None,
+            session_duration_secs: 30 * 24 * 60 * 60,
+            leaderboard: None,
+            bans: None,
+        }
+    }
+}
+
+/// Which part of the server this process runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Serves HTTP and runs the games in a single process.
+    Standalone,
+
+    /// Serves HTTP, and passes joins on to the connected workers.
+    Front,
+
+    /// Runs games for a front door.
+    Worker,
+}
+
+impl std::str::FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "standalone" => Ok(Role::Standalone),
+            "front" => Ok(Role::Front),
+            "worker" => Ok(Role::Worker),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cluster {
+    pub role: Role,
+
+    /// For the front door, the address to accept workers at. For workers,
+    /// the address of the front door.
+    pub address: SocketAddr,
+
+    /// For workers, the URL at which clients reach their WebRTC session
+    /// endpoint, e.g. `http://<your-ip>:8081/connect_webrtc`.
+    pub session_url: Option<String>,
+
+    /// Path to a file with the secret that workers need to know in order to
+    /// connect to the front door. It is not given on the command line, so
+    /// that it does not show up in the process list.
+    pub secret_file: Option<PathBuf>,
+}
+
+impl Default for Cluster {
+    fn default() -> Self {
+        Self {
+            role: Role::Standalone,
+            address: DEFAULT_CLUSTER_ADDRESS.parse().unwrap(),
+            session_url: None,
+            secret_file: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    TOML(toml::de::Error),
+    Invalid(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Error::TOML(err)
+    }
+}
+
+impl File {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data = std::fs::read_to_string(path)?;
+
+        Ok(toml::from_str(&data)?)
+    }
+
+    /// Overrides settings with the arguments that were given on the command
+    /// line.
+    pub fn apply_args(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+        if let Some(address) = parse_arg(matches, "http_address")? {
+            self.http.address = Some(address);
+        }
+        if let Some(clnt_dir) = matches.value_of("clnt_dir") {
+            self.http.clnt_dir = PathBuf::from(clnt_dir);
+        }
+        if let Some(token_file) = matches.value_of("admin_token_file") {
+            self.http.admin_token_file = Some(PathBuf::from(token_file));
+        }
+        if let Some(address) = parse_arg(matches, "webrtc_address")? {
+            self.webrtc.address = Some(address);
+        }
+
+        if let Some(max_num_games) = parse_arg(matches, "max_num_games")? {
+            self.runner.max_num_games = max_num_games;
+        }
+        if let Some(max_num_players) = parse_arg(matches, "max_num_players")? {
+            self.runner.max_num_players = max_num_players;
+        }
+        if let Some(ticks_per_second) = parse_arg(matches, "ticks_per_second")? {
+            self.runner.ticks_per_second = ticks_per_second;
+        }
+        if let Some(num_workers) = parse_arg(matches, "num_workers")? {
+            self.runner.num_workers = num_workers;
+        }
+        if let Some(map) = matches.value_of("map") {
+            self.runner.map = PathBuf::from(map);
+        }
+        if let Some(chat_filter) = matches.value_of("chat_filter") {
+            self.runner.chat_filter = Some(PathBuf::from(chat_filter));
+        }
+
+        if matches.is_present("gen_map") && self.runner.map_gen.is_none() {
+            self.runner.map_gen = Some(mapgen::Config::default());
+        }
+        if let Some(map_gen) = self.runner.map_gen.as_mut() {
+            if let Some(seed) = parse_arg(matches, "gen_seed")? {
+                map_gen.seed = seed;
+            }
+            if let Some(size) = matches.value_of("gen_size") {
+                map_gen.size = parse_size(size)?;
+            }
+            if let Some(wall_density) = parse_arg(matches, "gen_wall_density")? {
+                map_gen.wall_density = wall_density;
+            }
+            if let Some(num_spawn_points) = parse_arg(matches, "gen_spawn_points")? {
+                map_gen.num_spawn_points = num_spawn_points;
+            }
+            if let Some(num_turrets) = parse_arg(matches, "gen_turrets")? {
+                map_gen.num_turrets = num_turrets;
+            }
+            if let Some(num_food_spawns) = parse_arg(matches, "gen_food_spawns")? {
+                map_gen.num_food_spawns = num_food_spawns;
+            }
+            if let Some(num_danger_guys) = parse_arg(matches, "gen_danger_guys")? {
+                map_gen.num_danger_guys = num_danger_guys;
+            }
+        }
+
+        if let Some(profiles) = matches.value_of("profiles") {
+            self.accounts.profiles = Some(PathBuf::from(profiles));
+        }
+        if let Some(secret_file) = matches.value_of("auth_secret_file") {
+            self.accounts.auth_secret_file = Some(PathBuf::from(secret_file));
+        }
+        if let Some(leaderboard) = matches.value_of("leaderboard") {
+            self.accounts.leaderboard = Some(PathBuf::from(leaderboard));
+        }
+        if let Some(bans) = matches.value_of("bans") {
+            self.accounts.bans = Some(PathBuf::from(bans));
+        }
+
+        if let Some(role) = parse_arg(matches, "role")? {
+            self.cluster.role = role;
+        }
+        if let Some(address) = parse_arg(matches, "cluster_address")? {
+            self.cluster.address = address;
+        }
+        if let Some(session_url) = matches.value_of("session_url") {
+            self.cluster.session_url = Some(session_url.to_string());
+        }
+        if let Some(secret_file) = matches.value_of("cluster_secret_file") {
+            self.cluster.secret_file = Some(PathBuf::from(secret_file));
+        }
+
+        if let Some(preset) = matches.value_of("fake_bad_net") {
+            let link = fake_bad_net::Link::preset(preset).ok_or_else(|| {
+                Error::Invalid(format!("unknown fake_bad_net preset {:?}", preset))
+            })?;
+
+            self.fake_bad_net
+                .get_or_insert_with(fake_bad_net::Profiles::default)
+                .default = link;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the settings make sense, so that we fail on startup
+    /// instead of running into problems later.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.http.address.is_none() {
+            return invalid("the HTTP address must be given");
+        }
+        if self.webrtc.address.is_none() && self.cluster.role != Role::Front {
+            return invalid("the WebRTC address must be given");
+        }
+
+        if self.runner.max_num_games == 0 {
+            return invalid("max_num_games must be positive");
+        }
+        if self.runner.max_num_players == 0 {
+            return invalid("max_num_players must be positive");
+        }
+        if self.runner.ticks_per_second == 0 || self.runner.ticks_per_second > MAX_TICKS_PER_SECOND
+        {
+            return invalid(&format!(
+                "ticks_per_second must be between 1 and {}",
+                MAX_TICKS_PER_SECOND
+            ));
+        }
+        if self.runner.num_workers == 0 {
+            return invalid("num_workers must be positive");
+        }
+        if let Some(map_gen) = self.runner.map_gen.as_ref() {
+            if !(0.0..1.0).contains(&map_gen.wall_density) {
+                return invalid("wall_density must be between 0 and 1");
+            }
+            if map_gen.num_spawn_points == 0 {
+                return invalid("generated maps need at least one spawn point");
+            }
+        }
+
+        if self.accounts.auth_secret_file.is_some() && self.accounts.profiles.is_none() {
+            return invalid("auth_secret_file is given, but accounts are not enabled");
+        }
+        if self.accounts.session_duration_secs == 0 {
+            return invalid("session_duration_secs must be positive");
+        }
+
+        if self.cluster.role != Role::Standalone && self.cluster.secret_file.is_none() {
+            return invalid("the cluster secret file must be given for the front door and workers");
+        }
+        if self.cluster.role == Role::Worker && self.cluster.session_url.is_none() {
+            return invalid("workers need a session URL");
+        }
+        if self.cluster.role == Role::Worker
+            && (self.accounts.profiles.is_some()
+                || self.accounts.leaderboard.is_some()
+                || self.accounts.bans.is_some())
+        {
+            return invalid(
+                "accounts, leaderboards and bans are kept by the front door, not by workers",
+            );
+        }
+
+        if let Some(fake_bad_net) = self.fake_bad_net.as_ref() {
+            fake_bad_net
+                .validate()
+                .map_err(|err| Error::Invalid(format!("fake_bad_net: {}", err)))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a secret from a file, ignoring surrounding whitespace such as a
+/// trailing newline.
+pub fn read_secret(path: &Path) -> Result<String, Error> {
+    let secret = std::fs::read_to_string(path)?.trim().to_string();
+
+    if secret.is_empty() {
+        return Err(Error::Invalid(format!("the secret in {:?} is empty", path)));
+    }
+
+    Ok(secret)
+}
+
+fn invalid(message: &str) -> Result<(), Error> {
+    Err(Error::Invalid(message.to_string()))
+}
+
+fn parse_arg<T: std::str::FromStr>(
+    matches: &clap::ArgMatches,
+    name: &str,
+) -> Result<Option<T>, Error> {
+    matches
+        .value_of(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| Error::Invalid(format!("could not parse {}: {:?}", name, value)))
+        })
+        .transpose()
+}
+
+/// Parses a map size given as `<width>x<height>`.
+fn parse_size(size: &str) -> Result<(usize, usize), Error> {
+    let parts: Vec<&str> = size.split('x').collect();
+
+    match parts.as_slice() {
+        [width, height] => match (width.parse(), height.parse()) {
+            (Ok(width), Ok(height)) => Ok((width, height)),
+            _ => Err(Error::Invalid(format!(
+                "could not parse map size {:?}",
+                size
+            ))),
+        },
+        _ => Err(Error::Invalid(
+            "map size must be given as <width>x<height>".to_string(),
+        )),
+    }
+}
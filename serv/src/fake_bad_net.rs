@@ -1,38 +1,282 @@
-use std::time::Duration;
+//! Simulation of bad network conditions for testing.
+//!
+//! Datagrams pass through a `FakeBadNet` after being received, before they
+//! are reassembled into messages, and again after messages have been split
+//! into datagrams for sending. Conditions are described by a `Config` for each
+//! direction, and can be set for all peers or for specific addresses.
+//! The profiles are shared with the admin API, so that they can be changed
+//! while the server is running.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use log::warn;
 use rand::Rng;
 use rand_distr::Distribution;
+use serde::{Deserialize, Serialize};
 
 use futures::{pin_mut, prelude::Stream, select, FutureExt};
 use tokio::{stream::StreamExt, sync::mpsc, time::DelayQueue};
 
 use crate::webrtc::{MessageIn, MessageOut};
 
-pub trait AddFakeLag {
+/// With a bandwidth cap, datagrams that would have to wait longer than this
+/// for being transmitted are dropped, as in a full router buffer.
+const MAX_BANDWIDTH_QUEUE_DELAY: Duration = Duration::from_secs(1);
+
+/// We forget the state of peers that we have not seen for this long.
+const PEER_STATE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_NUM_PEER_STATES: usize = 1024;
+
+pub trait FakeBadNetMessage: Clone {
+    fn peer(&self) -> SocketAddr;
+    fn size(&self) -> usize;
     fn add_fake_lag(&mut self, lag: Duration);
 }
 
-impl AddFakeLag for MessageIn {
+impl FakeBadNetMessage for MessageIn {
+    fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
     fn add_fake_lag(&mut self, lag: Duration) {
         self.recv_time += lag;
     }
 }
 
-impl AddFakeLag for MessageOut {
+impl FakeBadNetMessage for MessageOut {
+    fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
     fn add_fake_lag(&mut self, _: Duration) {}
 }
 
-#[derive(Debug, Clone)]
+/// Network conditions in one direction. Probabilities are per datagram.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub lag_mean: Duration,
-    pub lag_std_dev: f32,
+    pub lag_mean_ms: f32,
+    pub lag_std_dev_ms: f32,
+
+    /// Loss probability while the connection is good.
     pub loss: f32,
+
+    /// Burst loss, following the Gilbert-Elliott model: the connection
+    /// switches between a good and a bad state, and datagrams are lost with
+    /// probability `burst_loss` while in the bad state.
+    pub burst_loss: f32,
+    pub burst_enter_prob: f32,
+    pub burst_exit_prob: f32,
+
+    /// Datagrams that are reordered get additional lag, so that later
+    /// datagrams overtake them.
+    pub reorder_prob: f32,
+    pub reorder_lag_ms: f32,
+
+    pub duplicate_prob: f32,
+
+    /// Maximal throughput per peer. Unlimited if not set.
+    pub bandwidth_bytes_per_sec: Option<u32>,
+
+    /// Jitter spikes add lag to all datagrams of a peer for a while, e.g.
+    /// when a mobile connection switches cells.
+    pub spike_prob: f32,
+    pub spike_duration_ms: f32,
+    pub spike_lag_ms: f32,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        let probs = [
+            ("loss", self.loss),
+            ("burst_loss", self.burst_loss),
+            ("burst_enter_prob", self.burst_enter_prob),
+            ("burst_exit_prob", self.burst_exit_prob),
+            ("reorder_prob", self.reorder_prob),
+            ("duplicate_prob", self.duplicate_prob),
+            ("spike_prob", self.spike_prob),
+        ];
+        for (name, prob) in probs.iter() {
+            if !(0.0..=1.0).contains(prob) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+
+        let durations = [
+            ("lag_mean_ms", self.lag_mean_ms),
+            ("lag_std_dev_ms", self.lag_std_dev_ms),
+            ("reorder_lag_ms", self.reorder_lag_ms),
+            ("spike_duration_ms", self.spike_duration_ms),
+            ("spike_lag_ms", self.spike_lag_ms),
+        ];
+        for (name, duration) in durations.iter() {
+            if !duration.is_finite() || *duration < 0.0 {
+                return Err(format!("{} must not be negative", name));
+            }
+        }
+
+        if self.bandwidth_bytes_per_sec == Some(0) {
+            return Err("bandwidth_bytes_per_sec must be positive".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Network conditions in both directions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Link {
+    /// Applied to datagrams that we receive.
+    pub incoming: Config,
+
+    /// Applied to datagrams that we send.
+    pub outgoing: Config,
+}
+
+impl Link {
+    /// Returns one of the predefined links, which model typical connections
+    /// of players.
+    pub fn preset(name: &str) -> Option<Self> {
+        let symmetric = |config: Config| Link {
+            incoming: config.clone(),
+            outgoing: config,
+        };
+
+        match name {
+            "none" => Some(Link::default()),
+            "wifi" => Some(symmetric(Config {
+                lag_mean_ms: 15.0,
+                lag_std_dev_ms: 5.0,
+                loss: 0.005,
+                ..Config::default()
+            })),
+            "dsl" => Some(symmetric(Config {
+                lag_mean_ms: 30.0,
+                lag_std_dev_ms: 3.0,
+                loss: 0.001,
+                ..Config::default()
+            })),
+            "mobile" => Some(symmetric(Config {
+                lag_mean_ms: 60.0,
+                lag_std_dev_ms: 15.0,
+                loss: 0.01,
+                burst_loss: 0.5,
+                burst_enter_prob: 0.01,
+                burst_exit_prob: 0.2,
+                reorder_prob: 0.01,
+                reorder_lag_ms: 30.0,
+                duplicate_prob: 0.002,
+                bandwidth_bytes_per_sec: Some(250_000),
+                spike_prob: 0.001,
+                spike_duration_ms: 500.0,
+                spike_lag_ms: 300.0,
+            })),
+            "bad_mobile" => Some(symmetric(Config {
+                lag_mean_ms: 150.0,
+                lag_std_dev_ms: 40.0,
+                loss: 0.03,
+                burst_loss: 0.8,
+                burst_enter_prob: 0.02,
+                burst_exit_prob: 0.1,
+                reorder_prob: 0.05,
+                reorder_lag_ms: 60.0,
+                duplicate_prob: 0.01,
+                bandwidth_bytes_per_sec: Some(50_000),
+                spike_prob: 0.005,
+                spike_duration_ms: 1500.0,
+                spike_lag_ms: 800.0,
+            })),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.incoming
+            .validate()
+            .map_err(|err| format!("incoming: {}", err))?;
+        self.outgoing
+            .validate()
+            .map_err(|err| format!("outgoing: {}", err))
+    }
+}
+
+/// The links of all peers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profiles {
+    /// Used for peers that do not have their own link.
+    pub default: Link,
+
+    pub peers: HashMap<IpAddr, Link>,
+}
+
+impl Profiles {
+    pub fn link(&self, ip: IpAddr) -> &Link {
+        self.peers.get(&ip).unwrap_or(&self.default)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.default.validate()?;
+
+        for (ip, link) in self.peers.iter() {
+            link.validate().map_err(|err| format!("{}: {}", ip, err))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub type SharedProfiles = Arc<Mutex<Profiles>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// What we need to remember about each peer for simulating correlated
+/// conditions.
+struct PeerState {
+    last_time: Instant,
+
+    /// Whether we are in the bad state of the Gilbert-Elliott model.
+    burst: bool,
+    spike_end_time: Option<Instant>,
+
+    /// With a bandwidth cap, the time at which the link will have finished
+    /// transmitting the previous datagrams.
+    link_free_time: Instant,
+}
+
+impl PeerState {
+    fn new(now: Instant) -> Self {
+        Self {
+            last_time: now,
+            burst: false,
+            spike_end_time: None,
+            link_free_time: now,
+        }
+    }
 }
 
 pub struct FakeBadNet<S: Stream> {
-    config: Config,
-    lag_distribution: rand_distr::Normal<f32>,
+    profiles: SharedProfiles,
+    direction: Direction,
+    peer_states: HashMap<SocketAddr, PeerState>,
     orig_rx: S,
     new_tx: mpsc::UnboundedSender<S::Item>,
     delay_queue: DelayQueue<S::Item>,
@@ -40,16 +284,18 @@ pub struct FakeBadNet<S: Stream> {
 
 impl<S: Stream> FakeBadNet<S>
 where
-    S::Item: AddFakeLag,
+    S::Item: FakeBadNetMessage,
 {
-    pub fn new(config: Config, orig_rx: S, new_tx: mpsc::UnboundedSender<S::Item>) -> Self {
-        let lag_distribution =
-            rand_distr::Normal::new(config.lag_mean.as_secs_f32() * 1000.0, config.lag_std_dev)
-                .unwrap();
-
+    pub fn new(
+        profiles: SharedProfiles,
+        direction: Direction,
+        orig_rx: S,
+        new_tx: mpsc::UnboundedSender<S::Item>,
+    ) -> Self {
         Self {
-            config,
-            lag_distribution,
+            profiles,
+            direction,
+            peer_states: HashMap::new(),
             orig_rx,
             new_tx,
             delay_queue: DelayQueue::new(),
@@ -63,14 +309,31 @@ where
         loop {
             select! {
                 message = orig_rx.next().fuse() => {
-                    let mut rng = rand::thread_rng();
-
                     match message {
-                        Some(mut message) => {
-                            if rng.gen::<f32>() > self.config.loss {
-                                let lag = Duration::from_secs_f32(
-                                    self.lag_distribution.sample(&mut rng).max(0.0) / 1000.0,
-                                );
+                        Some(message) => {
+                            let peer = message.peer();
+                            let config = {
+                                let profiles = self.profiles.lock().unwrap();
+                                let link = profiles.link(peer.ip());
+                                match self.direction {
+                                    Direction::Incoming => link.incoming.clone(),
+                                    Direction::Outgoing => link.outgoing.clone(),
+                                }
+                            };
+
+                            let now = Instant::now();
+                            if self.peer_states.len() > MAX_NUM_PEER_STATES {
+                                self.peer_states.retain(|_, state| {
+                                    now.duration_since(state.last_time) < PEER_STATE_TIMEOUT
+                                });
+                            }
+                            let state = self
+                                .peer_states
+                                .entry(peer)
+                                .or_insert_with(|| PeerState::new(now));
+
+                            for lag in simulate(&config, state, now, message.size()) {
+                                let mut message = message.clone();
                                 message.add_fake_lag(lag);
                                 self.delay_queue.insert(message, lag);
                             }
@@ -110,3 +373,84 @@ where
         }
     }
 }
+
+/// Decides what happens to a datagram of the given size. Returns the lag of
+/// each copy of the datagram that will arrive, which is empty if the datagram
+/// is lost.
+fn simulate(config: &Config, state: &mut PeerState, now: Instant, size: usize) -> Vec<Duration> {
+    let mut rng = rand::thread_rng();
+    state.last_time = now;
+
+    // Burst loss
+    let switch_prob = if state.burst {
+        config.burst_exit_prob
+    } else {
+        config.burst_enter_prob
+    };
+    if rng.gen::<f32>() < switch_prob {
+        state.burst = !state.burst;
+    }
+
+    let loss = if state.burst {
+        config.burst_loss
+    } else {
+        config.loss
+    };
+    if rng.gen::<f32>() < loss {
+        return Vec::new();
+    }
+
+    // Bandwidth cap
+    let mut queue_delay = Duration::from_secs(0);
+    if let Some(bandwidth) = config.bandwidth_bytes_per_sec {
+        let start_time = state.link_free_time.max(now);
+        queue_delay = start_time.duration_since(now);
+
+        if queue_delay > MAX_BANDWIDTH_QUEUE_DELAY {
+            return Vec::new();
+        }
+
+        state.link_free_time = start_time + Duration::from_secs_f32(size as f32 / bandwidth as f32);
+    }
+
+    // Jitter spikes
+    if state
+        .spike_end_time
+        .map_or(true, |end_time| now >= end_time)
+        && rng.gen::<f32>() < config.spike_prob
+    {
+        state.spike_end_time =
+            Some(now + Duration::from_secs_f32(config.spike_duration_ms / 1000.0));
+    }
+    let spike_lag_ms = if state
+        .spike_end_time
+        .map_or(false, |end_time| now < end_time)
+    {
+        config.spike_lag_ms
+    } else {
+        0.0
+    };
+
+    let num_copies = if rng.gen::<f32>() < config.duplicate_prob {
+        2
+    } else {
+        1
+    };
+
+    (0..num_copies)
+        .map(|_| {
+            let mut lag_ms = rand_distr::Normal::new(config.lag_mean_ms, config.lag_std_dev_ms)
+                .map_or(config.lag_mean_ms, |distribution| {
+                    distribution.sample(&mut rng)
+                })
+                .max(0.0)
+                + spike_lag_ms;
+
+            if rng.gen::<f32>() < config.reorder_prob {
+                lag_ms += config.reorder_lag_ms;
+            }
+
+            queue_delay + Duration::from_secs_f32(lag_ms / 1000.0)
+        })
+        .collect()
+}
@@ -4,17 +4,20 @@ use std::{
 };
 
 use log::{debug, info};
-use rand::seq::SliceRandom;
 
-use comn::{game::RunContext, Entity, PlayerState};
+use comn::{game::RunContext, util::Timer, Entity, PlayerState};
 
-use crate::{bot::Bot, run};
+use crate::{bot::Bot, run, spawn};
 
 pub const FIRST_SPAWN_DURATION: comn::GameTime = 0.5;
 pub const RESPAWN_DURATION: comn::GameTime = 2.0;
-pub const KEEP_PREV_STATES_DURATION: comn::GameTime = 1.0;
+pub const KEEP_PREV_STATES_DURATION: comn::GameTime = 2.0;
 pub const MAX_RECONCILIATION_DURATION: comn::GameTime = 0.2;
 
+pub const KILL_CAM_DURATION: comn::GameTime = 2.0;
+pub const KILL_CAM_TICKS_PER_FRAME: usize = 3;
+pub const KILL_CAM_RADIUS: f32 = 600.0;
+
 pub struct PlayerMeta {
     pub last_input_num: Option<comn::TickNum>,
     pub bot: Option<Bot>,
@@ -27,11 +30,24 @@ pub struct Game {
     /// can send them to the players in this game in `Runner`.
     pub last_events: Vec<comn::Event>,
 
+    /// Kill cams produced in the last update, together with the player that
+    /// should receive them.
+    pub last_kill_cams: Vec<(comn::PlayerId, comn::KillCam)>,
+
+    /// Schedules the ticks of this game. Every game ticks on its own, so
+    /// that games do not need to wait for each other.
+    pub tick_timer: Timer,
+
     next_entity_id: comn::EntityId,
 
     players_meta: BTreeMap<comn::PlayerId, PlayerMeta>,
 
-    /// Previous states, used for reconciliation. Sorted by tick number.
+    /// Number of bots that have joined so far. Bots are numbered by this,
+    /// so that their names stay unique after bots are removed.
+    num_bots_joined: usize,
+
+    /// Previous states, used for reconciliation and for kill cams. Sorted by
+    /// tick number.
     prev_states: VecDeque<comn::Game>,
 }
 
@@ -46,12 +62,17 @@ impl Game {
             .max()
             .unwrap_or(comn::EntityId(0));
 
+        let tick_timer = Timer::time_per_second(state.settings.ticks_per_second as f32);
+
         Self {
             state,
+            tick_timer,
             next_entity_id,
             players_meta: BTreeMap::new(),
+            num_bots_joined: 0,
             prev_states: VecDeque::new(),
             last_events: Vec::new(),
+            last_kill_cams: Vec::new(),
         }
     }
 
@@ -60,10 +81,49 @@ impl Game {
         self.state.players.len() == self.settings().max_num_players
     }
 
+    pub fn num_bots(&self) -> usize {
+        self.players_meta
+            .values()
+            .filter(|player_meta| player_meta.bot.is_some())
+            .count()
+    }
+
     pub fn settings(&self) -> &comn::Settings {
         &self.state.settings
     }
 
+    /// Replaces the settings of a running game. Only settings that do not
+    /// affect the clients' predictions should be changed this way.
+    pub fn set_settings(&mut self, settings: Arc<comn::Settings>) {
+        self.state.settings = settings;
+    }
+
+    /// Removes one of the bots from the game. Returns false if there are no
+    /// bots.
+    pub fn remove_bot(&mut self) -> bool {
+        let bot_id = self
+            .players_meta
+            .iter()
+            .find(|(_, player_meta)| player_meta.bot.is_some())
+            .map(|(player_id, _)| *player_id);
+
+        if let Some(bot_id) = bot_id {
+            self.remove_player(bot_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Adds a bot, named by the given prefix and the number of bots that
+    /// have joined before.
+    pub fn join_bot(&mut self, name_prefix: &str, bot: Bot) -> comn::PlayerId {
+        let name = format!("{}{}", name_prefix, self.num_bots_joined);
+        self.num_bots_joined += 1;
+
+        self.join(name, Some(bot))
+    }
+
     pub fn join(&mut self, player_name: String, bot: Option<Bot>) -> comn::PlayerId {
         // Runner takes care of not trying to join a full game.
         assert!(!self.is_full());
@@ -105,6 +165,8 @@ impl Game {
         let current_time = self.state.game_time();
         let mut context = RunContext::default();
 
+        self.last_kill_cams.clear();
+
         run::run_tick(&mut self.state, &mut context).unwrap();
 
         // TODO: Sort player input by tick num
@@ -157,6 +219,8 @@ impl Game {
             }
         }
 
+        let mut respawning_players = Vec::new();
+
         for (player_id, player) in self.state.players.iter_mut() {
             match player.state.clone() {
                 PlayerState::Alive => (),
@@ -166,29 +230,29 @@ impl Game {
                     };
                 }
                 PlayerState::Respawning { respawn_time } if current_time >= respawn_time => {
-                    debug!("Respawning player {:?}", player_id);
-
-                    // TODO: Random
-                    let spawn_pos = self
-                        .state
-                        .settings
-                        .map
-                        .spawn_points
-                        .choose(&mut rand::thread_rng())
-                        .unwrap();
-
-                    context
-                        .new_entities
-                        .push(Entity::Player(comn::PlayerEntity::new(
-                            *player_id, *spawn_pos,
-                        )));
-
-                    player.state = PlayerState::Alive;
+                    respawning_players.push(*player_id);
                 }
                 PlayerState::Respawning { .. } => (),
             }
         }
 
+        let mut spawn_positions = Vec::new();
+
+        for player_id in respawning_players {
+            debug!("Respawning player {:?}", player_id);
+
+            let spawn_pos = spawn::choose_spawn_point(&self.state, &spawn_positions);
+            spawn_positions.push(spawn_pos);
+
+            context
+                .new_entities
+                .push(Entity::Player(comn::PlayerEntity::new(
+                    player_id, spawn_pos,
+                )));
+
+            self.state.players.get_mut(&player_id).unwrap().state = PlayerState::Alive;
+        }
+
         for (player_id, reason) in context.killed_players.clone() {
             self.kill_player(player_id, reason, &mut context);
         }
@@ -241,7 +305,20 @@ impl Game {
         }
     }
 
-    pub fn prepare_state_for_player(&self, observer_id: comn::PlayerId, state: &mut comn::Game) {
+    /// Prepares the state that is sent to an observer. Players only receive
+    /// the full entity state of their own `PlayerEntity`. Spectators (with an
+    /// `observer_id` of `None`) receive the full state.
+    pub fn prepare_state_for_player(
+        &self,
+        observer_id: Option<comn::PlayerId>,
+        state: &mut comn::Game,
+    ) {
+        let observer_id = if let Some(observer_id) = observer_id {
+            observer_id
+        } else {
+            return;
+        };
+
         for entity in state.entities.values_mut() {
             match entity {
                 comn::Entity::Player(player) if player.owner != observer_id => {
@@ -284,10 +361,81 @@ impl Game {
 
         player.state = PlayerState::Dead;
 
+        let killer = match &reason {
+            comn::DeathReason::ShotBy(Some(killer)) => Some(*killer),
+            comn::DeathReason::CaughtBy(killer) => Some(*killer),
+            _ => None,
+        };
+        if let Some(killer) = killer.filter(|killer| *killer != player_id) {
+            let kill_cam = self.make_kill_cam(killer);
+            self.last_kill_cams.push((player_id, kill_cam));
+        }
+
         if let Some((player_entity_id, player_entity)) = self.state.get_player_entity(player_id) {
             let player_entity = player_entity.clone();
             run::on_kill_player(&mut self.state, &player_entity, reason, context).unwrap();
             self.remove_entity(player_entity_id);
         }
     }
+
+    /// Builds a replay of the last few seconds around the `killer`, using our
+    /// previous states. Only entities that change over time are included,
+    /// since the client already knows about the static ones.
+    fn make_kill_cam(&self, killer: comn::PlayerId) -> comn::KillCam {
+        let start_time = self.state.game_time() - KILL_CAM_DURATION;
+
+        let mut states: Vec<&comn::Game> = self
+            .prev_states
+            .iter()
+            .rev()
+            .take_while(|prev_state| prev_state.game_time() >= start_time)
+            .step_by(KILL_CAM_TICKS_PER_FRAME)
+            .collect();
+        states.reverse();
+
+        let mut center = None;
+        let frames = states
+            .into_iter()
+            .map(|prev_state| {
+                let time = prev_state.game_time();
+
+                // Keep looking at the last known position if the killer is
+                // not alive in this frame.
+                if let Some((_, killer_entity)) = prev_state.get_player_entity(killer) {
+                    center = Some(killer_entity.pos);
+                }
+
+                let entities = prev_state
+                    .entities
+                    .iter()
+                    .filter(|(_, entity)| match entity {
+                        comn::Entity::Wall(_) | comn::Entity::FoodSpawn(_) => false,
+                        _ => true,
+                    })
+                    .filter(|(_, entity)| {
+                        center.map_or(false, |center| {
+                            (entity.pos(time) - center).norm() <= KILL_CAM_RADIUS
+                        })
+                    })
+                    .map(|(entity_id, entity)| {
+                        let entity = match entity {
+                            comn::Entity::Player(player) => {
+                                comn::Entity::PlayerView(player.to_view())
+                            }
+                            entity => entity.clone(),
+                        };
+                        (*entity_id, entity)
+                    })
+                    .collect();
+
+                (prev_state.tick_num, entities)
+            })
+            .collect();
+
+        comn::KillCam {
+            killer,
+            death_tick_num: self.state.tick_num.next(),
+            frames,
+        }
+    }
 }
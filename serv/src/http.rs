@@ -8,25 +8,57 @@ use tokio::{fs::File, io::AsyncReadExt, stream::StreamExt, sync::oneshot};
 use hyper::{
     header::HeaderValue, server::conn::AddrStream, Body, Method, Request, Response, StatusCode,
 };
+use openssl::memcmp;
 use webrtc_unreliable::SessionEndpoint;
 
-use crate::runner::{JoinMessage, JoinTx};
+use crate::{
+    admin, cluster, fake_bad_net, metrics,
+    runner::{Request as RunnerRequest, RequestTx},
+    shared::Shared,
+};
 
 static INTERNAL_SERVER_ERROR: &[u8] = b"Internal Server Error";
 static NOT_FOUND: &[u8] = b"Not Found";
 static BAD_REQUEST: &[u8] = b"Bad Request";
+static UNAUTHORIZED: &[u8] = b"Unauthorized";
+static FORBIDDEN: &[u8] = b"Forbidden";
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Config {
     pub listen_addr: SocketAddr,
     pub clnt_dir: PathBuf,
+
+    /// Token that needs to be given as `Authorization: Bearer <token>` for
+    /// the admin endpoints. If not set, the admin endpoints are disabled.
+    pub admin_token: Option<String>,
+
+    /// Workers get their joins, reconnects and new games from the front
+    /// door, which checks them against the accounts and bans, so they do not
+    /// accept them via HTTP.
+    pub accept_joins: bool,
+}
+
+/// Where the games run.
+#[derive(Clone)]
+pub enum Backend {
+    /// The games run in this process.
+    Local {
+        request_tx: RequestTx,
+        session_endpoint: SessionEndpoint,
+    },
+
+    /// The games run in worker processes, and we are their front door.
+    /// Administration is done directly on the workers, and so is monitoring
+    /// of the games.
+    Front(cluster::Front),
 }
 
 #[derive(Clone)]
 pub struct Server {
     config: Arc<Config>,
-    join_tx: JoinTx,
-    session_endpoint: SessionEndpoint,
+    backend: Backend,
+    shared: Shared,
+    fake_bad_net: Option<fake_bad_net::SharedProfiles>,
 }
 
 pub const STATIC_FILES: &[(&str, &str, &str)] = &[
@@ -45,11 +77,17 @@ pub const STATIC_FILES: &[(&str, &str, &str)] = &[
 ];
 
 impl Server {
-    pub fn new(config: Config, join_tx: JoinTx, session_endpoint: SessionEndpoint) -> Self {
+    pub fn new(
+        config: Config,
+        backend: Backend,
+        shared: Shared,
+        fake_bad_net: Option<fake_bad_net::SharedProfiles>,
+    ) -> Self {
         Self {
             config: Arc::new(config),
-            join_tx,
-            session_endpoint,
+            backend,
+            shared,
+            fake_bad_net,
         }
     }
 
@@ -62,16 +100,18 @@ impl Server {
 
         let make_service = hyper::service::make_service_fn(move |addr_stream: &AddrStream| {
             let config = self.config.clone();
-            let join_tx = self.join_tx.clone();
-            let session_endpoint = self.session_endpoint.clone();
+            let backend = self.backend.clone();
+            let shared = self.shared.clone();
+            let fake_bad_net = self.fake_bad_net.clone();
             let remote_addr = addr_stream.remote_addr();
 
             async move {
                 Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
                     service(
                         config.clone(),
-                        join_tx.clone(),
-                        session_endpoint.clone(),
+                        backend.clone(),
+                        shared.clone(),
+                        fake_bad_net.clone(),
                         remote_addr,
                         req,
                     )
@@ -89,14 +129,91 @@ impl Server {
 
 async fn service(
     config: Arc<Config>,
-    join_tx: JoinTx,
-    mut session_endpoint: SessionEndpoint,
+    backend: Backend,
+    shared: Shared,
+    fake_bad_net: Option<fake_bad_net::SharedProfiles>,
     remote_addr: SocketAddr,
     req: Request<Body>,
 ) -> Result<Response<Body>, hyper::Error> {
     debug!("{}: {} {}", remote_addr, req.method(), req.uri().path());
 
+    if shared.bans.contains(remote_addr.ip()) {
+        debug!("Rejecting request from banned address {}", remote_addr);
+        return Ok(forbidden());
+    }
+
     match (req.method(), req.uri().path()) {
+        // Operate the server
+        (_, path) if path.starts_with("/admin/") => match (config.admin_token.as_ref(), backend) {
+            (Some(admin_token), Backend::Local { request_tx, .. }) => {
+                if is_authorized(&req, admin_token) {
+                    admin_service(request_tx, fake_bad_net, req).await
+                } else {
+                    warn!("Unauthorized admin request from {}", remote_addr);
+                    Ok(unauthorized())
+                }
+            }
+            _ => Ok(not_found()),
+        },
+
+        // List the public games
+        (&Method::GET, "/games") => match backend {
+            Backend::Local { request_tx, .. } => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                runner_request(&request_tx, RunnerRequest::ListGames(reply_tx), reply_rx).await
+            }
+            Backend::Front(front) => Ok(front_response(front.list_games().await)),
+        },
+
+        // Expose metrics for monitoring
+        (&Method::GET, "/metrics") => {
+            let request_tx = match backend {
+                Backend::Local { request_tx, .. } => request_tx,
+                Backend::Front(front) => {
+                    return Ok(Response::builder()
+                        .header("Content-Type", metrics::CONTENT_TYPE)
+                        .body(front.metrics().into())
+                        .unwrap());
+                }
+            };
+
+            let (reply_tx, reply_rx) = oneshot::channel();
+
+            if request_tx.send(RunnerRequest::Metrics(reply_tx)).is_err() {
+                warn!("request_tx closed, ignoring request");
+                return Ok(internal_server_error());
+            }
+
+            if let Ok(text) = reply_rx.await {
+                Ok(Response::builder()
+                    .header("Content-Type", metrics::CONTENT_TYPE)
+                    .body(text.into())
+                    .unwrap())
+            } else {
+                warn!("reply_rx closed, ignoring request");
+                Ok(internal_server_error())
+            }
+        }
+
+        // Show the leaderboards
+        (&Method::GET, "/leaderboards/daily")
+        | (&Method::GET, "/leaderboards/weekly")
+        | (&Method::GET, "/leaderboards/all_time") => {
+            let period = match req.uri().path() {
+                "/leaderboards/daily" => comn::LeaderboardPeriod::Daily,
+                "/leaderboards/weekly" => comn::LeaderboardPeriod::Weekly,
+                _ => comn::LeaderboardPeriod::AllTime,
+            };
+
+            if let Some(leaderboard) = shared.leaderboard {
+                Ok(json_response(
+                    &leaderboard.lock().unwrap().leaderboard(period),
+                ))
+            } else {
+                Ok(not_found())
+            }
+        }
+
         // Serve static files
         (&Method::GET, file) => {
             let item = STATIC_FILES.iter().find(|(key, _, _)| *key == file);
@@ -112,6 +229,15 @@ async fn service(
         (&Method::POST, "/connect_webrtc") => {
             debug!("WebRTC session request from {}", remote_addr);
 
+            let mut session_endpoint = if let Backend::Local {
+                session_endpoint, ..
+            } = backend
+            {
+                session_endpoint
+            } else {
+                return Ok(not_found());
+            };
+
             match session_endpoint.http_session_request(req.into_body()).await {
                 Ok(mut resp) => {
                     resp.headers_mut().insert(
@@ -126,37 +252,108 @@ async fn service(
 
         // Join a game
         (&Method::POST, "/join") => {
-            // FIXME: Does this allow attackers to OOM the server by sending an infinite request?
-            let body = req
-                .into_body()
-                .map(|chunk| chunk.map(|chunk| chunk.as_ref().to_vec()))
-                .try_concat()
-                .await?;
-
-            let join_request = match serde_json::from_slice(body.as_slice()) {
-                Ok(x) => x,
-                Err(_) => return Ok(bad_request()),
-            };
+            if !config.accept_joins {
+                return Ok(not_found());
+            }
 
-            let (reply_tx, reply_rx) = oneshot::channel();
-            let join_message = JoinMessage {
-                request: join_request,
-                reply_tx,
+            let join_request: comn::JoinRequest = match read_json_body(req).await? {
+                Some(x) => x,
+                None => return Ok(bad_request()),
             };
 
-            if join_tx.send(join_message).is_err() {
-                warn!("join_tx closed, ignoring join request");
-                return Ok(internal_server_error());
+            match backend {
+                Backend::Local { request_tx, .. } => {
+                    let identity = shared.identify(&join_request);
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    runner_request(
+                        &request_tx,
+                        RunnerRequest::Join(join_request, identity, reply_tx),
+                        reply_rx,
+                    )
+                    .await
+                }
+                Backend::Front(front) => Ok(front_response(front.join(join_request).await)),
             }
+        }
 
-            if let Ok(join_reply) = reply_rx.await {
-                Ok(Response::builder()
-                    .header("Content-Type", "application/json")
-                    .body(serde_json::to_string(&join_reply).unwrap().into())
-                    .unwrap())
+        // Register or log in to an account
+        (&Method::POST, "/register") | (&Method::POST, "/login") => {
+            let is_register = req.uri().path() == "/register";
+            let auth_request: comn::AuthRequest = match read_json_body(req).await? {
+                Some(x) => x,
+                None => return Ok(bad_request()),
+            };
+
+            let reply = if let Some(accounts) = shared.accounts {
+                // Password hashing is slow, so we do not want to block the
+                // HTTP server.
+                tokio::task::spawn_blocking(move || {
+                    if is_register {
+                        accounts.register(&auth_request)
+                    } else {
+                        accounts.login(&auth_request)
+                    }
+                })
+                .await
+                .unwrap_or(Err(comn::AuthError::Disabled))
             } else {
-                warn!("reply_rx closed, ignoring join request");
-                Ok(internal_server_error())
+                Err(comn::AuthError::Disabled)
+            };
+
+            Ok(json_response(&reply))
+        }
+
+        // Resume playing after losing the connection
+        (&Method::POST, "/reconnect") => {
+            if !config.accept_joins {
+                return Ok(not_found());
+            }
+
+            let reconnect_request = match read_json_body(req).await? {
+                Some(x) => x,
+                None => return Ok(bad_request()),
+            };
+
+            match backend {
+                Backend::Local { request_tx, .. } => {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    runner_request(
+                        &request_tx,
+                        RunnerRequest::Reconnect(reconnect_request, reply_tx),
+                        reply_rx,
+                    )
+                    .await
+                }
+                Backend::Front(front) => {
+                    Ok(front_response(front.reconnect(reconnect_request).await))
+                }
+            }
+        }
+
+        // Create a new game
+        (&Method::POST, "/create_game") => {
+            if !config.accept_joins {
+                return Ok(not_found());
+            }
+
+            let create_request = match read_json_body(req).await? {
+                Some(x) => x,
+                None => return Ok(bad_request()),
+            };
+
+            match backend {
+                Backend::Local { request_tx, .. } => {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    runner_request(
+                        &request_tx,
+                        RunnerRequest::CreateGame(create_request, reply_tx),
+                        reply_rx,
+                    )
+                    .await
+                }
+                Backend::Front(front) => {
+                    Ok(front_response(front.create_game(create_request).await))
+                }
             }
         }
 
@@ -165,6 +362,150 @@ async fn service(
     }
 }
 
+async fn admin_service(
+    request_tx: RequestTx,
+    fake_bad_net: Option<fake_bad_net::SharedProfiles>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    info!("Admin request: {} {}", req.method(), req.uri().path());
+
+    let command = match (req.method(), req.uri().path()) {
+        // List games and players
+        (&Method::GET, "/admin/games") => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            return runner_request(
+                &request_tx,
+                RunnerRequest::AdminListGames(reply_tx),
+                reply_rx,
+            )
+            .await;
+        }
+
+        // Show or change the simulated network conditions
+        (&Method::GET, "/admin/fake_bad_net") => {
+            return Ok(if let Some(profiles) = fake_bad_net {
+                json_response(&*profiles.lock().unwrap())
+            } else {
+                not_found()
+            });
+        }
+        (&Method::POST, "/admin/fake_bad_net") => {
+            let request = match read_json_body(req).await? {
+                Some(x) => x,
+                None => return Ok(bad_request()),
+            };
+
+            let reply = if let Some(profiles) = fake_bad_net {
+                admin::update_fake_bad_net(&mut profiles.lock().unwrap(), request)
+            } else {
+                Err(admin::Error::FakeBadNetDisabled)
+            };
+
+            return Ok(json_response(&reply));
+        }
+
+        (&Method::POST, "/admin/kick") => read_json_body(req)
+            .await?
+            .map(|request: admin::PlayerRequest| admin::Command::Kick(request.token)),
+        (&Method::POST, "/admin/ban") => read_json_body(req).await?.map(admin::Command::Ban),
+        (&Method::POST, "/admin/unban") => read_json_body(req)
+            .await?
+            .map(|request: admin::UnbanRequest| admin::Command::Unban(request.ip)),
+        (&Method::POST, "/admin/end_game") => read_json_body(req)
+            .await?
+            .map(|request: admin::GameRequest| admin::Command::EndGame(request.game_id)),
+        (&Method::POST, "/admin/add_bot") => read_json_body(req)
+            .await?
+            .map(|request: admin::GameRequest| admin::Command::AddBot(request.game_id)),
+        (&Method::POST, "/admin/remove_bot") => read_json_body(req)
+            .await?
+            .map(|request: admin::GameRequest| admin::Command::RemoveBot(request.game_id)),
+        (&Method::POST, "/admin/settings") => read_json_body(req)
+            .await?
+            .map(admin::Command::UpdateSettings),
+        (&Method::POST, "/admin/new_game_settings") => read_json_body(req)
+            .await?
+            .map(admin::Command::UpdateNewGameSettings),
+
+        _ => return Ok(not_found()),
+    };
+
+    if let Some(command) = command {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        runner_request(
+            &request_tx,
+            RunnerRequest::Admin(command, reply_tx),
+            reply_rx,
+        )
+        .await
+    } else {
+        Ok(bad_request())
+    }
+}
+
+/// Checks the bearer token of an admin request in constant time.
+fn is_authorized(req: &Request<Body>, admin_token: &str) -> bool {
+    let expected = format!("Bearer {}", admin_token);
+
+    req.headers()
+        .get(hyper::header::AUTHORIZATION)
+        .map_or(false, |value| {
+            value.len() == expected.len() && memcmp::eq(value.as_bytes(), expected.as_bytes())
+        })
+}
+
+/// Reads the body of a request as JSON. Returns `None` if the body cannot be
+/// parsed.
+async fn read_json_body<T: serde::de::DeserializeOwned>(
+    req: Request<Body>,
+) -> Result<Option<T>, hyper::Error> {
+    // FIXME: Does this allow attackers to OOM the server by sending an infinite request?
+    let body = req
+        .into_body()
+        .map(|chunk| chunk.map(|chunk| chunk.as_ref().to_vec()))
+        .try_concat()
+        .await?;
+
+    Ok(serde_json::from_slice(body.as_slice()).ok())
+}
+
+/// Passes a request on to the runner, and responds with its reply as JSON.
+async fn runner_request<T: serde::Serialize>(
+    request_tx: &RequestTx,
+    request: RunnerRequest,
+    reply_rx: oneshot::Receiver<T>,
+) -> Result<Response<Body>, hyper::Error> {
+    if request_tx.send(request).is_err() {
+        warn!("request_tx closed, ignoring request");
+        return Ok(internal_server_error());
+    }
+
+    if let Ok(reply) = reply_rx.await {
+        Ok(json_response(&reply))
+    } else {
+        warn!("reply_rx closed, ignoring request");
+        Ok(internal_server_error())
+    }
+}
+
+/// Responds with the answer of a worker as JSON.
+fn front_response<T: serde::Serialize>(reply: Result<T, cluster::Error>) -> Response<Body> {
+    match reply {
+        Ok(reply) => json_response(&reply),
+        Err(err) => {
+            warn!("Request to worker failed: {:?}", err);
+            internal_server_error()
+        }
+    }
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(value).unwrap().into())
+        .unwrap()
+}
+
 /// Serve a file.
 ///
 /// TODO: We'll need to cache the files eventually, but for now reloading
@@ -211,6 +552,20 @@ fn bad_request() -> Response<Body> {
         .unwrap()
 }
 
+fn unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(UNAUTHORIZED.into())
+        .unwrap()
+}
+
+fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(FORBIDDEN.into())
+        .unwrap()
+}
+
 fn not_found() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
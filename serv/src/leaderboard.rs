@@ -0,0 +1,251 @@
+//! Persistent leaderboards, aggregated from the results of finished matches.
+//!
+//! A match lasts from joining a game until leaving it. Only players that are
+//! logged in with an account take part, so that entries can not be taken over
+//! by guests using the same name. Results are appended to a file with one JSON
+//! object per line, so that we never need to rewrite the whole file.
+//!
+//! In memory, we only keep the totals of every account for each of the last
+//! days and for all time. Daily leaderboards cover the current day and weekly
+//! leaderboards the last seven days, in UTC.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_LEADERBOARD_ENTRIES: usize = 50;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const DAYS_PER_WEEK: u64 = 7;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchResult {
+    pub account: String,
+
+    /// Unix time at which the match ended.
+    pub end_time: u64,
+
+    /// Food that the player had when leaving the game.
+    pub food: u32,
+    pub catches: u32,
+    pub deaths: u32,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    JSON(serde_json::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JSON(err)
+    }
+}
+
+/// Summed up results of each account.
+type Totals = HashMap<String, comn::LeaderboardEntry>;
+
+pub struct Store {
+    path: PathBuf,
+
+    /// Totals of the last days, by the number of the day since the Unix
+    /// epoch.
+    days: BTreeMap<u64, Totals>,
+    all_time: Totals,
+
+    /// Results that have not been appended to the file yet.
+    unsaved: Vec<MatchResult>,
+
+    /// Leaderboards that have been ranked since the last change, together
+    /// with the day for which they are valid.
+    cache: Vec<(u64, comn::Leaderboard)>,
+}
+
+impl Store {
+    /// Loads all the results from `path`. The file is created when saving
+    /// for the first time. Lines that cannot be parsed are skipped.
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        let mut store = Self {
+            path,
+            days: BTreeMap::new(),
+            all_time: Totals::new(),
+            unsaved: Vec::new(),
+            cache: Vec::new(),
+        };
+        let mut num_results = 0;
+
+        match File::open(&store.path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    match serde_json::from_str(&line?) {
+                        Ok(result) => {
+                            store.add(&result);
+                            num_results += 1;
+                        }
+                        Err(err) => warn!("Skipping invalid match result: {:?}", err),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        store.forget_old_days(today());
+
+        info!("Loaded {} match results from {:?}", num_results, store.path);
+
+        Ok(store)
+    }
+
+    pub fn record(&mut self, result: MatchResult) {
+        self.add(&result);
+        self.unsaved.push(result);
+        self.cache.clear();
+    }
+
+    /// Ranks the players in the given period by the food they collected, and
+    /// then by their catches.
+    pub fn leaderboard(&mut self, period: comn::LeaderboardPeriod) -> comn::Leaderboard {
+        let today = today();
+
+        if let Some((_, leaderboard)) = self
+            .cache
+            .iter()
+            .find(|(day, leaderboard)| *day == today && leaderboard.period == period)
+        {
+            return leaderboard.clone();
+        }
+
+        self.forget_old_days(today);
+
+        let mut entries: Vec<comn::LeaderboardEntry> = match period {
+            comn::LeaderboardPeriod::Daily => self
+                .days
+                .get(&today)
+                .map_or_else(Vec::new, |totals| totals.values().cloned().collect()),
+            comn::LeaderboardPeriod::Weekly => {
+                let mut week = Totals::new();
+                for totals in self.days.values() {
+                    for entry in totals.values() {
+                        add_entry(&mut week, entry);
+                    }
+                }
+                week.into_iter().map(|(_, entry)| entry).collect()
+            }
+            comn::LeaderboardPeriod::AllTime => self.all_time.values().cloned().collect(),
+        };
+
+        entries.sort_by(|a, b| {
+            b.food
+                .cmp(&a.food)
+                .then(b.catches.cmp(&a.catches))
+                .then(a.name.cmp(&b.name))
+        });
+        entries.truncate(MAX_LEADERBOARD_ENTRIES);
+
+        let leaderboard = comn::Leaderboard { period, entries };
+
+        self.cache.retain(|(day, _)| *day == today);
+        self.cache.push((today, leaderboard.clone()));
+
+        leaderboard
+    }
+
+    fn add(&mut self, result: &MatchResult) {
+        let entry = comn::LeaderboardEntry {
+            name: result.account.clone(),
+            matches: 1,
+            food: result.food as u64,
+            catches: result.catches as u64,
+            deaths: result.deaths as u64,
+        };
+
+        add_entry(&mut self.all_time, &entry);
+
+        let day = result.end_time / SECS_PER_DAY;
+        if day + DAYS_PER_WEEK > today() {
+            add_entry(self.days.entry(day).or_insert_with(Totals::new), &entry);
+        }
+    }
+
+    fn forget_old_days(&mut self, today: u64) {
+        let first_day = (today + 1).saturating_sub(DAYS_PER_WEEK);
+        self.days = self.days.split_off(&first_day);
+    }
+}
+
+/// Appends the results that have been recorded since the last call to the
+/// file. The store is locked only while taking the results, not while
+/// writing them.
+pub fn save(store: &Mutex<Store>) {
+    let (path, results) = {
+        let mut store = store.lock().unwrap();
+        (store.path.clone(), mem::take(&mut store.unsaved))
+    };
+
+    if results.is_empty() {
+        return;
+    }
+
+    if let Err(err) = append(&path, &results) {
+        warn!("Failed to write match results: {:?}", err);
+
+        // Try again next time.
+        let mut store = store.lock().unwrap();
+        let newer = mem::replace(&mut store.unsaved, results);
+        store.unsaved.extend(newer);
+    }
+}
+
+fn append(path: &Path, results: &[MatchResult]) -> Result<(), Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut lines = String::new();
+
+    for result in results {
+        lines.push_str(&serde_json::to_string(result)?);
+        lines.push('\n');
+    }
+
+    file.write_all(lines.as_bytes())?;
+
+    Ok(())
+}
+
+fn add_entry(totals: &mut Totals, entry: &comn::LeaderboardEntry) {
+    let total = totals
+        .entry(entry.name.clone())
+        .or_insert_with(|| comn::LeaderboardEntry {
+            name: entry.name.clone(),
+            ..comn::LeaderboardEntry::default()
+        });
+    total.matches += entry.matches;
+    total.food += entry.food;
+    total.catches += entry.catches;
+    total.deaths += entry.deaths;
+}
+
+fn today() -> u64 {
+    unix_time() / SECS_PER_DAY
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
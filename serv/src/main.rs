@@ -3,29 +3,51 @@
 // Needed for pareen stuff
 #![type_length_limit = "600000000"]
 
+mod admin;
+mod auth;
 mod bot;
+mod chat;
+mod cluster;
+mod config;
 mod fake_bad_net;
 mod game;
 mod http;
+mod leaderboard;
+mod metrics;
+mod profile;
+mod rating;
 mod run;
 mod runner;
+mod shared;
+mod spawn;
 mod tiled;
 mod webrtc;
+mod workers;
 
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use clap::Arg;
 use log::{info, warn};
 
 use tokio::sync::oneshot;
 
-use fake_bad_net::FakeBadNet;
+use comn::game::mapgen;
+
+use shared::Shared;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub http_server: http::Config,
     pub webrtc_server: webrtc::Config,
     pub runner: runner::Config,
+
+    /// Simulated lag and loss for incoming and outgoing datagrams.
+    pub fake_bad_net: Option<fake_bad_net::Profiles>,
 }
 
 #[tokio::main]
@@ -33,116 +55,338 @@ async fn main() {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
 
     let matches = clap::App::new("serv")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .help("Path to a TOML configuration file. Other arguments override its settings"),
+        )
         .arg(
             Arg::with_name("http_address")
                 .long("http_address")
                 .takes_value(true)
-                .required(true)
                 .help("listen on the specified address/port for HTTP"),
         )
         .arg(
             Arg::with_name("webrtc_address")
                 .long("webrtc_address")
                 .takes_value(true)
-                .required(true)
                 .help("listen on the specified address/port for WebRTC"),
         )
         .arg(
             Arg::with_name("clnt_dir")
                 .long("clnt_dir")
                 .takes_value(true)
-                .default_value("clnt/static")
                 .help("Directory containing static files to be served over HTTP"),
         )
+        .arg(
+            Arg::with_name("max_num_games")
+                .long("max_num_games")
+                .takes_value(true)
+                .help("Maximal number of games that run at the same time"),
+        )
+        .arg(
+            Arg::with_name("max_num_players")
+                .long("max_num_players")
+                .takes_value(true)
+                .help("Maximal number of players per game"),
+        )
+        .arg(
+            Arg::with_name("ticks_per_second")
+                .long("ticks_per_second")
+                .takes_value(true)
+                .help("Number of game updates per second"),
+        )
+        .arg(
+            Arg::with_name("num_workers")
+                .long("num_workers")
+                .takes_value(true)
+                .help("Number of threads that run game updates in parallel"),
+        )
         .arg(
             Arg::with_name("map")
                 .long("map")
                 .takes_value(true)
-                .default_value("maps/test.tmx")
                 .help("Path to TMX map file"),
         )
+        .arg(
+            Arg::with_name("gen_map")
+                .long("gen_map")
+                .help("Generate a new map for every game instead of loading a TMX map file"),
+        )
+        .arg(
+            Arg::with_name("gen_seed")
+                .long("gen_seed")
+                .takes_value(true)
+                .help("Seed of the first generated map"),
+        )
+        .arg(
+            Arg::with_name("gen_size")
+                .long("gen_size")
+                .takes_value(true)
+                .help("Size of generated maps in cells, given as <width>x<height>"),
+        )
+        .arg(
+            Arg::with_name("gen_wall_density")
+                .long("gen_wall_density")
+                .takes_value(true)
+                .help("Fraction of cells covered by walls in generated maps"),
+        )
+        .arg(
+            Arg::with_name("gen_spawn_points")
+                .long("gen_spawn_points")
+                .takes_value(true)
+                .help("Number of player spawn points in generated maps"),
+        )
+        .arg(
+            Arg::with_name("gen_turrets")
+                .long("gen_turrets")
+                .takes_value(true)
+                .help("Number of turrets in generated maps"),
+        )
+        .arg(
+            Arg::with_name("gen_food_spawns")
+                .long("gen_food_spawns")
+                .takes_value(true)
+                .help("Number of food spawns in generated maps"),
+        )
+        .arg(
+            Arg::with_name("gen_danger_guys")
+                .long("gen_danger_guys")
+                .takes_value(true)
+                .help("Number of danger guys in generated maps"),
+        )
+        .arg(
+            Arg::with_name("chat_filter")
+                .long("chat_filter")
+                .takes_value(true)
+                .help("Path to a file with words to censor in chat, one per line"),
+        )
+        .arg(
+            Arg::with_name("profiles")
+                .long("profiles")
+                .takes_value(true)
+                .help("Enable player accounts, storing their profiles in the given JSON file"),
+        )
+        .arg(
+            Arg::with_name("leaderboard")
+                .long("leaderboard")
+                .takes_value(true)
+                .help("Enable leaderboards, storing match results in the given file"),
+        )
+        .arg(
+            Arg::with_name("bans")
+                .long("bans")
+                .takes_value(true)
+                .help("Keep the bans made through the admin API in the given JSON file"),
+        )
+        .arg(
+            Arg::with_name("auth_secret_file")
+                .long("auth_secret_file")
+                .takes_value(true)
+                .help("File with the key for session tokens, so that logins survive restarts"),
+        )
+        .arg(
+            Arg::with_name("fake_bad_net")
+                .long("fake_bad_net")
+                .takes_value(true)
+                .help("Simulate bad network conditions: none, wifi, dsl, mobile or bad_mobile"),
+        )
+        .arg(
+            Arg::with_name("admin_token_file")
+                .long("admin_token_file")
+                .takes_value(true)
+                .help("Enable the admin API with the bearer token in the given file"),
+        )
+        .arg(
+            Arg::with_name("role")
+                .long("role")
+                .takes_value(true)
+                .help("Run as standalone server, as front door, or as game worker"),
+        )
+        .arg(
+            Arg::with_name("cluster_address")
+                .long("cluster_address")
+                .takes_value(true)
+                .help("Address at which the front door accepts workers (default: 127.0.0.1:7000)"),
+        )
+        .arg(
+            Arg::with_name("cluster_secret_file")
+                .long("cluster_secret_file")
+                .takes_value(true)
+                .help("Path to a file with the secret that workers need to join the cluster"),
+        )
+        .arg(
+            Arg::with_name("session_url")
+                .long("session_url")
+                .takes_value(true)
+                .help("URL at which clients reach the WebRTC session endpoint of a worker"),
+        )
         .get_matches();
 
-    let game_map = tiled::load_map(matches.value_of("map").unwrap()).unwrap();
+    let mut file_config = if let Some(path) = matches.value_of("config") {
+        config::File::load(Path::new(path)).expect("could not load config file")
+    } else {
+        config::File::default()
+    };
+    file_config
+        .apply_args(&matches)
+        .expect("invalid command line arguments");
+    file_config.validate().expect("invalid configuration");
+
+    let http_server_config = http::Config {
+        listen_addr: file_config.http.address.unwrap(),
+        clnt_dir: file_config.http.clnt_dir.clone(),
+        admin_token: file_config
+            .http
+            .admin_token_file
+            .as_ref()
+            .map(|path| config::read_secret(path).expect("could not read admin token")),
+        accept_joins: file_config.cluster.role != config::Role::Worker,
+    };
+
+    let accounts = file_config.accounts.profiles.as_ref().map(|path| {
+        let auth = auth::Auth::new(auth::Config {
+            secret: file_config.accounts.auth_secret_file.as_ref().map(|path| {
+                config::read_secret(path)
+                    .expect("could not read auth secret")
+                    .into_bytes()
+            }),
+            session_duration: Duration::from_secs(file_config.accounts.session_duration_secs),
+        })
+        .expect("could not initialize authentication");
+        let profiles = profile::Store::load(path.clone()).expect("could not load profiles");
+
+        Arc::new(auth::Accounts::new(auth, profiles))
+    });
+
+    let leaderboard = file_config.accounts.leaderboard.as_ref().map(|path| {
+        let store = leaderboard::Store::open(path.clone()).expect("could not open leaderboard");
+
+        Arc::new(Mutex::new(store))
+    });
+
+    let bans = if let Some(path) = file_config.accounts.bans.as_ref() {
+        admin::Bans::load(path.clone()).expect("could not load bans")
+    } else {
+        admin::Bans::default()
+    };
+
+    let shared = Shared {
+        accounts,
+        leaderboard,
+        bans: Arc::new(bans),
+    };
+    tokio::spawn(shared.clone().save_periodically());
+
+    let cluster_secret = file_config.cluster.secret_file.as_ref().map(|path| {
+        config::read_secret(path)
+            .expect("could not read cluster secret")
+            .into_bytes()
+    });
+
+    if file_config.cluster.role == config::Role::Front {
+        run_front(
+            http_server_config,
+            file_config.cluster.address,
+            cluster_secret.unwrap(),
+            shared,
+        )
+        .await;
+        return;
+    }
+
+    let map_gen = file_config.runner.map_gen.clone();
+    let game_map = if let Some(map_gen) = map_gen.as_ref() {
+        mapgen::generate_map(map_gen).expect("could not generate map")
+    } else {
+        tiled::load_map(&file_config.runner.map).unwrap()
+    };
     let runner_config = runner::Config {
-        max_num_games: 32,
+        max_num_games: file_config.runner.max_num_games,
         game_settings: comn::Settings {
-            max_num_players: 64,
-            ticks_per_second: 30,
+            max_num_players: file_config.runner.max_num_players,
+            ticks_per_second: file_config.runner.ticks_per_second,
             map: game_map,
         },
-    };
-    let http_server_config = http::Config {
-        listen_addr: matches
-            .value_of("http_address")
-            .unwrap()
-            .parse()
-            .expect("could not parse HTTP address/port"),
-        clnt_dir: PathBuf::from(matches.value_of("clnt_dir").unwrap()),
+        map_gen,
+        chat_filter: file_config
+            .runner
+            .chat_filter
+            .as_ref()
+            .map(|path| chat::Filter::load(path).expect("could not load chat filter")),
+        num_workers: file_config.runner.num_workers,
+        record_join_failures: file_config.cluster.role != config::Role::Worker,
     };
     let webrtc_server_config = webrtc::Config {
-        listen_addr: matches
-            .value_of("webrtc_address")
-            .unwrap()
-            .parse()
-            .expect("could not parse WebRTC address/port"),
+        listen_addr: file_config.webrtc.address.unwrap(),
     };
+
     let config = Config {
         http_server: http_server_config,
         webrtc_server: webrtc_server_config,
         runner: runner_config,
+        fake_bad_net: file_config.fake_bad_net.clone(),
     };
 
     let (recv_message_tx, recv_message_rx) = webrtc::recv_message_channel();
     let (send_message_tx, send_message_rx) = webrtc::send_message_channel();
 
-    let fake_bad_net_config = Some((
-        fake_bad_net::Config {
-            lag_mean: Duration::from_millis(125),
-            lag_std_dev: 0.0,
-            loss: 0.00,
-        },
-        fake_bad_net::Config {
-            lag_mean: Duration::from_millis(125),
-            lag_std_dev: 0.0,
-            loss: 0.00,
-        },
-    ));
-    let fake_bad_net_config = None;
-
-    let (recv_message_rx, send_message_rx) = if let Some((config_in, config_out)) =
-        fake_bad_net_config
-    {
-        let (lag_recv_message_tx, lag_recv_message_rx) = webrtc::recv_message_channel();
-        let (lag_send_message_tx, lag_send_message_rx) = webrtc::send_message_channel();
-        let fake_bad_net_recv = FakeBadNet::new(config_in, recv_message_rx, lag_recv_message_tx);
-        let fake_bad_net_send = FakeBadNet::new(config_out, send_message_rx, lag_send_message_tx);
-        tokio::spawn(fake_bad_net_recv.run());
-        tokio::spawn(fake_bad_net_send.run());
-
-        (lag_recv_message_rx, lag_send_message_rx)
-    } else {
-        (recv_message_rx, send_message_rx)
-    };
+    let fake_bad_net_profiles = config
+        .fake_bad_net
+        .map(|profiles| Arc::new(Mutex::new(profiles)));
 
     let (shutdown_http_tx, shutdown_http_rx) = oneshot::channel();
     let (shutdown_runner_tx, shutdown_runner_rx) = oneshot::channel();
     let (shutdown_webrtc_tx, shutdown_webrtc_rx) = oneshot::channel();
 
-    let webrtc_server = webrtc::Server::new(config.webrtc_server, recv_message_tx, send_message_rx)
-        .await
-        .expect("Error starting WebRTC server");
+    let webrtc_server = webrtc::Server::new(
+        config.webrtc_server,
+        recv_message_tx,
+        send_message_rx,
+        fake_bad_net_profiles.clone(),
+    )
+    .await
+    .expect("Error starting WebRTC server");
     let session_endpoint = webrtc_server.session_endpoint();
 
+    let (update_tx, update_rx) = shared::update_channel();
     let runner = runner::Runner::new(
         config.runner,
+        update_tx,
+        shared.bans.clone(),
         recv_message_rx,
         send_message_tx,
         shutdown_runner_rx,
     );
-    let join_tx = runner.join_tx();
+    let request_tx = runner.request_tx();
+
+    let apply_updates_task = if file_config.cluster.role == config::Role::Worker {
+        let worker_config = cluster::WorkerConfig {
+            front_addr: file_config.cluster.address,
+            session_url: file_config.cluster.session_url.clone().unwrap(),
+            secret: cluster_secret.unwrap(),
+        };
+        tokio::spawn(cluster::run_worker(
+            worker_config,
+            request_tx.clone(),
+            shared.bans.clone(),
+            update_rx,
+        ));
+        None
+    } else {
+        Some(tokio::spawn(shared.clone().apply_updates(update_rx)))
+    };
 
-    let http_server = http::Server::new(config.http_server, join_tx, session_endpoint);
+    let http_server = http::Server::new(
+        config.http_server,
+        http::Backend::Local {
+            request_tx,
+            session_endpoint,
+        },
+        shared.clone(),
+        fake_bad_net_profiles,
+    );
 
     let runner_thread = tokio::task::spawn_blocking(move || runner.run());
     let http_server_task =
@@ -151,20 +395,7 @@ async fn main() {
         tokio::task::spawn(async move { webrtc_server.serve(shutdown_webrtc_rx).await });
 
     // Shutdown handling...
-    ctrlc::set_handler_mut({
-        let mut shutdown_http_tx = Some(shutdown_http_tx);
-
-        move || {
-            info!("Received Ctrl-C signal, shutting down tasks");
-
-            if let Some(shutdown_http_tx) = shutdown_http_tx.take() {
-                shutdown_http_tx
-                    .send(())
-                    .expect("Failed to send shutdown to HTTP server");
-            }
-        }
-    })
-    .expect("Error setting Ctrl-C handler");
+    set_shutdown_handler(shutdown_http_tx);
 
     if let Err(err) = http_server_task.await.expect("Failed to join HTTP server") {
         warn!("HTTP server died: {:?}", err);
@@ -177,6 +408,15 @@ async fn main() {
 
     runner_thread.await.expect("Failed to join runner thread");
 
+    // The runner is gone, so the last updates are about to be applied.
+    if let Some(apply_updates_task) = apply_updates_task {
+        apply_updates_task
+            .await
+            .expect("Failed to join update task");
+    }
+
+    shared.save();
+
     info!("Runner thread terminated, shutting down WebRTC server");
     if shutdown_webrtc_tx.send(()).is_err() {
         info!("WebRTC server has already shut down");
@@ -186,3 +426,58 @@ async fn main() {
         .await
         .expect("Failed to join WebRTC server");
 }
+
+/// Runs the front door, which serves HTTP and passes joins on to the game
+/// workers.
+async fn run_front(
+    http_server_config: http::Config,
+    cluster_addr: SocketAddr,
+    cluster_secret: Vec<u8>,
+    shared: Shared,
+) {
+    let front = cluster::Front::new(cluster_secret, shared.clone());
+
+    tokio::spawn({
+        let front = front.clone();
+
+        async move {
+            if let Err(err) = front.listen(cluster_addr).await {
+                warn!("Stopped accepting workers: {:?}", err);
+            }
+        }
+    });
+
+    let (shutdown_http_tx, shutdown_http_rx) = oneshot::channel();
+
+    let http_server = http::Server::new(
+        http_server_config,
+        http::Backend::Front(front),
+        shared.clone(),
+        None,
+    );
+
+    set_shutdown_handler(shutdown_http_tx);
+
+    if let Err(err) = http_server.serve(shutdown_http_rx).await {
+        warn!("HTTP server died: {:?}", err);
+    }
+
+    shared.save();
+}
+
+fn set_shutdown_handler(shutdown_http_tx: oneshot::Sender<()>) {
+    ctrlc::set_handler_mut({
+        let mut shutdown_http_tx = Some(shutdown_http_tx);
+
+        move || {
+            info!("Received Ctrl-C signal, shutting down tasks");
+
+            if let Some(shutdown_http_tx) = shutdown_http_tx.take() {
+                shutdown_http_tx
+                    .send(())
+                    .expect("Failed to send shutdown to HTTP server");
+            }
+        }
+    })
+    .expect("Error setting Ctrl-C handler");
+}
@@ -0,0 +1,75 @@
+//! Rendering of server metrics in the Prometheus text exposition format.
+//!
+//! See https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::fmt::Write;
+
+use comn::util::stats;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const PREFIX: &str = "catchub_";
+
+#[derive(Default)]
+pub struct Writer {
+    out: String,
+}
+
+impl Writer {
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        writeln!(self.out, "{}{} {}", PREFIX, name, value).unwrap();
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        writeln!(self.out, "{}{} {}", PREFIX, name, value).unwrap();
+    }
+
+    pub fn labeled_counter(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        values: impl Iterator<Item = (String, u64)>,
+    ) {
+        self.header(name, help, "counter");
+        for (label_value, value) in values {
+            writeln!(
+                self.out,
+                "{}{}{{{}=\"{}\"}} {}",
+                PREFIX,
+                name,
+                label,
+                escape_label_value(&label_value),
+                value
+            )
+            .unwrap();
+        }
+    }
+
+    /// Writes the mean and the maximum of the recent samples of a variable.
+    /// Variables without recent samples are skipped.
+    pub fn var(&mut self, name: &str, help: &str, var: &stats::Var) {
+        if let (Some(mean), Some(max)) = (var.mean(), var.max()) {
+            self.gauge(&format!("{}_mean", name), help, mean as f64);
+            self.gauge(&format!("{}_max", name), help, max as f64);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn header(&mut self, name: &str, help: &str, metric_type: &str) {
+        writeln!(self.out, "# HELP {}{} {}", PREFIX, name, help).unwrap();
+        writeln!(self.out, "# TYPE {}{} {}", PREFIX, name, metric_type).unwrap();
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,156 @@
+//! Persistent profiles of registered players, stored in a JSON file.
+
+use std::{collections::BTreeMap, io, path::PathBuf};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::PasswordHash, rating};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub games_played: u64,
+    pub catches: u64,
+    pub food_eaten: u64,
+
+    /// Skill rating used for matchmaking. Profiles that were created before
+    /// we had ratings start with the initial rating.
+    #[serde(default = "rating::initial_rating")]
+    pub rating: f32,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            games_played: 0,
+            catches: 0,
+            food_eaten: 0,
+            rating: rating::INITIAL_RATING,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    /// The name as it was registered. Lookups ignore case, so that nobody
+    /// can register a name that only differs in case.
+    pub name: String,
+    pub password_salt: String,
+    pub password_hash: String,
+    pub stats: Stats,
+}
+
+impl Profile {
+    pub fn new(name: String, password_hash: PasswordHash) -> Self {
+        Self {
+            name,
+            password_salt: password_hash.salt,
+            password_hash: password_hash.hash,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn password_hash(&self) -> PasswordHash {
+        PasswordHash {
+            salt: self.password_salt.clone(),
+            hash: self.password_hash.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    JSON(serde_json::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JSON(err)
+    }
+}
+
+pub struct Store {
+    path: PathBuf,
+    profiles: BTreeMap<String, Profile>,
+    dirty: bool,
+}
+
+impl Store {
+    /// Loads the profiles from `path`. If the file does not exist yet, we
+    /// start with no profiles.
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let profiles = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        info!("Loaded {} profiles from {:?}", profiles.len(), path);
+
+        Ok(Self {
+            path,
+            profiles,
+            dirty: false,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(&Self::key(name))
+    }
+
+    pub fn stats(&self, name: &str) -> Option<&Stats> {
+        self.get(name).map(|profile| &profile.stats)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.profiles.contains_key(&Self::key(name))
+    }
+
+    /// Adds a new profile. Returns false if the name is taken.
+    pub fn insert(&mut self, profile: Profile) -> bool {
+        let key = Self::key(&profile.name);
+
+        if self.profiles.contains_key(&key) {
+            false
+        } else {
+            self.profiles.insert(key, profile);
+            self.dirty = true;
+            true
+        }
+    }
+
+    pub fn update_stats(&mut self, name: &str, f: impl FnOnce(&mut Stats)) {
+        if let Some(profile) = self.profiles.get_mut(&Self::key(name)) {
+            f(&mut profile.stats);
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the profiles to disk if anything has changed. We first write
+    /// to a temporary file, so that we do not lose the profiles if we crash
+    /// while writing.
+    pub fn save_if_dirty(&mut self) -> Result<(), Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let data = serde_json::to_vec_pretty(&self.profiles)?;
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn key(name: &str) -> String {
+        name.to_lowercase()
+    }
+}
@@ -0,0 +1,31 @@
+//! Elo ratings of players, used for matchmaking.
+//!
+//! There are no rounds with a winner in our game, so we treat every catch
+//! as a duel that the catcher won against the player who was caught.
+
+/// Rating of players that have not played yet.
+pub const INITIAL_RATING: f32 = 1500.0;
+
+/// Maximal change of a rating in a single duel.
+const K_FACTOR: f32 = 24.0;
+
+/// Difference in ratings at which the expected score of the better player
+/// is ten times that of the worse player.
+const SCALE: f32 = 400.0;
+
+pub fn initial_rating() -> f32 {
+    INITIAL_RATING
+}
+
+/// Expected score of a player with rating `a` in a duel against a player
+/// with rating `b`, between zero (certain loss) and one (certain win).
+pub fn expected_score(a: f32, b: f32) -> f32 {
+    1.0 / (1.0 + 10.0f32.powf((b - a) / SCALE))
+}
+
+/// Returns the new ratings of the winner and the loser of a duel.
+pub fn update(winner: f32, loser: f32) -> (f32, f32) {
+    let delta = K_FACTOR * (1.0 - expected_score(winner, loser));
+
+    (winner + delta, loser - delta)
+}
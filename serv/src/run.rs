@@ -105,6 +105,13 @@ fn update_entity(
             update_turret(state, entity_id, turret, context);
             true
         }
+        Entity::Player(player) if player.spawn_protection > 0.0 => {
+            // This counts down with the game time rather than with the
+            // inputs of the player, so that players whose inputs stop
+            // arriving do not stay protected.
+            player.spawn_protection = (player.spawn_protection - dt).max(0.0);
+            true
+        }
         Entity::FoodSpawn(spawn) if !spawn.has_food => {
             if let Some(respawn_time) = spawn.respawn_time {
                 if state.game_time() >= respawn_time {
@@ -148,13 +155,18 @@ fn update_turret(state: &Game, entity_id: EntityId, turret: &mut Turret, context
         .iter()
         .filter(|(other_id, _)| **other_id != entity_id)
         .filter_map(|(other_id, other_entity)| {
-            other_entity.player().ok().map(|player| {
-                (
-                    other_id,
-                    other_entity,
-                    (turret.pos - player.pos).norm_squared(),
-                )
-            })
+            // Players that have just spawned are left alone.
+            other_entity
+                .player()
+                .ok()
+                .filter(|player| !player.is_protected())
+                .map(|player| {
+                    (
+                        other_id,
+                        other_entity,
+                        (turret.pos - player.pos).norm_squared(),
+                    )
+                })
         })
         .filter(|(other_id, other_entity, dist)| {
             let ray = Ray {
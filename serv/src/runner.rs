@@ -1,12 +1,12 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
-use log::{debug, info, warn};
-use rand::seq::IteratorRandom;
+use log::{debug, error, info, warn};
+use rand::Rng;
 use tokio::sync::{
     mpsc::{self, error::TryRecvError},
     oneshot,
@@ -14,34 +14,120 @@ use tokio::sync::{
 use uuid::Uuid;
 
 use comn::{
-    util::{diff::Diffable, stats, GameTimeEstimation, PingEstimation, Timer},
+    game::{
+        mapgen,
+        sent_ticks::{Ack, SentTicks},
+    },
+    util::{reliable, stats, GameTimeEstimation, PingEstimation, Timer},
     GameTime,
 };
 
 use crate::{
+    admin,
     bot::Bot,
+    chat, cluster, config,
     game::Game,
+    leaderboard, metrics, rating,
+    shared::{Identity, StatsChange, Update, UpdateTx},
     webrtc::{self, RecvMessageRx, SendMessageTx},
+    workers,
 };
 
 const PLAYER_INPUT_BUFFER: f32 = 1.5;
 const MAX_PLAYER_INPUT_AGE: f32 = 1.0;
-const MAX_DIFF_TICKS: u32 = 50;
+const KILL_CAM_FRAMES_PER_MESSAGE: usize = 2;
+const MAX_SHUTDOWN_DURATION: Duration = Duration::from_secs(1);
+const INVITE_CODE_LEN: usize = 6;
+const INVITE_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const PRIVATE_GAME_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// When there is nothing to do, we wait for the workers for at most this
+/// long, so that incoming messages are still handled promptly.
+const MAX_IDLE_WAIT: Duration = Duration::from_millis(1);
+
+/// Players with poor connections get ticks less often, down to every
+/// `MAX_SEND_INTERVAL`-th tick.
+const MAX_SEND_INTERVAL: u32 = 3;
+
+/// Ping loss above which we send ticks less often, and below which we try
+/// sending them more often again.
+const SEND_INTERVAL_POOR_LOSS: f32 = 0.1;
+const SEND_INTERVAL_GOOD_LOSS: f32 = 0.02;
+
+/// Fraction of the tick bytes that the player acknowledges. If we send more
+/// than the connection of the player can carry, this drops.
+const SEND_INTERVAL_POOR_ACKED: f32 = 0.8;
+const SEND_INTERVAL_GOOD_ACKED: f32 = 0.95;
+
+/// We send less often soon after the connection gets worse, but wait longer
+/// before sending more often again, so that we do not keep switching.
+const SEND_INTERVAL_INCREASE_DELAY: Duration = Duration::from_secs(2);
+const SEND_INTERVAL_DECREASE_DELAY: Duration = Duration::from_secs(10);
+
+const TICK_BYTES_SAMPLE_DURATION: Duration = Duration::from_secs(4);
+
+/// Encoding ticks a second time is not free, so we only measure the size of
+/// every `PACKED_TICK_SIZE_SAMPLE_PERIOD`-th tick message with the bit-packed
+/// encoding. The measured encoding is never sent, ticks are always sent as
+/// MessagePack.
+const PACKED_TICK_SIZE_SAMPLE_PERIOD: u64 = 50;
+
+/// Difference in rating that we accept between a joining player and the
+/// average rating of a game right away.
+const MATCHMAKING_INITIAL_TOLERANCE: f32 = 100.0;
+
+/// The longer a player waits in the join queue, the more we widen the
+/// tolerance.
+const MATCHMAKING_TOLERANCE_PER_SEC: f32 = 50.0;
+
+/// How long a player waits for a matching game before we create a new one.
+const MATCHMAKING_NEW_GAME_DELAY: Duration = Duration::from_secs(3);
+
+/// After waiting this long, a player joins any game that is not full.
+const MATCHMAKING_MAX_WAIT: Duration = Duration::from_secs(15);
 
 #[derive(Debug, Clone)]
 struct Player {
     /// Each player is in exactly one running game.
     game_id: comn::GameId,
 
-    /// The player id is unique only in the game.
-    player_id: comn::PlayerId,
+    /// The player id is unique only in the game. Spectators do not have a
+    /// player id, since they are not part of the game state.
+    player_id: Option<comn::PlayerId>,
+
+    /// Name given in the join request. For players, this is the same as the
+    /// name in the game state.
+    name: String,
+
+    /// The account that the player logged in with, if any.
+    account: Option<String>,
+
+    /// Skill rating. For registered players, this is kept in their profile.
+    /// Guests start with the initial rating every time they join.
+    rating: f32,
+
+    /// Statistics of the player since joining the game, which are recorded
+    /// in the leaderboard when the player leaves.
+    match_stats: MatchStats,
 
     /// WebRTC peer address.
     peer: Option<SocketAddr>,
 
+    /// Set when we lost the connection to the player. We keep the player in
+    /// the game until `RECONNECT_GRACE_PERIOD` has passed, so that they can
+    /// resume with the same token.
+    disconnect_time: Option<Instant>,
+
     /// Ping estimation.
     ping: PingEstimation,
 
+    /// Reliable messages exchanged with this player.
+    reliable: reliable::Endpoint<comn::ReliableServerMessage, comn::ReliableClientMessage>,
+
+    /// Limits how often the player may chat.
+    chat_rate_limit: chat::RateLimit,
+
     /// The last input that the player executed, if any. We remember this so
     /// that we can re-execute the input if we do not receive the packet for
     /// some tick.
@@ -59,27 +145,110 @@ struct Player {
     /// stream `GameTime`. This is used for buffering `inputs`.
     recv_input_time: GameTimeEstimation,
 
-    /// Last tick that the player has acknowledged receiving from us. Used as
-    /// the basis for delta encoding.
-    last_ack_tick: Option<comn::TickNum>,
+    /// States and events that we have sent to the player, for delta encoding
+    /// and for resending events until they are acknowledged.
+    sent_ticks: SentTicks,
+
+    /// We send ticks to the player only every `send_interval` ticks,
+    /// depending on the quality of their connection.
+    send_interval: u32,
+    send_interval_time: Instant,
+    ticks_since_send: u32,
 
-    /// Last states that we have sent to the player, ordered by the tick number
-    /// ascending.
-    last_sent: VecDeque<(Vec<comn::Event>, comn::Game)>,
+    /// Sizes of the tick messages that we sent recently, and whether the
+    /// player acknowledged them.
+    sent_tick_sizes: VecDeque<SentTickSize>,
+}
+
+#[derive(Debug, Clone)]
+struct SentTickSize {
+    tick_num: comn::TickNum,
+    send_time: Instant,
+    size: usize,
+    acked: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct MatchStats {
+    catches: u32,
+    deaths: u32,
 }
 
 impl Player {
-    fn new(input_period: GameTime, game_id: comn::GameId, player_id: comn::PlayerId) -> Self {
+    fn new(
+        input_period: GameTime,
+        game_id: comn::GameId,
+        player_id: Option<comn::PlayerId>,
+        name: String,
+    ) -> Self {
         Self {
             game_id,
             player_id,
+            name,
+            account: None,
+            rating: rating::INITIAL_RATING,
+            match_stats: MatchStats::default(),
             peer: None,
+            disconnect_time: None,
             ping: PingEstimation::default(),
+            reliable: reliable::Endpoint::default(),
+            chat_rate_limit: chat::RateLimit::default(),
             last_input: None,
             inputs: Vec::new(),
             recv_input_time: GameTimeEstimation::new(input_period),
-            last_ack_tick: None,
-            last_sent: VecDeque::new(),
+            sent_ticks: SentTicks::default(),
+            send_interval: 1,
+            send_interval_time: Instant::now(),
+            ticks_since_send: 0,
+            sent_tick_sizes: VecDeque::new(),
+        }
+    }
+
+    fn record_sent_tick_size(&mut self, tick_num: comn::TickNum, now: Instant, size: usize) {
+        self.sent_tick_sizes.push_back(SentTickSize {
+            tick_num,
+            send_time: now,
+            size,
+            acked: false,
+        });
+
+        while self.sent_tick_sizes.front().map_or(false, |sent| {
+            now.duration_since(sent.send_time) > TICK_BYTES_SAMPLE_DURATION
+        }) {
+            self.sent_tick_sizes.pop_front();
+        }
+    }
+
+    fn record_acked_tick_size(&mut self, tick_num: comn::TickNum) {
+        if let Some(sent) = self
+            .sent_tick_sizes
+            .iter_mut()
+            .find(|sent| sent.tick_num == tick_num)
+        {
+            sent.acked = true;
+        }
+    }
+
+    /// Returns the fraction of recently sent tick bytes that the player
+    /// acknowledged. Ticks that we sent within the last round trip are left
+    /// out, since their acknowledgments cannot have arrived yet.
+    fn acked_tick_fraction(&self, now: Instant) -> Option<f32> {
+        let rtt = self.ping.estimate();
+        let (sent, acked) = self
+            .sent_tick_sizes
+            .iter()
+            .filter(|sent| now.duration_since(sent.send_time) >= rtt)
+            .fold((0, 0), |(sent_sum, acked_sum), sent| {
+                (
+                    sent_sum + sent.size,
+                    acked_sum + if sent.acked { sent.size } else { 0 },
+                )
+            });
+
+        if sent > 0 {
+            Some(acked as f32 / sent as f32)
+        } else {
+            None
         }
     }
 }
@@ -88,6 +257,20 @@ impl Player {
 pub struct Config {
     pub max_num_games: usize,
     pub game_settings: comn::Settings,
+
+    /// If set, every new game gets a freshly generated map, replacing the map
+    /// in `game_settings`.
+    pub map_gen: Option<mapgen::Config>,
+
+    /// If set, chat messages are censored with this filter.
+    pub chat_filter: Option<chat::Filter>,
+
+    /// Number of threads that run game ticks in parallel.
+    pub num_workers: usize,
+
+    /// Workers of a cluster are asked about joins for games that they may
+    /// not have, so the front door counts the failed joins instead.
+    pub record_join_failures: bool,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -98,25 +281,121 @@ pub struct Stats {
     pub input_delay: stats::Var,
     pub last_sent_len: stats::Var,
     pub tick_message_size: stats::Var,
+
+    /// Size that a sample of tick messages would have with the bit-packed
+    /// encoding of `comn::game::wire`, for comparing against MessagePack.
+    pub packed_tick_message_size: stats::Var,
+    pub num_tick_messages: u64,
+    pub tick_duration: stats::Var,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+
+    /// Number of ticks that players acknowledged with the wrong checksum.
+    pub desyncs: u64,
+
+    /// Number of failed join requests by `JoinError`.
+    pub join_failures: BTreeMap<String, u64>,
+
+    /// Number of times that updating a game took longer than the tick period.
+    pub tick_overruns: HashMap<comn::GameId, u64>,
 }
 
-pub struct JoinMessage {
-    pub request: comn::JoinRequest,
-    pub reply_tx: oneshot::Sender<comn::JoinReply>,
+/// Requests that reach the runner via HTTP.
+pub enum Request {
+    /// A join request, together with the identity of the player, which is
+    /// determined by whoever keeps the accounts.
+    Join(
+        comn::JoinRequest,
+        Result<Identity, comn::JoinError>,
+        oneshot::Sender<comn::JoinReply>,
+    ),
+    ListGames(oneshot::Sender<Vec<comn::GameInfo>>),
+    CreateGame(
+        comn::CreateGameRequest,
+        oneshot::Sender<comn::CreateGameReply>,
+    ),
+    Reconnect(comn::ReconnectRequest, oneshot::Sender<comn::JoinReply>),
+    Metrics(oneshot::Sender<String>),
+    AdminListGames(oneshot::Sender<Vec<admin::GameInfo>>),
+    Admin(admin::Command, oneshot::Sender<admin::Reply>),
+    Load(oneshot::Sender<cluster::Load>),
 }
 
 // TODO: Check if we should make channels bounded
-pub type JoinTx = mpsc::UnboundedSender<JoinMessage>;
-pub type JoinRx = mpsc::UnboundedReceiver<JoinMessage>;
+pub type RequestTx = mpsc::UnboundedSender<Request>;
+pub type RequestRx = mpsc::UnboundedReceiver<Request>;
+
+/// A player that wants to join just any game, waiting for a game whose
+/// players have a similar rating.
+struct QueuedJoin {
+    name: String,
+    account: Option<String>,
+    rating: f32,
+    enqueue_time: Instant,
+    reply_tx: oneshot::Sender<comn::JoinReply>,
+}
+
+/// What the game listings show about a game. Games whose tick is running
+/// are not in `Runner::games`, so we keep their summary from before the tick.
+#[derive(Debug, Clone)]
+struct GameSummary {
+    map_name: String,
+    num_players: usize,
+    num_bots: usize,
+    max_num_players: usize,
+    game_time: comn::GameTime,
+    catcher_name: Option<String>,
+}
+
+impl GameSummary {
+    fn new(game: &Game) -> Self {
+        Self {
+            map_name: game.settings().map.name.clone(),
+            num_players: game.state.players.len(),
+            num_bots: game.num_bots(),
+            max_num_players: game.settings().max_num_players,
+            game_time: game.state.game_time(),
+            catcher_name: game
+                .state
+                .catcher
+                .and_then(|catcher| game.state.players.get(&catcher))
+                .map(|player| player.name.clone()),
+        }
+    }
+}
+
+/// A game whose tick is being run by the workers.
+struct RunningGame {
+    /// Messages from the players of the game need the game, so they are kept
+    /// here until the tick is done.
+    messages: Vec<(SocketAddr, Instant, comn::SignedClientMessage)>,
+
+    summary: GameSummary,
+}
+
+struct PrivateGame {
+    invite_code: String,
+
+    /// The last time at which someone was in the game. Private games are
+    /// removed once they have been empty for a while.
+    last_occupied_time: Instant,
+}
 
 pub struct Runner {
     config: Config,
 
+    /// Profiles and the leaderboard are not kept by the runner. We report
+    /// the changes to them here.
+    update_tx: UpdateTx,
+    bans: Arc<admin::Bans>,
+
     games: HashMap<comn::GameId, Game>,
     players: HashMap<comn::PlayerToken, Player>,
+    private_games: HashMap<comn::GameId, PrivateGame>,
+    join_queue: Vec<QueuedJoin>,
 
-    join_tx: JoinTx,
-    join_rx: JoinRx,
+    request_tx: RequestTx,
+    request_rx: RequestRx,
 
     recv_message_rx: RecvMessageRx,
     send_message_tx: SendMessageTx,
@@ -124,7 +403,14 @@ pub struct Runner {
     shutdown_rx: oneshot::Receiver<()>,
     shutdown: bool,
 
-    tick_timer: Timer,
+    workers: workers::Pool,
+
+    /// Games whose tick is being run by the workers right now. They are
+    /// not in `games` until the tick is done.
+    running_games: HashMap<comn::GameId, RunningGame>,
+
+    /// Number of games created so far. Used for deriving map seeds.
+    num_games_created: u64,
 
     stats: Stats,
     print_stats_timer: Timer,
@@ -133,31 +419,38 @@ pub struct Runner {
 impl Runner {
     pub fn new(
         config: Config,
+        update_tx: UpdateTx,
+        bans: Arc<admin::Bans>,
         recv_message_rx: RecvMessageRx,
         send_message_tx: SendMessageTx,
         shutdown_rx: oneshot::Receiver<()>,
     ) -> Self {
-        let (join_tx, join_rx) = mpsc::unbounded_channel();
-        let tick_timer =
-            comn::util::Timer::time_per_second(config.game_settings.ticks_per_second as f32);
+        let (request_tx, request_rx) = mpsc::unbounded_channel();
+        let workers = workers::Pool::new(config.num_workers);
         Runner {
             config,
+            update_tx,
+            bans,
             games: HashMap::new(),
             players: HashMap::new(),
-            join_tx,
-            join_rx,
+            private_games: HashMap::new(),
+            join_queue: Vec::new(),
+            request_tx,
+            request_rx,
             recv_message_rx,
             send_message_tx,
             shutdown_rx,
             shutdown: false,
-            tick_timer,
+            workers,
+            running_games: HashMap::new(),
+            num_games_created: 0,
             stats: Stats::default(),
             print_stats_timer: Timer::with_duration(Duration::from_secs(5)),
         }
     }
 
-    pub fn join_tx(&self) -> mpsc::UnboundedSender<JoinMessage> {
-        self.join_tx.clone()
+    pub fn request_tx(&self) -> RequestTx {
+        self.request_tx.clone()
     }
 
     pub fn run(mut self) {
@@ -174,33 +467,61 @@ impl Runner {
                 debug!("input delay:          {}", self.stats.input_delay);
                 debug!("last sent len:        {}", self.stats.last_sent_len);
                 debug!("tick message size:    {}", self.stats.tick_message_size);
+                debug!(
+                    "packed tick size:     {}",
+                    self.stats.packed_tick_message_size
+                );
+                debug!("tick duration:        {}", self.stats.tick_duration);
             }
 
-            std::thread::sleep(std::time::Duration::from_millis(1));
+            if let Some(reply) = self.workers.recv_timeout(MAX_IDLE_WAIT) {
+                self.finish_tick(reply);
+            }
         }
     }
 
     fn run_update(&mut self) {
+        // Pick up the ticks that the workers have finished.
+        while let Some(reply) = self.workers.try_recv() {
+            self.finish_tick(reply);
+        }
+
         // Handle external shutdown requests.
         if self.shutdown_rx.try_recv().is_ok() {
+            self.finish_running_ticks();
+
             info!("Sending disconnect messages to clients...");
 
-            // Send unreliable disconnect messages a few times to increase
-            // chance of arrival.
-            let peers: Vec<_> = self
-                .players
-                .values()
-                .filter_map(|player| player.peer)
-                .collect();
+            let player_tokens: Vec<_> = self.players.keys().copied().collect();
+            for player_token in player_tokens {
+                self.send_reliable(player_token, comn::ReliableServerMessage::Disconnect);
+            }
 
-            for _ in 0..3 {
-                for &peer in &peers {
-                    self.send(peer, comn::ServerMessage::Disconnect);
+            // Keep resending until every client has acknowledged, but do not
+            // wait forever for clients that are gone.
+            let start_time = Instant::now();
+            while Instant::now().duration_since(start_time) < MAX_SHUTDOWN_DURATION
+                && self
+                    .players
+                    .values()
+                    .any(|player| player.peer.is_some() && !player.reliable.is_idle())
+            {
+                if !self.handle_incoming_messages() {
+                    break;
                 }
+                self.send_reliable_packets();
+
+                std::thread::sleep(Duration::from_millis(1));
             }
 
             // Wait a little bit to allow WebRTC to send packages.
-            std::thread::sleep(Duration::from_secs(1));
+            std::thread::sleep(Duration::from_millis(100));
+
+            // Everyone's match ends here.
+            let player_tokens: Vec<_> = self.players.keys().copied().collect();
+            for player_token in player_tokens {
+                self.remove_player(player_token);
+            }
 
             info!("Finished shutting down");
 
@@ -208,55 +529,53 @@ impl Runner {
             return;
         }
 
-        // Handle incoming join requests via HTTP channel.
-        while let Some(join_message) = match self.join_rx.try_recv() {
-            Ok(join_message) => Some(join_message),
+        // Handle incoming requests via HTTP channel.
+        while let Some(request) = match self.request_rx.try_recv() {
+            Ok(request) => Some(request),
             Err(TryRecvError::Empty) => None,
             Err(TryRecvError::Closed) => {
-                info!("join_rx closed, terminating thread");
+                info!("request_rx closed, terminating thread");
                 return;
             }
         } {
-            info!("Processing {:?}", join_message.request);
-
-            let reply = self.try_join_game(join_message.request);
-
-            if join_message.reply_tx.send(reply).is_err() {
+            // Requests that refer to a game wait for its tick to be done.
+            // The others are answered right away.
+            if !self.handle_request(request) {
                 info!("reply_tx closed, terminating thread");
                 return;
             }
         }
 
+        self.process_join_queue();
+
         // Handle incoming messages via WebRTC channel.
-        while let Some(message_in) = match self.recv_message_rx.try_recv() {
-            Ok(message_in) => Some(message_in),
-            Err(TryRecvError::Empty) => None,
-            Err(TryRecvError::Closed) => {
-                info!("recv_message_rx closed, terminating thread");
-                return;
-            }
-        } {
-            let signed_message = comn::SignedClientMessage::deserialize(&message_in.data);
+        if !self.handle_incoming_messages() {
+            return;
+        }
 
-            match signed_message {
-                Some(signed_message) => {
-                    self.handle_message(message_in.peer, message_in.recv_time, signed_message);
-                }
-                None => {
-                    warn!(
-                        "Failed to deserialize message from {:?}, ignoring",
-                        message_in.peer,
-                    );
-                }
+        // Disconnect players. Players that time out keep their slot for a
+        // while, in case they reconnect.
+        let now = Instant::now();
+        for (player_token, player) in self.players.iter_mut() {
+            if player.disconnect_time.is_none() && player.ping.is_timeout(now) {
+                info!(
+                    "Lost connection to player with token {:?}, keeping slot",
+                    player_token
+                );
+                player.disconnect_time = Some(now);
+                player.peer = None;
+                player.last_input = None;
+                player.inputs.clear();
             }
         }
 
-        // Disconnect players.
         let remove_player_tokens: Vec<comn::PlayerToken> = self
             .players
             .iter()
             .filter_map(|(player_token, player)| {
-                if player.ping.is_timeout(Instant::now()) {
+                if player.disconnect_time.map_or(false, |disconnect_time| {
+                    now.duration_since(disconnect_time) > RECONNECT_GRACE_PERIOD
+                }) {
                     Some(*player_token)
                 } else {
                     None
@@ -265,14 +584,13 @@ impl Runner {
             .collect();
 
         for player_token in remove_player_tokens {
-            let player = self.players.remove(&player_token).unwrap();
             info!("Player with token {:?} timed out", player_token);
-            self.games
-                .get_mut(&player.game_id)
-                .unwrap()
-                .remove_player(player.player_id);
+            self.finish_running_tick_of_player(player_token);
+            self.remove_player(player_token);
         }
 
+        self.remove_idle_private_games();
+
         // Ping players.
         let mut messages = Vec::new();
 
@@ -288,10 +606,120 @@ impl Runner {
             self.send(peer, message);
         }
 
-        // Run the game.
-        while self.tick_timer.tick() {
-            self.run_tick();
+        // (Re)send reliable messages.
+        self.send_reliable_packets();
+
+        // Start the ticks of the games that are due. Games that have fallen
+        // behind are due again as soon as their tick is done, until they
+        // have caught up.
+        let game_ids: Vec<comn::GameId> = self
+            .games
+            .iter_mut()
+            .filter_map(|(game_id, game)| {
+                if game.tick_timer.tick() {
+                    Some(*game_id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        for game_id in game_ids {
+            self.start_tick(game_id);
+        }
+    }
+
+    /// Waits until the workers have finished all the ticks that they are
+    /// running, so that every game is in `games` again.
+    fn finish_running_ticks(&mut self) {
+        while !self.running_games.is_empty() {
+            if let Some(reply) = self.workers.recv_timeout(MAX_IDLE_WAIT) {
+                self.finish_tick(reply);
+            }
+        }
+    }
+
+    /// Waits until the workers have finished the tick of the given game, if
+    /// it is running. Afterwards, the game is in `games` again, unless its
+    /// tick panicked.
+    fn finish_running_tick(&mut self, game_id: comn::GameId) {
+        while self.running_games.contains_key(&game_id) {
+            if let Some(reply) = self.workers.recv_timeout(MAX_IDLE_WAIT) {
+                self.finish_tick(reply);
+            }
+        }
+    }
+
+    /// Waits until the workers have finished the tick of the game that the
+    /// given player is in.
+    fn finish_running_tick_of_player(&mut self, player_token: comn::PlayerToken) {
+        if let Some(game_id) = self.players.get(&player_token).map(|player| player.game_id) {
+            self.finish_running_tick(game_id);
+        }
+    }
+
+    /// Summaries of all games, including the ones whose tick is running.
+    fn game_summaries(&self) -> Vec<(comn::GameId, GameSummary)> {
+        self.games
+            .iter()
+            .map(|(game_id, game)| (*game_id, GameSummary::new(game)))
+            .chain(
+                self.running_games
+                    .iter()
+                    .map(|(game_id, running_game)| (*game_id, running_game.summary.clone())),
+            )
+            .collect()
+    }
+
+    /// Number of games, including the ones whose tick is running.
+    fn num_games(&self) -> usize {
+        self.games.len() + self.running_games.len()
+    }
+
+    /// Handles the messages we received via the WebRTC channel. Returns false
+    /// if the channel has been closed.
+    fn handle_incoming_messages(&mut self) -> bool {
+        while let Some(message_in) = match self.recv_message_rx.try_recv() {
+            Ok(message_in) => Some(message_in),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Closed) => {
+                info!("recv_message_rx closed, terminating thread");
+                return false;
+            }
+        } {
+            self.stats.bytes_in += message_in.data.len() as u64;
+
+            let signed_message = comn::SignedClientMessage::deserialize(&message_in.data);
+
+            match signed_message {
+                Some(signed_message) => {
+                    let running_games = &mut self.running_games;
+                    let running_messages = self
+                        .players
+                        .get(&signed_message.0)
+                        .and_then(|player| running_games.get_mut(&player.game_id))
+                        .map(|running_game| &mut running_game.messages);
+
+                    if let Some(running_messages) = running_messages {
+                        running_messages.push((
+                            message_in.peer,
+                            message_in.recv_time,
+                            signed_message,
+                        ));
+                    } else {
+                        self.handle_message(message_in.peer, message_in.recv_time, signed_message);
+                    }
+                }
+                None => {
+                    warn!(
+                        "Failed to deserialize message from {:?}, ignoring",
+                        message_in.peer,
+                    );
+                }
+            }
         }
+
+        true
     }
 
     fn handle_message(
@@ -300,6 +728,14 @@ impl Runner {
         recv_time: Instant,
         message: comn::SignedClientMessage,
     ) {
+        if self.bans.contains(peer.ip()) {
+            if self.players.contains_key(&message.0) {
+                info!("Kicking player {:?} with banned address", message.0);
+                self.kick_player(message.0);
+            }
+            return;
+        }
+
         let player = if let Some(player) = self.players.get_mut(&message.0) {
             player
         } else {
@@ -307,6 +743,16 @@ impl Runner {
             return;
         };
 
+        if player.disconnect_time.is_some() {
+            // The player needs to go through `/reconnect` first, so that we
+            // can start from a clean state.
+            debug!(
+                "Received message from disconnected player {:?}, ignoring",
+                message.0
+            );
+            return;
+        }
+
         if Some(peer) != player.peer {
             debug!("Changing peer from {:?} to {:?}", player.peer, peer);
             player.peer = Some(peer);
@@ -318,103 +764,521 @@ impl Runner {
             }
             comn::ClientMessage::Pong(sequence_num) => {
                 if player.ping.record_pong(recv_time, sequence_num).is_err() {
-                    warn!("Ignoring pong with invalid sequence number from {:?}", peer);
+                    // Pongs arrive late if the ping has already been
+                    // counted as lost.
+                    debug!("Ignoring pong with invalid sequence number from {:?}", peer);
                 }
             }
-            comn::ClientMessage::Input(inputs) => {
-                self.record_player_input(message.0, &inputs);
+            comn::ClientMessage::Input(runs) => {
+                if let Some(inputs) = runs.inputs(comn::MAX_INPUTS_PER_MESSAGE) {
+                    self.record_player_input(message.0, &inputs);
+                } else {
+                    warn!(
+                        "Received invalid inputs ({} runs) from {:?}, ignoring",
+                        runs.runs.len(),
+                        message.0,
+                    );
+                }
+            }
+            comn::ClientMessage::AckTick(ack_num, checksum) => {
+                self.record_player_ack_tick(message.0, ack_num, checksum);
+            }
+            comn::ClientMessage::Reliable(packet) => {
+                match player.reliable.record_packet(packet) {
+                    Ok(Some(ack_num)) => {
+                        self.send(peer, comn::ServerMessage::AckReliable(ack_num));
+                    }
+                    Ok(None) => (),
+                    Err(err) => {
+                        warn!("Ignoring reliable message from {:?}: {:?}", message.0, err);
+                    }
+                }
+
+                // Handle the reliable messages that are now ready in order.
+                while let Some(reliable_message) = self
+                    .players
+                    .get_mut(&message.0)
+                    .and_then(|player| player.reliable.take_message())
+                {
+                    self.handle_reliable_message(recv_time, message.0, reliable_message);
+                }
             }
-            comn::ClientMessage::AckTick(ack_num) => {
-                self.record_player_ack_tick(message.0, ack_num);
+            comn::ClientMessage::AckReliable(ack_num) => {
+                player.reliable.record_ack(ack_num);
             }
             comn::ClientMessage::Disconnect => {
                 debug!("Player {:?} disconnected", message.0);
+                self.remove_player(message.0);
+            }
+        }
+    }
+
+    /// Handles a message that we received in order through the reliable
+    /// layer.
+    fn handle_reliable_message(
+        &mut self,
+        recv_time: Instant,
+        player_token: comn::PlayerToken,
+        message: comn::ReliableClientMessage,
+    ) {
+        match message {
+            comn::ReliableClientMessage::Chat(text) => {
+                self.handle_chat_message(player_token, recv_time, text);
+            }
+        }
+    }
 
-                let game = self.games.get_mut(&player.game_id).unwrap();
-                game.remove_player(player.player_id);
-                self.players.remove(&message.0);
+    fn remove_player(&mut self, player_token: comn::PlayerToken) {
+        let player = self.players.remove(&player_token).unwrap();
+
+        // The game is gone if it has just been ended.
+        if let (Some(player_id), Some(game)) =
+            (player.player_id, self.games.get_mut(&player.game_id))
+        {
+            let food = game.state.players.get(&player_id).map_or(0, |p| p.food);
+            game.remove_player(player_id);
+
+            if let Some(account) = player.account {
+                self.send_update(Update::MatchResult(leaderboard::MatchResult {
+                    account: account.clone(),
+                    end_time: leaderboard::unix_time(),
+                    food,
+                    catches: player.match_stats.catches,
+                    deaths: player.match_stats.deaths,
+                }));
+
+                self.send_update(Update::Stats {
+                    account,
+                    change: StatsChange::GamePlayed,
+                });
             }
         }
     }
 
-    fn run_tick(&mut self) {
-        let tick_inputs = self.collect_player_inputs_for_tick();
+    fn send_update(&self, update: Update) {
+        if self.update_tx.send(update).is_err() {
+            warn!("update_tx closed, dropping update");
+        }
+    }
+
+    /// Removes a player from the game immediately. We tell the client, but
+    /// do not wait for an acknowledgement.
+    fn kick_player(&mut self, player_token: comn::PlayerToken) {
+        if let Some(peer) = self.players[&player_token].peer {
+            self.send(peer, comn::ServerMessage::Disconnect);
+        }
+
+        self.remove_player(player_token);
+    }
+
+    /// Kicks all the players of a game and removes it.
+    fn end_game(&mut self, game_id: comn::GameId) {
+        let player_tokens: Vec<comn::PlayerToken> = self
+            .players
+            .iter()
+            .filter(|(_, player)| player.game_id == game_id)
+            .map(|(player_token, _)| *player_token)
+            .collect();
+        for player_token in player_tokens {
+            self.kick_player(player_token);
+        }
+
+        info!("Ending game {:?}", game_id);
+        self.private_games.remove(&game_id);
+        self.games.remove(&game_id);
+        self.stats.tick_overruns.remove(&game_id);
+    }
+
+    /// Hands the given game to the workers for running its next tick.
+    fn start_tick(&mut self, game_id: comn::GameId) {
+        let mut tick_inputs = self.collect_player_inputs_for_tick(&[game_id]);
+        let inputs = tick_inputs.remove(&game_id).unwrap();
 
         // Record some statistics for monitoring.
-        self.stats.num_players.record(self.players.len() as f32);
-        self.stats.num_games.record(self.games.len() as f32);
-        self.stats.num_inputs_per_player_tick.record(
-            tick_inputs
-                .values()
-                .map(|inputs| inputs.len() as f32)
-                .sum::<f32>()
-                / (self.players.len() as f32 * self.games.len() as f32),
+        let num_game_players = self
+            .players
+            .values()
+            .filter(|player| player.game_id == game_id && player.player_id.is_some())
+            .count();
+        if num_game_players > 0 {
+            self.stats
+                .num_inputs_per_player_tick
+                .record(inputs.len() as f32 / num_game_players as f32);
+        }
+
+        let game = self.games.remove(&game_id).unwrap();
+        let summary = GameSummary::new(&game);
+
+        self.workers.start_tick(workers::TickJob {
+            game_id,
+            game,
+            inputs,
+        });
+        self.running_games.insert(
+            game_id,
+            RunningGame {
+                messages: Vec::new(),
+                summary,
+            },
         );
+    }
+
+    /// Takes back a game whose tick the workers have finished, and sends the
+    /// new state to its players.
+    fn finish_tick(&mut self, reply: workers::Reply) {
+        let result = match reply {
+            Ok(result) => result,
+            Err(panic) => {
+                // We cannot trust the state of the game anymore, so it ends
+                // here. The other games keep running.
+                error!(
+                    "Tick of game {:?} panicked, ending the game: {}",
+                    panic.game_id, panic.message
+                );
+                self.running_games.remove(&panic.game_id);
+                self.end_game(panic.game_id);
+                return;
+            }
+        };
+
+        let start_time = Instant::now();
+        let game_id = result.game_id;
+        let running_messages = self
+            .running_games
+            .remove(&game_id)
+            .map_or_else(Vec::new, |running_game| running_game.messages);
+
+        self.stats.num_players.record(self.players.len() as f32);
+        self.stats.num_games.record(self.num_games() as f32);
 
-        // Update the games given the player inputs.
-        for (game_id, game) in self.games.iter_mut() {
-            game.run_tick(tick_inputs[game_id].as_slice());
+        let tick_period = Duration::from_secs_f32(result.game.settings().tick_period());
+        if result.duration > tick_period {
+            *self.stats.tick_overruns.entry(game_id).or_insert(0) += 1;
         }
 
+        self.games.insert(game_id, result.game);
+
+        let game_ids = [game_id];
+        self.update_player_stats(&game_ids);
+        self.update_ratings(&game_ids);
+
         // Send out tick messages.
+        let now = Instant::now();
         let mut messages = Vec::new();
         for player in self.players.values_mut() {
+            if !game_ids.contains(&player.game_id) {
+                continue;
+            }
+
             if let Some(peer) = player.peer {
                 let game = &self.games[&player.game_id];
-                let tick = Self::prepare_tick_for_player(player, game);
-                messages.push((peer, comn::ServerMessage::Tick(tick)));
 
-                self.stats
-                    .last_sent_len
-                    .record(player.last_sent.len() as f32);
+                Self::update_send_interval(player, now);
+                player.ticks_since_send += 1;
+
+                if player.ticks_since_send >= player.send_interval {
+                    player.ticks_since_send = 0;
+
+                    let tick = Self::prepare_tick_for_player(player, game);
+
+                    if self.stats.num_tick_messages % PACKED_TICK_SIZE_SAMPLE_PERIOD == 0 {
+                        let packed_size =
+                            comn::game::wire::encode_tick(&tick, game.settings().map.size).len();
+                        self.stats
+                            .packed_tick_message_size
+                            .record(packed_size as f32);
+                    }
+                    self.stats.num_tick_messages += 1;
+
+                    let data = comn::ServerMessage::Tick(tick).serialize();
+
+                    // Remember the size, so that we can tell how much of what
+                    // we send actually arrives.
+                    player.record_sent_tick_size(game.state.tick_num, now, data.len());
+
+                    self.stats.tick_message_size.record(data.len() as f32);
+                    self.stats
+                        .last_sent_len
+                        .record(player.sent_ticks.num_states() as f32);
+
+                    messages.push((peer, data));
+                } else if !game.last_events.is_empty() {
+                    // The events still need to reach the player, so they
+                    // are sent along with the next tick.
+                    player
+                        .sent_ticks
+                        .skip(game.state.tick_num, game.last_events.clone());
+                }
+
+                // Kill cams are too large for a single message, so we split
+                // them up into chunks of a few frames.
+                let kill_cams = game
+                    .last_kill_cams
+                    .iter()
+                    .filter(|(victim, _)| Some(*victim) == player.player_id);
+                for (_, kill_cam) in kill_cams {
+                    for frames in kill_cam.frames.chunks(KILL_CAM_FRAMES_PER_MESSAGE) {
+                        let chunk = comn::KillCam {
+                            killer: kill_cam.killer,
+                            death_tick_num: kill_cam.death_tick_num,
+                            frames: frames.to_vec(),
+                        };
+                        messages.push((peer, comn::ServerMessage::KillCam(chunk).serialize()));
+                    }
+                }
             }
         }
 
-        for (peer, message) in messages {
-            // FIXME: Here, we will run into a problem as soon as a state
-            // update is larger than the MTU of WebRTC (1200 Bytes, AFAIK).
-            // We'll need to do one of two things:
-            // 1. Make the tick smaller by removing the least important
-            //    updates. For example, remove the oldest events, or the
-            //    entities that are the farthest away.
-            // 2. Implement sending fragmented packets.
-            self.send(peer, message);
+        for (peer, data) in messages {
+            // Ticks that are larger than the MTU of WebRTC are fragmented by
+            // `webrtc::Server`. Each lost fragment loses the whole tick
+            // though, so we should also make ticks smaller by removing the
+            // least important updates, such as the entities that are farthest
+            // away.
+            self.send_data(peer, data);
+        }
+
+        self.stats
+            .tick_duration
+            .record((result.duration + start_time.elapsed()).as_secs_f32());
+
+        // Now that the game is back, we can handle the messages that arrived
+        // in the meantime.
+        for (peer, recv_time, message) in running_messages {
+            self.handle_message(peer, recv_time, message);
         }
     }
 
-    fn send(&mut self, peer: SocketAddr, message: comn::ServerMessage) {
-        let data = message.serialize();
+    /// Updates the match statistics of players and the profiles of
+    /// registered players given the events of the last tick.
+    fn update_player_stats(&mut self, game_ids: &[comn::GameId]) {
+        let update_tx = &self.update_tx;
+        let games = &self.games;
+
+        for player in self.players.values_mut() {
+            if !game_ids.contains(&player.game_id) {
+                continue;
+            }
 
-        if let comn::ServerMessage::Tick(_) = message {
-            self.stats.tick_message_size.record(data.len() as f32);
+            let player_id = if let Some(player_id) = player.player_id {
+                player_id
+            } else {
+                continue;
+            };
+            let account = player.account.as_ref();
+            let send_stats = |change| {
+                if let Some(account) = account {
+                    let _ = update_tx.send(Update::Stats {
+                        account: account.clone(),
+                        change,
+                    });
+                }
+            };
+
+            for event in games[&player.game_id].last_events.iter() {
+                match event {
+                    comn::Event::PlayerDied {
+                        reason: comn::DeathReason::CaughtBy(catcher_id),
+                        ..
+                    } if *catcher_id == player_id => {
+                        player.match_stats.catches += 1;
+                        send_stats(StatsChange::Catch);
+                    }
+                    comn::Event::PlayerDied {
+                        player_id: victim_id,
+                        ..
+                    } if *victim_id == player_id => {
+                        player.match_stats.deaths += 1;
+                    }
+                    comn::Event::PlayerAteFood {
+                        player_id: eater_id,
+                        amount,
+                    } if *eater_id == player_id => {
+                        send_stats(StatsChange::FoodEaten(*amount as u64));
+                    }
+                    _ => (),
+                }
+            }
         }
+    }
 
-        let message_out = webrtc::MessageOut { peer, data };
+    /// Updates the ratings of players given the catches of the last tick.
+    /// Bots do not have a rating, so catches involving bots do not count.
+    fn update_ratings(&mut self, game_ids: &[comn::GameId]) {
+        let mut duels = Vec::new();
+
+        for game_id in game_ids {
+            let game = &self.games[game_id];
+
+            for event in game.last_events.iter() {
+                if let comn::Event::PlayerDied {
+                    player_id,
+                    reason: comn::DeathReason::CaughtBy(catcher_id),
+                    ..
+                } = event
+                {
+                    let winner = self.player_token_by_id(*game_id, *catcher_id);
+                    let loser = self.player_token_by_id(*game_id, *player_id);
+
+                    if let (Some(winner), Some(loser)) = (winner, loser) {
+                        duels.push((winner, loser));
+                    }
+                }
+            }
+        }
 
-        if self.send_message_tx.send(message_out).is_err() {
-            info!("send_message_tx closed, will terminate thread");
+        for (winner, loser) in duels {
+            let (winner_rating, loser_rating) =
+                rating::update(self.players[&winner].rating, self.players[&loser].rating);
+
+            self.set_rating(winner, winner_rating);
+            self.set_rating(loser, loser_rating);
         }
     }
 
-    fn record_player_input(
+    fn set_rating(&mut self, player_token: comn::PlayerToken, rating: f32) {
+        let player = self.players.get_mut(&player_token).unwrap();
+        player.rating = rating;
+
+        if let Some(account) = player.account.clone() {
+            self.send_update(Update::Stats {
+                account,
+                change: StatsChange::Rating(rating),
+            });
+        }
+    }
+
+    fn player_token_by_id(
+        &self,
+        game_id: comn::GameId,
+        player_id: comn::PlayerId,
+    ) -> Option<comn::PlayerToken> {
+        self.players
+            .iter()
+            .find(|(_, player)| player.game_id == game_id && player.player_id == Some(player_id))
+            .map(|(player_token, _)| *player_token)
+    }
+
+    fn handle_chat_message(
         &mut self,
         player_token: comn::PlayerToken,
-        inputs: &[(comn::TickNum, comn::Input)],
+        recv_time: Instant,
+        text: String,
     ) {
         let player = self.players.get_mut(&player_token).unwrap();
-        let game = &self.games[&player.game_id].state;
 
-        if inputs.is_empty() || inputs.len() > comn::MAX_INPUTS_PER_MESSAGE {
+        let len = text.chars().count();
+        if len > comn::MAX_CHAT_MESSAGE_LEN {
             warn!(
-                "Received invalid number of inputs ({}) from {:?}, ignoring",
-                inputs.len(),
-                player_token,
+                "Ignoring chat message of length {} from {:?}",
+                len, player_token
             );
             return;
         }
 
-        // Keep track of when we receive player input, so that we can predict
-        // when we'll receive the next player input. This results in a mapping
+        if !player.chat_rate_limit.try_send(recv_time) {
+            debug!("Rate limiting chat message from {:?}", player_token);
+            return;
+        }
+
+        let text = if let Some(text) = chat::sanitize(&text) {
+            text
+        } else {
+            return;
+        };
+        let text = if let Some(filter) = self.config.chat_filter.as_ref() {
+            filter.censor(&text)
+        } else {
+            text
+        };
+
+        info!("Chat from {:?} ({}): {}", player_token, player.name, text);
+
+        let message = comn::ReliableServerMessage::Chat(comn::ChatMessage {
+            player_id: player.player_id,
+            player_name: player.name.clone(),
+            text,
+        });
+        let game_id = player.game_id;
+        let receivers: Vec<comn::PlayerToken> = self
+            .players
+            .iter()
+            .filter(|(_, other)| other.game_id == game_id)
+            .map(|(other_token, _)| *other_token)
+            .collect();
+
+        for receiver in receivers {
+            self.send_reliable(receiver, message.clone());
+        }
+    }
+
+    /// Sends a message to a player reliably and in order. The message is
+    /// resent until the player acknowledges it.
+    fn send_reliable(
+        &mut self,
+        player_token: comn::PlayerToken,
+        message: comn::ReliableServerMessage,
+    ) {
+        if let Some(player) = self.players.get_mut(&player_token) {
+            player.reliable.push(message);
+        }
+    }
+
+    fn send_reliable_packets(&mut self) {
+        let now = Instant::now();
+        let mut messages = Vec::new();
+
+        for player in self.players.values_mut() {
+            if let Some(peer) = player.peer {
+                for packet in player.reliable.packets_to_send(now, player.ping.estimate()) {
+                    messages.push((peer, comn::ServerMessage::Reliable(packet)));
+                }
+            }
+        }
+
+        for (peer, message) in messages {
+            self.send(peer, message);
+        }
+    }
+
+    fn send(&mut self, peer: SocketAddr, message: comn::ServerMessage) {
+        self.send_data(peer, message.serialize());
+    }
+
+    fn send_data(&mut self, peer: SocketAddr, data: Vec<u8>) {
+        self.stats.bytes_out += data.len() as u64;
+
+        let message_out = webrtc::MessageOut { peer, data };
+
+        if self.send_message_tx.send(message_out).is_err() {
+            info!("send_message_tx closed, will terminate thread");
+        }
+    }
+
+    fn record_player_input(
+        &mut self,
+        player_token: comn::PlayerToken,
+        inputs: &[(comn::TickNum, comn::Input)],
+    ) {
+        let player = self.players.get_mut(&player_token).unwrap();
+        let game = &self.games[&player.game_id].state;
+
+        if player.player_id.is_none() {
+            // Spectators have nothing to control.
+            return;
+        }
+
+        if inputs.is_empty() || inputs.len() > comn::MAX_INPUTS_PER_MESSAGE {
+            warn!(
+                "Received invalid number of inputs ({}) from {:?}, ignoring",
+                inputs.len(),
+                player_token,
+            );
+            return;
+        }
+
+        // Keep track of when we receive player input, so that we can predict
+        // when we'll receive the next player input. This results in a mapping
         // from our game time to the player's input game time as we receive it.
         {
             let max_input_num = inputs.iter().map(|(tick_num, _)| *tick_num).max().unwrap();
@@ -467,7 +1331,12 @@ impl Runner {
         }
     }
 
-    fn record_player_ack_tick(&mut self, player_token: comn::PlayerToken, ack_num: comn::TickNum) {
+    fn record_player_ack_tick(
+        &mut self,
+        player_token: comn::PlayerToken,
+        ack_num: comn::TickNum,
+        checksum: u64,
+    ) {
         let player = self.players.get_mut(&player_token).unwrap();
         let game = &self.games[&player.game_id].state;
 
@@ -476,99 +1345,830 @@ impl Runner {
                 "Received AckTick from {:?} which is ahead of us ({:?} vs {:?}), ignoring",
                 player_token, game.tick_num, ack_num,
             );
-        } else if player
-            .last_ack_tick
-            .map_or(true, |last_ack_num| ack_num > last_ack_num)
-        {
-            player.last_ack_tick = Some(ack_num);
-
-            // We can now forget all the states that are older than the one
-            // whose acknowledgment we just received.
-            while player
-                .last_sent
-                .front()
-                .map_or(false, |(_events, state)| state.tick_num < ack_num)
-            {
-                player.last_sent.pop_front();
+        } else {
+            match player.sent_ticks.record_ack(ack_num, checksum) {
+                Ack::Valid => player.record_acked_tick_size(ack_num),
+                Ack::Desync => {
+                    // The next tick is sent from scratch.
+                    warn!(
+                        "Checksum mismatch in AckTick {:?} from {:?}, resending full state",
+                        ack_num, player_token,
+                    );
+                    self.stats.desyncs += 1;
+                }
+                Ack::Outdated => (),
+            }
+        }
+    }
+
+    /// Handles a request that we received via HTTP. Returns false if the
+    /// reply could not be sent.
+    fn handle_request(&mut self, request: Request) -> bool {
+        match request {
+            Request::Join(request, identity, reply_tx) => {
+                info!("Processing {:?}", request);
+
+                if let Err(err) = Self::check_protocol_version(request.protocol_version) {
+                    let reply = Err(err);
+                    self.record_join_reply(&reply);
+                    return reply_tx.send(reply).is_ok();
+                }
+
+                let identity = match identity {
+                    Ok(identity) => identity,
+                    Err(err) => {
+                        let reply = Err(err);
+                        self.record_join_reply(&reply);
+                        return reply_tx.send(reply).is_ok();
+                    }
+                };
+
+                if request.spectate || request.game_id.is_some() || request.invite_code.is_some() {
+                    let reply = self.try_join_game(request, identity);
+                    self.record_join_reply(&reply);
+                    reply_tx.send(reply).is_ok()
+                } else {
+                    // Players that want to join any game go through
+                    // matchmaking, which may take a while.
+                    self.enqueue_join(identity, reply_tx);
+                    true
+                }
+            }
+            Request::ListGames(reply_tx) => reply_tx.send(self.list_games()).is_ok(),
+            Request::CreateGame(request, reply_tx) => {
+                info!("Processing {:?}", request);
+                let reply = self.try_create_game(request);
+                reply_tx.send(reply).is_ok()
+            }
+            Request::Reconnect(request, reply_tx) => {
+                info!("Processing {:?}", request);
+                let reply = self.try_reconnect(request);
+                self.record_join_reply(&reply);
+                reply_tx.send(reply).is_ok()
+            }
+            Request::Metrics(reply_tx) => reply_tx.send(self.metrics()).is_ok(),
+            Request::AdminListGames(reply_tx) => reply_tx.send(self.admin_list_games()).is_ok(),
+            Request::Admin(command, reply_tx) => {
+                info!("Processing admin command {:?}", command);
+                let reply = self.handle_admin_command(command);
+                reply_tx.send(reply).is_ok()
+            }
+            Request::Load(reply_tx) => reply_tx.send(self.load()).is_ok(),
+        }
+    }
+
+    fn check_protocol_version(protocol_version: u32) -> Result<(), comn::JoinError> {
+        if protocol_version != comn::PROTOCOL_VERSION {
+            warn!(
+                "Rejecting client with protocol version {} (ours: {})",
+                protocol_version,
+                comn::PROTOCOL_VERSION,
+            );
+            Err(comn::JoinError::IncompatibleVersion)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn record_join_reply(&mut self, reply: &comn::JoinReply) {
+        if !self.config.record_join_failures {
+            return;
+        }
+
+        if let Err(err) = reply {
+            *self
+                .stats
+                .join_failures
+                .entry(format!("{:?}", err))
+                .or_insert(0) += 1;
+        }
+    }
+
+    fn metrics(&self) -> String {
+        let mut writer = metrics::Writer::default();
+
+        writer.gauge(
+            "players",
+            "Number of connected players and spectators.",
+            self.players.len() as f64,
+        );
+        writer.gauge("games", "Number of running games.", self.num_games() as f64);
+        writer.gauge(
+            "join_queue_len",
+            "Number of players waiting for matchmaking.",
+            self.join_queue.len() as f64,
+        );
+        writer.var(
+            "inputs_per_player_tick",
+            "Number of inputs executed per player and tick.",
+            &self.stats.num_inputs_per_player_tick,
+        );
+        writer.var(
+            "input_delay_seconds",
+            "Delay between receiving and executing player inputs.",
+            &self.stats.input_delay,
+        );
+        writer.var(
+            "last_sent_len",
+            "Number of states kept per player for delta encoding.",
+            &self.stats.last_sent_len,
+        );
+        writer.var(
+            "tick_message_bytes",
+            "Size of tick messages sent to players.",
+            &self.stats.tick_message_size,
+        );
+        writer.var(
+            "packed_tick_message_bytes",
+            "Size that a sample of tick messages would have with the bit-packed encoding.",
+            &self.stats.packed_tick_message_size,
+        );
+        writer.var(
+            "tick_duration_seconds",
+            "Time spent running a tick of a game.",
+            &self.stats.tick_duration,
+        );
+        writer.counter(
+            "received_bytes_total",
+            "Bytes received via WebRTC.",
+            self.stats.bytes_in,
+        );
+        writer.counter(
+            "sent_bytes_total",
+            "Bytes sent via WebRTC.",
+            self.stats.bytes_out,
+        );
+        writer.counter(
+            "desyncs_total",
+            "Number of ticks that clients decoded to a different state than we sent.",
+            self.stats.desyncs,
+        );
+        writer.labeled_counter(
+            "join_failures_total",
+            "Number of failed join requests.",
+            "error",
+            self.stats
+                .join_failures
+                .iter()
+                .map(|(err, count)| (err.clone(), *count)),
+        );
+        writer.labeled_counter(
+            "tick_overruns_total",
+            "Number of game updates that took longer than the tick period.",
+            "game_id",
+            self.stats
+                .tick_overruns
+                .iter()
+                .map(|(game_id, count)| (game_id.0.to_string(), *count)),
+        );
+
+        writer.finish()
+    }
+
+    fn admin_list_games(&self) -> Vec<admin::GameInfo> {
+        self.game_summaries()
+            .into_iter()
+            .map(|(game_id, summary)| {
+                let players = self
+                    .players
+                    .iter()
+                    .filter(|(_, player)| player.game_id == game_id)
+                    .map(|(player_token, player)| admin::PlayerInfo {
+                        token: *player_token,
+                        name: player.name.clone(),
+                        account: player.account.clone(),
+                        player_id: player.player_id,
+                        ip: player.peer.map(|peer| peer.ip()),
+                        ping_ms: player.ping.estimate().as_secs_f32() * 1000.0,
+                        loss: player.ping.loss(),
+                        send_interval: player.send_interval,
+                        rating: player.rating,
+                        disconnected: player.disconnect_time.is_some(),
+                    })
+                    .collect();
+
+                admin::GameInfo {
+                    game_id,
+                    map_name: summary.map_name,
+                    private: self.private_games.contains_key(&game_id),
+                    num_bots: summary.num_bots,
+                    max_num_players: summary.max_num_players,
+                    game_time: summary.game_time,
+                    players,
+                }
+            })
+            .collect()
+    }
+
+    fn handle_admin_command(&mut self, command: admin::Command) -> admin::Reply {
+        match command {
+            admin::Command::Kick(player_token) => {
+                self.finish_running_tick_of_player(player_token);
+
+                if !self.players.contains_key(&player_token) {
+                    return Err(admin::Error::InvalidPlayerToken);
+                }
+
+                self.kick_player(player_token);
+            }
+            admin::Command::Ban(request) => {
+                let ip = match (request.token, request.ip) {
+                    (_, Some(ip)) => ip,
+                    (Some(player_token), None) => self
+                        .players
+                        .get(&player_token)
+                        .ok_or(admin::Error::InvalidPlayerToken)?
+                        .peer
+                        .ok_or(admin::Error::UnknownAddress)?
+                        .ip(),
+                    (None, None) => return Err(admin::Error::UnknownAddress),
+                };
+
+                // Bans take effect here right away, and are passed on to
+                // the front door, if any, so that every worker knows them.
+                info!("Banning {:?}", ip);
+                self.bans.insert(ip);
+                self.send_update(Update::Ban(ip));
+
+                let player_tokens: Vec<comn::PlayerToken> = self
+                    .players
+                    .iter()
+                    .filter(|(_, player)| player.peer.map(|peer| peer.ip()) == Some(ip))
+                    .map(|(player_token, _)| *player_token)
+                    .collect();
+                for player_token in player_tokens {
+                    self.finish_running_tick_of_player(player_token);
+
+                    // The player is gone if the tick of their game panicked.
+                    if self.players.contains_key(&player_token) {
+                        self.kick_player(player_token);
+                    }
+                }
+            }
+            admin::Command::Unban(ip) => {
+                if !self.bans.remove(ip) {
+                    return Err(admin::Error::UnknownAddress);
+                }
+
+                self.send_update(Update::Unban(ip));
+            }
+            admin::Command::EndGame(game_id) => {
+                self.finish_running_tick(game_id);
+
+                if !self.games.contains_key(&game_id) {
+                    return Err(admin::Error::InvalidGameId);
+                }
+
+                self.end_game(game_id);
+            }
+            admin::Command::AddBot(game_id) => {
+                self.finish_running_tick(game_id);
+
+                let game = self
+                    .games
+                    .get_mut(&game_id)
+                    .ok_or(admin::Error::InvalidGameId)?;
+
+                if game.is_full() {
+                    return Err(admin::Error::FullGame);
+                }
+
+                game.join_bot("left_right_bot", Bot::left_right(2.0));
+            }
+            admin::Command::RemoveBot(game_id) => {
+                self.finish_running_tick(game_id);
+
+                let game = self
+                    .games
+                    .get_mut(&game_id)
+                    .ok_or(admin::Error::InvalidGameId)?;
+
+                if !game.remove_bot() {
+                    return Err(admin::Error::NoBots);
+                }
+            }
+            admin::Command::UpdateSettings(request) => {
+                self.finish_running_tick(request.game_id);
+
+                let game = self
+                    .games
+                    .get_mut(&request.game_id)
+                    .ok_or(admin::Error::InvalidGameId)?;
+                // Clients cannot follow changes of the tick rate or the map
+                // in a running game.
+                if request.ticks_per_second.is_some() || request.map_gen.is_some() {
+                    return Err(admin::Error::UseNewGameSettings);
+                }
+
+                let mut settings = game.settings().clone();
+
+                if let Some(max_num_players) = request.max_num_players {
+                    if max_num_players == 0 || max_num_players < game.state.players.len() {
+                        return Err(admin::Error::InvalidSettings);
+                    }
+
+                    settings.max_num_players = max_num_players;
+                }
+
+                game.set_settings(Arc::new(settings));
+            }
+            admin::Command::UpdateNewGameSettings(request) => {
+                if let Some(ticks_per_second) = request.ticks_per_second {
+                    if ticks_per_second == 0 || ticks_per_second > config::MAX_TICKS_PER_SECOND {
+                        return Err(admin::Error::InvalidSettings);
+                    }
+                }
+                if let Some(map_gen) = request.map_gen.as_ref() {
+                    // Find out now if the config does not work, rather than
+                    // when creating the next game.
+                    mapgen::generate_map(map_gen).map_err(|_| admin::Error::InvalidSettings)?;
+                }
+
+                if let Some(ticks_per_second) = request.ticks_per_second {
+                    self.config.game_settings.ticks_per_second = ticks_per_second;
+                }
+                if let Some(map_gen) = request.map_gen {
+                    self.config.map_gen = Some(map_gen);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn try_reconnect(&mut self, request: comn::ReconnectRequest) -> comn::JoinReply {
+        // Clients that were built against another protocol would fail to
+        // decode our messages after resuming.
+        Self::check_protocol_version(request.protocol_version)?;
+
+        self.finish_running_tick_of_player(request.token);
+
+        let player = self
+            .players
+            .get_mut(&request.token)
+            .ok_or(comn::JoinError::InvalidPlayerToken)?;
+        let game = &self.games[&player.game_id];
+
+        info!(
+            "Player {:?} with token {:?} reconnected",
+            player.name, request.token
+        );
+
+        // Start over with the connection state, but keep the slot in the
+        // game. Since we forget about the ticks that the player has
+        // acknowledged, the next tick will not be delta encoded.
+        let mut new_player = Player::new(
+            game.settings().tick_period(),
+            player.game_id,
+            player.player_id,
+            player.name.clone(),
+        );
+        new_player.account = player.account.clone();
+        new_player.rating = player.rating;
+        new_player.chat_rate_limit = player.chat_rate_limit.clone();
+        new_player.match_stats = player.match_stats.clone();
+        *player = new_player;
+
+        Ok(comn::JoinSuccess {
+            game_id: player.game_id,
+            game_settings: game.settings().clone(),
+            your_token: request.token,
+            your_player_id: player.player_id,
+            webrtc_session_url: None,
+        })
+    }
+
+    fn load(&self) -> cluster::Load {
+        cluster::Load {
+            num_games: self.num_games(),
+            max_num_games: self.config.max_num_games,
+            num_players: self.players.len(),
+            max_num_players_per_game: self.config.game_settings.max_num_players,
+            game_ids: self
+                .games
+                .keys()
+                .chain(self.running_games.keys())
+                .copied()
+                .collect(),
+        }
+    }
+
+    fn list_games(&self) -> Vec<comn::GameInfo> {
+        let mut infos: Vec<_> = self
+            .game_summaries()
+            .into_iter()
+            .filter(|(game_id, _)| !self.private_games.contains_key(game_id))
+            .map(|(game_id, summary)| {
+                let num_spectators = self
+                    .players
+                    .values()
+                    .filter(|player| player.game_id == game_id && player.player_id.is_none())
+                    .count();
+
+                comn::GameInfo {
+                    game_id,
+                    map_name: summary.map_name,
+                    mode: comn::GameMode::Catch,
+                    num_players: summary.num_players - summary.num_bots,
+                    num_bots: summary.num_bots,
+                    num_spectators,
+                    max_num_players: summary.max_num_players,
+                    game_time: summary.game_time,
+                    catcher_name: summary.catcher_name,
+                }
+            })
+            .collect();
+
+        // Show the busiest games first.
+        infos.sort_by(|a, b| b.num_players.cmp(&a.num_players));
+
+        infos
+    }
+
+    fn try_create_game(&mut self, request: comn::CreateGameRequest) -> comn::CreateGameReply {
+        if self.num_games() >= self.config.max_num_games {
+            warn!(
+                "Cannot create game, reached the game limit of {}",
+                self.config.max_num_games
+            );
+            return Err(comn::CreateGameError::TooManyGames);
+        }
+
+        let game_id = self.add_game();
+
+        let invite_code = if request.private {
+            let invite_code = self.new_invite_code();
+            self.private_games.insert(
+                game_id,
+                PrivateGame {
+                    invite_code: invite_code.clone(),
+                    last_occupied_time: Instant::now(),
+                },
+            );
+            Some(invite_code)
+        } else {
+            None
+        };
+
+        info!(
+            "Created game {:?} with invite code {:?}",
+            game_id, invite_code
+        );
+
+        Ok(comn::CreateGameSuccess {
+            game_id,
+            invite_code,
+        })
+    }
+
+    fn new_invite_code(&self) -> String {
+        let mut rng = rand::thread_rng();
+
+        loop {
+            let invite_code: String = (0..INVITE_CODE_LEN)
+                .map(|_| INVITE_CODE_CHARS[rng.gen_range(0, INVITE_CODE_CHARS.len())] as char)
+                .collect();
+
+            if self.find_private_game(&invite_code).is_none() {
+                return invite_code;
+            }
+        }
+    }
+
+    fn find_private_game(&self, invite_code: &str) -> Option<comn::GameId> {
+        self.private_games
+            .iter()
+            .find(|(_, private_game)| private_game.invite_code.eq_ignore_ascii_case(invite_code))
+            .map(|(game_id, _)| *game_id)
+    }
+
+    fn remove_idle_private_games(&mut self) {
+        let now = Instant::now();
+        let players = &self.players;
+
+        for (game_id, private_game) in self.private_games.iter_mut() {
+            if players.values().any(|player| player.game_id == *game_id) {
+                private_game.last_occupied_time = now;
             }
         }
+
+        let remove_game_ids: Vec<comn::GameId> = self
+            .private_games
+            .iter()
+            .filter(|(_, private_game)| {
+                now.duration_since(private_game.last_occupied_time) > PRIVATE_GAME_IDLE_TIMEOUT
+            })
+            .map(|(game_id, _)| *game_id)
+            .collect();
+
+        for game_id in remove_game_ids {
+            info!("Removing idle private game {:?}", game_id);
+            self.finish_running_tick(game_id);
+            self.private_games.remove(&game_id);
+            self.games.remove(&game_id);
+            self.stats.tick_overruns.remove(&game_id);
+        }
     }
 
-    fn try_join_game(&mut self, request: comn::JoinRequest) -> comn::JoinReply {
-        let game_id = self.get_non_full_game_to_join(request.game_id)?;
+    /// Resolves the game that a join request refers to. Private games can
+    /// only be joined with their invite code.
+    fn requested_game_id(
+        &self,
+        request: &comn::JoinRequest,
+    ) -> Result<Option<comn::GameId>, comn::JoinError> {
+        if let Some(invite_code) = request.invite_code.as_ref() {
+            self.find_private_game(invite_code)
+                .map(Some)
+                .ok_or(comn::JoinError::InvalidInviteCode)
+        } else if request
+            .game_id
+            .map_or(false, |game_id| self.private_games.contains_key(&game_id))
+        {
+            Err(comn::JoinError::InvalidInviteCode)
+        } else {
+            Ok(request.game_id)
+        }
+    }
+
+    /// Handles a request to join a specific game.
+    fn try_join_game(&mut self, request: comn::JoinRequest, identity: Identity) -> comn::JoinReply {
+        if request.spectate {
+            return self.try_spectate_game(request, identity);
+        }
+
+        let game_id = self
+            .requested_game_id(&request)?
+            .ok_or(comn::JoinError::InvalidGameId)?;
+        let game_id = self.get_non_full_game_to_join(game_id)?;
+
+        Ok(self.add_player(game_id, identity.name, identity.account, identity.rating))
+    }
+
+    fn add_player(
+        &mut self,
+        game_id: comn::GameId,
+        name: String,
+        account: Option<String>,
+        rating: f32,
+    ) -> comn::JoinSuccess {
         let game = self.games.get_mut(&game_id).unwrap();
         assert!(!game.is_full());
 
         let player_token = comn::PlayerToken(Uuid::new_v4());
         assert!(!self.players.contains_key(&player_token));
 
-        let player_id = game.join(request.player_name, None);
-        let player = Player::new(game.settings().tick_period(), game_id, player_id);
+        info!(
+            "Player {:?} with rating {:.0} joined game {:?}",
+            name, rating, game_id
+        );
+
+        let player_id = game.join(name.clone(), None);
+        let mut player = Player::new(
+            game.settings().tick_period(),
+            game_id,
+            Some(player_id),
+            name,
+        );
+        player.account = account;
+        player.rating = rating;
+        self.players.insert(player_token, player);
+
+        comn::JoinSuccess {
+            game_id,
+            game_settings: game.settings().clone(),
+            your_token: player_token,
+            your_player_id: Some(player_id),
+            webrtc_session_url: None,
+        }
+    }
+
+    fn enqueue_join(&mut self, identity: Identity, reply_tx: oneshot::Sender<comn::JoinReply>) {
+        self.join_queue.push(QueuedJoin {
+            name: identity.name,
+            account: identity.account,
+            rating: identity.rating,
+            enqueue_time: Instant::now(),
+            reply_tx,
+        });
+    }
+
+    /// Places the players in the join queue into games, as soon as we find
+    /// a game whose players have a similar rating.
+    fn process_join_queue(&mut self) {
+        let now = Instant::now();
+
+        // Players that have waited the longest go first.
+        let join_queue = std::mem::replace(&mut self.join_queue, Vec::new());
+
+        for queued in join_queue {
+            let wait_time = now.duration_since(queued.enqueue_time);
+
+            match self.find_game_to_join(queued.rating, wait_time) {
+                Some(Ok(game_id)) => {
+                    let reply =
+                        self.add_player(game_id, queued.name, queued.account, queued.rating);
+                    let player_token = reply.your_token;
+
+                    if queued.reply_tx.send(Ok(reply)).is_err() {
+                        // The player has given up on waiting.
+                        info!("Join request was canceled, removing player");
+                        let player = self.players.remove(&player_token).unwrap();
+                        self.games
+                            .get_mut(&player.game_id)
+                            .unwrap()
+                            .remove_player(player.player_id.unwrap());
+                    }
+                }
+                Some(Err(err)) => {
+                    let reply = Err(err);
+                    self.record_join_reply(&reply);
+                    let _ = queued.reply_tx.send(reply);
+                }
+                None => self.join_queue.push(queued),
+            }
+        }
+    }
+
+    /// Average rating of the players in each public game that is not full.
+    /// Games without players are mapped to `None`.
+    fn non_full_game_ratings(&self) -> Vec<(comn::GameId, Option<f32>)> {
+        self.games
+            .iter()
+            .filter(|(game_id, game)| !game.is_full() && !self.private_games.contains_key(game_id))
+            .map(|(game_id, _)| {
+                let ratings: Vec<f32> = self
+                    .players
+                    .values()
+                    .filter(|player| player.game_id == *game_id && player.player_id.is_some())
+                    .map(|player| player.rating)
+                    .collect();
+
+                let average = if ratings.is_empty() {
+                    None
+                } else {
+                    Some(ratings.iter().sum::<f32>() / ratings.len() as f32)
+                };
+
+                (*game_id, average)
+            })
+            .collect()
+    }
+
+    /// Chooses a game for a player with the given rating who has been
+    /// waiting in the join queue for `wait_time`. Returns `None` if the
+    /// player should keep waiting.
+    fn find_game_to_join(
+        &mut self,
+        rating: f32,
+        wait_time: Duration,
+    ) -> Option<Result<comn::GameId, comn::JoinError>> {
+        let tolerance =
+            MATCHMAKING_INITIAL_TOLERANCE + MATCHMAKING_TOLERANCE_PER_SEC * wait_time.as_secs_f32();
+        let game_ratings = self.non_full_game_ratings();
+
+        let closest_game = game_ratings
+            .iter()
+            .filter_map(|(game_id, average)| {
+                average.map(|average| (*game_id, (average - rating).abs()))
+            })
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+
+        if let Some((game_id, distance)) = closest_game {
+            if distance <= tolerance {
+                return Some(Ok(game_id));
+            }
+        }
+
+        // Games with only bots are fine for everyone.
+        if let Some((game_id, _)) = game_ratings.iter().find(|(_, average)| average.is_none()) {
+            return Some(Ok(*game_id));
+        }
+
+        if wait_time >= MATCHMAKING_NEW_GAME_DELAY && self.num_games() < self.config.max_num_games {
+            let game_id = self.add_game();
+            info!(
+                "No game matches rating {:.0}, created a new one with id {:?}",
+                rating, game_id
+            );
+            return Some(Ok(game_id));
+        }
+
+        if wait_time >= MATCHMAKING_MAX_WAIT {
+            return if let Some((game_id, _)) = closest_game {
+                Some(Ok(game_id))
+            } else {
+                // All games are full, and we have reached the game limit.
+                // Reject the join request.
+                warn!(
+                    "All games are full and we have reached the game limit of {}",
+                    self.config.max_num_games
+                );
+                Some(Err(comn::JoinError::FullGame))
+            };
+        }
+
+        None
+    }
+
+    fn try_spectate_game(
+        &mut self,
+        request: comn::JoinRequest,
+        identity: Identity,
+    ) -> comn::JoinReply {
+        let (name, account) = (identity.name, identity.account);
+        let game_id = self.requested_game_id(&request)?;
+        let game_id = self.get_game_to_spectate(game_id)?;
+        let game = &self.games[&game_id];
+
+        let player_token = comn::PlayerToken(Uuid::new_v4());
+        assert!(!self.players.contains_key(&player_token));
+
+        info!("New spectator {:?} joined game {:?}", name, game_id);
+
+        // Spectators do not take up a slot in the game, so they can join
+        // even if the game is full.
+        let mut player = Player::new(game.settings().tick_period(), game_id, None, name);
+        player.account = account;
         self.players.insert(player_token, player);
 
         Ok(comn::JoinSuccess {
             game_id,
             game_settings: game.settings().clone(),
             your_token: player_token,
-            your_player_id: player_id,
+            your_player_id: None,
+            webrtc_session_url: None,
         })
     }
 
-    fn get_non_full_game_to_join(
+    fn get_game_to_spectate(
         &mut self,
         game_id: Option<comn::GameId>,
     ) -> Result<comn::GameId, comn::JoinError> {
         if let Some(game_id) = game_id {
-            // The player requested to join a specific game.
-            if let Some(game) = self.games.get(&game_id) {
-                if game.is_full() {
-                    info!("Game is full");
-                    Err(comn::JoinError::FullGame)
-                } else {
-                    Ok(game_id)
-                }
+            self.finish_running_tick(game_id);
+
+            return if self.games.contains_key(&game_id) {
+                Ok(game_id)
             } else {
                 info!("game_id is invalid");
                 Err(comn::JoinError::InvalidGameId)
+            };
+        }
+
+        // The spectator wants to watch just any game, so show them the
+        // busiest one.
+        let busiest_game_id = self
+            .game_summaries()
+            .into_iter()
+            .filter(|(game_id, _)| !self.private_games.contains_key(game_id))
+            .max_by_key(|(_, summary)| summary.num_players)
+            .map(|(game_id, _)| game_id);
+
+        if let Some(game_id) = busiest_game_id {
+            self.finish_running_tick(game_id);
+
+            // The game is gone if its tick panicked.
+            if self.games.contains_key(&game_id) {
+                return Ok(game_id);
             }
+        }
+
+        if self.num_games() < self.config.max_num_games {
+            Ok(self.add_game())
         } else {
-            // The player wants to join just any game.
-            let non_full_games = self.games.iter().filter(|(_, game)| !game.is_full());
+            Err(comn::JoinError::FullGame)
+        }
+    }
 
-            if let Some((game_id, _)) = non_full_games.choose(&mut rand::thread_rng()) {
-                Ok(*game_id)
-            } else if self.games.len() == self.config.max_num_games {
-                // All games are full, and we have reached the game limit.
-                // Reject the join request.
-                warn!(
-                    "All games are full and we have reached the game limit of {}",
-                    self.config.max_num_games
-                );
+    fn get_non_full_game_to_join(
+        &mut self,
+        game_id: comn::GameId,
+    ) -> Result<comn::GameId, comn::JoinError> {
+        self.finish_running_tick(game_id);
+
+        if let Some(game) = self.games.get(&game_id) {
+            if game.is_full() {
+                info!("Game is full");
                 Err(comn::JoinError::FullGame)
             } else {
-                // We still have capacity, create a new game.
-                assert!(self.games.len() < self.config.max_num_games);
-
-                let game_id = self.add_game();
-                info!(
-                    "All games are full, created a new one with id {:?}",
-                    game_id
-                );
                 Ok(game_id)
             }
+        } else {
+            info!("game_id is invalid");
+            Err(comn::JoinError::InvalidGameId)
         }
     }
 
     fn add_game(&mut self) -> comn::GameId {
         let game_id = comn::GameId(Uuid::new_v4());
-        let mut game = Game::new(Arc::new(self.config.game_settings.clone()));
+        let mut game = Game::new(Arc::new(self.next_game_settings()));
 
         /*for i in 0..2 {
             game.join(format!("random_bot{}", i), Some(Bot::random()));
         }*/
 
-        for i in 0..2 {
-            game.join(format!("left_right_bot{}", i), Some(Bot::left_right(2.0)));
+        for _ in 0..2 {
+            game.join_bot("left_right_bot", Bot::left_right(2.0));
         }
 
         assert!(!self.games.contains_key(&game_id));
@@ -577,16 +2177,53 @@ impl Runner {
         game_id
     }
 
+    fn next_game_settings(&mut self) -> comn::Settings {
+        let mut settings = self.config.game_settings.clone();
+
+        if let Some(map_gen) = self.config.map_gen.as_ref() {
+            let map_gen = mapgen::Config {
+                seed: map_gen.seed.wrapping_add(self.num_games_created),
+                ..map_gen.clone()
+            };
+
+            match mapgen::generate_map(&map_gen) {
+                Ok(map) => {
+                    info!("Generated map with seed {}", map_gen.seed);
+                    settings.map = map;
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to generate map with seed {}, using default map: {:?}",
+                        map_gen.seed, err
+                    );
+                }
+            }
+        }
+
+        self.num_games_created += 1;
+
+        settings
+    }
+
     fn collect_player_inputs_for_tick(
         &mut self,
+        game_ids: &[comn::GameId],
     ) -> HashMap<comn::GameId, Vec<(comn::PlayerId, comn::TickNum, comn::Input)>> {
-        let mut tick_inputs: HashMap<_, _> = self
-            .games
-            .keys()
+        let mut tick_inputs: HashMap<_, _> = game_ids
+            .iter()
             .map(|game_id| (*game_id, Vec::new()))
             .collect();
 
         for player in self.players.values_mut() {
+            if !game_ids.contains(&player.game_id) {
+                continue;
+            }
+
+            let player_id = if let Some(player_id) = player.player_id {
+                player_id
+            } else {
+                continue;
+            };
             let game = &self.games[&player.game_id].state;
 
             // We explicitly buffer player inputs for some time, so that we can
@@ -609,7 +2246,7 @@ impl Runner {
                     .input_delay
                     .record((game.tick_num.0 - oldest_tick_num.0) as f32);
 
-                player_tick_inputs.push((player.player_id, oldest_tick_num, oldest_input));
+                player_tick_inputs.push((player_id, oldest_tick_num, oldest_input));
                 player.inputs.pop();
             }
 
@@ -617,9 +2254,9 @@ impl Runner {
                 // We did not receive the matching input in time, just reuse the
                 // previous one.
                 if let Some((last_input_num, last_input)) = player.last_input.clone() {
-                    debug!("Reusing input for player {:?}", player.player_id);
+                    debug!("Reusing input for player {:?}", player_id);
 
-                    player_tick_inputs.push((player.player_id, last_input_num.next(), last_input));
+                    player_tick_inputs.push((player_id, last_input_num.next(), last_input));
                 }
             }
 
@@ -640,63 +2277,15 @@ impl Runner {
         let mut state = game.state.clone();
         game.prepare_state_for_player(player.player_id, &mut state);
 
-        let mut events = vec![(game.state.tick_num, game.last_events.clone())];
-
-        // Attempt to do delta encoding w.r.t. a previous state if
-        // possible.
-        let ack_num_and_sent_state = player
-            .last_ack_tick
-            .and_then(|ack_num| {
-                player
-                    .last_sent
-                    .front()
-                    .as_ref()
-                    .map(|(_, sent_state)| (ack_num, sent_state))
-            })
-            .filter(|(ack_num, sent_state)| {
-                *ack_num == sent_state.tick_num && ack_num.0 + MAX_DIFF_TICKS > state.tick_num.0
-            });
-
-        let (diff_base, diff) = if let Some((ack_num, sent_state)) = ack_num_and_sent_state {
-            // Okay, we know that the player has acknowledged a tick for which
-            // we also still have the state. We can use this state as the basis
-            // for delta encoding.
-
-            // Re-send all the events that happened since the base tick.
-            for (sent_events, sent_state) in player.last_sent.iter() {
-                if !sent_events.is_empty() {
-                    events.push((sent_state.tick_num, sent_events.clone()));
-                }
-            }
+        let (diff_base, diff, events) = player.sent_ticks.send(state, game.last_events.clone());
 
-            (Some(ack_num), sent_state.diff(&state))
-        } else {
-            // We cannot do delta encoding.
+        if diff_base.is_none() {
             info!(
                 "Sending tick {:?} from scratch to {:?} (last ack: {:?})",
-                game.state.tick_num, player.player_id, player.last_ack_tick,
-            );
-            let base_state = comn::Game::new(game.state.settings.clone());
-            (None, base_state.diff(&state))
-        };
-
-        // Remember the state we're sending, so that we may use it as the basis
-        // for delta encoding in the future (assuming that we will receive the
-        // client's receival acknowledgement).
-        player
-            .last_sent
-            .push_back((game.last_events.clone(), state.clone()));
-
-        // Prune the state memory. This should be rarely necessary, since we
-        // already prune states when we receive acknowledgements.
-        if player.last_sent.len() > MAX_DIFF_TICKS as usize {
-            /*warn!(
-                "Player {:?}'s state memory grew too long ({}), pruning",
+                game.state.tick_num,
                 player.player_id,
-                player.last_sent.len(),
-            );*/
-
-            player.last_sent.pop_front();
+                player.sent_ticks.last_ack_tick(),
+            );
         }
 
         comn::Tick {
@@ -704,6 +2293,42 @@ impl Runner {
             diff,
             events,
             your_last_input_num: player.last_input.clone().map(|(num, _)| num),
+            send_interval: player.send_interval,
         }
     }
+
+    /// Adapts how often we send ticks to the player, given the loss and the
+    /// bandwidth of their connection.
+    fn update_send_interval(player: &mut Player, now: Instant) {
+        let loss = player.ping.loss();
+        let acked = player.acked_tick_fraction(now);
+        let elapsed = now.duration_since(player.send_interval_time);
+
+        let is_poor =
+            loss > SEND_INTERVAL_POOR_LOSS || acked.map_or(false, |a| a < SEND_INTERVAL_POOR_ACKED);
+        let is_good =
+            loss < SEND_INTERVAL_GOOD_LOSS && acked.map_or(true, |a| a > SEND_INTERVAL_GOOD_ACKED);
+
+        let send_interval = if is_poor
+            && player.send_interval < MAX_SEND_INTERVAL
+            && elapsed >= SEND_INTERVAL_INCREASE_DELAY
+        {
+            player.send_interval + 1
+        } else if is_good && player.send_interval > 1 && elapsed >= SEND_INTERVAL_DECREASE_DELAY {
+            player.send_interval - 1
+        } else {
+            return;
+        };
+
+        debug!(
+            "Sending every {} ticks to {:?} (loss: {}, acked: {:?})",
+            send_interval, player.name, loss, acked,
+        );
+
+        player.send_interval = send_interval;
+        player.send_interval_time = now;
+
+        // The sizes that we recorded so far belong to the previous rate.
+        player.sent_tick_sizes.clear();
+    }
 }
@@ -0,0 +1,192 @@
+//! State that is shared by all games: player accounts, leaderboards and bans.
+//!
+//! In a cluster, only the front door keeps this state, so that players see
+//! the same accounts and bans no matter which worker runs their game. Joins
+//! are checked against the accounts before they reach the runner, and the
+//! runner reports what happens in the games as `Update`s, which are applied
+//! wherever the state is kept.
+
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, time};
+
+use crate::{
+    admin,
+    auth::{self, Accounts},
+    leaderboard, rating,
+};
+
+pub const SAVE_PERIOD: Duration = Duration::from_secs(10);
+
+/// A joining player, as determined from the join request by whoever keeps
+/// the accounts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub name: String,
+
+    /// The account that the player logged in with, if any.
+    pub account: Option<String>,
+
+    /// Skill rating. Guests start with the initial rating every time they
+    /// join.
+    pub rating: f32,
+}
+
+/// Changes to the profile of an account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StatsChange {
+    Catch,
+    FoodEaten(u64),
+    Rating(f32),
+    GamePlayed,
+}
+
+/// Something that the runner reports about the games.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Update {
+    Stats {
+        account: String,
+        change: StatsChange,
+    },
+    MatchResult(leaderboard::MatchResult),
+    Ban(IpAddr),
+    Unban(IpAddr),
+}
+
+impl Update {
+    pub fn changes_bans(&self) -> bool {
+        match self {
+            Update::Ban(_) | Update::Unban(_) => true,
+            _ => false,
+        }
+    }
+}
+
+pub type UpdateTx = mpsc::UnboundedSender<Update>;
+pub type UpdateRx = mpsc::UnboundedReceiver<Update>;
+
+pub fn update_channel() -> (UpdateTx, UpdateRx) {
+    mpsc::unbounded_channel()
+}
+
+#[derive(Clone)]
+pub struct Shared {
+    pub accounts: Option<Arc<Accounts>>,
+    pub leaderboard: Option<Arc<Mutex<leaderboard::Store>>>,
+
+    /// On workers, this is a copy of the bans of the front door.
+    pub bans: Arc<admin::Bans>,
+}
+
+impl Shared {
+    /// Determines the name that a joining player will have. Players that are
+    /// logged in get the name of their account, while names of other
+    /// players need to be valid and not be reserved by an account.
+    pub fn identify(&self, request: &comn::JoinRequest) -> Result<Identity, comn::JoinError> {
+        if let Some(session_token) = request.session_token.as_ref() {
+            let accounts = self
+                .accounts
+                .as_ref()
+                .ok_or(comn::JoinError::InvalidSessionToken)?;
+            let account = accounts
+                .verify_session_token(session_token)
+                .ok_or(comn::JoinError::InvalidSessionToken)?;
+            let rating = accounts
+                .stats(&account)
+                .map_or(rating::INITIAL_RATING, |stats| stats.rating);
+
+            return Ok(Identity {
+                name: account.clone(),
+                account: Some(account),
+                rating,
+            });
+        }
+
+        let name = request.player_name.trim();
+
+        if !auth::is_valid_player_name(name) {
+            info!("Player name {:?} is invalid", name);
+            return Err(comn::JoinError::InvalidPlayerName);
+        }
+
+        if self
+            .accounts
+            .as_ref()
+            .map_or(false, |accounts| accounts.is_registered(name))
+        {
+            info!("Player name {:?} is reserved", name);
+            return Err(comn::JoinError::ReservedPlayerName);
+        }
+
+        Ok(Identity {
+            name: name.to_string(),
+            account: None,
+            rating: rating::INITIAL_RATING,
+        })
+    }
+
+    pub fn apply(&self, update: Update) {
+        match update {
+            Update::Stats { account, change } => {
+                if let Some(accounts) = self.accounts.as_ref() {
+                    accounts.update_stats(&account, |stats| match change {
+                        StatsChange::Catch => stats.catches += 1,
+                        StatsChange::FoodEaten(amount) => stats.food_eaten += amount,
+                        StatsChange::Rating(rating) => stats.rating = rating,
+                        StatsChange::GamePlayed => stats.games_played += 1,
+                    });
+                }
+            }
+            Update::MatchResult(result) => {
+                if let Some(leaderboard) = self.leaderboard.as_ref() {
+                    leaderboard.lock().unwrap().record(result);
+                }
+            }
+            Update::Ban(ip) => self.bans.insert(ip),
+            Update::Unban(ip) => {
+                self.bans.remove(ip);
+            }
+        }
+    }
+
+    /// Saves the profiles, the match results that have not been saved yet
+    /// and the bans.
+    pub fn save(&self) {
+        if let Some(accounts) = self.accounts.as_ref() {
+            accounts.save();
+        }
+        if let Some(leaderboard) = self.leaderboard.as_ref() {
+            leaderboard::save(leaderboard);
+        }
+        self.bans.save();
+    }
+
+    /// Regularly saves the profiles, the leaderboard and the bans, in case
+    /// we do not shut down cleanly.
+    pub async fn save_periodically(self) {
+        let mut interval = time::interval(SAVE_PERIOD);
+
+        loop {
+            interval.tick().await;
+
+            // Writing the files may take a while, so it should not block the
+            // other tasks.
+            let shared = self.clone();
+            let _ = tokio::task::spawn_blocking(move || shared.save()).await;
+        }
+    }
+
+    /// Applies the updates of a runner in this process until the runner has
+    /// stopped.
+    pub async fn apply_updates(self, mut update_rx: UpdateRx) {
+        while let Some(update) = update_rx.recv().await {
+            self.apply(update);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use rand::seq::SliceRandom;
+
+use comn::{game::run::TURRET_RANGE, geom::Ray, DangerGuy, Entity, Game, Point};
+
+/// Distance to the catcher beyond which we stop caring about it.
+const CATCHER_SAFE_DISTANCE: f32 = 1200.0;
+
+/// Distance to other players beyond which we stop caring about them.
+const PLAYER_SAFE_DISTANCE: f32 = 600.0;
+
+/// Distance to a `DangerGuy`'s path that we consider to be unsafe.
+const DANGER_GUY_SAFE_DISTANCE: f32 = 150.0;
+
+const CATCHER_WEIGHT: f32 = 3.0;
+const PLAYER_WEIGHT: f32 = 1.0;
+const TURRET_PENALTY: f32 = 2.0;
+const DANGER_GUY_PENALTY: f32 = 4.0;
+
+/// Chooses the spawn point that is least dangerous for a newly spawning
+/// player. `taken` contains positions at which other players are being
+/// spawned in the same tick, so that we can spread them out as well.
+///
+/// Spawn points are shuffled before scoring, so that ties are broken
+/// randomly.
+pub fn choose_spawn_point(state: &Game, taken: &[Point]) -> Point {
+    let mut candidates = state.settings.map.spawn_points.clone();
+    candidates.shuffle(&mut rand::thread_rng());
+
+    candidates
+        .into_iter()
+        .map(|pos| (pos, score_spawn_point(state, taken, pos)))
+        .max_by(|(_, score1), (_, score2)| score1.partial_cmp(score2).unwrap())
+        .map(|(pos, _)| pos)
+        .expect("Map has no spawn points")
+}
+
+fn score_spawn_point(state: &Game, taken: &[Point], pos: Point) -> f32 {
+    let mut score = 0.0;
+
+    // Stay away from the catcher
+    let catcher_pos = state
+        .catcher
+        .and_then(|catcher| state.get_player_entity(catcher))
+        .map(|(_, ent)| ent.pos);
+    if let Some(catcher_pos) = catcher_pos {
+        score += CATCHER_WEIGHT * safety((catcher_pos - pos).norm(), CATCHER_SAFE_DISTANCE);
+    } else {
+        score += CATCHER_WEIGHT;
+    }
+
+    // Stay away from other players
+    let min_player_dist = state
+        .entities
+        .values()
+        .filter_map(|entity| entity.player().ok().map(|ent| ent.pos))
+        .chain(taken.iter().copied())
+        .map(|other_pos| (other_pos - pos).norm())
+        .min_by(|dist1, dist2| dist1.partial_cmp(dist2).unwrap());
+    score += PLAYER_WEIGHT * min_player_dist.map_or(1.0, |dist| safety(dist, PLAYER_SAFE_DISTANCE));
+
+    // Avoid being shot right away or walking into danger
+    for (entity_id, entity) in state.entities.iter() {
+        match entity {
+            Entity::Turret(turret) => {
+                if (turret.pos - pos).norm() > TURRET_RANGE {
+                    continue;
+                }
+
+                let ray = Ray {
+                    origin: turret.pos,
+                    dir: pos - turret.pos,
+                };
+                let blocked = Game::trace_ray(
+                    &ray,
+                    state.game_time(),
+                    state.entities.iter().filter(|(between_id, between)| {
+                        *between_id != entity_id && between.is_wall_like()
+                    }),
+                )
+                .map_or(false, |(t, _, _)| t <= 1.0);
+
+                if !blocked {
+                    score -= TURRET_PENALTY;
+                }
+            }
+            Entity::DangerGuy(danger_guy) => {
+                if danger_guy_path_dist(danger_guy, pos) < DANGER_GUY_SAFE_DISTANCE {
+                    score -= DANGER_GUY_PENALTY;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    score
+}
+
+/// Maps a distance to a safety value in `[0, 1]`, saturating at
+/// `safe_dist`.
+fn safety(dist: f32, safe_dist: f32) -> f32 {
+    (dist / safe_dist).min(1.0)
+}
+
+/// Distance from `pos` to the area swept by a `DangerGuy` walking along its
+/// path.
+fn danger_guy_path_dist(danger_guy: &DangerGuy, pos: Point) -> f32 {
+    let delta = danger_guy.end_pos - danger_guy.start_pos;
+    let t = if delta.norm_squared() > 0.0 {
+        ((pos - danger_guy.start_pos).dot(&delta) / delta.norm_squared())
+            .max(0.0)
+            .min(1.0)
+    } else {
+        0.0
+    };
+    let closest = danger_guy.start_pos + t * delta;
+
+    ((pos - closest).norm() - danger_guy.size.norm() / 2.0).max(0.0)
+}
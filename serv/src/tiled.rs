@@ -49,7 +49,13 @@ pub fn load_map<P: AsRef<Path>>(path: P) -> Result<comn::Map, LoadError> {
         })
         .collect();
 
+    let name = path
+        .as_ref()
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+
     Ok(comn::Map {
+        name,
         spawn_points,
         entities: entities?,
         size,
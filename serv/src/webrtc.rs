@@ -1,16 +1,30 @@
-use std::{net::SocketAddr, time::Instant};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use log::{info, warn};
 
 use futures::{select, FutureExt};
 use tokio::sync::{mpsc, oneshot};
 
+use comn::util::fragment;
+
+use crate::fake_bad_net::{self, Direction, FakeBadNet};
+
+/// Reassembly state of peers that we have not heard from for this long is
+/// dropped.
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 pub struct MessageIn {
     pub peer: SocketAddr,
     pub data: Vec<u8>,
     pub recv_time: Instant,
 }
 
+#[derive(Clone)]
 pub struct MessageOut {
     pub peer: SocketAddr,
     pub data: Vec<u8>,
@@ -40,6 +54,23 @@ pub struct Server {
     send_message_rx: SendMessageRx,
 
     webrtc_server: webrtc_unreliable::Server,
+
+    /// Splits messages that are too large for a single datagram.
+    splitter: fragment::Splitter,
+
+    /// Reassembles the messages of each peer, together with the time at
+    /// which we last received a datagram from the peer.
+    reassemblers: HashMap<SocketAddr, (Instant, fragment::Reassembler)>,
+    last_prune_time: Instant,
+
+    /// If we simulate bad network conditions, datagrams take a detour
+    /// through a `FakeBadNet` in each direction, so that losing a single
+    /// fragment loses the whole message.
+    fake_bad_net: Option<(SendMessageTx, RecvMessageTx)>,
+
+    /// Datagrams coming back from the `FakeBadNet`.
+    delayed_send_datagrams: (SendMessageTx, SendMessageRx),
+    delayed_recv_datagrams: (RecvMessageTx, RecvMessageRx),
 }
 
 impl Server {
@@ -47,6 +78,7 @@ impl Server {
         config: Config,
         recv_message_tx: RecvMessageTx,
         send_message_rx: SendMessageRx,
+        fake_bad_net: Option<fake_bad_net::SharedProfiles>,
     ) -> Result<Self, std::io::Error> {
         // Note that the `webrtc_unreliable::Server` actually takes two
         // addresses: the listen address and the public address. In practice,
@@ -58,10 +90,43 @@ impl Server {
         let webrtc_server =
             webrtc_unreliable::Server::new(config.listen_addr, config.listen_addr).await?;
 
+        let delayed_send_datagrams = send_message_channel();
+        let delayed_recv_datagrams = recv_message_channel();
+        let fake_bad_net = fake_bad_net.map(|profiles| {
+            let (send_datagram_tx, send_datagram_rx) = send_message_channel();
+            let (recv_datagram_tx, recv_datagram_rx) = recv_message_channel();
+            tokio::spawn(
+                FakeBadNet::new(
+                    profiles.clone(),
+                    Direction::Outgoing,
+                    send_datagram_rx,
+                    delayed_send_datagrams.0.clone(),
+                )
+                .run(),
+            );
+            tokio::spawn(
+                FakeBadNet::new(
+                    profiles,
+                    Direction::Incoming,
+                    recv_datagram_rx,
+                    delayed_recv_datagrams.0.clone(),
+                )
+                .run(),
+            );
+
+            (send_datagram_tx, recv_datagram_tx)
+        });
+
         Ok(Self {
             recv_message_tx,
             send_message_rx,
             webrtc_server,
+            splitter: fragment::Splitter::default(),
+            reassemblers: HashMap::new(),
+            last_prune_time: Instant::now(),
+            fake_bad_net,
+            delayed_send_datagrams,
+            delayed_recv_datagrams,
         })
     }
 
@@ -69,10 +134,107 @@ impl Server {
         self.webrtc_server.session_endpoint()
     }
 
+    async fn send(&mut self, message_out: MessageOut) {
+        let datagrams = match self.splitter.split(&message_out.data) {
+            Ok(datagrams) => datagrams,
+            Err(fragment::TooLarge(size)) => {
+                warn!(
+                    "Message of {} bytes to {} is too large, dropping",
+                    size, message_out.peer,
+                );
+                return;
+            }
+        };
+
+        for datagram in datagrams {
+            let datagram_out = MessageOut {
+                peer: message_out.peer,
+                data: datagram,
+            };
+
+            if let Some((send_datagram_tx, _)) = self.fake_bad_net.as_ref() {
+                if send_datagram_tx.send(datagram_out).is_err() {
+                    warn!("send_datagram_tx closed, dropping datagram");
+                }
+            } else if !self.send_datagram(datagram_out).await {
+                return;
+            }
+        }
+    }
+
+    /// Sends a single datagram. Returns false if sending failed.
+    async fn send_datagram(&mut self, datagram_out: MessageOut) -> bool {
+        if let Err(err) = self
+            .webrtc_server
+            .send(
+                &datagram_out.data,
+                webrtc_unreliable::MessageType::Binary,
+                &datagram_out.peer,
+            )
+            .await
+        {
+            warn!("Failed to send message to {}: {}", datagram_out.peer, err);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Handles a datagram that we received. Once all the fragments of a
+    /// message have been received, the message is passed on. Returns false if
+    /// the receiver of the messages is gone.
+    fn recv(&mut self, datagram_in: MessageIn) -> bool {
+        // Large messages are split into fragments, so we may need to wait for
+        // more datagrams.
+        if let Some(data) =
+            self.reassemble(datagram_in.peer, datagram_in.recv_time, &datagram_in.data)
+        {
+            let message_in = MessageIn {
+                peer: datagram_in.peer,
+                data,
+                recv_time: datagram_in.recv_time,
+            };
+            if self.recv_message_tx.send(message_in).is_err() {
+                info!("recv_message_tx closed, terminating");
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Returns the message once all of its fragments have been received.
+    fn reassemble(&mut self, peer: SocketAddr, now: Instant, datagram: &[u8]) -> Option<Vec<u8>> {
+        if now.duration_since(self.last_prune_time) >= PEER_TIMEOUT {
+            self.reassemblers.retain(|_, (last_recv_time, _)| {
+                now.duration_since(*last_recv_time) < PEER_TIMEOUT
+            });
+            self.last_prune_time = now;
+        }
+
+        let (last_recv_time, reassembler) = self
+            .reassemblers
+            .entry(peer)
+            .or_insert_with(|| (now, fragment::Reassembler::default()));
+        *last_recv_time = now;
+
+        match reassembler.recv(now, datagram) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!(
+                    "Received invalid datagram from {} ({:?}), ignoring",
+                    peer, err
+                );
+                None
+            }
+        }
+    }
+
     pub async fn serve(mut self, shutdown_rx: oneshot::Receiver<()>) {
         let mut shutdown_rx = shutdown_rx.fuse();
 
-        // TODO: Check size of `message_buf` for receiving WebRTC messages
+        // Valid datagrams are never larger than `fragment::MAX_DATAGRAM_SIZE`,
+        // so this is more than enough. The reassembler rejects larger ones.
         let mut message_buf = vec![0; 0x10000];
 
         loop {
@@ -80,19 +242,7 @@ impl Server {
                 message_out = self.send_message_rx.recv().fuse() => {
                     match message_out {
                         Some(message_out) => {
-                            if let Err(err) = self.webrtc_server.send(
-                                    &message_out.data,
-                                    webrtc_unreliable::MessageType::Binary,
-                                    &message_out.peer,
-                                )
-                                .await
-                            {
-                                warn!(
-                                    "Failed to send message to {}: {}",
-                                    message_out.peer,
-                                    err,
-                                );
-                            }
+                            self.send(message_out).await;
                         }
                         None => {
                             info!("send_message_rx closed, terminating");
@@ -100,16 +250,25 @@ impl Server {
                         }
                     }
                 }
+                datagram_out = self.delayed_send_datagrams.1.recv().fuse() => {
+                    if let Some(datagram_out) = datagram_out {
+                        self.send_datagram(datagram_out).await;
+                    }
+                }
                 message_result = self.webrtc_server.recv(&mut message_buf).fuse() => {
                     match message_result {
                         Ok(message_result) => {
-                            let message_in = MessageIn {
+                            let datagram_in = MessageIn {
                                 peer: message_result.remote_addr,
                                 data: message_buf[0..message_result.message_len].to_vec(),
                                 recv_time: Instant::now(),
                             };
-                            if self.recv_message_tx.send(message_in).is_err() {
-                                info!("recv_message_tx closed, terminating");
+
+                            if let Some((_, recv_datagram_tx)) = self.fake_bad_net.as_ref() {
+                                if recv_datagram_tx.send(datagram_in).is_err() {
+                                    warn!("recv_datagram_tx closed, dropping datagram");
+                                }
+                            } else if !self.recv(datagram_in) {
                                 return;
                             }
                         }
@@ -118,6 +277,13 @@ impl Server {
                         }
                     }
                 }
+                datagram_in = self.delayed_recv_datagrams.1.recv().fuse() => {
+                    if let Some(datagram_in) = datagram_in {
+                        if !self.recv(datagram_in) {
+                            return;
+                        }
+                    }
+                }
                 _ = shutdown_rx => {
                     return;
                 }
@@ -0,0 +1,150 @@
+//! A pool of threads for running the ticks of games in parallel.
+//!
+//! Games are moved to the workers for the duration of a tick, and then
+//! moved back to the runner. Everything that involves players, such as
+//! receiving messages and sending ticks, stays on the runner thread, so
+//! that messages to each player remain in order.
+//!
+//! The runner does not wait for the workers. Each game is started as soon
+//! as it is due, and its result is picked up once it is done, so a slow
+//! game does not hold up the others.
+
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::game::Game;
+
+pub struct TickJob {
+    pub game_id: comn::GameId,
+    pub game: Game,
+    pub inputs: Vec<(comn::PlayerId, comn::TickNum, comn::Input)>,
+}
+
+pub struct TickResult {
+    pub game_id: comn::GameId,
+    pub game: Game,
+
+    /// Time that it took to run the tick.
+    pub duration: Duration,
+}
+
+/// A tick that panicked. The game is lost, since it may have been left in
+/// an inconsistent state.
+pub struct TickPanic {
+    pub game_id: comn::GameId,
+    pub message: String,
+}
+
+pub type Reply = Result<TickResult, TickPanic>;
+
+pub struct Pool {
+    job_tx: Option<mpsc::Sender<TickJob>>,
+    reply_rx: mpsc::Receiver<Reply>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Pool {
+    pub fn new(num_threads: usize) -> Self {
+        assert!(num_threads > 0);
+
+        let (job_tx, job_rx) = mpsc::channel::<TickJob>();
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        info!("Starting {} worker threads", num_threads);
+
+        let threads = (0..num_threads)
+            .map(|i| {
+                let job_rx = job_rx.clone();
+                let reply_tx = reply_tx.clone();
+
+                thread::Builder::new()
+                    .name(format!("worker{}", i))
+                    .spawn(move || loop {
+                        let job = job_rx.lock().unwrap().recv();
+                        let job = if let Ok(job) = job {
+                            job
+                        } else {
+                            // The pool has been dropped.
+                            return;
+                        };
+
+                        // If a game panics, we tell the runner thread, so
+                        // that it can remove the game and keep running the
+                        // others.
+                        let game_id = job.game_id;
+                        let reply = panic::catch_unwind(AssertUnwindSafe(move || {
+                            let mut game = job.game;
+                            let start_time = Instant::now();
+                            game.run_tick(&job.inputs);
+
+                            TickResult {
+                                game_id: job.game_id,
+                                game,
+                                duration: start_time.elapsed(),
+                            }
+                        }))
+                        .map_err(|payload| TickPanic {
+                            game_id,
+                            message: panic_message(payload),
+                        });
+
+                        if reply_tx.send(reply).is_err() {
+                            return;
+                        }
+                    })
+                    .expect("could not spawn worker thread")
+            })
+            .collect();
+
+        Self {
+            job_tx: Some(job_tx),
+            reply_rx,
+            threads,
+        }
+    }
+
+    /// Starts running a tick of the given game. The result can be taken
+    /// with `try_recv` or `recv_timeout` once it is done.
+    pub fn start_tick(&self, job: TickJob) {
+        self.job_tx.as_ref().unwrap().send(job).unwrap();
+    }
+
+    /// Returns the result of a tick that is done, if any, without waiting.
+    pub fn try_recv(&self) -> Option<Reply> {
+        self.reply_rx.try_recv().ok()
+    }
+
+    /// Waits for the result of a tick, but for at most `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Reply> {
+        self.reply_rx.recv_timeout(timeout).ok()
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // Closing the channel stops the workers.
+        self.job_tx = None;
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
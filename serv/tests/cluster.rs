@@ -0,0 +1,165 @@
+//! Runs a front door and a game worker as separate processes, the way they
+//! are deployed.
+
+use std::{
+    fs,
+    net::{TcpListener, UdpSocket},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use hyper::{Body, Client, Request, StatusCode};
+use uuid::Uuid;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Kills the server process when the test ends, even if it fails.
+struct Server(Child);
+
+impl Server {
+    fn start(args: &[&str]) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_serv"))
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start serv");
+
+        Server(child)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_tcp_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn free_udp_addr() -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().to_string()
+}
+
+fn write_secret_file() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("serv-cluster-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("cluster_secret");
+    fs::write(&path, "not-so-secret\n").unwrap();
+    path
+}
+
+/// Sends a POST request, returning `None` if the server is not up yet.
+async fn post(addr: &str, path: &str, body: String) -> Option<(StatusCode, String)> {
+    let request = Request::post(format!("http://{}{}", addr, path))
+        .body(Body::from(body))
+        .unwrap();
+    let response = Client::new().request(request).await.ok()?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.ok()?;
+
+    Some((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn requests() -> Vec<(&'static str, String)> {
+    let join_request = comn::JoinRequest {
+        game_id: None,
+        player_name: "Pioneer".to_string(),
+        spectate: false,
+        invite_code: None,
+        session_token: None,
+        protocol_version: comn::PROTOCOL_VERSION,
+    };
+    let reconnect_request = comn::ReconnectRequest {
+        token: comn::PlayerToken(Uuid::new_v4()),
+        protocol_version: comn::PROTOCOL_VERSION,
+    };
+    let create_game_request = comn::CreateGameRequest { private: false };
+
+    vec![
+        ("/join", serde_json::to_string(&join_request).unwrap()),
+        (
+            "/reconnect",
+            serde_json::to_string(&reconnect_request).unwrap(),
+        ),
+        (
+            "/create_game",
+            serde_json::to_string(&create_game_request).unwrap(),
+        ),
+    ]
+}
+
+#[tokio::test]
+async fn workers_only_accept_players_through_the_front_door() {
+    let secret_file = write_secret_file();
+    let secret_file = secret_file.to_str().unwrap();
+    let map = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../maps/test.tmx");
+
+    let front_http_addr = free_tcp_addr();
+    let cluster_addr = free_tcp_addr();
+    let worker_http_addr = free_tcp_addr();
+    let worker_webrtc_addr = free_udp_addr();
+    let session_url = format!("http://{}/connect_webrtc", worker_http_addr);
+
+    let _front = Server::start(&[
+        "--role",
+        "front",
+        "--http_address",
+        &front_http_addr,
+        "--cluster_address",
+        &cluster_addr,
+        "--cluster_secret_file",
+        secret_file,
+    ]);
+    let _worker = Server::start(&[
+        "--role",
+        "worker",
+        "--http_address",
+        &worker_http_addr,
+        "--webrtc_address",
+        &worker_webrtc_addr,
+        "--cluster_address",
+        &cluster_addr,
+        "--cluster_secret_file",
+        secret_file,
+        "--session_url",
+        &session_url,
+        "--map",
+        map.to_str().unwrap(),
+    ]);
+
+    // Once the worker has connected, the front door creates games on it.
+    let start_time = Instant::now();
+    let (_, create_game_body) = requests().pop().unwrap();
+    loop {
+        match post(&front_http_addr, "/create_game", create_game_body.clone()).await {
+            Some((StatusCode::OK, body)) if body.starts_with(r#"{"Ok""#) => break,
+            _ => {
+                assert!(
+                    start_time.elapsed() < STARTUP_TIMEOUT,
+                    "The worker did not connect to the front door",
+                );
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    // Requests that go to the worker directly would bypass the checks of the
+    // front door.
+    for (path, body) in requests() {
+        let (status, _) = post(&worker_http_addr, path, body)
+            .await
+            .expect("The worker is not reachable");
+
+        assert_eq!(status, StatusCode::NOT_FOUND, "{} was accepted", path);
+    }
+
+    let _ = fs::remove_file(secret_file);
+}